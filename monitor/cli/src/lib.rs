@@ -1,10 +1,16 @@
+pub mod thread_log;
+
 use std::env;
 
 use dotenv::dotenv;
 use jane_eyre::eyre;
 use settings::{IMAGE_DEPS_DIR, LIB_MONITOR_DIR};
 use tracing::info;
-use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    EnvFilter, Layer, filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+use crate::thread_log::{ThreadLogWriter, has_thread_log};
 
 pub fn init() -> eyre::Result<()> {
     init_logging_only()?;
@@ -30,6 +36,12 @@ pub fn init_logging_only() -> Result<(), eyre::Error> {
     }
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(|| ThreadLogWriter)
+                .with_filter(filter_fn(|_| has_thread_log())),
+        )
         .with(EnvFilter::builder().from_env_lossy())
         .init();
 
@@ -0,0 +1,52 @@
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+/// A log file shared by one or more threads, such as the threads for an image rebuild.
+pub type ThreadLog = Arc<Mutex<File>>;
+
+thread_local! {
+    static THREAD_LOG: RefCell<Option<ThreadLog>> = const { RefCell::new(None) };
+}
+
+/// Copies all logs from the current thread to the given file, or stops doing so if None.
+pub fn set_thread_log(log: Option<ThreadLog>) {
+    THREAD_LOG.with_borrow_mut(|thread_log| *thread_log = log);
+}
+
+/// Returns the file that logs from the current thread are being copied to, if any.
+pub fn thread_log() -> Option<ThreadLog> {
+    THREAD_LOG.with_borrow(|thread_log| thread_log.clone())
+}
+
+pub(crate) fn has_thread_log() -> bool {
+    THREAD_LOG.with_borrow(|thread_log| thread_log.is_some())
+}
+
+/// Writer for the logging layer that copies logs to the file for the current thread.
+pub(crate) struct ThreadLogWriter;
+
+impl Write for ThreadLogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        THREAD_LOG.with_borrow(|thread_log| match thread_log {
+            Some(file) => file
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .write(buf),
+            None => Ok(buf.len()),
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        THREAD_LOG.with_borrow(|thread_log| match thread_log {
+            Some(file) => file
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .flush(),
+            None => Ok(()),
+        })
+    }
+}
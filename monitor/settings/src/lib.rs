@@ -1,6 +1,7 @@
 pub mod data;
 pub mod profile;
 pub mod queue;
+pub mod storage;
 pub mod units;
 
 use std::{
@@ -17,7 +18,12 @@ use chrono::TimeDelta;
 use jane_eyre::eyre::{self, bail};
 use serde::Deserialize;
 
-use crate::{profile::Profile, queue::QueueConfig, units::MemorySize};
+use crate::{
+    profile::{Profile, SupportedProfile, SUPPORTED_PROFILES},
+    queue::QueueConfig,
+    storage::StorageConfig,
+    units::MemorySize,
+};
 
 pub static LIB_MONITOR_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     if let Some(lib_monitor_dir) = env::var_os("LIB_MONITOR_DIR") {
@@ -32,6 +38,10 @@ pub static IMAGE_DEPS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     PathBuf::from(&image_deps_dir)
 });
 
+/// Where to serve noVNC from, for live consoles, if it’s installed.
+pub static NOVNC_DIR: LazyLock<Option<PathBuf>> =
+    LazyLock::new(|| env::var_os("NOVNC_DIR").map(PathBuf::from));
+
 pub static DOTENV: LazyLock<Dotenv> = LazyLock::new(|| {
     #[cfg(not(any(test, feature = "test")))]
     // FIXME: ensure that this is called before any other threads are started.
@@ -63,6 +73,7 @@ pub struct Dotenv {
 #[derive(Deserialize)]
 pub struct Toml {
     pub listen_on: Vec<String>,
+    listen_port: Option<u16>,
     pub external_base_url: String,
     pub github_api_scope: String,
     pub allowed_qualified_repo_prefix: String,
@@ -80,13 +91,39 @@ pub struct Toml {
     pub main_repo_path: String,
     base_image_max_age: u64,
     dont_update_cached_servo_repo: Option<bool>,
+    main_repo_update_interval: Option<u64>,
+    smoke_test_timeout: Option<u64>,
+    memory_snapshot_timeout: Option<u64>,
+    rebuild_backoff_initial: Option<u64>,
+    rebuild_backoff_max: Option<u64>,
+    rebuild_max_failures: Option<usize>,
+    runner_heartbeat_timeout: Option<u64>,
+    runner_min_disk_free_gib: Option<u64>,
+    preserve_failed_runners: Option<bool>,
+    preserved_runners_max_size_gib: Option<u64>,
+    garbage_collection_interval: Option<u64>,
+    images_high_water_mark_percent: Option<u8>,
+    orphan_check_interval: Option<u64>,
+    orphan_min_age_hours: Option<u64>,
+    fix_orphans: Option<bool>,
+    runner_hold_ttl: Option<u64>,
+    runner_hold_ssh_destination: Option<String>,
+    screenshot_history_length: Option<usize>,
+    screenshot_interval: Option<u64>,
+    screenshot_concurrency: Option<usize>,
+    runner_create_concurrency: Option<usize>,
+    runner_destroy_concurrency: Option<usize>,
     libvirt_template_guest_prefix: Option<String>,
     libvirt_rebuild_guest_prefix: Option<String>,
     libvirt_runner_guest_prefix: Option<String>,
+    libvirt_images_path: Option<String>,
     pub available_1g_hugepages: usize,
     pub available_normal_memory: MemorySize,
+    pub available_disk: Option<MemorySize>,
     queue_member: Option<bool>,
     pub queue: Option<QueueConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
     profiles: BTreeMap<String, Profile>,
 }
 
@@ -170,6 +207,11 @@ impl Toml {
 
     #[cfg(any(test, feature = "test"))]
     fn load_for_tests() -> eyre::Result<Self> {
+        // The end-to-end tests run our binaries, which are built with this feature too, so give
+        // them a way to load their own settings.
+        if let Some(path) = env::var_os("SERVO_CI_MONITOR_TEST_TOML") {
+            return Self::load(path);
+        }
         let result: Toml = toml::from_str(include_str!("../../../monitor.toml.example"))?;
 
         result.validate()
@@ -185,11 +227,25 @@ impl Toml {
                 *key, profile.profile_name,
                 "Runner::profile_name relies on Toml.profiles key (profile name) and profile_name being equal"
             );
+            if SupportedProfile::get(&profile.profile_name).is_none() {
+                let supported_profile_names = SUPPORTED_PROFILES
+                    .iter()
+                    .map(|supported| supported.profile_name)
+                    .collect::<Vec<_>>();
+                bail!(
+                    "Profile {key} is not supported! Supported profiles: {}",
+                    supported_profile_names.join(", ")
+                );
+            }
         }
 
         Ok(self)
     }
 
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.unwrap_or(8000)
+    }
+
     pub fn monitor_poll_interval(&self) -> Duration {
         Duration::from_secs(self.monitor_poll_interval)
     }
@@ -240,6 +296,94 @@ impl Toml {
         self.dont_update_cached_servo_repo.unwrap_or(false)
     }
 
+    pub fn main_repo_update_interval(&self) -> Option<Duration> {
+        self.main_repo_update_interval.map(Duration::from_secs)
+    }
+
+    pub fn smoke_test_timeout(&self) -> Duration {
+        Duration::from_secs(self.smoke_test_timeout.unwrap_or(600))
+    }
+
+    pub fn memory_snapshot_timeout(&self) -> Duration {
+        Duration::from_secs(self.memory_snapshot_timeout.unwrap_or(600))
+    }
+
+    pub fn rebuild_backoff_initial(&self) -> Duration {
+        Duration::from_secs(self.rebuild_backoff_initial.unwrap_or(60))
+    }
+
+    pub fn rebuild_backoff_max(&self) -> Duration {
+        Duration::from_secs(self.rebuild_backoff_max.unwrap_or(3600))
+    }
+
+    pub fn rebuild_max_failures(&self) -> usize {
+        self.rebuild_max_failures.unwrap_or(5)
+    }
+
+    pub fn runner_heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.runner_heartbeat_timeout.unwrap_or(300))
+    }
+
+    pub fn runner_min_disk_free(&self) -> u64 {
+        self.runner_min_disk_free_gib.unwrap_or(5) * 1024 * 1024 * 1024
+    }
+
+    pub fn preserve_failed_runners(&self) -> bool {
+        self.preserve_failed_runners.unwrap_or(false)
+    }
+
+    pub fn preserved_runners_max_size(&self) -> u64 {
+        self.preserved_runners_max_size_gib.unwrap_or(100) * 1024 * 1024 * 1024
+    }
+
+    pub fn garbage_collection_interval(&self) -> Duration {
+        Duration::from_secs(self.garbage_collection_interval.unwrap_or(600))
+    }
+
+    pub fn images_high_water_mark_percent(&self) -> u8 {
+        self.images_high_water_mark_percent.unwrap_or(90)
+    }
+
+    pub fn orphan_check_interval(&self) -> Duration {
+        Duration::from_secs(self.orphan_check_interval.unwrap_or(600))
+    }
+
+    pub fn orphan_min_age(&self) -> Duration {
+        Duration::from_secs(self.orphan_min_age_hours.unwrap_or(24) * 3600)
+    }
+
+    pub fn fix_orphans(&self) -> bool {
+        self.fix_orphans.unwrap_or(false)
+    }
+
+    pub fn runner_hold_ttl(&self) -> Duration {
+        Duration::from_secs(self.runner_hold_ttl.unwrap_or(3600))
+    }
+
+    pub fn runner_hold_ssh_destination(&self) -> Option<&str> {
+        self.runner_hold_ssh_destination.as_deref()
+    }
+
+    pub fn screenshot_history_length(&self) -> usize {
+        self.screenshot_history_length.unwrap_or(200)
+    }
+
+    pub fn screenshot_interval(&self) -> Duration {
+        Duration::from_secs(self.screenshot_interval.unwrap_or(5))
+    }
+
+    pub fn screenshot_concurrency(&self) -> usize {
+        self.screenshot_concurrency.unwrap_or(4)
+    }
+
+    pub fn runner_create_concurrency(&self) -> usize {
+        self.runner_create_concurrency.unwrap_or(4)
+    }
+
+    pub fn runner_destroy_concurrency(&self) -> usize {
+        self.runner_destroy_concurrency.unwrap_or(8)
+    }
+
     pub fn queue_member(&self) -> bool {
         self.queue_member.unwrap_or(false)
     }
@@ -262,6 +406,14 @@ impl Toml {
             .unwrap_or("ci-runner")
     }
 
+    pub fn libvirt_images_path(&self) -> &Path {
+        Path::new(
+            self.libvirt_images_path
+                .as_deref()
+                .unwrap_or("/var/lib/libvirt/images"),
+        )
+    }
+
     pub fn initial_profiles(&self) -> BTreeMap<String, Profile> {
         self.profiles.clone()
     }
@@ -1,8 +1,64 @@
+use std::time::Duration;
+
+use bytesize::ByteSize;
 use jane_eyre::eyre::{self, OptionExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{TOML, units::MemorySize};
 
+/// Profiles that the monitor knows how to build images and create runners for.
+pub const SUPPORTED_PROFILES: &[SupportedProfile] = &[
+    SupportedProfile::new("servo-macos13", GuestOs::Macos13, 90, 2000),
+    SupportedProfile::new("servo-macos14", GuestOs::Macos13, 90, 2000),
+    SupportedProfile::new("servo-macos15", GuestOs::Macos13, 90, 2000),
+    SupportedProfile::new("servo-ubuntu2204", GuestOs::Ubuntu2204, 90, 2000),
+    SupportedProfile::new("servo-ubuntu2204-bench", GuestOs::Ubuntu2204, 90, 1000),
+    SupportedProfile::new("base-ubuntu2204", GuestOs::Ubuntu2204, 20, 90),
+    SupportedProfile::new("servo-ubuntu2204-wpt", GuestOs::Ubuntu2204, 90, 2000),
+    SupportedProfile::new("servo-windows10", GuestOs::Windows10, 90, 3000),
+];
+
+/// How the monitor builds images and creates runners for a supported profile.
+#[derive(Clone, Copy, Debug)]
+pub struct SupportedProfile {
+    pub profile_name: &'static str,
+    pub guest_os: GuestOs,
+    /// Size of the disk image that the base image is built on.
+    pub base_image_size: ByteSize,
+    /// How long to wait for the image rebuild guest to shut down.
+    pub rebuild_timeout: Duration,
+}
+
+/// Which guest OS a supported profile is built for, and hence which code builds its images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuestOs {
+    Macos13,
+    Ubuntu2204,
+    Windows10,
+}
+
+impl SupportedProfile {
+    const fn new(
+        profile_name: &'static str,
+        guest_os: GuestOs,
+        base_image_size_gib: u64,
+        rebuild_timeout_secs: u64,
+    ) -> Self {
+        Self {
+            profile_name,
+            guest_os,
+            base_image_size: ByteSize::gib(base_image_size_gib),
+            rebuild_timeout: Duration::from_secs(rebuild_timeout_secs),
+        }
+    }
+
+    pub fn get(profile_name: &str) -> Option<&'static Self> {
+        SUPPORTED_PROFILES
+            .iter()
+            .find(|supported| supported.profile_name == profile_name)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Profile {
     pub profile_name: String,
@@ -12,6 +68,29 @@ pub struct Profile {
     pub image_type: ImageType,
     pub requires_1g_hugepages: usize,
     pub requires_normal_memory: MemorySize,
+    /// If set, keep serving jobs with the old image while rebuilding the image, then replace idle
+    /// runners with new runners, keeping at least this many healthy runners at all times.
+    #[serde(default)]
+    pub rolling_upgrade_min_runners: Option<usize>,
+    /// Rebuild the image when any of these inputs change, not only when the image is too old.
+    #[serde(default)]
+    pub rebuild_triggers: Vec<RebuildTrigger>,
+    /// If set, check the job of any runner that has been busy for longer than this many seconds,
+    /// destroying the runner if the job has finished, or flagging it otherwise.
+    #[serde(default)]
+    pub max_busy_duration: Option<u64>,
+    /// Keep this many runners cloned but unregistered and shut off, so that bringing up a runner
+    /// only needs to register and start one. These count towards `available_disk`, but not
+    /// towards hugepages or memory.
+    #[serde(default)]
+    pub warm_pool_size: usize,
+    /// Disk space needed for each runner, warm runner, and image rebuild, if we should check it.
+    #[serde(default)]
+    pub requires_disk: MemorySize,
+    /// If set, save the RAM state of each new image once its boot script is waiting for a runner
+    /// token, and restore runners from that state instead of booting them.
+    #[serde(default)]
+    pub memory_snapshot: bool,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -20,7 +99,26 @@ pub enum ImageType {
     Rust,
 }
 
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum RebuildTrigger {
+    /// Files in the profile configuration directory, like `user-data`, `boot-script`, and `guest.xml`.
+    Configuration,
+    /// The commit checked out in our cached Servo repo (`main_repo_path`).
+    MainRepo,
+    /// Files in `IMAGE_DEPS_DIR`.
+    ImageDeps,
+}
+
 impl Profile {
+    pub fn uses_rolling_upgrades(&self) -> bool {
+        self.rolling_upgrade_min_runners.is_some()
+    }
+
+    pub fn max_busy_duration(&self) -> Option<Duration> {
+        self.max_busy_duration.map(Duration::from_secs)
+    }
+
     pub fn snapshot_path_slug(&self, snapshot_name: &str) -> String {
         format!("{}@{snapshot_name}", self.profile_name)
     }
@@ -41,6 +139,12 @@ impl Profile {
         )
     }
 
+    /// Label for the runner registered when smoke testing a new base image, which no jobs
+    /// should ever use.
+    pub fn smoke_test_runner_label(&self) -> String {
+        format!("{}-smoke-test", self.github_runner_label)
+    }
+
     pub fn runner_guest_name(&self, id: usize) -> String {
         format!(
             "{}-{}.{}",
@@ -3,4 +3,11 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Deserialize, Serialize)]
 pub struct QueueConfig {
     pub servers: Vec<String>,
+    listen_port: Option<u16>,
+}
+
+impl QueueConfig {
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.unwrap_or(8002)
+    }
 }
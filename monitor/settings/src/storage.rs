@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+
+/// How template and runner disk images are stored on this host, and how runner images are
+/// cloned from template images.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum StorageConfig {
+    /// Image files, cloned with reflink copies, or full copies if the filesystem doesn’t support
+    /// them (slow and big, but sparse).
+    #[default]
+    Reflink,
+    /// Image files, where runner images are qcow2 overlays backed by their template image. Works
+    /// on any filesystem, like ext4.
+    Qcow2,
+    /// ZFS volumes in the given dataset, where runner volumes are clones of their template volume.
+    Zfs { dataset: String },
+    /// LVM thin volumes in the given thin pool, where runner volumes are thin snapshots of their
+    /// template volume.
+    LvmThin {
+        volume_group: String,
+        thin_pool: String,
+    },
+}
@@ -288,6 +288,10 @@ async fn take_route(unique_id: String, token: String) -> rocket_eyre::Result<Raw
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     cli::init()?;
+    let config = TOML
+        .queue
+        .as_ref()
+        .ok_or_eyre("monitor.toml has no [queue]!")?;
 
     tokio::task::spawn(async move {
         let thread = thread::spawn(queue_thread);
@@ -310,7 +314,7 @@ async fn main() -> eyre::Result<()> {
     let rocket = |listen_addr: &str| {
         rocket::custom(
             rocket::Config::figment()
-                .merge(("port", 8002))
+                .merge(("port", config.listen_port()))
                 .merge(("address", listen_addr)),
         )
         .mount(
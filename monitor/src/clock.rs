@@ -0,0 +1,115 @@
+//! The current time, as seen by the monitor.
+//!
+//! Code that makes decisions based on how much time has passed should call [`now`], [`utc_now`],
+//! or [`instant`] instead of `SystemTime::now()`, `Utc::now()`, or `Instant::now()`, so that tests
+//! can replace the clock with a [`FakeClock`] and control time deterministically.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+
+pub trait Clock: Debug {
+    fn now(&self) -> SystemTime;
+    fn instant(&self) -> Instant;
+}
+
+/// The real clock, used unless the thread has been given another one.
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that starts at the real time, but only moves when told to.
+#[derive(Debug)]
+pub struct FakeClock {
+    start_time: SystemTime,
+    start_instant: Instant,
+    elapsed: Cell<Duration>,
+}
+
+impl FakeClock {
+    pub fn new() -> Self {
+        Self {
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+}
+
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.start_time + self.elapsed.get()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + self.elapsed.get()
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Option<Rc<dyn Clock>>> = RefCell::new(None);
+}
+
+/// Replaces the clock for the current thread, or restores the real clock if `None`.
+pub fn set_clock_for_thread(clock: Option<Rc<dyn Clock>>) {
+    CLOCK.set(clock);
+}
+
+pub fn now() -> SystemTime {
+    CLOCK.with_borrow(|clock| clock.as_ref().map_or_else(SystemTime::now, |c| c.now()))
+}
+
+pub fn utc_now() -> DateTime<Utc> {
+    now().into()
+}
+
+pub fn instant() -> Instant {
+    CLOCK.with_borrow(|clock| clock.as_ref().map_or_else(Instant::now, |c| c.instant()))
+}
+
+/// Like [`Instant::elapsed`], but according to our clock.
+pub fn elapsed_since(earlier: Instant) -> Duration {
+    instant().saturating_duration_since(earlier)
+}
+
+/// Like [`SystemTime::elapsed`], but according to our clock.
+pub fn elapsed_since_time(earlier: SystemTime) -> Result<Duration, std::time::SystemTimeError> {
+    now().duration_since(earlier)
+}
+
+#[test]
+fn test_fake_clock() -> Result<(), std::time::SystemTimeError> {
+    let clock = Rc::new(FakeClock::new());
+    set_clock_for_thread(Some(clock.clone()));
+    let (time, instant) = (now(), instant());
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(elapsed_since(instant), Duration::from_secs(60));
+    assert_eq!(elapsed_since_time(time)?, Duration::from_secs(60));
+    set_clock_for_thread(None);
+
+    Ok(())
+}
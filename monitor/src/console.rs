@@ -0,0 +1,207 @@
+//! Live graphical consoles for guests, proxied from their VNC displays over WebSocket.
+//!
+//! This works like websockify: the browser speaks VNC (via noVNC) in binary WebSocket messages,
+//! and we relay the bytes to and from the VNC server that QEMU listens on for the guest.
+//!
+//! Browsers can’t set the `Authorization` header for WebSocket requests, so the console page
+//! trades the monitor API token for a console ticket, and opens the WebSocket with that. This
+//! keeps the token itself out of URLs, where it would end up in logs and browser history.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures_util::{SinkExt, StreamExt};
+use monitor::clock;
+use rand::distr::{Alphanumeric, SampleString};
+use rocket::{
+    data::{IoHandler, IoStream},
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    },
+    Request, Response,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+use tracing::{debug, info};
+use web::auth::ApiKeyGuard;
+
+/// How long after it was issued a console ticket can be used.
+const CONSOLE_TICKET_TTL: Duration = Duration::from_secs(30);
+
+/// Console tickets that have not been used yet, and when they expire.
+static CONSOLE_TICKETS: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Issues a ticket that can be used once to open a console WebSocket, within
+/// [`CONSOLE_TICKET_TTL`].
+pub fn issue_console_ticket() -> String {
+    let ticket = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    let now = clock::instant();
+    let mut tickets = CONSOLE_TICKETS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    tickets.retain(|_ticket, expires_at| *expires_at > now);
+    tickets.insert(ticket.clone(), now + CONSOLE_TICKET_TTL);
+
+    ticket
+}
+
+/// Uses up the given console ticket, returning true iff it was valid.
+fn redeem_console_ticket(ticket: &str) -> bool {
+    CONSOLE_TICKETS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .remove(ticket)
+        .is_some_and(|expires_at| clock::instant() < expires_at)
+}
+
+/// Like [`ApiKeyGuard`], but also accepts a ticket from [`issue_console_ticket`] in a `ticket`
+/// query parameter, for console WebSockets opened by browsers.
+pub struct ConsoleTicketGuard {
+    /// Only FromRequest can construct this.
+    _private: (),
+}
+
+#[rocket::async_trait]
+impl<'req> FromRequest<'req> for ConsoleTicketGuard {
+    type Error = ();
+
+    async fn from_request(req: &'req Request<'_>) -> Outcome<Self, Self::Error> {
+        if req.headers().get_one("Authorization").is_some() {
+            return ApiKeyGuard::from_request(req)
+                .await
+                .map(|_guard| ConsoleTicketGuard { _private: () });
+        }
+        match req.query_value::<&str>("ticket") {
+            None => Outcome::Error((Status::Unauthorized, ())),
+            Some(Ok(ticket)) if redeem_console_ticket(ticket) => {
+                Outcome::Success(ConsoleTicketGuard { _private: () })
+            }
+            Some(_) => Outcome::Error((Status::Forbidden, ())),
+        }
+    }
+}
+
+/// Guests with a graphical console that can be opened from the dashboard.
+#[derive(Debug)]
+pub enum ConsoleGuest {
+    Runner(usize),
+    Rebuild(String),
+}
+
+/// Responder that upgrades the request to a WebSocket, then relays it to a VNC server.
+pub struct ConsoleProxy {
+    pub vnc_port: u16,
+}
+
+impl<'r> Responder<'r, 'static> for ConsoleProxy {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let Some(key) = request.headers().get_one("Sec-WebSocket-Key") else {
+            return Err(Status::UpgradeRequired);
+        };
+        let mut response = Response::build();
+        response
+            .status(Status::SwitchingProtocols)
+            .raw_header("Connection", "upgrade")
+            .raw_header("Upgrade", "websocket")
+            .raw_header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()));
+        // Older versions of noVNC ask for the `binary` subprotocol, and fail if we don’t agree.
+        if request
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .flat_map(|protocols| protocols.split(','))
+            .any(|protocol| protocol.trim() == "binary")
+        {
+            response.raw_header("Sec-WebSocket-Protocol", "binary");
+        }
+
+        response.upgrade("websocket", self).ok()
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for ConsoleProxy {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let vnc_port = self.vnc_port;
+        info!(vnc_port, "Opening console");
+        let websocket = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        let (mut websocket_tx, mut websocket_rx) = websocket.split();
+        let (mut vnc_rx, mut vnc_tx) = TcpStream::connect(("127.0.0.1", vnc_port))
+            .await?
+            .into_split();
+
+        let to_vnc = async {
+            while let Some(message) = websocket_rx.next().await {
+                match message.map_err(io::Error::other)? {
+                    Message::Binary(data) => vnc_tx.write_all(&data).await?,
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+        let from_vnc = async {
+            let mut buffer = vec![0; 65536];
+            loop {
+                let len = vnc_rx.read(&mut buffer).await?;
+                if len == 0 {
+                    break;
+                }
+                websocket_tx
+                    .send(Message::Binary(buffer[..len].to_vec()))
+                    .await
+                    .map_err(io::Error::other)?;
+            }
+            Ok::<_, io::Error>(())
+        };
+
+        // Stop relaying as soon as either side disconnects.
+        let result = rocket::tokio::select! {
+            result = to_vnc => result,
+            result = from_vnc => result,
+        };
+        debug!(vnc_port, ?result, "Closed console");
+
+        result
+    }
+}
+
+#[test]
+fn test_console_tickets() {
+    use std::rc::Rc;
+
+    use monitor::clock::FakeClock;
+
+    let clock = Rc::new(FakeClock::new());
+    clock::set_clock_for_thread(Some(clock.clone()));
+
+    // Tickets can only be used once.
+    let ticket = issue_console_ticket();
+    assert!(redeem_console_ticket(&ticket));
+    assert!(!redeem_console_ticket(&ticket));
+    assert!(!redeem_console_ticket("bogus"));
+
+    // Tickets expire if not used in time, and expired tickets are forgotten.
+    let ticket = issue_console_ticket();
+    clock.advance(CONSOLE_TICKET_TTL);
+    assert!(!redeem_console_ticket(&ticket));
+    let old_ticket = issue_console_ticket();
+    clock.advance(CONSOLE_TICKET_TTL);
+    issue_console_ticket();
+    assert!(!CONSOLE_TICKETS
+        .lock()
+        .expect("Poisoned")
+        .contains_key(&old_ticket));
+
+    clock::set_clock_for_thread(None);
+}
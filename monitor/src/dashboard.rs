@@ -1,13 +1,17 @@
 use std::collections::BTreeMap;
 
 use askama::Template;
+use bytesize::ByteSize;
 use jane_eyre::eyre;
 use serde_json::json;
 use settings::profile::Profile;
 
 use crate::{
-    policy::{Policy, RunnerCounts},
-    runner::Runner,
+    gc::GarbageCollectionReport,
+    orphans::OrphanReport,
+    policy::{Policy, RunnerCounts, StuckBusyRunner},
+    runner::{Runner, Status},
+    workers::{RunnerOperation, RunnerWorkersStatus},
     TOML,
 };
 
@@ -22,21 +26,33 @@ pub struct Dashboard {
 struct DashboardTemplate<'monitor> {
     policy: &'monitor Policy,
     profile_runner_counts: &'monitor BTreeMap<String, RunnerCounts>,
+    runner_workers: &'monitor RunnerWorkersStatus,
+    garbage_collection: Option<&'monitor GarbageCollectionReport>,
+    orphans: Option<&'monitor OrphanReport>,
 }
 
 impl Dashboard {
     pub fn render(
         policy: &Policy,
         profile_runner_counts: &BTreeMap<String, RunnerCounts>,
+        runner_workers: &RunnerWorkersStatus,
+        garbage_collection: Option<&GarbageCollectionReport>,
+        orphans: Option<&OrphanReport>,
     ) -> eyre::Result<Self> {
         let json = serde_json::to_string(&json!({
             "profile_runner_counts": &profile_runner_counts,
+            "runner_workers": runner_workers,
+            "garbage_collection": garbage_collection,
+            "orphans": orphans,
             "runners": &policy.runners()
                 .map(|(id, runner)| {
                     json!({
                         "id": id,
                         "screenshot_url": format!("{}runner/{id}/screenshot", TOML.external_base_url),
                         "runner": runner,
+                        "busy_duration": policy.busy_duration(*id),
+                        "stuck_busy": policy.stuck_busy_runner(*id),
+                        "pending_operation": policy.pending_runner_operation(*id),
                     })
                 })
                 .collect::<Vec<_>>(),
@@ -44,6 +60,9 @@ impl Dashboard {
         let html = DashboardTemplate {
             policy,
             profile_runner_counts,
+            runner_workers,
+            garbage_collection,
+            orphans,
         }
         .render()?;
 
@@ -52,12 +71,60 @@ impl Dashboard {
 }
 
 impl DashboardTemplate<'_> {
+    fn byte_size(&self, bytes: &u64) -> ByteSize {
+        ByteSize(*bytes)
+    }
+
     fn profile(&self, key: impl AsRef<str>) -> Option<&Profile> {
         self.policy.profile(key.as_ref())
     }
 
     fn status(&self, runner: &Runner) -> String {
-        format!("{:?}", runner.status())
+        if let Some(stage) = runner
+            .provisioning_stage()
+            .filter(|_| runner.status() == Status::Provisioning)
+        {
+            format!("{:?} ({stage:?})", runner.status())
+        } else if let Some(reason) = runner.unresponsive_reason() {
+            format!("{:?} ({reason})", runner.status())
+        } else if let Some(hold) = runner.hold().filter(|_| runner.is_held()) {
+            format!("{:?} (until {})", runner.status(), hold.expires_at)
+        } else {
+            format!("{:?}", runner.status())
+        }
+    }
+
+    fn busy(&self, id: &usize) -> Option<String> {
+        let duration = self.policy.busy_duration(*id)?;
+        let result = match self.policy.stuck_busy_runner(*id) {
+            None => format!("busy for {duration:?}"),
+            Some(StuckBusyRunner::JobFinished { conclusion }) => {
+                format!("busy for {duration:?}, but job finished ({conclusion:?})")
+            }
+            Some(StuckBusyRunner::Flagged { reason }) => {
+                format!("busy for too long ({duration:?}): {reason}")
+            }
+        };
+
+        Some(result)
+    }
+
+    fn pending_operation(&self, id: &usize) -> Option<&'static str> {
+        match self.policy.pending_runner_operation(*id)? {
+            RunnerOperation::Create => Some("being created"),
+            RunnerOperation::CreateWarm => Some("being created for the warm pool"),
+            RunnerOperation::Destroy => Some("being destroyed"),
+        }
+    }
+
+    fn heartbeat(&self, runner: &Runner) -> Option<String> {
+        runner.heartbeat().map(|heartbeat| {
+            format!(
+                "{} disk free, load {}",
+                ByteSize::b(heartbeat.disk_free),
+                heartbeat.load
+            )
+        })
     }
 
     fn age(&self, runner: &Runner) -> eyre::Result<String> {
@@ -31,6 +31,19 @@ pub fn get_profile_data_path<'p>(
     })
 }
 
+pub fn get_snapshot_data_path<'p>(
+    key: &str,
+    snapshot_name: &str,
+    path: impl Into<Option<&'p Path>>,
+) -> eyre::Result<PathBuf> {
+    let snapshot_data = get_profile_data_path(key, Path::new("snapshots"))?.join(snapshot_name);
+
+    Ok(match path.into() {
+        Some(path) => snapshot_data.join(path),
+        None => snapshot_data,
+    })
+}
+
 pub fn get_profile_configuration_path<'p>(
     profile: &Profile,
     path: impl Into<Option<&'p Path>>,
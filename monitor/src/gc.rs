@@ -0,0 +1,600 @@
+//! Garbage collection of image storage.
+//!
+//! Runner images are deleted when their runners are destroyed, and templates are pruned after each
+//! image rebuild, but those can fail or be interrupted, leaving files and guests that nothing will
+//! ever clean up. Every `garbage_collection_interval`, we reconcile runner images, OVMF variable
+//! stores, and template guests against the runners and templates we know about. If image storage
+//! is fuller than `images_high_water_mark_percent`, we also delete all templates except the ones
+//! in use, and the oldest preserved runners, until it’s back under.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read_dir, remove_file},
+    path::{Path, PathBuf},
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use bytesize::ByteSize;
+use chrono::{DateTime, Utc};
+use jane_eyre::eyre;
+use monitor::clock;
+use serde::Serialize;
+use settings::{
+    profile::{parse_template_guest_name, Profile},
+    TOML,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    image::{delete_template, prune_templates, Rebuilds},
+    libvirt::list_template_guests,
+    policy::{runner_images_path, template_or_rebuild_image_path, Policy},
+    preserved::{discard_preserved_runner, list_preserved_runners},
+    storage::{self, StorageUsage},
+    wakeup::{wake, Wakeup},
+};
+
+/// Where macOS guests keep their OVMF variable stores, named `OVMF_VARS.<name>.fd`.
+const OVMF_VARS_DIR: &str = "/var/lib/libvirt/images/OSX-KVM";
+
+#[derive(Debug, Default)]
+pub struct GarbageCollector {
+    last_run: Option<Instant>,
+    thread: Option<JoinHandle<eyre::Result<GarbageCollectionReport>>>,
+    last_report: Option<GarbageCollectionReport>,
+}
+
+/// What garbage collection needs to know about our runners and templates, taken on the monitor
+/// thread when it starts, so that it can run on its own thread.
+#[derive(Debug, Default)]
+struct GarbageCollectionInputs {
+    profiles: BTreeMap<String, Profile>,
+    /// The active base image snapshot of each profile that has one.
+    base_image_snapshots: BTreeMap<String, String>,
+    /// The base image snapshots that runners of each profile were cloned from.
+    in_use_snapshots: BTreeMap<String, BTreeSet<String>>,
+    known_runner_ids: BTreeSet<usize>,
+    /// The last runner id we allocated, if any. Runners with later ids were created after we
+    /// started, so they are known too.
+    last_runner_id: Option<usize>,
+    rebuilding_profile_keys: BTreeSet<String>,
+}
+
+impl GarbageCollectionInputs {
+    fn is_known_runner(&self, runner_id: usize) -> bool {
+        self.known_runner_ids.contains(&runner_id)
+            || self.last_runner_id.is_none_or(|last| runner_id > last)
+    }
+}
+
+/// What the last garbage collection deleted, for the API.
+#[derive(Clone, Debug, Serialize)]
+pub struct GarbageCollectionReport {
+    pub finished_at: DateTime<Utc>,
+    pub deleted_runner_images: usize,
+    pub deleted_ovmf_vars: usize,
+    pub deleted_templates: usize,
+    pub discarded_preserved_runners: usize,
+    /// Space freed in the storage backend, in bytes, which may be less than the size of the
+    /// deleted images if they shared blocks with other images.
+    pub reclaimed: u64,
+    pub usage: Option<StorageUsage>,
+    pub over_high_water_mark: bool,
+}
+
+impl GarbageCollector {
+    /// Starts collecting garbage on another thread if `garbage_collection_interval` has elapsed
+    /// since the last time, and reaps the thread once it has finished.
+    ///
+    /// `pending_runner_ids` are the runners being created or destroyed by workers, which may
+    /// have images but no guest yet (or anymore).
+    pub fn run_if_due(
+        &mut self,
+        policy: &Policy,
+        image_rebuilds: &Rebuilds,
+        pending_runner_ids: impl IntoIterator<Item = usize>,
+        last_runner_id: Option<usize>,
+    ) {
+        // Reap the garbage collection thread, if needed.
+        if let Some(thread) = self.thread.take() {
+            if !thread.is_finished() {
+                self.thread = Some(thread);
+                return;
+            }
+            match thread.join() {
+                Ok(Ok(report)) => {
+                    info!(
+                        ?report,
+                        "Garbage collection reclaimed {}",
+                        ByteSize(report.reclaimed)
+                    );
+                    self.last_report = Some(report);
+                }
+                Ok(Err(error)) => warn!(?error, "Failed to collect garbage: {error}"),
+                Err(panic) => error!(?panic, "Garbage collection thread panic"),
+            }
+        }
+
+        if !self.is_due() {
+            return;
+        }
+        self.last_run = Some(clock::instant());
+
+        let inputs = GarbageCollectionInputs {
+            profiles: policy
+                .profiles()
+                .map(|(key, profile)| (key.clone(), profile.clone()))
+                .collect(),
+            base_image_snapshots: policy
+                .profiles()
+                .flat_map(|(key, _profile)| {
+                    let snapshot_name = policy.base_image_snapshot(key)?;
+                    Some((key.clone(), snapshot_name.clone()))
+                })
+                .collect(),
+            in_use_snapshots: policy.runners().fold(
+                BTreeMap::default(),
+                |mut result, (_id, runner)| {
+                    if let Some(snapshot_name) = runner.base_image_snapshot() {
+                        result
+                            .entry(runner.profile_name().to_owned())
+                            .or_default()
+                            .insert(snapshot_name.to_owned());
+                    }
+                    result
+                },
+            ),
+            known_runner_ids: policy
+                .runners()
+                .map(|(&id, _runner)| id)
+                .chain(pending_runner_ids)
+                .chain(image_rebuilds.runner_ids())
+                .collect(),
+            last_runner_id,
+            rebuilding_profile_keys: image_rebuilds.rebuild_guest_names().into_keys().collect(),
+        };
+        self.thread = Some(thread::spawn(move || {
+            let result = collect_garbage(&inputs);
+            wake(Wakeup::GarbageCollectionFinished);
+            result
+        }));
+    }
+
+    /// Returns true iff garbage collection is running. Image rebuilds must not be started while
+    /// it is, because it can’t tell the image files of a new snapshot apart from garbage.
+    pub fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+
+    pub fn last_report(&self) -> Option<&GarbageCollectionReport> {
+        self.last_report.as_ref()
+    }
+
+    /// Returns true iff we have never collected garbage, or `garbage_collection_interval` has
+    /// elapsed since we last started.
+    fn is_due(&self) -> bool {
+        self.last_run.is_none_or(|last_run| {
+            clock::elapsed_since(last_run) >= TOML.garbage_collection_interval()
+        })
+    }
+}
+
+fn collect_garbage(inputs: &GarbageCollectionInputs) -> eyre::Result<GarbageCollectionReport> {
+    let usage_before = storage::usage()
+        .inspect_err(|error| warn!(?error, "Failed to get storage usage: {error}"))
+        .ok();
+
+    let template_guest_names = list_template_guests()?.into_iter().collect::<BTreeSet<_>>();
+    let deleted_runner_images = delete_unknown_runner_images(&runner_images_path(), inputs)?;
+    let deleted_ovmf_vars =
+        delete_unknown_ovmf_vars(Path::new(OVMF_VARS_DIR), &template_guest_names, inputs)?;
+    let mut deleted_templates = 0;
+    for (profile, snapshot_name) in
+        find_broken_templates(&template_guest_names, inputs, |profile, snapshot_name| {
+            template_or_rebuild_image_path(profile, snapshot_name, "base.img")
+        })
+    {
+        match delete_template(profile, snapshot_name) {
+            Ok(()) => deleted_templates += 1,
+            Err(error) => warn!(
+                profile.profile_name,
+                snapshot_name,
+                ?error,
+                "Failed to delete: {error}"
+            ),
+        }
+    }
+    let mut discarded_preserved_runners = 0;
+
+    let high_water_mark = f64::from(TOML.images_high_water_mark_percent());
+    let is_over_high_water_mark =
+        || storage::usage().is_ok_and(|usage| usage.percent() > high_water_mark);
+    let over_high_water_mark = is_over_high_water_mark();
+    if over_high_water_mark {
+        warn!(high_water_mark, "Image storage is over high water mark");
+        for (profile_key, profile) in inputs.profiles.iter() {
+            // Without an active snapshot, we have no way of knowing which template to keep.
+            if inputs.rebuilding_profile_keys.contains(profile_key)
+                || !inputs.base_image_snapshots.contains_key(profile_key)
+            {
+                continue;
+            }
+            // Runners may still need the templates they were cloned from, like qcow2 overlays.
+            let no_snapshots = BTreeSet::default();
+            let in_use = inputs
+                .in_use_snapshots
+                .get(profile_key)
+                .unwrap_or(&no_snapshots);
+            deleted_templates += prune_templates(profile, 0, in_use)?;
+        }
+        for preserved_runner in list_preserved_runners()? {
+            if !is_over_high_water_mark() {
+                break;
+            }
+            discard_preserved_runner(preserved_runner.id)?;
+            discarded_preserved_runners += 1;
+        }
+    }
+
+    let usage = storage::usage()
+        .inspect_err(|error| warn!(?error, "Failed to get storage usage: {error}"))
+        .ok();
+    let reclaimed = usage_before
+        .zip(usage)
+        .map_or(0, |(before, after)| before.used.saturating_sub(after.used));
+
+    Ok(GarbageCollectionReport {
+        finished_at: clock::utc_now(),
+        deleted_runner_images,
+        deleted_ovmf_vars,
+        deleted_templates,
+        discarded_preserved_runners,
+        reclaimed,
+        usage,
+        over_high_water_mark,
+    })
+}
+
+/// Deletes the images of runners we don’t know about, named `<id>-<filename>`.
+fn delete_unknown_runner_images(
+    runner_images_path: &Path,
+    inputs: &GarbageCollectionInputs,
+) -> eyre::Result<usize> {
+    let Ok(entries) = read_dir(runner_images_path) else {
+        return Ok(0);
+    };
+    let mut result = 0;
+    for entry in entries {
+        let entry = entry?;
+        // Skip the preserved runners directory, which has its own budget.
+        if entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Some(runner_id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.split_once('-'))
+            .and_then(|(id, _filename)| id.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        if inputs.is_known_runner(runner_id) {
+            continue;
+        }
+        let path = entry.path();
+        info!(runner_id, ?path, "Deleting image of unknown runner");
+        match storage::delete_image(&path) {
+            Ok(()) => result += 1,
+            Err(error) => warn!(?path, ?error, "Failed to delete: {error}"),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Deletes the OVMF variable stores of runners we don’t know about, and of templates that no
+/// longer exist. Leaves alone any stores whose names we don’t recognise, like the stores of the
+/// hand-made clean guests.
+fn delete_unknown_ovmf_vars(
+    ovmf_vars_dir: &Path,
+    template_guest_names: &BTreeSet<String>,
+    inputs: &GarbageCollectionInputs,
+) -> eyre::Result<usize> {
+    let Ok(entries) = read_dir(ovmf_vars_dir) else {
+        return Ok(0);
+    };
+    let runner_guest_prefix = format!("{}-", TOML.libvirt_runner_guest_prefix());
+    let mut result = 0;
+    for entry in entries {
+        let entry = entry?;
+        let Some(name) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("OVMF_VARS."))
+            .and_then(|name| name.strip_suffix(".fd"))
+            .map(str::to_owned)
+        else {
+            continue;
+        };
+        let unused = if let Some((profile_key, snapshot_name)) = name.split_once('@') {
+            // Snapshot path slug of a template.
+            inputs.profiles.get(profile_key).is_some_and(|profile| {
+                !inputs.rebuilding_profile_keys.contains(profile_key)
+                    && !template_guest_names.contains(&profile.template_guest_name(snapshot_name))
+            })
+        } else if let Some(rest) = name.strip_prefix(&runner_guest_prefix) {
+            // Runner guest name.
+            rest.rsplit_once('.')
+                .and_then(|(_profile_key, id)| id.parse::<usize>().ok())
+                .is_some_and(|id| !inputs.is_known_runner(id))
+        } else {
+            false
+        };
+        if !unused {
+            continue;
+        }
+        let path = entry.path();
+        info!(?path, "Deleting unused OVMF variable store");
+        match remove_file(&path) {
+            Ok(()) => result += 1,
+            Err(error) => warn!(?path, ?error, "Failed to delete: {error}"),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Finds templates whose base image is missing, since runners can’t be cloned from them.
+fn find_broken_templates<'inputs, 'names>(
+    template_guest_names: &'names BTreeSet<String>,
+    inputs: &'inputs GarbageCollectionInputs,
+    base_image_path: impl Fn(&Profile, &str) -> PathBuf,
+) -> Vec<(&'inputs Profile, &'names str)> {
+    let mut result = vec![];
+    for template_guest_name in template_guest_names {
+        let Ok((profile_key, snapshot_name)) = parse_template_guest_name(template_guest_name)
+        else {
+            continue;
+        };
+        let Some(profile) = inputs.profiles.get(profile_key) else {
+            continue;
+        };
+        if inputs.rebuilding_profile_keys.contains(profile_key) {
+            continue;
+        }
+        // For backends that store images as volumes, this also checks that the volume exists.
+        let base_image_path = base_image_path(profile, snapshot_name);
+        if base_image_path.exists() {
+            continue;
+        }
+        if inputs
+            .base_image_snapshots
+            .get(profile_key)
+            .map(String::as_str)
+            == Some(snapshot_name)
+        {
+            warn!(
+                template_guest_name,
+                ?base_image_path,
+                "Active template has missing base image"
+            );
+            continue;
+        }
+        info!(
+            template_guest_name,
+            ?base_image_path,
+            "Found template with missing base image"
+        );
+        result.push((profile, snapshot_name));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeSet,
+        fs::{create_dir, read_dir, File},
+        path::{Path, PathBuf},
+        rc::Rc,
+    };
+
+    use jane_eyre::eyre;
+    use mktemp::Temp;
+    use monitor::clock::{self, FakeClock};
+    use settings::{profile::Profile, TOML};
+
+    use crate::policy::test::profile;
+
+    use super::{
+        delete_unknown_ovmf_vars, delete_unknown_runner_images, find_broken_templates,
+        GarbageCollectionInputs, GarbageCollector,
+    };
+
+    fn inputs(
+        known_runner_ids: &[usize],
+        last_runner_id: Option<usize>,
+    ) -> GarbageCollectionInputs {
+        GarbageCollectionInputs {
+            profiles: [("linux".to_owned(), profile("linux", 1, 0, "0 B"))].into(),
+            base_image_snapshots: [("linux".to_owned(), "active".to_owned())].into(),
+            known_runner_ids: known_runner_ids.iter().copied().collect(),
+            last_runner_id,
+            ..Default::default()
+        }
+    }
+
+    fn touch(dir: &Path, names: &[&str]) -> eyre::Result<()> {
+        for name in names {
+            File::create(dir.join(name))?;
+        }
+        Ok(())
+    }
+
+    fn list(dir: &Path) -> eyre::Result<BTreeSet<String>> {
+        let mut result = BTreeSet::default();
+        for entry in read_dir(dir)? {
+            result.insert(
+                entry?
+                    .file_name()
+                    .to_str()
+                    .expect("Bad name in test")
+                    .to_owned(),
+            );
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn test_is_due() {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut garbage_collector = GarbageCollector::default();
+
+        // Garbage is collected right away, then every `garbage_collection_interval`.
+        assert!(garbage_collector.is_due());
+        garbage_collector.last_run = Some(clock::instant());
+        assert!(!garbage_collector.is_due());
+        clock.advance(TOML.garbage_collection_interval() / 2);
+        assert!(!garbage_collector.is_due());
+        clock.advance(TOML.garbage_collection_interval() / 2);
+        assert!(garbage_collector.is_due());
+
+        clock::set_clock_for_thread(None);
+    }
+
+    #[test]
+    fn test_delete_unknown_runner_images() -> eyre::Result<()> {
+        let dir = Temp::new_dir()?;
+        touch(
+            &dir,
+            &[
+                "1-base.img",
+                "2-base.img",
+                "3-base.img",
+                "9-base.img",
+                "foo.img",
+            ],
+        )?;
+        create_dir(dir.join("preserved"))?;
+
+        // Runner 1 is known, runner 9 was created after we took our inputs, and we can’t parse
+        // a runner id out of foo.img, so only the images of runners 2 and 3 are deleted.
+        assert_eq!(
+            delete_unknown_runner_images(&dir, &inputs(&[1], Some(5)))?,
+            2
+        );
+        assert_eq!(
+            list(&dir)?,
+            ["1-base.img", "9-base.img", "foo.img", "preserved"]
+                .map(str::to_owned)
+                .into()
+        );
+
+        // Without a last runner id, any runner could have been created after we took our inputs.
+        assert_eq!(delete_unknown_runner_images(&dir, &inputs(&[], None))?, 0);
+        assert_eq!(
+            delete_unknown_runner_images(&dir, &inputs(&[], Some(9)))?,
+            2
+        );
+        assert_eq!(
+            list(&dir)?,
+            ["foo.img", "preserved"].map(str::to_owned).into()
+        );
+
+        // A missing directory has nothing to delete.
+        assert_eq!(
+            delete_unknown_runner_images(&dir.join("missing"), &inputs(&[], Some(9)))?,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_unknown_ovmf_vars() -> eyre::Result<()> {
+        let dir = Temp::new_dir()?;
+        touch(
+            &dir,
+            &[
+                "OVMF_VARS.ci-runner-linux.1.fd",
+                "OVMF_VARS.ci-runner-linux.2.fd",
+                "OVMF_VARS.ci-runner-linux.9.fd",
+                "OVMF_VARS.linux@active.fd",
+                "OVMF_VARS.linux@old.fd",
+                "OVMF_VARS.macos@old.fd",
+                "OVMF_VARS.servo-macos13.fd",
+                "OVMF_VARS.fd",
+            ],
+        )?;
+        let template_guest_names = BTreeSet::from(["ci-template-linux@active".to_owned()]);
+
+        // Runner 2 is unknown, and linux@old has no template guest. The template store of an
+        // unknown profile and the store of the hand-made clean guest are left alone.
+        let mut inputs = inputs(&[1], Some(5));
+        assert_eq!(
+            delete_unknown_ovmf_vars(&dir, &template_guest_names, &inputs)?,
+            2
+        );
+        assert_eq!(
+            list(&dir)?,
+            [
+                "OVMF_VARS.ci-runner-linux.1.fd",
+                "OVMF_VARS.ci-runner-linux.9.fd",
+                "OVMF_VARS.linux@active.fd",
+                "OVMF_VARS.macos@old.fd",
+                "OVMF_VARS.servo-macos13.fd",
+                "OVMF_VARS.fd",
+            ]
+            .map(str::to_owned)
+            .into()
+        );
+
+        // Template stores of profiles being rebuilt are left alone, since the template guest
+        // may not be defined yet.
+        inputs.rebuilding_profile_keys.insert("linux".to_owned());
+        assert_eq!(
+            delete_unknown_ovmf_vars(&dir, &BTreeSet::default(), &inputs)?,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_broken_templates() -> eyre::Result<()> {
+        let dir = Temp::new_dir()?;
+        touch(&dir, &["linux@ok"])?;
+        let base_image_path = |profile: &Profile, snapshot_name: &str| -> PathBuf {
+            dir.join(format!("{}@{snapshot_name}", profile.profile_name))
+        };
+        let template_guest_names = [
+            "ci-template-linux@ok",
+            "ci-template-linux@broken",
+            "ci-template-linux@active",
+            "ci-template-macos@broken",
+            "not-a-template",
+        ]
+        .map(str::to_owned)
+        .into();
+
+        // The active template is broken too, but deleting it would leave the profile with no
+        // template at all, and templates of unknown profiles are left alone.
+        let mut inputs = inputs(&[], None);
+        let broken = |inputs: &GarbageCollectionInputs| {
+            find_broken_templates(&template_guest_names, inputs, base_image_path)
+                .into_iter()
+                .map(|(profile, snapshot_name)| format!("{}@{snapshot_name}", profile.profile_name))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(broken(&inputs), ["linux@broken"]);
+
+        // Templates of profiles being rebuilt may not have their images yet.
+        inputs.rebuilding_profile_keys.insert("linux".to_owned());
+        assert_eq!(broken(&inputs), [] as [String; 0]);
+
+        Ok(())
+    }
+}
@@ -10,6 +10,8 @@ use serde::{Deserialize, Serialize};
 use settings::TOML;
 use tracing::trace;
 
+use crate::clock;
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ApiRunner {
     pub id: usize,
@@ -42,6 +44,14 @@ pub struct ApiArtifact {
     pub archive_download_url: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApiWorkflowJob {
+    pub id: usize,
+    pub status: String,
+    pub conclusion: Option<String>,
+    pub runner_id: Option<usize>,
+}
+
 impl ApiRunner {
     pub fn labels(&self) -> impl Iterator<Item = &str> {
         self.labels.iter().map(|label| label.name.as_str())
@@ -69,7 +79,7 @@ struct CacheData<Response> {
 impl<Response: Clone + Debug> Cache<Response> {
     pub fn get(&mut self, miss: impl FnOnce() -> eyre::Result<Response>) -> eyre::Result<Response> {
         if let Some(cached) = &mut self.inner {
-            let now = Instant::now();
+            let now = clock::instant();
             let age = now.duration_since(cached.cached_at);
             if age >= TOML.api_cache_timeout() {
                 trace!(?age, "Cache expired");
@@ -87,7 +97,7 @@ impl<Response: Clone + Debug> Cache<Response> {
         let response = miss()?;
         self.inner = Some(CacheData {
             response: response.clone(),
-            cached_at: Instant::now(),
+            cached_at: clock::instant(),
         });
 
         Ok(response)
@@ -99,7 +109,7 @@ impl<Response: Clone + Debug> Cache<Response> {
     }
 
     pub fn invalidate_in(&mut self, duration: Duration) {
-        let forced_expiry = Instant::now() + duration;
+        let forced_expiry = clock::instant() + duration;
         if self.forced_expiry.is_none_or(|e| forced_expiry < e) {
             self.forced_expiry = Some(forced_expiry);
         }
@@ -172,6 +182,17 @@ pub fn list_workflow_run_artifacts(
     Ok(result.artifacts)
 }
 
+pub fn list_workflow_run_jobs(
+    qualified_repo: &str,
+    run_id: &str,
+) -> eyre::Result<Vec<ApiWorkflowJob>> {
+    let result = run_fun!(gh api -H "Accept: application/vnd.github+json" -H "X-GitHub-Api-Version: 2022-11-28"
+        "/repos/$qualified_repo/actions/runs/$run_id/jobs?per_page=100" --paginate -q ".jobs[]"
+        | jq -s .)?;
+
+    serde_json::from_str(&result).wrap_err("Failed to parse JSON")
+}
+
 pub fn download_artifact_string(url: &str) -> eyre::Result<String> {
     Ok(run_fun!(gh api -- $url | funzip)?)
 }
@@ -32,6 +32,11 @@ impl IdGen {
         Self { last: None }
     }
 
+    /// Returns the last runner id we generated, if any.
+    pub fn last(&self) -> Option<usize> {
+        self.last
+    }
+
     /// Returns a new runner id, then write it to a file.
     ///
     /// If writing fails, log a warning.
@@ -1,4 +1,8 @@
+pub mod inputs;
 pub mod macos13;
+pub mod memory;
+pub mod rebuild_log;
+pub mod smoke;
 pub mod ubuntu2204;
 pub mod windows10;
 
@@ -6,35 +10,55 @@ use core::str;
 use std::{
     collections::{BTreeMap, BTreeSet},
     ffi::OsStr,
-    fs::{create_dir_all, read_dir, remove_file, set_permissions, File},
-    io::{Seek, Write},
+    fs::{create_dir_all, read_dir, remove_file},
     mem::take,
-    os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use bytesize::ByteSize;
 use chrono::{SecondsFormat, Utc};
-use cmd_lib::{run_cmd, run_fun, spawn_with_output};
-use jane_eyre::eyre::{self, bail, OptionExt};
+use cmd_lib::{run_fun, spawn_with_output};
+use jane_eyre::eyre::{self, bail, eyre, OptionExt};
+use serde::Serialize;
 use settings::{
-    profile::{parse_rebuild_guest_name, parse_template_guest_name, Profile},
+    profile::{
+        parse_rebuild_guest_name, parse_template_guest_name, GuestOs, Profile, RebuildTrigger,
+        SupportedProfile,
+    },
     TOML,
 };
 use tracing::{debug, error, info, warn};
 
 use crate::{
+    data::get_profile_data_path,
+    id::IdGen,
+    image::{
+        inputs::{
+            read_rebuild_record, write_rebuild_record, ImageInputs, ImageInputsCache, RebuildRecord,
+        },
+        memory::{delete_memory_snapshot, MemorySnapshot},
+        rebuild_log::{capture_serial_console, phase, RebuildLog},
+        smoke::{has_health_script, SmokeTest, SmokeTestResult},
+    },
     libvirt::{list_rebuild_guests, list_template_guests},
-    policy::{runner_images_path, template_or_rebuild_images_path, Policy},
-    shell::{log_output_as_info, reflink_or_copy_with_warning},
+    policy::{
+        read_base_image_snapshot, runner_images_path, snapshot_age, template_or_rebuild_image_path,
+        template_or_rebuild_images_path, Policy,
+    },
+    shell::{atomic_symlink, log_output_as_info, run_cmd_logged},
+    storage,
+    wakeup::{wake, Wakeup},
 };
 
 #[derive(Debug, Default)]
 pub struct Rebuilds {
     cached_servo_repo_update: Option<JoinHandle<eyre::Result<()>>>,
+    cached_servo_repo_last_updated: Option<Instant>,
     rebuilds: BTreeMap<String, Rebuild>,
+    image_inputs_cache: ImageInputsCache,
 }
 
 #[derive(Debug)]
@@ -42,27 +66,78 @@ struct Rebuild {
     thread: JoinHandle<eyre::Result<()>>,
     snapshot_name: String,
     guest_name: String,
+    inputs: Option<ImageInputs>,
+    /// Runner ids of the smoke test and memory snapshot guests, whose images are named after them.
+    runner_ids: Vec<usize>,
+    smoke_test: Arc<SmokeTest>,
+    memory_snapshot: Arc<MemorySnapshot>,
+}
+
+/// A base image snapshot for a profile, for the API.
+#[derive(Clone, Debug, Serialize)]
+pub struct Snapshot {
+    pub name: String,
+    pub age: Option<Duration>,
+    pub status: SnapshotStatus,
+    pub pinned: bool,
+    pub reasons: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotStatus {
+    /// New runners are created from this snapshot.
+    Active,
+    /// This snapshot is kept, and can be activated manually.
+    Available,
+    /// This snapshot is still being rebuilt or smoke tested.
+    Rebuilding,
+    /// This snapshot failed its smoke test, and is kept for inspection.
+    Failed,
 }
 
 impl Rebuilds {
-    pub fn run(&mut self, policy: &mut Policy) -> eyre::Result<()> {
+    /// Reaps finished image rebuilds and starts new ones.
+    ///
+    /// No rebuilds are started while `garbage_collecting`, since garbage collection can’t tell the
+    /// images of a template being built apart from those of a broken one.
+    pub fn run(
+        &mut self,
+        policy: &mut Policy,
+        id_gen: &mut IdGen,
+        garbage_collecting: bool,
+    ) -> eyre::Result<()> {
         // Clean up any dangling resources from past rebuilds.
         let current_known_rebuild_guest_names = self
             .rebuild_guest_names()
             .into_iter()
             .map(|(_key, guest_name)| guest_name)
             .collect::<BTreeSet<_>>();
-        for rebuild_guest_name in list_rebuild_guests()? {
+        let rebuild_guest_names = list_rebuild_guests().unwrap_or_else(|error| {
+            error!(?error, "Failed to list rebuild guests: {error}");
+            vec![]
+        });
+        for rebuild_guest_name in rebuild_guest_names {
             if !current_known_rebuild_guest_names.contains(&rebuild_guest_name) {
-                undefine_libvirt_guest(&rebuild_guest_name)?;
-                let (profile_key, snapshot_name) =
-                    match parse_rebuild_guest_name(&rebuild_guest_name) {
-                        Ok(result) => result,
-                        Err(error) => {
-                            warn!(?error, "Failed to clean up bad image files");
-                            continue;
+                let parsed = parse_rebuild_guest_name(&rebuild_guest_name);
+                if let Err(error) = undefine_libvirt_guest(&rebuild_guest_name) {
+                    let reason = format!("Failed to undefine rebuild guest: {error}");
+                    match parsed {
+                        Ok((profile_key, _)) if policy.profile(profile_key).is_some() => {
+                            let profile_key = profile_key.to_owned();
+                            policy.quarantine_profile(&profile_key, reason);
                         }
-                    };
+                        _ => warn!(rebuild_guest_name, ?error, "{reason}"),
+                    }
+                    continue;
+                }
+                let (profile_key, snapshot_name) = match parsed {
+                    Ok(result) => result,
+                    Err(error) => {
+                        warn!(?error, "Failed to clean up bad image files");
+                        continue;
+                    }
+                };
                 let Some(profile) = policy.profile(profile_key) else {
                     warn!(
                         ?profile_key,
@@ -70,7 +145,13 @@ impl Rebuilds {
                     );
                     continue;
                 };
-                delete_template(profile, snapshot_name)?;
+                if let Err(error) = delete_template(profile, snapshot_name) {
+                    let profile_key = profile_key.to_owned();
+                    policy.quarantine_profile(
+                        &profile_key,
+                        format!("Failed to clean up bad image files: {error}"),
+                    );
+                }
             }
         }
 
@@ -84,6 +165,7 @@ impl Rebuilds {
                     Ok(Ok(())) => {
                         info!("Servo update thread exited");
                         cached_servo_repo_was_just_updated = true;
+                        self.cached_servo_repo_last_updated = Some(Instant::now());
                     }
                     Ok(Err(report)) => error!(%report, "Servo update thread error"),
                     Err(panic) => error!(?panic, "Servo update thread panic"),
@@ -93,6 +175,66 @@ impl Rebuilds {
             }
         }
 
+        // Update the inputs that each image would be built from, if the profile is sensitive to
+        // them. Skip this while our cached Servo repo is being updated, since it may be in flux.
+        let current_image_inputs = policy
+            .profiles()
+            .filter(|_| self.cached_servo_repo_update.is_none())
+            .filter(|(_key, profile)| !profile.rebuild_triggers.is_empty())
+            .flat_map(|(key, profile)| {
+                match ImageInputs::collect(profile, &mut self.image_inputs_cache) {
+                    Ok(inputs) => Some((key.clone(), inputs)),
+                    Err(error) => {
+                        warn!(?error, "profile {key}: failed to collect image inputs");
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        for (key, inputs) in current_image_inputs {
+            policy.set_current_image_inputs(&key, inputs);
+        }
+
+        // Update our cached Servo repo periodically, if any profiles are sensitive to its commit.
+        if let Some(interval) = TOML.main_repo_update_interval() {
+            if self.rebuilds.is_empty()
+                && self.cached_servo_repo_update.is_none()
+                && !TOML.dont_update_cached_servo_repo()
+                && self
+                    .cached_servo_repo_last_updated
+                    .is_none_or(|last_updated| last_updated.elapsed() > interval)
+                && policy.profiles().any(|(_key, profile)| {
+                    profile.rebuild_triggers.contains(&RebuildTrigger::MainRepo)
+                })
+            {
+                info!("Updating our cached Servo repo, to check for changes");
+                self.cached_servo_repo_update = Some(thread::spawn(servo_update_thread));
+            }
+        }
+
+        // Charge running and pending rebuilds against our available resources. Pending rebuilds
+        // hold resources even if they can’t start yet, so new runners for other profiles won’t
+        // keep them waiting forever.
+        let pending_rebuild_profile_keys = policy
+            .profiles()
+            .filter(|(_key, profile)| policy.image_needs_rebuild(profile) == Some(true))
+            .filter(|(key, _profile)| {
+                !policy.profile_is_quarantined(key)
+                    && !policy.rebuild_circuit_open(key)
+                    && policy.rebuild_backoff_remaining(key).is_none()
+            })
+            .filter(|(_key, profile)| {
+                profile.uses_rolling_upgrades() || policy.runners_for_profile(profile).count() == 0
+            })
+            .map(|(key, _profile)| key.clone());
+        let rebuild_profile_keys = self
+            .rebuilds
+            .keys()
+            .cloned()
+            .chain(pending_rebuild_profile_keys)
+            .collect();
+        policy.set_rebuild_profile_keys(rebuild_profile_keys);
+
         // Determine which profiles need their images rebuilt.
         for (key, profile) in policy.profiles() {
             let needs_rebuild = policy.image_needs_rebuild(profile);
@@ -104,11 +246,23 @@ impl Rebuilds {
                     info!( "profile {key}: image needs rebuild; cached Servo repo update still running" );
                 } else if self.rebuilds.contains_key(key) {
                     info!("profile {key}: image needs rebuild; image rebuild still running");
-                } else if runner_count > 0 {
+                } else if garbage_collecting {
+                    info!("profile {key}: image needs rebuild; garbage collection still running");
+                } else if let Some(reason) = policy.quarantine_reason(key) {
+                    info!("profile {key}: image needs rebuild; profile quarantined: {reason}");
+                } else if policy.rebuild_circuit_open(key) {
+                    info!(
+                        "profile {key}: image needs rebuild; too many failures, waiting for reset"
+                    );
+                } else if let Some(remaining) = policy.rebuild_backoff_remaining(key) {
+                    info!("profile {key}: image needs rebuild; backing off for {remaining:?} after failure");
+                } else if runner_count > 0 && !profile.uses_rolling_upgrades() {
                     info!(
                         runner_count,
                         "profile {key}: image needs rebuild; waiting for runners"
                     );
+                } else if let Err(error) = policy.validate_rebuild_resource_requirements(profile) {
+                    info!("profile {key}: image needs rebuild; waiting for resources: {error}");
                 } else {
                     info!("profile {key}: image needs rebuild");
                     profiles_needing_rebuild.insert(key, profile);
@@ -135,13 +289,61 @@ impl Rebuilds {
         for (key, profile) in profiles_needing_rebuild {
             let snapshot_name = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
 
+            // Record why we are rebuilding the image, and what we are building it from.
+            let reasons = policy.image_rebuild_reasons(profile).unwrap_or_default();
+            info!(?reasons, "profile {key}: starting image rebuild");
+            let inputs = match ImageInputs::collect(profile, &mut self.image_inputs_cache) {
+                Ok(inputs) => Some(inputs),
+                Err(error) => {
+                    warn!(?error, "profile {key}: failed to collect image inputs");
+                    None
+                }
+            };
+            let record = RebuildRecord {
+                reasons,
+                inputs: inputs.clone(),
+                smoke_test_passed: None,
+            };
+            if let Err(error) = write_rebuild_record(key, &snapshot_name, &record) {
+                warn!(?error, "profile {key}: failed to write rebuild record");
+            }
+
+            // Smoke test guests are throwaway clones like runners, so they need a runner id.
+            let smoke_test_runner_id = match has_health_script(profile) {
+                Ok(true) => Some(id_gen.next()),
+                Ok(false) => None,
+                Err(error) => {
+                    warn!(?error, "profile {key}: failed to check for health script");
+                    None
+                }
+            };
+            let smoke_test = Arc::new(SmokeTest::default());
+            let memory_snapshot_runner_id = profile.memory_snapshot.then(|| id_gen.next());
+            let memory_snapshot = Arc::new(MemorySnapshot::default());
+
             let key_for_thread = key.clone();
             let snapshot_name_for_thread = snapshot_name.clone();
+            let smoke_test_for_thread = smoke_test.clone();
+            let memory_snapshot_for_thread = memory_snapshot.clone();
             let thread = match profile.image_type {
                 settings::profile::ImageType::Rust => {
                     let profile = profile.clone();
                     thread::spawn(move || {
-                        rebuild_with_rust(&key_for_thread, profile, &snapshot_name_for_thread)
+                        let _log = RebuildLog::create(&key_for_thread, &snapshot_name_for_thread)
+                            .inspect_err(|error| warn!(?error, "Failed to create rebuild log"));
+                        let result = rebuild_with_rust(
+                            &key_for_thread,
+                            profile,
+                            &snapshot_name_for_thread,
+                            smoke_test_runner_id,
+                            &smoke_test_for_thread,
+                            memory_snapshot_runner_id,
+                            &memory_snapshot_for_thread,
+                        );
+                        wake(Wakeup::RebuildFinished {
+                            profile_key: key_for_thread,
+                        });
+                        result
                     })
                 }
             };
@@ -152,21 +354,68 @@ impl Rebuilds {
                     thread,
                     snapshot_name: snapshot_name.clone(),
                     guest_name: profile.rebuild_guest_name(&snapshot_name),
+                    inputs,
+                    runner_ids: smoke_test_runner_id
+                        .into_iter()
+                        .chain(memory_snapshot_runner_id)
+                        .collect(),
+                    smoke_test,
+                    memory_snapshot,
                 },
             );
         }
 
-        // Reap image rebuild threads, updating the profile on success.
+        // Reap image rebuild threads, promoting the new snapshot on success.
         let mut remaining_rebuilds = BTreeMap::default();
         for (profile_key, rebuild) in take(&mut self.rebuilds) {
             if rebuild.thread.is_finished() {
                 match rebuild.thread.join() {
                     Ok(Ok(())) => {
                         info!(profile_key, "Image rebuild thread exited");
-                        policy.set_base_image_snapshot(&profile_key, &rebuild.snapshot_name)?;
+                        let snapshot_symlink_path =
+                            match get_profile_data_path(&profile_key, Path::new("snapshot")) {
+                                Ok(path) => path,
+                                Err(error) => {
+                                    policy.quarantine_profile(
+                                        &profile_key,
+                                        format!("Failed to get snapshot symlink path: {error}"),
+                                    );
+                                    continue;
+                                }
+                            };
+                        if let Err(error) =
+                            atomic_symlink(&rebuild.snapshot_name, snapshot_symlink_path)
+                        {
+                            error!(profile_key, ?error, "Failed to promote snapshot");
+                            policy.record_rebuild_failure(&profile_key, format!("{error}"));
+                            continue;
+                        }
+                        policy.record_rebuild_success(&profile_key);
+                        if let Err(error) =
+                            policy.set_base_image_snapshot(&profile_key, &rebuild.snapshot_name)
+                        {
+                            policy.quarantine_profile(
+                                &profile_key,
+                                format!("Failed to set base image snapshot: {error}"),
+                            );
+                            continue;
+                        }
+                        if let Some(inputs) = rebuild.inputs {
+                            policy.set_base_image_inputs(&profile_key, inputs);
+                        }
+                        if let Err(error) = unpin_snapshot(policy, &profile_key) {
+                            warn!(profile_key, ?error, "Failed to unpin snapshot");
+                        }
+                    }
+                    Ok(Err(report)) => {
+                        error!(profile_key, %report, "Image rebuild thread error");
+                        policy.record_rebuild_failure(&profile_key, format!("{report}"));
+                    }
+                    Err(panic) => {
+                        error!(profile_key, ?panic, "Image rebuild thread panic");
+                        policy.record_rebuild_failure(&profile_key, format!("panic: {panic:?}"));
+                        policy.quarantine_profile(&profile_key, format!("panic: {panic:?}"));
                     }
-                    Ok(Err(report)) => error!(profile_key, %report, "Image rebuild thread error"),
-                    Err(panic) => error!(profile_key, ?panic, "Image rebuild thread panic"),
                 };
             } else {
                 remaining_rebuilds.insert(profile_key, rebuild);
@@ -183,10 +432,199 @@ impl Rebuilds {
             .map(|(profile_key, rebuild)| (profile_key.clone(), rebuild.guest_name.clone()))
             .collect()
     }
+
+    /// Returns the runner ids used by image rebuilds, which have images but no runner.
+    pub fn runner_ids(&self) -> BTreeSet<usize> {
+        self.rebuilds
+            .values()
+            .flat_map(|rebuild| rebuild.runner_ids.iter().copied())
+            .collect()
+    }
+
+    /// Returns the profiles whose rebuild guests are currently smoke test guests.
+    pub fn smoke_testing_profile_keys(&self) -> BTreeSet<String> {
+        self.rebuilds
+            .iter()
+            .filter(|(_key, rebuild)| rebuild.smoke_test.is_running())
+            .map(|(key, _rebuild)| key.clone())
+            .collect()
+    }
+
+    pub fn smoke_test_github_jitconfig(&self, profile_key: &str) -> Option<String> {
+        self.rebuilds
+            .get(profile_key)
+            .and_then(|rebuild| rebuild.smoke_test.github_jitconfig())
+    }
+
+    /// Returns the profiles whose rebuild guests are currently memory snapshot guests.
+    pub fn memory_snapshotting_profile_keys(&self) -> BTreeSet<String> {
+        self.rebuilds
+            .iter()
+            .filter(|(_key, rebuild)| rebuild.memory_snapshot.is_running())
+            .map(|(key, _rebuild)| key.clone())
+            .collect()
+    }
+
+    pub fn report_memory_snapshot_ready(&self, profile_key: &str) -> eyre::Result<()> {
+        let Some(rebuild) = self.rebuilds.get(profile_key) else {
+            bail!("No image rebuild running for profile {profile_key}");
+        };
+        info!(profile_key, "Memory snapshot guest is ready");
+
+        rebuild.memory_snapshot.report_ready()
+    }
+
+    pub fn report_smoke_test_result(
+        &self,
+        profile_key: &str,
+        result: SmokeTestResult,
+    ) -> eyre::Result<()> {
+        let Some(rebuild) = self.rebuilds.get(profile_key) else {
+            bail!("No image rebuild running for profile {profile_key}");
+        };
+        info!(
+            profile_key,
+            passed = result.passed,
+            "Smoke test result reported"
+        );
+
+        rebuild.smoke_test.report(result)
+    }
+
+    /// Lists the snapshots kept for the given profile, including any rebuild in progress.
+    pub fn snapshots(&self, policy: &Policy, profile_key: &str) -> eyre::Result<Vec<Snapshot>> {
+        if policy.profile(profile_key).is_none() {
+            bail!("No profile with key {profile_key}");
+        }
+        let active_snapshot = policy.base_image_snapshot(profile_key);
+        let mut snapshot_names = template_snapshot_names(profile_key)?;
+        if let Some(rebuild) = self.rebuilds.get(profile_key) {
+            snapshot_names.insert(rebuild.snapshot_name.clone());
+        }
+
+        let mut result = vec![];
+        for name in snapshot_names.into_iter().rev() {
+            let status = if self
+                .rebuilds
+                .get(profile_key)
+                .is_some_and(|rebuild| rebuild.snapshot_name == name)
+            {
+                SnapshotStatus::Rebuilding
+            } else if active_snapshot == Some(&name) {
+                SnapshotStatus::Active
+            } else {
+                SnapshotStatus::Available
+            };
+            let pinned = status == SnapshotStatus::Active
+                && policy.base_image_snapshot_is_pinned(profile_key);
+            let record = match read_rebuild_record(profile_key, &name) {
+                Ok(record) => record.unwrap_or_default(),
+                Err(error) => {
+                    warn!(profile_key, name, ?error, "Failed to read rebuild record");
+                    RebuildRecord::default()
+                }
+            };
+            let status =
+                if status == SnapshotStatus::Available && record.smoke_test_passed == Some(false) {
+                    SnapshotStatus::Failed
+                } else {
+                    status
+                };
+            let reasons = record.reasons;
+            result.push(Snapshot {
+                age: snapshot_age(&name).ok(),
+                name,
+                status,
+                pinned,
+                reasons,
+            });
+        }
+
+        Ok(result)
+    }
+
+    /// Forces the image for the given profile to be rebuilt, even if its snapshot is pinned.
+    pub fn request_rebuild(&self, policy: &mut Policy, profile_key: &str) -> eyre::Result<()> {
+        if self.rebuilds.contains_key(profile_key) {
+            bail!("Image rebuild still running for profile {profile_key}");
+        }
+        policy.request_rebuild(profile_key)?;
+        info!(profile_key, "Image rebuild requested");
+
+        Ok(())
+    }
+
+    /// Makes new runners for the given profile use the given snapshot, and pins that snapshot
+    /// so it won’t be rebuilt until another rebuild is requested.
+    pub fn activate_snapshot(
+        &self,
+        policy: &mut Policy,
+        profile_key: &str,
+        snapshot_name: &str,
+    ) -> eyre::Result<()> {
+        if policy.profile(profile_key).is_none() {
+            bail!("No profile with key {profile_key}");
+        }
+        if self.rebuilds.contains_key(profile_key) {
+            bail!("Image rebuild still running for profile {profile_key}");
+        }
+        if !template_snapshot_names(profile_key)?.contains(snapshot_name) {
+            bail!("No snapshot {snapshot_name} for profile {profile_key}");
+        }
+
+        let snapshot_symlink_path = get_profile_data_path(profile_key, Path::new("snapshot"))?;
+        atomic_symlink(snapshot_name, snapshot_symlink_path)?;
+        let pinned_symlink_path = get_profile_data_path(profile_key, Path::new("pinned-snapshot"))?;
+        atomic_symlink(snapshot_name, pinned_symlink_path)?;
+
+        policy.set_base_image_snapshot(profile_key, snapshot_name)?;
+        policy.set_pinned_base_image_snapshot(profile_key, Some(snapshot_name));
+        match read_rebuild_record(profile_key, snapshot_name) {
+            Ok(Some(RebuildRecord {
+                inputs: Some(inputs),
+                ..
+            })) => policy.set_base_image_inputs(profile_key, inputs),
+            Ok(_) => {}
+            Err(error) => warn!(profile_key, ?error, "Failed to read rebuild record"),
+        }
+        info!(profile_key, snapshot_name, "Activated snapshot");
+
+        Ok(())
+    }
+}
+
+/// Returns the names of the template snapshots for the given profile, in sorted order.
+fn template_snapshot_names(profile_key: &str) -> eyre::Result<BTreeSet<String>> {
+    let mut result = BTreeSet::default();
+    for template_guest_name in list_template_guests()? {
+        if let Ok((key, snapshot_name)) = parse_template_guest_name(&template_guest_name) {
+            if key == profile_key {
+                result.insert(snapshot_name.to_owned());
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn unpin_snapshot(policy: &mut Policy, profile_key: &str) -> eyre::Result<()> {
+    policy.set_pinned_base_image_snapshot(profile_key, None);
+    let pinned_symlink_path = get_profile_data_path(profile_key, Path::new("pinned-snapshot"))?;
+    if pinned_symlink_path.symlink_metadata().is_ok() {
+        remove_file(pinned_symlink_path)?;
+    }
+
+    Ok(())
 }
 
 #[tracing::instrument]
 fn servo_update_thread() -> eyre::Result<()> {
+    let result = update_servo_repo();
+    wake(Wakeup::ServoUpdateFinished);
+    result
+}
+
+fn update_servo_repo() -> eyre::Result<()> {
     info!("Starting repo update");
 
     let main_repo_path = &TOML.main_repo_path;
@@ -203,112 +641,108 @@ fn servo_update_thread() -> eyre::Result<()> {
     Ok(())
 }
 
-#[tracing::instrument(skip(profile, snapshot_name))]
+#[tracing::instrument(skip(profile, snapshot_name, smoke_test, memory_snapshot))]
 fn rebuild_with_rust(
     profile_key: &str,
     profile: Profile,
     snapshot_name: &str,
+    smoke_test_runner_id: Option<usize>,
+    smoke_test: &SmokeTest,
+    memory_snapshot_runner_id: Option<usize>,
+    memory_snapshot: &MemorySnapshot,
 ) -> Result<(), eyre::Error> {
     info!(?snapshot_name, "Starting image rebuild");
 
     let base_images_path = create_template_or_rebuild_images_dir(&profile)?;
 
-    match match &*profile.profile_name {
-        "servo-macos13" => macos13::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(2000),
-        ),
-        "servo-macos14" => macos13::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(2000),
-        ),
-        "servo-macos15" => macos13::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(2000),
-        ),
-        "servo-ubuntu2204" => ubuntu2204::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(2000),
-        ),
-        "servo-ubuntu2204-bench" => ubuntu2204::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(1000),
-        ),
-        "base-ubuntu2204" => ubuntu2204::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(20),
-            Duration::from_secs(90),
-        ),
-        "servo-ubuntu2204-wpt" => ubuntu2204::rebuild(
-            &base_images_path,
-            &profile,
-            snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(2000),
-        ),
-        "servo-windows10" => windows10::rebuild(
+    let supported = supported_profile(&profile)?;
+    let rebuild = match supported.guest_os {
+        GuestOs::Macos13 => macos13::rebuild,
+        GuestOs::Ubuntu2204 => ubuntu2204::rebuild,
+        GuestOs::Windows10 => windows10::rebuild,
+    };
+    match phase("build", || {
+        rebuild(
             &base_images_path,
             &profile,
             snapshot_name,
-            ByteSize::gib(90),
-            Duration::from_secs(3000),
-        ),
-        other => todo!("Rebuild not yet implemented: {other}"),
-    } {
-        result @ Ok(()) => {
-            prune_templates(&profile)?;
-            result
-        }
+            supported.base_image_size,
+            supported.rebuild_timeout,
+        )
+    }) {
+        Ok(()) => {}
         Err(error) => {
             warn!(?error, "Image rebuild error");
             delete_template(&profile, snapshot_name)?;
-            Err(error)
+            return Err(error);
         }
     }
+
+    // Smoke test the new image before it gets promoted. If it fails, keep it for inspection.
+    if let Some(runner_id) = smoke_test_runner_id {
+        let result = phase("smoke test", || {
+            smoke::run(&profile, snapshot_name, runner_id, smoke_test)
+        });
+        if let Err(error) = record_smoke_test_result(profile_key, snapshot_name, result.is_ok()) {
+            warn!(?error, "Failed to record smoke test result");
+        }
+        if let Err(error) = result {
+            warn!(?error, "Smoke test error");
+            phase("prune templates", || {
+                prune_templates(&profile, 3, &BTreeSet::default())
+            })?;
+            return Err(error);
+        }
+    }
+
+    // Save the RAM state of the new image, so runners can be restored instead of booted. If this
+    // fails, runners are booted as usual.
+    if let Some(runner_id) = memory_snapshot_runner_id {
+        let result = phase("memory snapshot", || {
+            memory::run(&profile, snapshot_name, runner_id, memory_snapshot)
+        });
+        if let Err(error) = result {
+            warn!(?error, "Memory snapshot error");
+        }
+    }
+
+    phase("prune templates", || {
+        prune_templates(&profile, 3, &BTreeSet::default())
+    })?;
+
+    Ok(())
+}
+
+fn record_smoke_test_result(
+    profile_key: &str,
+    snapshot_name: &str,
+    passed: bool,
+) -> eyre::Result<()> {
+    let mut record = read_rebuild_record(profile_key, snapshot_name)?.unwrap_or_default();
+    record.smoke_test_passed = Some(passed);
+    write_rebuild_record(profile_key, snapshot_name, &record)?;
+
+    Ok(())
 }
 
 pub fn delete_template(profile: &Profile, snapshot_name: &str) -> eyre::Result<()> {
-    match &*profile.profile_name {
-        "servo-macos13" => macos13::delete_template(profile, snapshot_name),
-        "servo-macos14" => macos13::delete_template(profile, snapshot_name),
-        "servo-macos15" => macos13::delete_template(profile, snapshot_name),
-        "servo-ubuntu2204" => ubuntu2204::delete_template(profile, snapshot_name),
-        "servo-ubuntu2204-bench" => ubuntu2204::delete_template(profile, snapshot_name),
-        "base-ubuntu2204" => ubuntu2204::delete_template(profile, snapshot_name),
-        "servo-ubuntu2204-wpt" => ubuntu2204::delete_template(profile, snapshot_name),
-        "servo-windows10" => windows10::delete_template(profile, snapshot_name),
-        other => todo!("Image pruning not yet implemented: {other}"),
+    delete_memory_snapshot(profile, snapshot_name);
+    match supported_profile(profile)?.guest_os {
+        GuestOs::Macos13 => macos13::delete_template(profile, snapshot_name),
+        GuestOs::Ubuntu2204 => ubuntu2204::delete_template(profile, snapshot_name),
+        GuestOs::Windows10 => windows10::delete_template(profile, snapshot_name),
     }
 }
 
-pub fn register_runner(profile: &Profile, runner_guest_name: &str) -> eyre::Result<String> {
-    match &*profile.profile_name {
-        "servo-macos13" => macos13::register_runner(profile, runner_guest_name),
-        "servo-macos14" => macos13::register_runner(profile, runner_guest_name),
-        "servo-macos15" => macos13::register_runner(profile, runner_guest_name),
-        "servo-ubuntu2204" => ubuntu2204::register_runner(profile, runner_guest_name),
-        "servo-ubuntu2204-bench" => ubuntu2204::register_runner(profile, runner_guest_name),
-        "base-ubuntu2204" => ubuntu2204::register_runner(profile, runner_guest_name),
-        "servo-ubuntu2204-wpt" => ubuntu2204::register_runner(profile, runner_guest_name),
-        "servo-windows10" => windows10::register_runner(profile, runner_guest_name),
-        other => todo!("Runner registration not yet implemented: {other}"),
+pub fn register_runner(
+    profile: &Profile,
+    runner_guest_name: &str,
+    label: &str,
+) -> eyre::Result<String> {
+    match supported_profile(profile)?.guest_os {
+        GuestOs::Macos13 => macos13::register_runner(runner_guest_name, label),
+        GuestOs::Ubuntu2204 => ubuntu2204::register_runner(runner_guest_name, label),
+        GuestOs::Windows10 => windows10::register_runner(runner_guest_name, label),
     }
 }
 
@@ -318,32 +752,16 @@ pub fn create_runner(
     runner_guest_name: &str,
     runner_id: usize,
 ) -> eyre::Result<String> {
-    match &*profile.profile_name {
-        "servo-macos13" => {
-            macos13::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
-        }
-        "servo-macos14" => {
+    match supported_profile(profile)?.guest_os {
+        GuestOs::Macos13 => {
             macos13::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
         }
-        "servo-macos15" => {
-            macos13::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
-        }
-        "servo-ubuntu2204" => {
-            ubuntu2204::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
-        }
-        "servo-ubuntu2204-bench" => {
-            ubuntu2204::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
-        }
-        "base-ubuntu2204" => {
+        GuestOs::Ubuntu2204 => {
             ubuntu2204::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
         }
-        "servo-ubuntu2204-wpt" => {
-            ubuntu2204::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
-        }
-        "servo-windows10" => {
+        GuestOs::Windows10 => {
             windows10::create_runner(profile, snapshot_name, runner_guest_name, runner_id)
         }
-        other => todo!("Runner creation not yet implemented: {other}"),
     }
 }
 
@@ -352,19 +770,18 @@ pub fn destroy_runner(
     runner_guest_name: &str,
     runner_id: usize,
 ) -> eyre::Result<()> {
-    match &*profile.profile_name {
-        "servo-macos13" => macos13::destroy_runner(runner_guest_name, runner_id),
-        "servo-macos14" => macos13::destroy_runner(runner_guest_name, runner_id),
-        "servo-macos15" => macos13::destroy_runner(runner_guest_name, runner_id),
-        "servo-ubuntu2204" => ubuntu2204::destroy_runner(runner_guest_name, runner_id),
-        "servo-ubuntu2204-bench" => ubuntu2204::destroy_runner(runner_guest_name, runner_id),
-        "base-ubuntu2204" => ubuntu2204::destroy_runner(runner_guest_name, runner_id),
-        "servo-ubuntu2204-wpt" => ubuntu2204::destroy_runner(runner_guest_name, runner_id),
-        "servo-windows10" => windows10::destroy_runner(runner_guest_name, runner_id),
-        other => todo!("Runner destruction not yet implemented: {other}"),
+    match supported_profile(profile)?.guest_os {
+        GuestOs::Macos13 => macos13::destroy_runner(runner_guest_name, runner_id),
+        GuestOs::Ubuntu2204 => ubuntu2204::destroy_runner(runner_guest_name, runner_id),
+        GuestOs::Windows10 => windows10::destroy_runner(runner_guest_name, runner_id),
     }
 }
 
+fn supported_profile(profile: &Profile) -> eyre::Result<&'static SupportedProfile> {
+    SupportedProfile::get(&profile.profile_name)
+        .ok_or_else(|| eyre!("Profile not supported: {}", profile.profile_name))
+}
+
 pub(self) fn create_template_or_rebuild_images_dir(profile: &Profile) -> eyre::Result<PathBuf> {
     let base_images_path = template_or_rebuild_images_path(profile);
     debug!(?base_images_path, "Creating base images subdirectory");
@@ -381,7 +798,17 @@ pub(self) fn create_runner_images_dir() -> eyre::Result<PathBuf> {
     Ok(runner_images_path)
 }
 
-pub(self) fn prune_templates(profile: &Profile) -> eyre::Result<()> {
+/// Deletes the templates for the given profile, except the `keep_recent` most recent, the active
+/// one, any in `in_use`, and any with dependent images, then deletes any image files that don’t
+/// belong to those templates. Returns the number of templates deleted.
+///
+/// Outside of the rebuild thread for the profile, this must only be called while the profile is
+/// not being rebuilt, because the image files of a new snapshot don’t belong to a template yet.
+pub fn prune_templates(
+    profile: &Profile,
+    keep_recent: usize,
+    in_use: &BTreeSet<String>,
+) -> eyre::Result<usize> {
     // Build a sorted list of template guest names for this profile.
     let mut snapshot_names = vec![];
     for template_guest_name in list_template_guests()? {
@@ -395,16 +822,36 @@ pub(self) fn prune_templates(profile: &Profile) -> eyre::Result<()> {
     }
     snapshot_names.sort();
 
-    // Delete all of those templates, except the three most recent.
+    // Delete all of those templates, except the most recent and the active one, which may be
+    // older if newer snapshots failed their smoke tests or were rolled back.
     // Since the snapshot names are RFC 3339 timestamps, we can use the sorted order (until year 10000).
-    let keep_snapshots = snapshot_names.clone().into_iter().rev().take(3);
-    let delete_snapshots = snapshot_names.iter().rev().skip(3);
+    let active_snapshot = read_base_image_snapshot(profile)?;
+    let mut keep_snapshots = snapshot_names
+        .iter()
+        .rev()
+        .take(keep_recent)
+        .chain(active_snapshot.as_ref())
+        .chain(in_use)
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let delete_snapshots = snapshot_names
+        .iter()
+        .filter(|snapshot_name| !keep_snapshots.contains(*snapshot_name))
+        .collect::<Vec<_>>();
+    let mut deleted_count = 0;
     for snapshot_name in delete_snapshots {
+        // Runners may still be using images cloned from the template, even if they are not
+        // runners we know about, and some storage backends can’t delete it until they are gone.
+        if template_has_dependents(profile, snapshot_name)? {
+            info!(snapshot_name, "Keeping template with dependent images");
+            keep_snapshots.insert(snapshot_name.clone());
+            continue;
+        }
         delete_template(profile, snapshot_name)?;
+        deleted_count += 1;
     }
 
     // Now delete any files that are not associated with a known snapshot.
-    let keep_snapshots = keep_snapshots.collect::<BTreeSet<_>>();
     let base_images_path = template_or_rebuild_images_path(profile);
     info!(?base_images_path, "Pruning base image files");
     create_dir_all(&base_images_path)?;
@@ -421,14 +868,27 @@ pub(self) fn prune_templates(profile: &Profile) -> eyre::Result<()> {
         }
     }
 
-    Ok(())
+    Ok(deleted_count)
+}
+
+/// Returns true iff any images depend on the images that runners are cloned from for the given
+/// template, per [`storage::has_dependents`].
+fn template_has_dependents(profile: &Profile, snapshot_name: &str) -> eyre::Result<bool> {
+    for filename in ["base.img", "memory.img"] {
+        let path = template_or_rebuild_image_path(profile, snapshot_name, filename);
+        if path.exists() && storage::has_dependents(&path)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
 }
 
 pub(self) fn delete_template_or_rebuild_image_file(profile: &Profile, filename: &str) {
     let base_images_path = template_or_rebuild_images_path(profile);
     let path = base_images_path.join(filename);
     info!(?path, "Deleting");
-    if let Err(error) = remove_file(&path) {
+    if let Err(error) = storage::delete_image(&path) {
         warn!(?path, ?error, "Failed to delete");
     }
 }
@@ -443,29 +903,7 @@ pub(self) fn create_disk_image<'icp>(
     let base_image_filename = format!("base.img@{snapshot_name}");
     let base_image_path = base_images_path.join(&base_image_filename);
 
-    info!(?base_image_path, "Creating base image file");
-    let mut base_image_file = if let Some(from) = initial_contents_path.into() {
-        reflink_or_copy_with_warning(from, &base_image_path)?;
-
-        // Copying out of the nix store yields a file with mode 444 (read only). Make sure the file is writable.
-        set_permissions(&base_image_path, PermissionsExt::from_mode(0o644))?;
-
-        File::options().write(true).open(&base_image_path)?
-    } else {
-        File::create_new(&base_image_path)?
-    };
-
-    let delta = size
-        .0
-        .checked_sub(base_image_file.stream_position()?)
-        .ok_or_eyre("`size` is smaller than `initial_contents`")?;
-
-    // If `size` is bigger than `initial_contents`, extend the file quickly by seeking and writing at least one byte.
-    // We could write all the zeros, but this is not necessarily helpful since ZFS is a COW file system.
-    if let Some(delta) = delta.checked_sub(1) {
-        base_image_file.seek_relative(delta.try_into()?)?;
-        base_image_file.write_all(&[0])?;
-    }
+    storage::create_image(&base_image_path, size, initial_contents_path.into())?;
 
     Ok(base_image_path)
 }
@@ -480,10 +918,10 @@ pub(self) fn define_libvirt_guest(
     // This dance is needed to randomise the MAC address of the guest.
     let guest_xml_path = guest_xml_path.as_ref();
     let args = args.iter().map(|x| x.as_ref()).collect::<Vec<_>>();
-    run_cmd!(virsh define -- $guest_xml_path)?;
-    run_cmd!(virt-clone --preserve-data --check path_in_use=off -o $profile_name.init -n $guest_name $[args])?;
+    run_cmd_logged!(virsh define -- $guest_xml_path)?;
+    run_cmd_logged!(virt-clone --preserve-data --check path_in_use=off -o $profile_name.init -n $guest_name $[args])?;
     libvirt_change_media(guest_name, cdrom_images)?;
-    run_cmd!(virsh undefine -- $profile_name.init)?;
+    run_cmd_logged!(virsh undefine -- $profile_name.init)?;
 
     Ok(())
 }
@@ -493,17 +931,17 @@ pub(self) fn libvirt_change_media(
     cdrom_images: &[CdromImage],
 ) -> eyre::Result<()> {
     for CdromImage { target_dev, path } in cdrom_images {
-        run_cmd!(virsh change-media -- $guest_name $target_dev $path)?;
+        run_cmd_logged!(virsh change-media -- $guest_name $target_dev $path)?;
     }
 
     Ok(())
 }
 
 pub(self) fn undefine_libvirt_guest(guest_name: &str) -> eyre::Result<()> {
-    if run_cmd!(virsh domstate -- $guest_name).is_ok() {
+    if run_cmd_logged!(virsh domstate -- $guest_name).is_ok() {
         // FIXME make this idempotent in a less noisy way?
-        let _ = run_cmd!(virsh destroy -- $guest_name);
-        run_cmd!(virsh undefine --nvram -- $guest_name)?;
+        let _ = run_cmd_logged!(virsh destroy -- $guest_name);
+        run_cmd_logged!(virsh undefine --nvram -- $guest_name)?;
     }
 
     Ok(())
@@ -518,17 +956,40 @@ impl<'path> CdromImage<'path> {
         Self { target_dev, path }
     }
 }
+/// Starting guests in parallel causes errors, so start one at a time.
+static START_GUEST_LOCK: Mutex<()> = Mutex::new(());
+
 pub fn start_libvirt_guest(guest_name: &str) -> eyre::Result<()> {
+    let _guard = START_GUEST_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
     info!(?guest_name, "Starting guest");
-    run_cmd!(virsh start -- $guest_name)?;
+    run_cmd_logged!(virsh start -- $guest_name)?;
+
+    Ok(())
+}
+
+fn restore_libvirt_guest(
+    guest_name: &str,
+    saved_state_path: impl AsRef<Path>,
+    guest_xml_path: impl AsRef<Path>,
+) -> eyre::Result<()> {
+    let _guard = START_GUEST_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let saved_state_path = saved_state_path.as_ref();
+    let guest_xml_path = guest_xml_path.as_ref();
+    info!(?guest_name, ?saved_state_path, "Restoring guest");
+    run_cmd_logged!(virsh restore --xml $guest_xml_path -- $saved_state_path)?;
 
     Ok(())
 }
 
 pub(self) fn wait_for_guest(guest_name: &str, timeout: Duration) -> eyre::Result<()> {
     let timeout = timeout.as_secs();
+    capture_serial_console(guest_name);
     info!("Waiting for guest to shut down (max {timeout} seconds)");
-    if !run_cmd!(time virsh event --timeout $timeout -- $guest_name lifecycle).is_ok() {
+    if run_cmd_logged!(time virsh event --timeout $timeout -- $guest_name lifecycle).is_err() {
         bail!("`virsh event` failed or timed out!");
     }
     for _ in 0..100 {
@@ -541,6 +1002,6 @@ pub(self) fn wait_for_guest(guest_name: &str, timeout: Duration) -> eyre::Result
 }
 
 pub(self) fn rename_guest(old_guest_name: &str, new_guest_name: &str) -> eyre::Result<()> {
-    run_cmd!(virsh domrename -- $old_guest_name $new_guest_name)?;
+    run_cmd_logged!(virsh domrename -- $old_guest_name $new_guest_name)?;
     Ok(())
 }
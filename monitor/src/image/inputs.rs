@@ -0,0 +1,328 @@
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read_dir, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use cmd_lib::run_fun;
+use jane_eyre::eyre::{self, OptionExt};
+use serde::{Deserialize, Serialize};
+use settings::{
+    profile::{Profile, RebuildTrigger},
+    IMAGE_DEPS_DIR, TOML,
+};
+use tracing::warn;
+
+use crate::data::{get_profile_configuration_path, get_snapshot_data_path};
+
+/// Record of why and from what inputs an image was built, stored alongside each snapshot.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RebuildRecord {
+    pub reasons: Vec<String>,
+    pub inputs: Option<ImageInputs>,
+    /// Whether the image passed its smoke test, or None if it was not smoke tested.
+    #[serde(default)]
+    pub smoke_test_passed: Option<bool>,
+}
+
+/// Inputs that an image is built from, for deciding whether the image needs to be rebuilt.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ImageInputs {
+    /// SHA-256 digest of each file in the profile configuration directory, by filename.
+    pub configuration: BTreeMap<String, String>,
+    /// Commit checked out in our cached Servo repo, if any.
+    pub main_repo_commit: Option<String>,
+    /// Canonical path and size of each file in `IMAGE_DEPS_DIR`, by relative path. Since the
+    /// files are symlinks into the Nix store, their canonical paths change with their contents.
+    pub image_deps: BTreeMap<String, String>,
+}
+
+/// Digests and commits from previous calls to `ImageInputs::collect()`, so we only need to run
+/// sha256sum and git again when the files they were computed from have changed.
+#[derive(Debug, Default)]
+pub struct ImageInputsCache {
+    /// SHA-256 digest of each profile configuration file, by canonical path.
+    digests: BTreeMap<PathBuf, (FileStamp, String)>,
+    /// Commit checked out in our cached Servo repo, if any.
+    main_repo_commit: Option<(Vec<FileStamp>, Option<String>)>,
+}
+
+/// Modification time and size of a file, for telling whether it has changed.
+#[derive(Clone, Debug, PartialEq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> eyre::Result<Self> {
+        let metadata = path.metadata()?;
+
+        Ok(Self {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+impl ImageInputsCache {
+    /// Returns the SHA-256 digest of the given file, running sha256sum only if the file has
+    /// changed since we last did.
+    fn digest(&mut self, path: &Path) -> eyre::Result<String> {
+        let path = path.canonicalize()?;
+        let stamp = FileStamp::of(&path)?;
+        if let Some((cached_stamp, digest)) = self.digests.get(&path) {
+            if *cached_stamp == stamp {
+                return Ok(digest.clone());
+            }
+        }
+        let digest = run_fun!(sha256sum -- $path)?;
+        let digest = digest
+            .split_ascii_whitespace()
+            .next()
+            .ok_or_eyre("Bad sha256sum output")?
+            .to_owned();
+        self.digests.insert(path, (stamp, digest.clone()));
+
+        Ok(digest)
+    }
+
+    /// Returns the commit checked out in the given repo, running git only if the repo’s HEAD or
+    /// git directory have changed since we last did.
+    fn main_repo_commit(&mut self, main_repo_path: &Path) -> Option<String> {
+        let git_dir = main_repo_path.join(".git");
+        let stamps = [git_dir.clone(), git_dir.join("HEAD")]
+            .iter()
+            .map(|path| FileStamp::of(path))
+            .collect::<eyre::Result<Vec<_>>>()
+            .ok();
+        if let (Some(stamps), Some((cached_stamps, commit))) = (&stamps, &self.main_repo_commit) {
+            if stamps == cached_stamps {
+                return commit.clone();
+            }
+        }
+        let commit = match run_fun!(git -C $main_repo_path rev-parse HEAD) {
+            Ok(commit) => Some(commit.trim().to_owned()),
+            Err(error) => {
+                warn!(?error, "Failed to get commit of cached Servo repo");
+                None
+            }
+        };
+        self.main_repo_commit = stamps.map(|stamps| (stamps, commit.clone()));
+
+        commit
+    }
+}
+
+impl ImageInputs {
+    pub fn collect(profile: &Profile, cache: &mut ImageInputsCache) -> eyre::Result<Self> {
+        let mut configuration = BTreeMap::default();
+        let profile_configuration_path = get_profile_configuration_path(profile, None)?;
+        for entry in read_dir(&profile_configuration_path)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let filename = path
+                .file_name()
+                .and_then(|filename| filename.to_str())
+                .ok_or_eyre("Unsupported path")?;
+            configuration.insert(filename.to_owned(), cache.digest(&path)?);
+        }
+
+        let main_repo_commit = cache.main_repo_commit(Path::new(&TOML.main_repo_path));
+
+        let mut image_deps = BTreeMap::default();
+        collect_image_deps(&IMAGE_DEPS_DIR, Path::new(""), &mut image_deps)?;
+
+        Ok(Self {
+            configuration,
+            main_repo_commit,
+            image_deps,
+        })
+    }
+
+    /// Returns the reasons to rebuild an image built from `self`, given the `current` inputs.
+    pub fn changes(&self, current: &Self, triggers: &[RebuildTrigger]) -> Vec<String> {
+        let mut result = vec![];
+        for trigger in triggers {
+            match trigger {
+                RebuildTrigger::Configuration => {
+                    result.extend(
+                        map_changes(&self.configuration, &current.configuration)
+                            .map(|filename| format!("profile configuration changed: {filename}")),
+                    );
+                }
+                RebuildTrigger::MainRepo => {
+                    if let (Some(built), Some(current)) =
+                        (&self.main_repo_commit, &current.main_repo_commit)
+                    {
+                        if built != current {
+                            result.push(format!("main repo changed: {built} -> {current}"));
+                        }
+                    }
+                }
+                RebuildTrigger::ImageDeps => {
+                    result.extend(
+                        map_changes(&self.image_deps, &current.image_deps)
+                            .map(|path| format!("image deps changed: {path}")),
+                    );
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// Returns the keys that were added, removed, or changed.
+fn map_changes<'m>(
+    old: &'m BTreeMap<String, String>,
+    new: &'m BTreeMap<String, String>,
+) -> impl Iterator<Item = &'m str> {
+    let removed_or_changed = old
+        .iter()
+        .filter(|(key, value)| new.get(*key) != Some(value))
+        .map(|(key, _)| key.as_str());
+    let added = new
+        .keys()
+        .filter(|key| !old.contains_key(*key))
+        .map(|key| key.as_str());
+
+    removed_or_changed.chain(added)
+}
+
+fn collect_image_deps(
+    base: &Path,
+    relative: &Path,
+    result: &mut BTreeMap<String, String>,
+) -> eyre::Result<()> {
+    for entry in read_dir(base.join(relative))? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        let path = base.join(&relative);
+        // Follow symlinks, since the image deps are mostly symlinks into the Nix store.
+        let metadata = path.metadata()?;
+        if metadata.is_dir() {
+            collect_image_deps(base, &relative, result)?;
+        } else {
+            let key = relative.to_str().ok_or_eyre("Unsupported path")?;
+            let canonical_path = path.canonicalize()?;
+            let value = format!("{} {}", canonical_path.display(), metadata.len());
+            result.insert(key.to_owned(), value);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_rebuild_record(
+    profile_key: &str,
+    snapshot_name: &str,
+) -> eyre::Result<Option<RebuildRecord>> {
+    let path = get_snapshot_data_path(profile_key, snapshot_name, Path::new("rebuild.toml"))?;
+    let Ok(mut file) = File::open(path) else {
+        return Ok(None);
+    };
+    let mut contents = String::default();
+    file.read_to_string(&mut contents)?;
+
+    Ok(Some(toml::from_str(&contents)?))
+}
+
+pub fn write_rebuild_record(
+    profile_key: &str,
+    snapshot_name: &str,
+    record: &RebuildRecord,
+) -> eyre::Result<()> {
+    let snapshot_data_path = get_snapshot_data_path(profile_key, snapshot_name, None)?;
+    create_dir_all(&snapshot_data_path)?;
+    let mut file = File::create(snapshot_data_path.join("rebuild.toml"))?;
+    file.write_all(toml::to_string(record)?.as_bytes())?;
+
+    Ok(())
+}
+
+#[test]
+fn test_image_inputs_changes() {
+    let built = ImageInputs {
+        configuration: [
+            ("boot-script".to_owned(), "a".to_owned()),
+            ("user-data".to_owned(), "b".to_owned()),
+        ]
+        .into(),
+        main_repo_commit: Some("c".to_owned()),
+        image_deps: [("ubuntu2204/rustup-init".to_owned(), "d".to_owned())].into(),
+    };
+    let all = [
+        RebuildTrigger::Configuration,
+        RebuildTrigger::MainRepo,
+        RebuildTrigger::ImageDeps,
+    ];
+
+    // Nothing changed.
+    assert!(built.changes(&built, &all).is_empty());
+
+    // Changes are only reported for the given triggers.
+    let current = ImageInputs {
+        configuration: [
+            ("boot-script".to_owned(), "a".to_owned()),
+            ("guest.xml".to_owned(), "e".to_owned()),
+        ]
+        .into(),
+        main_repo_commit: Some("f".to_owned()),
+        image_deps: [("ubuntu2204/rustup-init".to_owned(), "g".to_owned())].into(),
+    };
+    assert_eq!(
+        built.changes(&current, &all),
+        [
+            "profile configuration changed: user-data",
+            "profile configuration changed: guest.xml",
+            "main repo changed: c -> f",
+            "image deps changed: ubuntu2204/rustup-init",
+        ]
+    );
+    assert_eq!(
+        built.changes(&current, &[RebuildTrigger::MainRepo]),
+        ["main repo changed: c -> f"]
+    );
+    assert!(built.changes(&current, &[]).is_empty());
+
+    // Unknown commits are not considered a change.
+    let current = ImageInputs {
+        main_repo_commit: None,
+        ..built.clone()
+    };
+    assert!(built.changes(&current, &all).is_empty());
+}
+
+#[test]
+fn test_image_inputs_cache_digest() -> eyre::Result<()> {
+    use std::fs::write;
+
+    use mktemp::Temp;
+
+    let dir = Temp::new_dir()?;
+    let path = dir.join("boot-script");
+    write(&path, "foo")?;
+    let mut cache = ImageInputsCache::default();
+    let foo = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+    assert_eq!(cache.digest(&path)?, foo);
+
+    // Unchanged files are not hashed again.
+    cache
+        .digests
+        .get_mut(&path.canonicalize()?)
+        .expect("Just inserted")
+        .1 = "cached".to_owned();
+    assert_eq!(cache.digest(&path)?, "cached");
+
+    // Changed files are hashed again.
+    write(&path, "quux")?;
+    assert_ne!(cache.digest(&path)?, "cached");
+    assert_ne!(cache.digest(&path)?, foo);
+
+    Ok(())
+}
@@ -5,25 +5,22 @@ use std::path::Path;
 use std::time::Duration;
 
 use bytesize::ByteSize;
-use cmd_lib::run_cmd;
 use cmd_lib::spawn_with_output;
 use jane_eyre::eyre;
 use jane_eyre::eyre::OptionExt;
 use settings::profile::Profile;
 use tracing::warn;
 
-use crate::data::get_profile_data_path;
 use crate::image::create_runner_images_dir;
 use crate::image::delete_template_or_rebuild_image_file;
 use crate::image::libvirt_change_media;
+use crate::image::memory::template_disk_image_path;
 use crate::image::rename_guest;
 use crate::image::undefine_libvirt_guest;
 use crate::image::CdromImage;
 use crate::policy::runner_image_path;
-use crate::policy::template_or_rebuild_image_path;
-use crate::shell::atomic_symlink;
-use crate::shell::log_output_as_info;
-use crate::shell::reflink_or_copy_with_warning;
+use crate::shell::{log_output_as_info, run_cmd_logged};
+use crate::storage;
 
 use super::create_disk_image;
 use super::start_libvirt_guest;
@@ -60,9 +57,6 @@ pub(super) fn rebuild(
 
     let template_guest_name = &profile.template_guest_name(snapshot_name);
     rename_guest(rebuild_guest_name, template_guest_name)?;
-    let snapshot_symlink_path =
-        get_profile_data_path(&profile.profile_name, Path::new("snapshot"))?;
-    atomic_symlink(snapshot_name, snapshot_symlink_path)?;
 
     Ok(())
 }
@@ -80,7 +74,7 @@ fn define_base_guest(
         .to_str()
         .ok_or_eyre("Unsupported path")?;
     // Clone the hand-made clean guest, since we can’t yet automate the macOS install
-    run_cmd!(virt-clone --preserve-data --check path_in_use=off -o $clean_guest_name -n $rebuild_guest_name --nvram /var/lib/libvirt/images/OSX-KVM/OVMF_VARS.$clean_guest_name.fd --skip-copy sda -f $base_image_path --skip-copy sdc)?;
+    run_cmd_logged!(virt-clone --preserve-data --check path_in_use=off -o $clean_guest_name -n $rebuild_guest_name --nvram /var/lib/libvirt/images/OSX-KVM/OVMF_VARS.$clean_guest_name.fd --skip-copy sda -f $base_image_path --skip-copy sdc)?;
     libvirt_change_media(rebuild_guest_name, cdrom_images)?;
 
     Ok(())
@@ -92,12 +86,8 @@ pub(super) fn delete_template(profile: &Profile, snapshot_name: &str) -> eyre::R
     Ok(())
 }
 
-pub fn register_runner(profile: &Profile, runner_guest_name: &str) -> eyre::Result<String> {
-    monitor::github::register_runner(
-        runner_guest_name,
-        &profile.github_runner_label,
-        "/Users/servo/a",
-    )
+pub fn register_runner(runner_guest_name: &str, label: &str) -> eyre::Result<String> {
+    monitor::github::register_runner(runner_guest_name, label, "/Users/servo/a")
 }
 
 pub fn create_runner(
@@ -110,12 +100,12 @@ pub fn create_runner(
     let snapshot_path_slug = &profile.snapshot_path_slug(snapshot_name);
     let template_guest_name = &profile.template_guest_name(snapshot_name);
 
-    // Copy images in the monitor, not with `virt-clone --auto-clone --reflink`,
+    // Clone images in the monitor, not with `virt-clone --auto-clone --reflink`,
     // because the latter can’t be parallelised without causing errors.
-    let template_base_img = template_or_rebuild_image_path(profile, snapshot_name, "base.img");
+    let template_base_img = template_disk_image_path(profile, snapshot_name);
     create_runner_images_dir()?;
     let runner_base_img = runner_image_path(runner_id, "base.img");
-    reflink_or_copy_with_warning(&template_base_img, &runner_base_img)?;
+    storage::clone_image(&template_base_img, &runner_base_img)?;
 
     let ovmf_vars_base_path =
         format!("/var/lib/libvirt/images/OSX-KVM/OVMF_VARS.{snapshot_path_slug}.fd");
@@ -124,14 +114,15 @@ pub fn create_runner(
     copy(ovmf_vars_base_path, ovmf_vars_path)?;
 
     spawn_with_output!(virt-clone -o $template_guest_name -n $runner_guest_name --nvram /var/lib/libvirt/images/OSX-KVM/OVMF_VARS.$runner_guest_name.fd --preserve-data --skip-copy sda -f $runner_base_img --skip-copy sdc 2>&1)?.wait_with_pipe(&mut pipe())?;
+    storage::set_runner_disk_driver_type(runner_guest_name, "sdb")?;
 
     Ok(runner_guest_name.to_owned())
 }
 
 pub fn destroy_runner(runner_guest_name: &str, runner_id: usize) -> eyre::Result<()> {
     let runner_base_image_path = runner_image_path(runner_id, "base.img");
-    if let Err(error) = remove_file(&runner_base_image_path) {
-        warn!(?runner_base_image_path, ?error, "Failed to delete file");
+    if let Err(error) = storage::delete_image(&runner_base_image_path) {
+        warn!(?runner_base_image_path, ?error, "Failed to delete image");
     }
     let ovmf_vars_path =
         format!("/var/lib/libvirt/images/OSX-KVM/OVMF_VARS.{runner_guest_name}.fd");
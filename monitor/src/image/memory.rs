@@ -0,0 +1,494 @@
+use std::{
+    fs::{remove_file, File},
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use cmd_lib::run_fun;
+use jane_eyre::eyre::{self, bail, eyre, OptionExt};
+use settings::{profile::Profile, TOML};
+use tracing::{info, warn};
+
+use crate::{
+    image::{create_runner, destroy_runner, restore_libvirt_guest, start_libvirt_guest},
+    policy::{runner_image_path, template_or_rebuild_image_path},
+    shell::run_cmd_logged,
+    storage,
+};
+
+/// State shared between an image rebuild thread and the monitor thread, while the RAM state of
+/// the new image is being saved.
+#[derive(Debug, Default)]
+pub struct MemorySnapshot {
+    state: Mutex<MemorySnapshotState>,
+}
+
+#[derive(Debug, Default)]
+struct MemorySnapshotState {
+    running: bool,
+    ready: bool,
+}
+
+impl MemorySnapshot {
+    pub fn is_running(&self) -> bool {
+        self.lock().running
+    }
+
+    /// Records that the boot script in the guest is waiting for a runner token, which is the
+    /// point we want runners to resume from.
+    pub fn report_ready(&self) -> eyre::Result<()> {
+        let mut state = self.lock();
+        if !state.running {
+            bail!("Memory snapshot not running");
+        }
+        state.ready = true;
+
+        Ok(())
+    }
+
+    fn start(&self) {
+        *self.lock() = MemorySnapshotState {
+            running: true,
+            ready: false,
+        };
+    }
+
+    fn finish(&self) {
+        *self.lock() = MemorySnapshotState::default();
+    }
+
+    fn is_ready(&self) -> bool {
+        self.lock().ready
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemorySnapshotState> {
+        // The state is always valid, even if another thread panicked while holding the lock.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Returns the saved RAM state that runners for the given snapshot should be restored from, if
+/// the profile uses memory snapshots and one was saved for that snapshot.
+///
+/// Since the files are named after the base image snapshot, a new base image always needs a new
+/// memory snapshot, and runners are booted as usual until it has one.
+pub fn saved_state_path(profile: &Profile, snapshot_name: &str) -> Option<PathBuf> {
+    if !profile.memory_snapshot {
+        return None;
+    }
+    let saved_state_path = template_or_rebuild_image_path(profile, snapshot_name, "memory.sav");
+    let disk_image_path = template_or_rebuild_image_path(profile, snapshot_name, "memory.img");
+
+    (saved_state_path.exists() && disk_image_path.exists()).then_some(saved_state_path)
+}
+
+/// Returns the disk image that runners for the given snapshot should be cloned from.
+///
+/// The RAM state only makes sense with the disk it was saved with, so if there is a memory
+/// snapshot, this is the disk of that guest, not the disk of the template.
+pub fn template_disk_image_path(profile: &Profile, snapshot_name: &str) -> PathBuf {
+    if saved_state_path(profile, snapshot_name).is_some() {
+        template_or_rebuild_image_path(profile, snapshot_name, "memory.img")
+    } else {
+        template_or_rebuild_image_path(profile, snapshot_name, "base.img")
+    }
+}
+
+/// Starts a runner guest, restoring it from the memory snapshot for its base image snapshot if
+/// there is one, or booting it otherwise.
+///
+/// libvirt only restores a saved guest with the UUID and MAC address it was saved with, so the
+/// runner guest is replaced with the saved guest, changing only its name, disk, and NVRAM, then
+/// given its own MAC address by replugging its network interface. Only one guest can have a given
+/// UUID, so only one runner per memory snapshot can be restored at a time, and any others are
+/// booted as usual.
+pub fn start_or_restore_runner_guest(
+    profile: &Profile,
+    snapshot_name: &str,
+    runner_guest_name: &str,
+    runner_id: usize,
+) -> eyre::Result<()> {
+    let Some(saved_state_path) = saved_state_path(profile, snapshot_name) else {
+        return start_libvirt_guest(runner_guest_name);
+    };
+
+    let runner_xml = run_fun!(virsh dumpxml -- $runner_guest_name)?;
+    let runner_xml_path = runner_image_path(runner_id, "runner.xml");
+    File::create(&runner_xml_path)?.write_all(runner_xml.as_bytes())?;
+    let result = restore_runner_guest(runner_guest_name, runner_id, &saved_state_path, &runner_xml)
+        .or_else(|error| {
+            warn!(
+                ?error,
+                "Failed to restore runner guest, booting it instead: {error}"
+            );
+            let _ = run_cmd_logged!(virsh destroy -- $runner_guest_name);
+            let _ = run_cmd_logged!(virsh undefine --keep-nvram -- $runner_guest_name);
+            run_cmd_logged!(virsh define -- $runner_xml_path)?;
+            start_libvirt_guest(runner_guest_name)
+        });
+    if let Err(error) = remove_file(&runner_xml_path) {
+        warn!(?runner_xml_path, ?error, "Failed to delete file");
+    }
+
+    result
+}
+
+fn restore_runner_guest(
+    runner_guest_name: &str,
+    runner_id: usize,
+    saved_state_path: &Path,
+    runner_xml: &str,
+) -> eyre::Result<()> {
+    let saved_xml = run_fun!(virsh save-image-dumpxml -- $saved_state_path)?;
+    let restore_xml = restore_xml(&saved_xml, runner_xml)?;
+    let saved_interface = first_element(&saved_xml, "interface")
+        .map(|range| &saved_xml[range])
+        .ok_or_eyre("Saved guest has no network interface")?;
+    let runner_interface = first_element(runner_xml, "interface")
+        .map(|range| &runner_xml[range])
+        .ok_or_eyre("Runner guest has no network interface")?;
+
+    let restore_xml_path = runner_image_path(runner_id, "restore.xml");
+    let saved_interface_path = runner_image_path(runner_id, "saved-interface.xml");
+    let runner_interface_path = runner_image_path(runner_id, "runner-interface.xml");
+    File::create(&restore_xml_path)?.write_all(restore_xml.as_bytes())?;
+    File::create(&saved_interface_path)?.write_all(saved_interface.as_bytes())?;
+    File::create(&runner_interface_path)?.write_all(runner_interface.as_bytes())?;
+    let result = (|| {
+        // Keep the disk and NVRAM of the runner guest, since the restored guest uses them.
+        run_cmd_logged!(virsh undefine --keep-nvram -- $runner_guest_name)?;
+        restore_libvirt_guest(runner_guest_name, saved_state_path, &restore_xml_path)?;
+        // Make the restored guest persistent, like the guests of other runners.
+        run_cmd_logged!(virsh define -- $restore_xml_path)?;
+        // The guest renews its DHCP lease while it waits for a jitconfig, so it gets its own
+        // address for its new MAC address.
+        run_cmd_logged!(virsh detach-device --live --config -- $runner_guest_name $saved_interface_path)?;
+        run_cmd_logged!(virsh attach-device --live --config -- $runner_guest_name $runner_interface_path)?;
+        Ok(())
+    })();
+    for path in [
+        restore_xml_path,
+        saved_interface_path,
+        runner_interface_path,
+    ] {
+        if let Err(error) = remove_file(&path) {
+            warn!(?path, ?error, "Failed to delete file");
+        }
+    }
+
+    result
+}
+
+/// Returns the definition to restore a saved guest with, as the given runner guest.
+///
+/// This is the definition of the saved guest, since libvirt rejects any changes to what the guest
+/// can see, like its UUID or MAC address, but with the name, disks, and NVRAM of the runner guest.
+fn restore_xml(saved_xml: &str, runner_xml: &str) -> eyre::Result<String> {
+    let mut result = saved_xml.to_owned();
+
+    let runner_name = first_element(runner_xml, "name").ok_or_eyre("Runner guest has no name")?;
+    let saved_name = first_element(&result, "name").ok_or_eyre("Saved guest has no name")?;
+    result.replace_range(saved_name, &runner_xml[runner_name]);
+
+    for runner_disk in elements(runner_xml, "disk").map(|range| &runner_xml[range]) {
+        if attribute(runner_disk, "device") != Some("disk") {
+            continue;
+        }
+        let target_dev = first_element(runner_disk, "target")
+            .and_then(|range| attribute(&runner_disk[range], "dev"))
+            .ok_or_eyre("Runner guest has a disk with no target")?;
+        let runner_source = first_element(runner_disk, "source")
+            .map(|range| &runner_disk[range])
+            .ok_or_eyre("Runner guest has a disk with no source")?;
+        let saved_disk = elements(&result, "disk")
+            .find(|range| {
+                first_element(&result[range.clone()], "target")
+                    .and_then(|target| attribute(&result[range.clone()][target], "dev"))
+                    == Some(target_dev)
+            })
+            .ok_or_else(|| eyre!("Saved guest has no disk with target: {target_dev}"))?;
+        let saved_source = first_element(&result[saved_disk.clone()], "source")
+            .map(|range| saved_disk.start + range.start..saved_disk.start + range.end)
+            .ok_or_else(|| eyre!("Saved guest has a disk with no source: {target_dev}"))?;
+        result.replace_range(saved_source, runner_source);
+    }
+
+    if let Some(runner_nvram) = first_element(runner_xml, "nvram") {
+        let saved_nvram = first_element(&result, "nvram").ok_or_eyre("Saved guest has no NVRAM")?;
+        result.replace_range(saved_nvram, &runner_xml[runner_nvram]);
+    }
+
+    Ok(result)
+}
+
+/// Returns where each element with the given name is in some libvirt XML, in order. Elements with
+/// the given name must not be nested in each other.
+fn elements<'xml>(xml: &'xml str, name: &str) -> impl Iterator<Item = Range<usize>> + 'xml {
+    let open = format!("<{name}");
+    let close = format!("</{name}>");
+    let mut offset = 0;
+
+    std::iter::from_fn(move || loop {
+        let start = offset + xml[offset..].find(&open)?;
+        offset = start + open.len();
+        // Skip elements whose names merely start with the given name.
+        if !xml[offset..].starts_with([' ', '/', '>']) {
+            continue;
+        }
+        let start_tag_end = offset + xml[offset..].find('>')? + 1;
+        offset = if xml[..start_tag_end].ends_with("/>") {
+            start_tag_end
+        } else {
+            start_tag_end + xml[start_tag_end..].find(&close)? + close.len()
+        };
+        return Some(start..offset);
+    })
+}
+
+fn first_element(xml: &str, name: &str) -> Option<Range<usize>> {
+    elements(xml, name).next()
+}
+
+/// Returns the raw value of the given attribute of an element.
+fn attribute<'xml>(element: &'xml str, name: &str) -> Option<&'xml str> {
+    let start_tag = &element[..element.find('>')?];
+    for quote in ['\'', '"'] {
+        let prefix = format!(" {name}={quote}");
+        if let Some(start) = start_tag.find(&prefix) {
+            let value = &start_tag[start + prefix.len()..];
+            return Some(&value[..value.find(quote)?]);
+        }
+    }
+
+    None
+}
+
+/// Boots a throwaway clone of the given template, waits for its boot script to ask for a runner
+/// token, then saves its RAM state and disk for runners to be restored from.
+///
+/// The clone reuses the rebuild guest name, so the monitor can serve it the boot script and tell
+/// when it is ready.
+pub(super) fn run(
+    profile: &Profile,
+    snapshot_name: &str,
+    runner_id: usize,
+    memory_snapshot: &MemorySnapshot,
+) -> eyre::Result<()> {
+    let guest_name = profile.rebuild_guest_name(snapshot_name);
+    info!(guest_name, runner_id, "Starting memory snapshot");
+
+    memory_snapshot.start();
+    let result = create_runner(profile, snapshot_name, &guest_name, runner_id)
+        .and_then(|_| start_libvirt_guest(&guest_name))
+        .and_then(|()| wait_until_ready(memory_snapshot))
+        .and_then(|()| save(profile, snapshot_name, &guest_name, runner_id));
+    memory_snapshot.finish();
+
+    if let Err(error) = destroy_runner(profile, &guest_name, runner_id) {
+        warn!(?error, "Failed to destroy memory snapshot guest");
+    }
+    if result.is_err() {
+        delete_memory_snapshot(profile, snapshot_name);
+    }
+
+    result
+}
+
+/// Deletes the memory snapshot for the given snapshot, if any.
+pub(super) fn delete_memory_snapshot(profile: &Profile, snapshot_name: &str) {
+    for filename in ["memory.sav", "memory.img"] {
+        let path = template_or_rebuild_image_path(profile, snapshot_name, filename);
+        if path.exists() {
+            info!(?path, "Deleting");
+            if let Err(error) = storage::delete_image(&path) {
+                warn!(?path, ?error, "Failed to delete");
+            }
+        }
+    }
+}
+
+fn wait_until_ready(memory_snapshot: &MemorySnapshot) -> eyre::Result<()> {
+    let timeout = TOML.memory_snapshot_timeout();
+    info!(
+        "Waiting for boot script to ask for a runner token (max {} seconds)",
+        timeout.as_secs()
+    );
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if memory_snapshot.is_ready() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    bail!("Memory snapshot timed out")
+}
+
+fn save(
+    profile: &Profile,
+    snapshot_name: &str,
+    guest_name: &str,
+    runner_id: usize,
+) -> eyre::Result<()> {
+    let saved_state_path = template_or_rebuild_image_path(profile, snapshot_name, "memory.sav");
+    let saved_state_path = saved_state_path.to_str().ok_or_eyre("Unsupported path")?;
+    info!(saved_state_path, "Saving RAM state");
+    // This stops the guest, so its disk won’t change after the RAM state is saved.
+    run_cmd_logged!(virsh save -- $guest_name $saved_state_path)?;
+
+    let guest_disk_image_path = runner_image_path(runner_id, "base.img");
+    let disk_image_path = template_or_rebuild_image_path(profile, snapshot_name, "memory.img");
+    info!(?disk_image_path, "Saving disk image");
+    storage::copy_image(&guest_disk_image_path, &disk_image_path)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_memory_snapshot_state() -> eyre::Result<()> {
+    let memory_snapshot = MemorySnapshot::default();
+    assert!(!memory_snapshot.is_running());
+    assert!(memory_snapshot.report_ready().is_err());
+
+    // Reports only count while the rebuild thread is waiting for one.
+    memory_snapshot.start();
+    assert!(memory_snapshot.is_running());
+    assert!(!memory_snapshot.is_ready());
+    memory_snapshot.report_ready()?;
+    assert!(memory_snapshot.is_ready());
+
+    // Starting again forgets the old report.
+    memory_snapshot.start();
+    assert!(!memory_snapshot.is_ready());
+    memory_snapshot.report_ready()?;
+
+    memory_snapshot.finish();
+    assert!(!memory_snapshot.is_running());
+    assert!(!memory_snapshot.is_ready());
+    assert!(memory_snapshot.report_ready().is_err());
+
+    // A thread that panics while holding the lock doesn’t break the state for everyone else.
+    let _ = thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let _state = memory_snapshot.lock();
+                panic!("Panic while holding lock");
+            })
+            .join()
+    });
+    assert!(memory_snapshot.state.is_poisoned());
+    memory_snapshot.start();
+    memory_snapshot.report_ready()?;
+    assert!(memory_snapshot.is_ready());
+
+    Ok(())
+}
+
+#[test]
+fn test_saved_state_path() {
+    let mut profile = crate::policy::test::profile("servo-ubuntu2204", 1, 0, "0 B");
+    let base_image_path = template_or_rebuild_image_path(&profile, "snapshot", "base.img");
+
+    // Runners are booted from the template as usual until there is a memory snapshot.
+    assert_eq!(saved_state_path(&profile, "snapshot"), None);
+    assert_eq!(
+        template_disk_image_path(&profile, "snapshot"),
+        base_image_path
+    );
+    profile.memory_snapshot = true;
+    assert_eq!(saved_state_path(&profile, "snapshot"), None);
+    assert_eq!(
+        template_disk_image_path(&profile, "snapshot"),
+        base_image_path
+    );
+}
+
+#[test]
+fn test_restore_xml() -> eyre::Result<()> {
+    let saved_xml = r#"<domain type='kvm' id='3'>
+  <name>ci-rebuild-servo-macos13@2025-01-01T00:00:00Z</name>
+  <uuid>11111111-1111-1111-1111-111111111111</uuid>
+  <os>
+    <nvram template='/usr/share/OVMF/OVMF_VARS.fd'>/var/lib/libvirt/images/OSX-KVM/OVMF_VARS.ci-rebuild.fd</nvram>
+  </os>
+  <devices>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='raw'/>
+      <source file='/var/lib/libvirt/images/runner/1/base.img' index='2'>
+        <seclabel model='dac' relabel='no'/>
+      </source>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <disk type='file' device='cdrom'>
+      <source file='/var/lib/libvirt/images/base/servo-macos13/config.iso'/>
+      <target dev='sda' bus='sata'/>
+    </disk>
+    <interface type='network'>
+      <mac address='52:54:00:11:11:11'/>
+      <source network='cinet'/>
+    </interface>
+  </devices>
+</domain>"#;
+    let runner_xml = r#"<domain type='kvm'>
+  <name>ci-runner-servo-macos13.7</name>
+  <uuid>77777777-7777-7777-7777-777777777777</uuid>
+  <os>
+    <nvram template="/usr/share/OVMF/OVMF_VARS.fd">/var/lib/libvirt/images/OSX-KVM/OVMF_VARS.ci-runner.7.fd</nvram>
+  </os>
+  <devices>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='raw'/>
+      <source file='/var/lib/libvirt/images/runner/7/base.img'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <disk type='file' device='cdrom'>
+      <source file='/var/lib/libvirt/images/base/servo-macos13/other.iso'/>
+      <target dev='sda' bus='sata'/>
+    </disk>
+    <interface type='network'>
+      <mac address='52:54:00:77:77:77'/>
+      <source network='cinet'/>
+    </interface>
+  </devices>
+</domain>"#;
+
+    // The name, disks, and NVRAM come from the runner guest, but everything the guest can see,
+    // like its UUID, MAC address, and CD-ROMs, stays as it was saved.
+    let result = restore_xml(saved_xml, runner_xml)?;
+    assert_eq!(
+        result,
+        saved_xml
+            .replace(
+                "ci-rebuild-servo-macos13@2025-01-01T00:00:00Z",
+                "ci-runner-servo-macos13.7"
+            )
+            .replace(
+                "<source file='/var/lib/libvirt/images/runner/1/base.img' index='2'>\n        <seclabel model='dac' relabel='no'/>\n      </source>",
+                "<source file='/var/lib/libvirt/images/runner/7/base.img'/>"
+            )
+            .replace(
+                "<nvram template='/usr/share/OVMF/OVMF_VARS.fd'>/var/lib/libvirt/images/OSX-KVM/OVMF_VARS.ci-rebuild.fd</nvram>",
+                r#"<nvram template="/usr/share/OVMF/OVMF_VARS.fd">/var/lib/libvirt/images/OSX-KVM/OVMF_VARS.ci-runner.7.fd</nvram>"#
+            )
+    );
+    assert!(result.contains("11111111-1111-1111-1111-111111111111"));
+    assert!(result.contains("52:54:00:11:11:11"));
+
+    // Guests without NVRAM are fine, but the saved guest needs every disk of the runner guest.
+    let no_nvram = |xml: &str| {
+        let range = first_element(xml, "nvram").expect("Has NVRAM");
+        let mut result = xml.to_owned();
+        result.replace_range(range, "");
+        result
+    };
+    assert!(restore_xml(&no_nvram(saved_xml), &no_nvram(runner_xml)).is_ok());
+    assert!(restore_xml(saved_xml, &runner_xml.replace("'vda'", "'vdb'")).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::{
+    collections::BTreeSet,
+    fs::{create_dir_all, File},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    thread,
+    time::Instant,
+};
+
+use cli::thread_log::{set_thread_log, thread_log};
+use jane_eyre::eyre;
+use tracing::{debug, info, info_span, warn};
+
+use crate::{
+    data::get_snapshot_data_path, libvirt::serial_console_path, shell::log_output_as_info,
+};
+
+/// Paths of rebuild logs that are still being written to, so the API can follow them.
+static OPEN_REBUILD_LOGS: LazyLock<Mutex<BTreeSet<PathBuf>>> = LazyLock::new(Mutex::default);
+
+pub fn rebuild_log_path(profile_key: &str, snapshot_name: &str) -> eyre::Result<PathBuf> {
+    get_snapshot_data_path(profile_key, snapshot_name, Path::new("rebuild.log"))
+}
+
+/// Returns whether the rebuild log at the given path may still be written to.
+pub fn rebuild_log_is_open(path: &Path) -> bool {
+    OPEN_REBUILD_LOGS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .contains(path)
+}
+
+/// Copies all logs from the current thread to the log for the given rebuild, until dropped.
+pub struct RebuildLog {
+    path: PathBuf,
+}
+
+impl RebuildLog {
+    pub fn create(profile_key: &str, snapshot_name: &str) -> eyre::Result<Self> {
+        let path = rebuild_log_path(profile_key, snapshot_name)?;
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        OPEN_REBUILD_LOGS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(path.clone());
+        set_thread_log(Some(Arc::new(Mutex::new(file))));
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for RebuildLog {
+    fn drop(&mut self) {
+        set_thread_log(None);
+        OPEN_REBUILD_LOGS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.path);
+    }
+}
+
+/// Runs one phase of an image rebuild, logging how long it took.
+pub fn phase<T>(name: &str, f: impl FnOnce() -> eyre::Result<T>) -> eyre::Result<T> {
+    info!("Starting phase: {name}");
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    match &result {
+        Ok(_) => info!(?elapsed, "Finished phase: {name}"),
+        Err(error) => warn!(?elapsed, ?error, "Failed phase: {name}"),
+    }
+
+    result
+}
+
+/// Copies the serial console output of the given guest to the log for the current thread, if
+/// any, until the guest shuts down.
+pub fn capture_serial_console(guest_name: &str) {
+    let Some(log) = thread_log() else {
+        return;
+    };
+    let tty_path = match serial_console_path(guest_name) {
+        Ok(tty_path) => tty_path,
+        Err(error) => {
+            debug!(guest_name, ?error, "Guest has no serial console");
+            return;
+        }
+    };
+    let guest_name = guest_name.to_owned();
+    thread::spawn(move || {
+        set_thread_log(Some(log));
+        let _span = info_span!("console", guest_name).entered();
+        match File::open(&tty_path) {
+            // Reads will fail or end once the guest shuts down.
+            Ok(file) => log_output_as_info(Box::new(file)),
+            Err(error) => warn!(tty_path, ?error, "Failed to open serial console"),
+        }
+        set_thread_log(None);
+    });
+}
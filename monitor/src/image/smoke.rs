@@ -0,0 +1,189 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use jane_eyre::eyre::{self, bail, eyre};
+use monitor::github::{
+    list_registered_runners_for_host, unregister_runner, ApiGenerateJitconfigResponse,
+};
+use settings::{profile::Profile, TOML};
+use tracing::{info, warn};
+
+use crate::{
+    data::{get_profile_configuration_path, get_snapshot_data_path},
+    image::{create_runner, destroy_runner, register_runner, start_libvirt_guest},
+};
+
+/// State shared between an image rebuild thread and the monitor thread, while the new image is
+/// being smoke tested.
+#[derive(Debug, Default)]
+pub struct SmokeTest {
+    state: Mutex<SmokeTestState>,
+}
+
+#[derive(Debug, Default)]
+struct SmokeTestState {
+    running: bool,
+    github_jitconfig: Option<String>,
+    result: Option<SmokeTestResult>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SmokeTestResult {
+    pub passed: bool,
+    pub output: String,
+}
+
+impl SmokeTest {
+    pub fn is_running(&self) -> bool {
+        self.lock().running
+    }
+
+    /// Returns the jitconfig for the runner registered for the smoke test guest, if any.
+    pub fn github_jitconfig(&self) -> Option<String> {
+        let state = self.lock();
+        state
+            .running
+            .then(|| state.github_jitconfig.clone())
+            .flatten()
+    }
+
+    /// Records the result reported by the health script in the smoke test guest.
+    pub fn report(&self, result: SmokeTestResult) -> eyre::Result<()> {
+        let mut state = self.lock();
+        if !state.running {
+            bail!("Smoke test not running");
+        }
+        state.result = Some(result);
+
+        Ok(())
+    }
+
+    fn start(&self, github_jitconfig: Option<String>) {
+        *self.lock() = SmokeTestState {
+            running: true,
+            github_jitconfig,
+            result: None,
+        };
+    }
+
+    fn finish(&self) {
+        *self.lock() = SmokeTestState::default();
+    }
+
+    fn take_result(&self) -> Option<SmokeTestResult> {
+        self.lock().result.take()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SmokeTestState> {
+        // The state is always valid, even if another thread panicked while holding the lock.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Returns whether images for the given profile should be smoke tested before promotion.
+pub fn has_health_script(profile: &Profile) -> eyre::Result<bool> {
+    let path = get_profile_configuration_path(profile, Path::new("health-script"))?;
+
+    Ok(path.exists())
+}
+
+/// Boots a throwaway clone of the given template, then waits for its health script to report
+/// success and for its runner to come online.
+///
+/// The clone reuses the rebuild guest name, so the monitor can serve it the health script.
+pub(super) fn run(
+    profile: &Profile,
+    snapshot_name: &str,
+    runner_id: usize,
+    smoke_test: &SmokeTest,
+) -> eyre::Result<()> {
+    let guest_name = profile.rebuild_guest_name(snapshot_name);
+    info!(guest_name, runner_id, "Starting smoke test");
+
+    // Register the runner with a label that no jobs use, so it won’t take any real jobs.
+    let registration = if TOML.dont_register_runners() {
+        None
+    } else {
+        let registration =
+            register_runner(profile, &guest_name, &profile.smoke_test_runner_label())?;
+        Some(serde_json::from_str::<ApiGenerateJitconfigResponse>(
+            &registration,
+        )?)
+    };
+
+    smoke_test.start(
+        registration
+            .as_ref()
+            .map(|registration| registration.encoded_jit_config.clone()),
+    );
+    let result = create_runner(profile, snapshot_name, &guest_name, runner_id)
+        .and_then(|_| start_libvirt_guest(&guest_name))
+        .and_then(|()| wait_for_result(profile, snapshot_name, smoke_test, registration.as_ref()));
+    smoke_test.finish();
+
+    if let Some(registration) = registration {
+        if let Err(error) = unregister_runner(registration.runner.id) {
+            warn!(?error, "Failed to unregister smoke test runner");
+        }
+    }
+    if let Err(error) = destroy_runner(profile, &guest_name, runner_id) {
+        warn!(?error, "Failed to destroy smoke test guest");
+    }
+
+    result
+}
+
+fn wait_for_result(
+    profile: &Profile,
+    snapshot_name: &str,
+    smoke_test: &SmokeTest,
+    registration: Option<&ApiGenerateJitconfigResponse>,
+) -> eyre::Result<()> {
+    let timeout = TOML.smoke_test_timeout();
+    info!(
+        "Waiting for smoke test result (max {} seconds)",
+        timeout.as_secs()
+    );
+    let start = Instant::now();
+    let mut health_script_passed = false;
+    while start.elapsed() < timeout {
+        if let Some(result) = smoke_test.take_result() {
+            let path = get_snapshot_data_path(
+                &profile.profile_name,
+                snapshot_name,
+                Path::new("smoke-test.log"),
+            )?;
+            File::create(path)?.write_all(result.output.as_bytes())?;
+            if !result.passed {
+                bail!("Health script failed");
+            }
+            info!("Health script passed");
+            health_script_passed = true;
+        }
+        if health_script_passed {
+            let Some(registration) = registration else {
+                return Ok(());
+            };
+            let online = list_registered_runners_for_host()?
+                .iter()
+                .any(|runner| runner.id == registration.runner.id && runner.status == "online");
+            if online {
+                info!("Smoke test runner is online");
+                return Ok(());
+            }
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+
+    Err(eyre!(
+        "Smoke test timed out (health script passed: {health_script_passed})"
+    ))
+}
@@ -1,10 +1,8 @@
 use std::ffi::OsStr;
-use std::fs::remove_file;
 use std::path::Path;
 use std::time::Duration;
 
 use bytesize::ByteSize;
-use cmd_lib::run_cmd;
 use cmd_lib::spawn_with_output;
 use jane_eyre::eyre;
 use settings::profile::Profile;
@@ -12,16 +10,14 @@ use tracing::info;
 use tracing::warn;
 
 use crate::data::get_profile_configuration_path;
-use crate::data::get_profile_data_path;
 use crate::image::create_runner_images_dir;
 use crate::image::delete_template_or_rebuild_image_file;
+use crate::image::memory::template_disk_image_path;
 use crate::image::rename_guest;
 use crate::image::undefine_libvirt_guest;
 use crate::policy::runner_image_path;
-use crate::policy::template_or_rebuild_image_path;
-use crate::shell::atomic_symlink;
-use crate::shell::log_output_as_info;
-use crate::shell::reflink_or_copy_with_warning;
+use crate::shell::{log_output_as_info, run_cmd_logged};
+use crate::storage;
 use crate::IMAGE_DEPS_DIR;
 
 use super::create_disk_image;
@@ -44,7 +40,7 @@ pub(super) fn rebuild(
     let config_iso_path = base_images_path.join(&config_iso_filename);
     let config_iso_path = config_iso_path.to_str().expect("Unsupported path");
     info!(config_iso_path, "Creating config image file");
-    run_cmd!(genisoimage -V CIDATA -R -f -o $config_iso_path $profile_configuration_path/user-data $profile_configuration_path/meta-data)?;
+    run_cmd_logged!(genisoimage -V CIDATA -R -f -o $config_iso_path $profile_configuration_path/user-data $profile_configuration_path/meta-data)?;
 
     let os_image_path = IMAGE_DEPS_DIR
         .join("ubuntu2204")
@@ -67,9 +63,6 @@ pub(super) fn rebuild(
 
     let template_guest_name = &profile.template_guest_name(snapshot_name);
     rename_guest(rebuild_guest_name, template_guest_name)?;
-    let snapshot_symlink_path =
-        get_profile_data_path(&profile.profile_name, Path::new("snapshot"))?;
-    atomic_symlink(snapshot_name, snapshot_symlink_path)?;
 
     Ok(())
 }
@@ -100,8 +93,8 @@ pub(super) fn delete_template(profile: &Profile, snapshot_name: &str) -> eyre::R
     Ok(())
 }
 
-pub fn register_runner(profile: &Profile, runner_guest_name: &str) -> eyre::Result<String> {
-    monitor::github::register_runner(runner_guest_name, &profile.github_runner_label, "/a")
+pub fn register_runner(runner_guest_name: &str, label: &str) -> eyre::Result<String> {
+    monitor::github::register_runner(runner_guest_name, label, "/a")
 }
 
 pub fn create_runner(
@@ -113,16 +106,17 @@ pub fn create_runner(
     let pipe = || |reader| log_output_as_info(reader);
     let template_guest_name = &profile.template_guest_name(snapshot_name);
 
-    // Copy images in the monitor, not with `virt-clone --auto-clone --reflink`,
+    // Clone images in the monitor, not with `virt-clone --auto-clone --reflink`,
     // because the latter can’t be parallelised without causing errors.
     // TODO copy config.iso?
-    let template_base_img = template_or_rebuild_image_path(profile, snapshot_name, "base.img");
+    let template_base_img = template_disk_image_path(profile, snapshot_name);
     create_runner_images_dir()?;
     let runner_base_img = runner_image_path(runner_id, "base.img");
-    reflink_or_copy_with_warning(&template_base_img, &runner_base_img)?;
+    storage::clone_image(&template_base_img, &runner_base_img)?;
 
     spawn_with_output!(virt-clone -o $template_guest_name -n $runner_guest_name --preserve-data -f $runner_base_img 2>&1)?
         .wait_with_pipe(&mut pipe())?;
+    storage::set_runner_disk_driver_type(runner_guest_name, "vda")?;
 
     Ok(runner_guest_name.to_owned())
 }
@@ -130,8 +124,8 @@ pub fn create_runner(
 pub fn destroy_runner(runner_guest_name: &str, runner_id: usize) -> eyre::Result<()> {
     // TODO delete config.iso?
     let runner_base_image_path = runner_image_path(runner_id, "base.img");
-    if let Err(error) = remove_file(&runner_base_image_path) {
-        warn!(?runner_base_image_path, ?error, "Failed to delete file");
+    if let Err(error) = storage::delete_image(&runner_base_image_path) {
+        warn!(?runner_base_image_path, ?error, "Failed to delete image");
     }
 
     let pipe = || |reader| log_output_as_info(reader);
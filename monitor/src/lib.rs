@@ -1,3 +1,4 @@
+pub mod clock;
 pub mod github;
 
 use std::collections::BTreeMap;
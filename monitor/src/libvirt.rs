@@ -1,16 +1,18 @@
 use core::str;
 use std::{
-    fs::{create_dir_all, rename},
+    fs::{create_dir_all, rename, File},
+    io,
     net::Ipv4Addr,
     path::Path,
+    thread,
 };
 
 use cmd_lib::{run_fun, spawn_with_output};
 use jane_eyre::eyre;
 use settings::TOML;
-use tracing::debug;
+use tracing::{debug, warn};
 
-use crate::shell::log_output_as_trace;
+use crate::{screenshots::record_screenshot, shell::log_output_as_trace};
 
 pub fn list_template_guests() -> eyre::Result<Vec<String>> {
     // Output is not filtered by prefix, so we must filter it ourselves.
@@ -48,12 +50,70 @@ pub fn list_runner_guests() -> eyre::Result<Vec<String>> {
     Ok(result.collect())
 }
 
-pub fn update_screenshot(guest_name: &str, output_dir: &Path) -> Result<(), eyre::Error> {
+/// Returns the names of the temporary `<profile>.init` guests that image rebuilds define and
+/// clone from, which should only exist while a rebuild is defining its guest.
+pub fn list_init_guests() -> eyre::Result<Vec<String>> {
+    let result = run_fun!(virsh list --name --all)?;
+    let result = result
+        .split_terminator('\n')
+        .filter(|name| name.ends_with(".init"))
+        .map(str::to_owned);
+
+    Ok(result.collect())
+}
+
+/// Returns the names of all guests that are running, regardless of prefix.
+pub fn list_running_guests() -> eyre::Result<Vec<String>> {
+    // Without `--all`, only active guests are listed.
+    let result = run_fun!(virsh list --name)?;
+    let result = result
+        .split_terminator('\n')
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned);
+
+    Ok(result.collect())
+}
+
+pub fn update_screenshot(
+    guest_name: &str,
+    output_dir: &Path,
+    history_dir: &Path,
+) -> Result<(), eyre::Error> {
     create_dir_all(output_dir)?;
     let new_path = output_dir.join("screenshot.png.new");
     take_screenshot(guest_name, &new_path)?;
     let path = output_dir.join("screenshot.png");
-    rename(new_path, path)?;
+    rename(new_path, &path)?;
+    record_screenshot(&path, history_dir)?;
+
+    Ok(())
+}
+
+/// Returns the path of the pty for the serial console of the given guest, which must be running.
+pub fn serial_console_path(guest_name: &str) -> eyre::Result<String> {
+    Ok(run_fun!(virsh ttyconsole -- $guest_name)?.trim().to_owned())
+}
+
+/// Copies the serial console output of the given guest to the given file in the background,
+/// until the guest shuts down.
+pub fn capture_serial_console_to_file(guest_name: &str, output_path: &Path) -> eyre::Result<()> {
+    let tty_path = serial_console_path(guest_name)?;
+    let mut output = File::create(output_path)?;
+    let guest_name = guest_name.to_owned();
+    thread::spawn(move || match File::open(&tty_path) {
+        // Reads will fail or end once the guest shuts down.
+        Ok(mut tty) => {
+            if let Err(error) = io::copy(&mut tty, &mut output) {
+                debug!(guest_name, ?error, "Serial console closed");
+            }
+        }
+        Err(error) => warn!(
+            guest_name,
+            tty_path,
+            ?error,
+            "Failed to open serial console"
+        ),
+    });
 
     Ok(())
 }
@@ -135,3 +195,42 @@ fn test_parse_virsh_domifaddr_output() {
         Some(Ipv4Addr::from_str("192.168.100.133").expect("Guaranteed by argument"))
     );
 }
+
+/// Returns the scheme (`spice` or `vnc`) and host port of the graphical console of the given
+/// guest, which must be running, optionally only for the given type of console.
+pub fn get_display_port(
+    guest_name: &str,
+    display_type: Option<&str>,
+) -> eyre::Result<Option<(String, u16)>> {
+    let output = match display_type {
+        Some(display_type) => run_fun!(virsh domdisplay --type $display_type -- $guest_name)?,
+        None => run_fun!(virsh domdisplay -- $guest_name)?,
+    };
+
+    Ok(parse_virsh_domdisplay_output(&output))
+}
+
+fn parse_virsh_domdisplay_output(output: &str) -> Option<(String, u16)> {
+    let (scheme, rest) = output.trim().split_once("://")?;
+    let (_host, port) = rest.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+    match scheme {
+        "spice" => Some((scheme.to_owned(), port)),
+        // VNC URIs contain the display number, not the port.
+        "vnc" => Some((scheme.to_owned(), port.checked_add(5900)?)),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_virsh_domdisplay_output() {
+    assert_eq!(
+        parse_virsh_domdisplay_output("spice://127.0.0.1:5901\n"),
+        Some(("spice".to_owned(), 5901))
+    );
+    assert_eq!(
+        parse_virsh_domdisplay_output("vnc://127.0.0.1:2\n"),
+        Some(("vnc".to_owned(), 5902))
+    );
+    assert_eq!(parse_virsh_domdisplay_output(""), None);
+}
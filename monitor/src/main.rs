@@ -1,18 +1,26 @@
+mod console;
 mod dashboard;
 mod data;
+mod gc;
 mod id;
 mod image;
 mod libvirt;
+mod orphans;
 mod policy;
+mod preserved;
 mod runner;
+mod screenshots;
 mod shell;
+mod storage;
+mod wakeup;
+mod workers;
 
 use core::str;
 use std::{
     collections::BTreeMap,
     env,
     fs::File,
-    path::Path,
+    path::{Path, PathBuf},
     process::exit,
     sync::{LazyLock, RwLock},
     thread::{self},
@@ -21,11 +29,12 @@ use std::{
 
 use askama::Template;
 use askama_web::WebTemplate;
+use chrono::DateTime;
 use crossbeam_channel::{Receiver, Sender};
 use jane_eyre::eyre::{self, eyre, Context, OptionExt};
 use mktemp::Temp;
 use monitor::{
-    github::{list_registered_runners_for_host, Cache},
+    github::{list_registered_runners_for_host, ApiRunner, Cache},
     validate_tokenless_select,
 };
 use rocket::{
@@ -34,12 +43,16 @@ use rocket::{
     get,
     http::ContentType,
     post,
-    response::content::{RawJson, RawText},
+    response::{
+        content::{RawJson, RawText},
+        stream::TextStream,
+    },
     serde::json::Json,
+    tokio::io::AsyncReadExt,
 };
 use serde::Deserialize;
 use serde_json::json;
-use settings::{IMAGE_DEPS_DIR, TOML};
+use settings::{IMAGE_DEPS_DIR, NOVNC_DIR, TOML};
 use tokio::task::JoinSet;
 use tracing::{debug, error, info, trace, warn};
 use web::{
@@ -48,25 +61,113 @@ use web::{
 };
 
 use crate::{
+    console::{issue_console_ticket, ConsoleGuest, ConsoleProxy, ConsoleTicketGuard},
     dashboard::Dashboard,
     data::{get_profile_data_path, get_runner_data_path, run_migrations},
+    gc::GarbageCollector,
     id::IdGen,
-    image::{start_libvirt_guest, Rebuilds},
-    libvirt::list_runner_guests,
-    policy::{Override, Policy, RunnerCounts},
-    runner::{Runners, Status},
+    image::{
+        rebuild_log::{rebuild_log_is_open, rebuild_log_path},
+        smoke::SmokeTestResult,
+        Rebuilds, Snapshot,
+    },
+    libvirt::{get_display_port, list_runner_guests, take_screenshot},
+    orphans::{OrphanReport, Orphans},
+    policy::{Override, Policy, RebuildFailures, Reservation, RunnerCounts},
+    preserved::{
+        discard_preserved_runner, enforce_preserved_runners_budget, list_preserved_runners,
+        read_preserved_runner, PreservedRunner,
+    },
+    runner::{Heartbeat, RunnerHold, RunnerHoldDetails, Runners, Status},
+    screenshots::{
+        list_screenshot_history, parse_screenshot_name, rebuild_screenshot_history_path,
+        runner_screenshot_history_path, screenshot_thread, set_screenshot_guests,
+        HistoricalScreenshot,
+    },
+    wakeup::{libvirt_event_thread, wakeups, Wakeup},
+    workers::{FinishedOperation, RunnerOperation, RunnerWorkers},
 };
 
 static DASHBOARD: RwLock<Option<Dashboard>> = RwLock::new(None);
+static SHARED_STATE: RwLock<Option<SharedState>> = RwLock::new(None);
+
+/// Requests that can be queued for the monitor thread before API callers start to block.
+const REQUEST_QUEUE_CAPACITY: usize = 64;
+
+/// State that the monitor thread publishes after each reconciliation pass and each batch of
+/// requests, so that requests that only read it can be served without the monitor thread.
+#[derive(Debug, Default)]
+struct SharedState {
+    override_policy: Option<Override>,
+    snapshots: BTreeMap<String, Vec<Snapshot>>,
+    runner_guest_names: BTreeMap<usize, String>,
+    rebuild_guest_names: BTreeMap<String, String>,
+}
+
+impl SharedState {
+    fn publish(
+        policy: &Policy,
+        image_rebuilds: &Rebuilds,
+        rebuild_guest_names: &BTreeMap<String, String>,
+    ) {
+        let mut snapshots = BTreeMap::default();
+        for (key, _profile) in policy.profiles() {
+            match image_rebuilds.snapshots(policy, key) {
+                Ok(result) => _ = snapshots.insert(key.clone(), result),
+                Err(error) => warn!(?error, "profile {key}: failed to list snapshots"),
+            }
+        }
+        let runner_guest_names = policy
+            .runners()
+            .flat_map(|(&id, runner)| Some((id, runner.guest_name()?.to_owned())))
+            .collect();
+        let result = Self {
+            override_policy: policy.get_override().cloned(),
+            snapshots,
+            runner_guest_names,
+            rebuild_guest_names: rebuild_guest_names.clone(),
+        };
+        if let Ok(mut shared_state) = SHARED_STATE.write() {
+            *shared_state = Some(result);
+        }
+    }
+
+    fn read<T>(f: impl FnOnce(&SharedState) -> T) -> rocket_eyre::Result<T> {
+        SHARED_STATE
+            .read()
+            .map_err(|e| eyre!("Failed to acquire RwLock: {e:?}"))
+            .map_err(EyreReport::ServiceUnavailable)?
+            .as_ref()
+            .map(f)
+            .ok_or_eyre("Monitor thread is still starting")
+            .map_err(EyreReport::ServiceUnavailable)
+    }
+}
 
 /// Requests that are handled synchronously by the monitor thread.
 ///
 /// The requests that can be handled without the monitor thread are as follows:
 /// - GET `/` => templates/index.html
 /// - GET `/dashboard.html` => templates/dashboard.html
-/// - GET `/dashboard.json` => `{"profile_runner_counts": {}, "runners": []}`
+/// - GET `/dashboard.json` => `{"profile_runner_counts": {}, "runner_workers": {}, "runners": []}`
 /// - GET `/profile/<profile key>/screenshot.png` => image/png
 /// - GET `/runner/<our runner id>/screenshot.png` => image/png
+/// - GET `/runner/<our runner id>/console.log` => text/plain
+/// - GET `/runner/<our runner id>/screenshots` => `[{"name", "time"}]`
+/// - GET `/runner/<our runner id>/screenshots/<name>` => image/png
+/// - GET `/runner/<our runner id>/timelapse` => templates/timelapse.html
+/// - GET `/profile/<profile key>/rebuild/<snapshot name>/screenshots` => `[{"name", "time"}]`
+/// - GET `/profile/<profile key>/rebuild/<snapshot name>/screenshots/<name>` => image/png
+/// - GET `/profile/<profile key>/rebuild/<snapshot name>/timelapse` => templates/timelapse.html
+/// - GET `/runner/<our runner id>/console` => templates/console.html
+/// - GET `/profile/<profile key>/console` => templates/console.html
+/// - GET `/policy/override` => `{"<profile_key...>": <count...>}` | `null`
+/// - GET `/profile/<profile_key>/snapshots` => `[{"name", "age", "status", "pinned", "reasons"}]`
+/// - GET `/runner/<our runner id>/screenshot/now` => image/png
+/// - GET `/runner/<our runner id>/console/ws` => WebSocket
+/// - GET `/profile/<profile key>/console/ws` => WebSocket
+/// - GET `/runners/preserved` => `[{"id", "profile_name", "reason", "preserved_at", "disk_size"}]`
+/// - GET `/runner/<our runner id>/preserved` => `{"id", "profile_name", "reason", "preserved_at", "disk_size"}`
 #[derive(Debug)]
 enum Request {
     /// POST `/profile/<profile_key>/take?unique_id&qualified_repo=<user>/<repo>&run_id` => `{"id", "runner"}` | `null`
@@ -78,11 +179,6 @@ enum Request {
         count: usize,
     },
 
-    /// GET `/policy/override`
-    GetOverridePolicy {
-        response_tx: Sender<Option<Override>>,
-    },
-
     /// POST `/policy/override?<profile_key...>=<count>` => `{"<profile_key...>": <count...>}`
     OverridePolicy {
         response_tx: Sender<eyre::Result<Override>>,
@@ -94,10 +190,56 @@ enum Request {
         response_tx: Sender<eyre::Result<Option<Override>>>,
     },
 
-    /// GET `/runner/<our runner id>/screenshot/now` => image/png
-    Screenshot {
-        response_tx: Sender<eyre::Result<Temp>>,
+    /// POST `/profile/<profile_key>/rebuild` => `[{"name", "age", "status", "pinned", "reasons"}]`
+    RebuildImage {
+        response_tx: Sender<eyre::Result<Vec<Snapshot>>>,
+        profile_key: String,
+    },
+
+    /// POST `/profile/<profile_key>/snapshot/<snapshot_name>/activate` => `[{"name", "age", "status", "pinned", "reasons"}]`
+    ActivateSnapshot {
+        response_tx: Sender<eyre::Result<Vec<Snapshot>>>,
+        profile_key: String,
+        snapshot_name: String,
+    },
+
+    /// DELETE `/profile/<profile_key>/rebuild/failures` => `{"count", "last_error"}` | `null`
+    ResetRebuildFailures {
+        response_tx: Sender<eyre::Result<Option<RebuildFailures>>>,
+        profile_key: String,
+    },
+
+    /// POST `/runner/<our runner id>/preserve?reason=<text>` => `null`
+    PreserveRunner {
+        response_tx: Sender<eyre::Result<()>>,
+        runner_id: usize,
+        reason: String,
+    },
+
+    /// DELETE `/runner/<our runner id>/preserved` => `{"id", "profile_name", "reason", "preserved_at", "disk_size"}`
+    DiscardPreservedRunner {
+        response_tx: Sender<eyre::Result<PreservedRunner>>,
+        runner_id: usize,
+    },
+
+    /// POST `/runner/<our runner id>/hold?ttl=<seconds>` => `{"runner_id", "guest_name", "ipv4_address", "hold", "ssh_command", "display_tunnel_command", "display_uri"}`
+    HoldRunner {
+        response_tx: Sender<eyre::Result<RunnerHoldDetails>>,
         runner_id: usize,
+        ttl: Duration,
+    },
+
+    /// DELETE `/runner/<our runner id>/hold` => `{"held_at", "expires_at"}`
+    ReleaseRunner {
+        response_tx: Sender<eyre::Result<RunnerHold>>,
+        runner_id: usize,
+    },
+
+    /// GET `/orphans` => `{"checked_at", "fixed", "data_dirs", "registrations", "guests", "init_guests"}`
+    /// POST `/orphans/fix` => `{"checked_at", "fixed", "data_dirs", "registrations", "guests", "init_guests"}`
+    Orphans {
+        response_tx: Sender<eyre::Result<OrphanReport>>,
+        fix: bool,
     },
 
     /// - GET `/github-jitconfig` => application/json
@@ -111,6 +253,20 @@ enum Request {
         response_tx: Sender<eyre::Result<String>>,
         remote_addr: web::auth::RemoteAddr,
     },
+
+    /// - POST `/smoke-test?passed=<bool>` with text/plain output => `null`
+    SmokeTestResult {
+        response_tx: Sender<eyre::Result<()>>,
+        remote_addr: web::auth::RemoteAddr,
+        result: SmokeTestResult,
+    },
+
+    /// - POST `/heartbeat?disk_free=<bytes>&load=<number>&runner_alive=<bool>` => `null`
+    Heartbeat {
+        response_tx: Sender<eyre::Result<()>>,
+        remote_addr: web::auth::RemoteAddr,
+        heartbeat: Heartbeat,
+    },
 }
 #[derive(Debug, Deserialize)]
 struct TakeRunnerQuery {
@@ -124,7 +280,7 @@ struct Channel<T> {
     receiver: Receiver<T>,
 }
 static REQUEST: LazyLock<Channel<Request>> = LazyLock::new(|| {
-    let (sender, receiver) = crossbeam_channel::bounded(0);
+    let (sender, receiver) = crossbeam_channel::bounded(REQUEST_QUEUE_CAPACITY);
     Channel { sender, receiver }
 });
 
@@ -134,6 +290,20 @@ pub struct IndexTemplate {
     pub content: String,
 }
 
+#[derive(Clone, Debug, Template, WebTemplate)]
+#[template(path = "console.html")]
+pub struct ConsoleTemplate {
+    pub title: String,
+    pub websocket_path: String,
+}
+
+#[derive(Clone, Debug, Template, WebTemplate)]
+#[template(path = "timelapse.html")]
+pub struct TimelapseTemplate {
+    pub title: String,
+    pub screenshots_path: String,
+}
+
 #[get("/")]
 fn index_route() -> rocket_eyre::Result<IndexTemplate> {
     Ok(DASHBOARD
@@ -266,15 +436,9 @@ fn select_runner_route(
 
 #[get("/policy/override")]
 fn get_override_policy_route() -> rocket_eyre::Result<Json<Option<Override>>> {
-    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
-    REQUEST.sender.send_timeout(
-        Request::GetOverridePolicy { response_tx },
-        TOML.monitor_thread_send_timeout(),
-    )?;
-
-    Ok(Json(
-        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())?,
-    ))
+    Ok(Json(SharedState::read(|state| {
+        state.override_policy.clone()
+    })?))
 }
 
 #[post("/policy/override?<profile_override_counts..>")]
@@ -309,6 +473,110 @@ fn delete_override_policy_route(_auth: ApiKeyGuard) -> rocket_eyre::Result<Json<
     ))
 }
 
+#[get("/profile/<profile_key>/snapshots")]
+fn list_snapshots_route(profile_key: String) -> rocket_eyre::Result<Json<Vec<Snapshot>>> {
+    let snapshots = SharedState::read(|state| state.snapshots.get(&profile_key).cloned())?
+        .ok_or_eyre("No profile with that key")
+        .map_err(EyreReport::NotFound)?;
+
+    Ok(Json(snapshots))
+}
+
+#[post("/profile/<profile_key>/rebuild")]
+fn rebuild_image_route(
+    profile_key: String,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<Json<Vec<Snapshot>>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::RebuildImage {
+            response_tx,
+            profile_key,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[post("/profile/<profile_key>/snapshot/<snapshot_name>/activate")]
+fn activate_snapshot_route(
+    profile_key: String,
+    snapshot_name: String,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<Json<Vec<Snapshot>>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::ActivateSnapshot {
+            response_tx,
+            profile_key,
+            snapshot_name,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[delete("/profile/<profile_key>/rebuild/failures")]
+fn reset_rebuild_failures_route(
+    profile_key: String,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<Json<Option<RebuildFailures>>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::ResetRebuildFailures {
+            response_tx,
+            profile_key,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[get("/profile/<profile_key>/rebuild/<snapshot_name>/log?<follow>")]
+async fn rebuild_log_route(
+    profile_key: String,
+    snapshot_name: String,
+    follow: Option<bool>,
+) -> rocket_eyre::Result<TextStream![String]> {
+    // Snapshot names are RFC 3339 timestamps, which also keeps them from escaping the data path.
+    DateTime::parse_from_rfc3339(&snapshot_name)
+        .wrap_err("Bad snapshot name")
+        .map_err(EyreReport::NotFound)?;
+    let path = rebuild_log_path(&profile_key, &snapshot_name)
+        .wrap_err("Failed to compute path")
+        .map_err(EyreReport::InternalServerError)?;
+    let mut file = rocket::tokio::fs::File::open(&path)
+        .await
+        .wrap_err("Failed to open rebuild log")
+        .map_err(EyreReport::NotFound)?;
+
+    Ok(TextStream! {
+        let mut buffer = vec![0; 8192];
+        loop {
+            // Check if the log is still open before reading, so we never miss the end of it.
+            let open = follow.unwrap_or(false) && rebuild_log_is_open(&path);
+            match file.read(&mut buffer).await {
+                Ok(0) if open => rocket::tokio::time::sleep(Duration::from_secs(1)).await,
+                Ok(0) => break,
+                Ok(len) => yield String::from_utf8_lossy(&buffer[..len]).into_owned(),
+                Err(error) => {
+                    warn!(?error, "Failed to read rebuild log");
+                    break;
+                }
+            }
+        }
+    })
+}
+
 #[get("/profile/<profile_key>/screenshot.png")]
 async fn profile_screenshot_route(profile_key: String) -> rocket_eyre::Result<NamedFile> {
     let path = get_profile_data_path(&profile_key, Path::new("screenshot.png"))
@@ -329,19 +597,293 @@ async fn runner_screenshot_route(runner_id: usize) -> rocket_eyre::Result<NamedF
 
 #[get("/runner/<runner_id>/screenshot/now")]
 fn runner_screenshot_now_route(runner_id: usize) -> rocket_eyre::Result<(ContentType, File)> {
+    let guest_name = SharedState::read(|state| state.runner_guest_names.get(&runner_id).cloned())?
+        .ok_or_eyre("No runner guest found with that id")
+        .map_err(EyreReport::NotFound)?;
+    let path = Temp::new_file()?;
+    take_screenshot(&guest_name, &path)?;
+    debug!(?path);
+
+    // Moving `path` into File ensures Temp is not dropped until close
+    Ok((ContentType::PNG, File::open(path)?))
+}
+
+#[get("/runner/<runner_id>/console.log")]
+async fn runner_console_log_route(runner_id: usize) -> rocket_eyre::Result<NamedFile> {
+    let path = get_runner_data_path(runner_id, Path::new("console.log"))
+        .wrap_err("Failed to compute path")
+        .map_err(EyreReport::InternalServerError)?;
+
+    Ok(NamedFile::open(path).await?)
+}
+
+#[get("/runner/<runner_id>/console")]
+fn runner_console_route(runner_id: usize) -> ConsoleTemplate {
+    ConsoleTemplate {
+        title: format!("runner id {runner_id}"),
+        websocket_path: format!("/runner/{runner_id}/console/ws"),
+    }
+}
+
+#[post("/console/ticket")]
+fn console_ticket_route(_auth: ApiKeyGuard) -> String {
+    issue_console_ticket()
+}
+
+#[get("/runner/<runner_id>/console/ws")]
+fn runner_console_ws_route(
+    runner_id: usize,
+    _auth: ConsoleTicketGuard,
+) -> rocket_eyre::Result<ConsoleProxy> {
+    console_proxy(ConsoleGuest::Runner(runner_id))
+}
+
+#[get("/profile/<profile_key>/console")]
+fn profile_console_route(profile_key: String) -> ConsoleTemplate {
+    ConsoleTemplate {
+        title: format!("image rebuild for {profile_key}"),
+        websocket_path: format!("/profile/{profile_key}/console/ws"),
+    }
+}
+
+#[get("/profile/<profile_key>/console/ws")]
+fn profile_console_ws_route(
+    profile_key: String,
+    _auth: ConsoleTicketGuard,
+) -> rocket_eyre::Result<ConsoleProxy> {
+    console_proxy(ConsoleGuest::Rebuild(profile_key))
+}
+
+fn console_proxy(guest: ConsoleGuest) -> rocket_eyre::Result<ConsoleProxy> {
+    let guest_name = SharedState::read(|state| match guest {
+        ConsoleGuest::Runner(runner_id) => state
+            .runner_guest_names
+            .get(&runner_id)
+            .cloned()
+            .ok_or_eyre("No runner guest found with that id"),
+        ConsoleGuest::Rebuild(profile_key) => state
+            .rebuild_guest_names
+            .get(&profile_key)
+            .cloned()
+            .ok_or_eyre("No rebuild guest found for that profile"),
+    })?
+    .map_err(EyreReport::NotFound)?;
+    let vnc_port = get_display_port(&guest_name, Some("vnc"))?
+        .map(|(_scheme, port)| port)
+        .ok_or_eyre("Guest has no VNC console")
+        .map_err(EyreReport::NotFound)?;
+
+    Ok(ConsoleProxy { vnc_port })
+}
+
+#[get("/runner/<runner_id>/screenshots")]
+fn runner_screenshots_route(
+    runner_id: usize,
+) -> rocket_eyre::Result<Json<Vec<HistoricalScreenshot>>> {
+    Ok(Json(list_screenshot_history(
+        &runner_screenshot_history_path(runner_id)?,
+    )?))
+}
+
+#[get("/runner/<runner_id>/screenshots/<name>")]
+async fn runner_historical_screenshot_route(
+    runner_id: usize,
+    name: String,
+) -> rocket_eyre::Result<NamedFile> {
+    historical_screenshot(runner_screenshot_history_path(runner_id), &name).await
+}
+
+#[get("/runner/<runner_id>/timelapse")]
+fn runner_timelapse_route(runner_id: usize) -> TimelapseTemplate {
+    TimelapseTemplate {
+        title: format!("runner id {runner_id}"),
+        screenshots_path: format!("/runner/{runner_id}/screenshots"),
+    }
+}
+
+#[get("/profile/<profile_key>/rebuild/<snapshot_name>/screenshots")]
+fn rebuild_screenshots_route(
+    profile_key: String,
+    snapshot_name: String,
+) -> rocket_eyre::Result<Json<Vec<HistoricalScreenshot>>> {
+    let path = rebuild_screenshot_history(&profile_key, &snapshot_name)?;
+
+    Ok(Json(list_screenshot_history(&path)?))
+}
+
+#[get("/profile/<profile_key>/rebuild/<snapshot_name>/screenshots/<name>")]
+async fn rebuild_historical_screenshot_route(
+    profile_key: String,
+    snapshot_name: String,
+    name: String,
+) -> rocket_eyre::Result<NamedFile> {
+    let path = rebuild_screenshot_history(&profile_key, &snapshot_name)?;
+    historical_screenshot(Ok(path), &name).await
+}
+
+#[get("/profile/<profile_key>/rebuild/<snapshot_name>/timelapse")]
+fn rebuild_timelapse_route(profile_key: String, snapshot_name: String) -> TimelapseTemplate {
+    TimelapseTemplate {
+        title: format!("image rebuild for {profile_key} ({snapshot_name})"),
+        screenshots_path: format!("/profile/{profile_key}/rebuild/{snapshot_name}/screenshots"),
+    }
+}
+
+fn rebuild_screenshot_history(
+    profile_key: &str,
+    snapshot_name: &str,
+) -> rocket_eyre::Result<PathBuf> {
+    // Snapshot names are RFC 3339 timestamps, which also keeps them from escaping the data path.
+    DateTime::parse_from_rfc3339(snapshot_name)
+        .wrap_err("Bad snapshot name")
+        .map_err(EyreReport::NotFound)?;
+
+    let result = rebuild_screenshot_history_path(profile_key, snapshot_name)
+        .wrap_err("Failed to compute path")
+        .map_err(EyreReport::InternalServerError)?;
+
+    Ok(result)
+}
+
+async fn historical_screenshot(
+    history_dir: eyre::Result<PathBuf>,
+    name: &str,
+) -> rocket_eyre::Result<NamedFile> {
+    // Only accept names we would have generated, which also keeps them from escaping the history.
+    parse_screenshot_name(name)
+        .ok_or_eyre("Bad screenshot name")
+        .map_err(EyreReport::NotFound)?;
+    let path = history_dir
+        .wrap_err("Failed to compute path")
+        .map_err(EyreReport::InternalServerError)?
+        .join(name);
+
+    Ok(NamedFile::open(path).await?)
+}
+
+#[get("/runners/preserved")]
+fn list_preserved_runners_route() -> rocket_eyre::Result<Json<Vec<PreservedRunner>>> {
+    Ok(Json(list_preserved_runners()?))
+}
+
+#[get("/runner/<runner_id>/preserved")]
+fn preserved_runner_route(runner_id: usize) -> rocket_eyre::Result<Json<PreservedRunner>> {
+    Ok(Json(
+        read_preserved_runner(runner_id)?
+            .ok_or_eyre("No preserved runner with that id")
+            .map_err(EyreReport::NotFound)?,
+    ))
+}
+
+#[post("/runner/<runner_id>/preserve?<reason>")]
+fn preserve_runner_route(
+    runner_id: usize,
+    reason: Option<String>,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<RawJson<String>> {
     let (response_tx, response_rx) = crossbeam_channel::bounded(0);
     REQUEST.sender.send_timeout(
-        Request::Screenshot {
+        Request::PreserveRunner {
             response_tx,
             runner_id,
+            reason: reason.unwrap_or_else(|| "flagged via API".to_owned()),
         },
         TOML.monitor_thread_send_timeout(),
     )?;
-    let path = response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??;
-    debug!(?path);
+    response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??;
 
-    // Moving `path` into File ensures Temp is not dropped until close
-    Ok((ContentType::PNG, File::open(path)?))
+    Ok(RawJson(json!(null).to_string()))
+}
+
+#[delete("/runner/<runner_id>/preserved")]
+fn discard_preserved_runner_route(
+    runner_id: usize,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<Json<PreservedRunner>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::DiscardPreservedRunner {
+            response_tx,
+            runner_id,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[post("/runner/<runner_id>/hold?<ttl>")]
+fn hold_runner_route(
+    runner_id: usize,
+    ttl: Option<u64>,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<Json<RunnerHoldDetails>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::HoldRunner {
+            response_tx,
+            runner_id,
+            ttl: ttl.map_or_else(|| TOML.runner_hold_ttl(), Duration::from_secs),
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[delete("/runner/<runner_id>/hold")]
+fn release_runner_route(
+    runner_id: usize,
+    _auth: ApiKeyGuard,
+) -> rocket_eyre::Result<Json<RunnerHold>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::ReleaseRunner {
+            response_tx,
+            runner_id,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[get("/orphans")]
+fn orphans_route(_auth: ApiKeyGuard) -> rocket_eyre::Result<Json<OrphanReport>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::Orphans {
+            response_tx,
+            fix: false,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
+}
+
+#[post("/orphans/fix")]
+fn fix_orphans_route(_auth: ApiKeyGuard) -> rocket_eyre::Result<Json<OrphanReport>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::Orphans {
+            response_tx,
+            fix: true,
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+
+    Ok(Json(
+        response_rx.recv_timeout(TOML.monitor_thread_recv_timeout())??,
+    ))
 }
 
 #[get("/github-jitconfig")]
@@ -380,6 +922,55 @@ fn boot_script_route(remote_addr: web::auth::RemoteAddr) -> rocket_eyre::Result<
     Ok(RawText(result))
 }
 
+#[post("/smoke-test?<passed>", data = "<output>")]
+fn smoke_test_route(
+    passed: bool,
+    output: String,
+    remote_addr: web::auth::RemoteAddr,
+) -> rocket_eyre::Result<RawJson<String>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::SmokeTestResult {
+            response_tx,
+            remote_addr,
+            result: SmokeTestResult { passed, output },
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+    response_rx
+        .recv_timeout(TOML.monitor_thread_recv_timeout())?
+        .map_err(EyreReport::ServiceUnavailable)?;
+
+    Ok(RawJson(json!(null).to_string()))
+}
+
+#[post("/heartbeat?<disk_free>&<load>&<runner_alive>")]
+fn heartbeat_route(
+    disk_free: u64,
+    load: f64,
+    runner_alive: bool,
+    remote_addr: web::auth::RemoteAddr,
+) -> rocket_eyre::Result<RawJson<String>> {
+    let (response_tx, response_rx) = crossbeam_channel::bounded(0);
+    REQUEST.sender.send_timeout(
+        Request::Heartbeat {
+            response_tx,
+            remote_addr,
+            heartbeat: Heartbeat {
+                disk_free,
+                load,
+                runner_alive,
+            },
+        },
+        TOML.monitor_thread_send_timeout(),
+    )?;
+    response_rx
+        .recv_timeout(TOML.monitor_thread_recv_timeout())?
+        .map_err(EyreReport::ServiceUnavailable)?;
+
+    Ok(RawJson(json!(null).to_string()))
+}
+
 #[rocket::main]
 async fn main() -> eyre::Result<()> {
     if env::var_os("RUST_LOG").is_none() {
@@ -390,6 +981,8 @@ async fn main() -> eyre::Result<()> {
     cli::init()?;
     run_migrations()?;
 
+    thread::spawn(screenshot_thread);
+    thread::spawn(libvirt_event_thread);
     tokio::task::spawn(async move {
         let thread = thread::spawn(monitor_thread);
         loop {
@@ -409,9 +1002,9 @@ async fn main() -> eyre::Result<()> {
     });
 
     let rocket = |listen_addr: &str| {
-        rocket::custom(
+        let rocket = rocket::custom(
             rocket::Config::figment()
-                .merge(("port", 8000))
+                .merge(("port", TOML.listen_port()))
                 .merge(("address", listen_addr)),
         )
         .mount(
@@ -426,11 +1019,38 @@ async fn main() -> eyre::Result<()> {
                 get_override_policy_route,
                 override_policy_route,
                 delete_override_policy_route,
+                list_snapshots_route,
+                rebuild_image_route,
+                activate_snapshot_route,
+                reset_rebuild_failures_route,
+                rebuild_log_route,
                 profile_screenshot_route,
                 runner_screenshot_route,
                 runner_screenshot_now_route,
+                runner_console_log_route,
+                runner_screenshots_route,
+                runner_historical_screenshot_route,
+                runner_timelapse_route,
+                rebuild_screenshots_route,
+                rebuild_historical_screenshot_route,
+                rebuild_timelapse_route,
+                runner_console_route,
+                console_ticket_route,
+                runner_console_ws_route,
+                profile_console_route,
+                profile_console_ws_route,
+                list_preserved_runners_route,
+                preserved_runner_route,
+                preserve_runner_route,
+                discard_preserved_runner_route,
+                hold_runner_route,
+                release_runner_route,
+                orphans_route,
+                fix_orphans_route,
                 github_jitconfig_route,
                 boot_script_route,
+                smoke_test_route,
+                heartbeat_route,
             ],
         )
         .mount(
@@ -443,8 +1063,17 @@ async fn main() -> eyre::Result<()> {
                 &TOML.main_repo_path,
                 rocket::fs::Options::NormalizeDirs | rocket::fs::Options::DotFiles,
             ),
-        )
-        .launch()
+        );
+        // For live consoles, serve our own copy of noVNC, rather than loading it from a CDN.
+        let rocket = if let Some(novnc_dir) = &*NOVNC_DIR {
+            rocket.mount(
+                "/novnc/",
+                FileServer::new(novnc_dir, rocket::fs::Options::None),
+            )
+        } else {
+            rocket
+        };
+        rocket.launch()
     };
 
     let mut set = JoinSet::new();
@@ -458,10 +1087,93 @@ async fn main() -> eyre::Result<()> {
     Ok(())
 }
 
+/// Queues the given runners to be unregistered, stopped, and destroyed by the runner workers.
+/// Queues the given runners to be destroyed, quarantining the profile of any runner that can’t
+/// be, so that one profile can’t stop us from destroying the runners of the others.
+fn unregister_stop_destroy_runners(
+    policy: &mut Policy,
+    runner_workers: &mut RunnerWorkers,
+    runner_ids: Vec<usize>,
+) {
+    for runner_id in runner_ids {
+        let profile_key = policy
+            .runner(runner_id)
+            .expect("Guaranteed by caller")
+            .profile_name()
+            .to_owned();
+        match policy.unregister_stop_destroy_runner(runner_id) {
+            Ok(work) => {
+                runner_workers.enqueue(RunnerOperation::Destroy, runner_id, &profile_key, work)
+            }
+            Err(error) => policy.quarantine_profile(&profile_key, format!("{error}")),
+        }
+    }
+}
+
+/// Handles the runner operations that the runner workers have finished, quarantining the profile
+/// of any runner whose work panicked.
+///
+/// Returns whether any operations had finished.
+fn handle_finished_runner_operations(
+    policy: &mut Policy,
+    runner_workers: &mut RunnerWorkers,
+) -> bool {
+    let finished = runner_workers.take_finished();
+    let any_finished = !finished.is_empty();
+    let mut destroyed_any = false;
+    for FinishedOperation {
+        operation,
+        runner_id,
+        profile_key,
+        result,
+    } in finished
+    {
+        let result = match result {
+            Ok(result) => result,
+            Err(panic) => {
+                policy.quarantine_profile(&profile_key, panic);
+                destroyed_any |= operation == RunnerOperation::Destroy;
+                continue;
+            }
+        };
+        match operation {
+            RunnerOperation::Create => match result {
+                Ok(()) => policy.runner_started(runner_id),
+                Err(error) => warn!(runner_id, ?error, "Failed to create runner: {error}"),
+            },
+            RunnerOperation::CreateWarm => {
+                if let Err(error) = result {
+                    warn!(runner_id, ?error, "Failed to create warm runner: {error}");
+                }
+            }
+            RunnerOperation::Destroy => {
+                if let Err(error) = result {
+                    warn!(runner_id, ?error, "Failed to destroy runner: {error}");
+                }
+                destroyed_any = true;
+            }
+        }
+    }
+    if destroyed_any {
+        if let Err(error) = enforce_preserved_runners_budget() {
+            warn!(
+                ?error,
+                "Failed to discard preserved runners over budget: {error}"
+            );
+        }
+    }
+
+    any_finished
+}
+
 /// The monitor thread is our single source of truth.
 ///
-/// It handles one [`Request`] at a time, polling for updated resources before
-/// each request, then sends one response to the API server for each request.
+/// Each pass polls for updated resources, reconciles runners and images, and publishes a
+/// [`SharedState`] snapshot, so that API requests that only read state can be served without
+/// waiting for this thread. It then waits in a `select!` for API requests, wakeups, or the next
+/// timer. Requests that change state are handled in batches, taking every [`Request`] that is
+/// queued before the next pass and sending one response for each, after which the snapshot is
+/// published again.
 fn monitor_thread() -> eyre::Result<()> {
     let mut id_gen = IdGen::new_load().unwrap_or_else(|error| {
         warn!(?error, "Failed to read last-runner-id: {error}");
@@ -471,9 +1183,15 @@ fn monitor_thread() -> eyre::Result<()> {
     let mut policy = Policy::new(TOML.initial_profiles())?;
     let mut registrations_cache = Cache::default();
     let mut image_rebuilds = Rebuilds::default();
+    let mut runner_workers = RunnerWorkers::start();
+    let mut garbage_collector = GarbageCollector::default();
+    let mut orphans = Orphans::default();
     policy.read_base_image_snapshots()?;
 
     loop {
+        if handle_finished_runner_operations(&mut policy, &mut runner_workers) {
+            registrations_cache.invalidate();
+        }
         let registrations = registrations_cache.get(|| list_registered_runners_for_host())?;
         let guests = list_runner_guests()?;
         trace!(?registrations, ?guests);
@@ -483,8 +1201,11 @@ fn monitor_thread() -> eyre::Result<()> {
             guests.len(),
         );
 
+        policy.set_pending_runner_operations(runner_workers.pending());
+        orphans.set_registrations(&registrations);
         policy.set_runners(Runners::new(registrations, guests));
-        image_rebuilds.run(&mut policy)?;
+        policy.update_stuck_busy_runners();
+        image_rebuilds.run(&mut policy, &mut id_gen, garbage_collector.is_running())?;
 
         let profile_runner_counts: BTreeMap<_, _> = policy
             .profiles()
@@ -495,212 +1216,500 @@ fn monitor_thread() -> eyre::Result<()> {
             RunnerCounts {
                 target,
                 healthy,
+                provisioning,
+                warm,
                 started_or_crashed,
                 idle,
                 reserved,
                 busy,
+                unresponsive,
+                held,
                 excess_healthy,
                 wanted,
+                outdated_idle,
+                rebuild_failures,
+                quarantined,
                 image_age,
             },
         ) in profile_runner_counts.iter()
         {
             let snapshot = policy.base_image_snapshot(key);
-            info!("profile {key}: {healthy}/{target} healthy runners ({idle} idle, {reserved} reserved, {busy} busy, {started_or_crashed} started or crashed, {excess_healthy} excess healthy, {wanted} wanted), snapshot {snapshot:?} age {image_age:?}");
+            info!("profile {key}: {healthy}/{target} healthy runners ({provisioning} provisioning, {warm} warm, {idle} idle, {reserved} reserved, {busy} busy, {unresponsive} unresponsive, {held} held, {started_or_crashed} started or crashed, {excess_healthy} excess healthy, {wanted} wanted, {outdated_idle} outdated idle), {rebuild_failures} rebuild failures, quarantined {quarantined}, snapshot {snapshot:?} age {image_age:?}");
         }
         for (_id, runner) in policy.runners() {
             runner.log_info();
         }
 
         let rebuild_guest_names = image_rebuilds.rebuild_guest_names();
-        policy.update_screenshots(&rebuild_guest_names);
+        set_screenshot_guests(policy.screenshot_guests(&rebuild_guest_names));
         policy.update_ipv4_addresses_for_rebuild_guests(&rebuild_guest_names);
 
         if TOML.destroy_all_non_busy_runners() {
             let non_busy_runners = policy
                 .runners()
-                .filter(|(_id, runner)| runner.status() != Status::Busy);
-            let mut threads = vec![];
-            for (&id, _runner) in non_busy_runners {
-                threads.push(policy.unregister_stop_destroy_runner(id)?);
-                registrations_cache.invalidate();
-            }
-            for thread in threads {
-                thread
-                    .join()
-                    .map_err(|e| eyre!("Thread panicked: {e:?}"))??;
-            }
+                .filter(|(_id, runner)| runner.status() != Status::Busy)
+                .filter(|(&id, _runner)| policy.pending_runner_operation(id).is_none())
+                .map(|(&id, _runner)| id)
+                .collect::<Vec<_>>();
+            unregister_stop_destroy_runners(&mut policy, &mut runner_workers, non_busy_runners);
         } else {
-            let changes = policy.compute_runner_changes()?;
-            if !changes.is_empty() {
-                info!(?changes, "Started executing runner changes");
-                let mut threads = vec![];
-                for runner_id in changes.unregister_and_destroy_runner_ids {
-                    threads.push(policy.unregister_stop_destroy_runner(runner_id)?);
-                    registrations_cache.invalidate();
-                }
-                for thread in threads {
-                    thread
-                        .join()
-                        .map_err(|e| eyre!("Thread panicked: {e:?}"))??;
+            let mut changes = policy.compute_runner_changes()?;
+            let warm_pool_changes = policy.compute_warm_pool_changes(&mut changes);
+            if !changes.is_empty() || !warm_pool_changes.is_empty() {
+                info!(?changes, ?warm_pool_changes, "Queueing runner changes");
+                unregister_stop_destroy_runners(
+                    &mut policy,
+                    &mut runner_workers,
+                    warm_pool_changes.destroy_runner_ids,
+                );
+                for runner_id in warm_pool_changes.promote_runner_ids {
+                    let Some(runner) = policy.runner(runner_id) else {
+                        continue;
+                    };
+                    let profile_key = runner.profile_name().to_owned();
+                    match policy.promote_warm_runner(runner_id, None) {
+                        Ok(work) => runner_workers.enqueue(
+                            RunnerOperation::Create,
+                            runner_id,
+                            &profile_key,
+                            work,
+                        ),
+                        Err(error) => {
+                            warn!(runner_id, ?error, "Failed to promote warm runner: {error}")
+                        }
+                    }
                 }
-                let mut threads = vec![];
+                unregister_stop_destroy_runners(
+                    &mut policy,
+                    &mut runner_workers,
+                    changes.unregister_and_destroy_runner_ids,
+                );
+                let mut quarantines = vec![];
                 for (profile_key, count) in changes.create_counts_by_profile_key {
                     let profile = policy
                         .profile(&profile_key)
                         .expect("Guaranteed by compute_runner_changes()");
                     for _ in 0..count {
-                        threads.push(policy.register_create_runner(profile, id_gen.next())?);
-                        registrations_cache.invalidate();
+                        let runner_id = id_gen.next();
+                        match policy.register_create_runner(profile, runner_id) {
+                            Ok(work) => runner_workers.enqueue(
+                                RunnerOperation::Create,
+                                runner_id,
+                                &profile_key,
+                                work,
+                            ),
+                            Err(error) => {
+                                quarantines.push((profile_key.clone(), format!("{error}")));
+                                break;
+                            }
+                        }
                     }
                 }
-                for thread in threads {
-                    if let Err(error) = thread
-                        .join()
-                        .map_err(|e| eyre!("Thread panicked: {e:?}"))
-                        .and_then(|inner_result| inner_result)
-                        .and_then(|runner_guest_name| start_libvirt_guest(&runner_guest_name))
-                    {
-                        warn!(?error, "Failed to create runner: {error}");
+                for (profile_key, count) in warm_pool_changes.create_counts_by_profile_key {
+                    let profile = policy
+                        .profile(&profile_key)
+                        .expect("Guaranteed by compute_warm_pool_changes()");
+                    for _ in 0..count {
+                        let runner_id = id_gen.next();
+                        match policy.create_warm_runner(profile, runner_id) {
+                            Ok(work) => runner_workers.enqueue(
+                                RunnerOperation::CreateWarm,
+                                runner_id,
+                                &profile_key,
+                                work,
+                            ),
+                            Err(error) => {
+                                quarantines.push((profile_key.clone(), format!("{error}")));
+                                break;
+                            }
+                        }
                     }
                 }
-                info!("Finished executing runner changes");
+                for (profile_key, reason) in quarantines {
+                    policy.quarantine_profile(&profile_key, reason);
+                }
+                // Count the runners we just queued, in the dashboard and the next pass.
+                policy.set_pending_runner_operations(runner_workers.pending());
             }
         }
 
+        garbage_collector.run_if_due(
+            &policy,
+            &image_rebuilds,
+            runner_workers.pending().into_keys(),
+            id_gen.last(),
+        );
+        if orphans.run_if_due(
+            &policy,
+            &image_rebuilds,
+            runner_workers.pending().into_keys(),
+            id_gen.last(),
+        ) {
+            registrations_cache.invalidate();
+        }
+
         // Update dashboard data, for the API.
         if let Ok(mut dashboard) = DASHBOARD.write() {
-            *dashboard = Some(Dashboard::render(&policy, &profile_runner_counts)?);
+            *dashboard = Some(Dashboard::render(
+                &policy,
+                &profile_runner_counts,
+                &runner_workers.status(),
+                garbage_collector.last_report(),
+                orphans.last_report(),
+            )?);
         }
+        SharedState::publish(&policy, &image_rebuilds, &rebuild_guest_names);
 
-        // Handle one request from the API.
-        if let Ok(request) = REQUEST.receiver.recv_timeout(TOML.monitor_poll_interval()) {
-            info!(?request, "Received API request");
-
-            match request {
-                Request::TakeRunners {
-                    response_tx,
-                    profile_key: profile,
-                    query:
-                        TakeRunnerQuery {
-                            unique_id,
-                            qualified_repo,
-                            run_id,
-                        },
-                    count,
-                } => {
-                    let mut result = vec![];
-                    let matching_runners = policy
-                        .runners()
-                        .filter(|(_, runner)| {
-                            runner.status() == Status::Idle && runner.profile_name() == profile
-                        })
-                        .take(count)
-                        .collect::<Vec<_>>();
-                    for (&id, runner) in matching_runners {
-                        registrations_cache.invalidate();
-                        if policy
-                            .reserve_runner(id, &unique_id, &qualified_repo, &run_id)
-                            .is_ok()
-                        {
-                            // Flush the dashboard, so we don’t mislead clients into thinking
-                            // the runners that were taken are still idle.
-                            if let Ok(mut dashboard) = DASHBOARD.write() {
-                                *dashboard = None;
-                            }
-                            result.push(json!({
-                                "id": id,
-                                "runner": runner,
-                            }));
+        // Sleep until something happens: a request from the API, a wakeup from a guest or
+        // thread, or a timer, but for no longer than one poll interval.
+        let timeout = policy
+            .time_until_next_timer()
+            .map_or(TOML.monitor_poll_interval(), |timer| {
+                timer.min(TOML.monitor_poll_interval())
+            });
+        crossbeam_channel::select! {
+            recv(REQUEST.receiver) -> first_request => {
+                // Handle all of the requests that are queued, including any that arrive while we
+                // handle them, before the next pass.
+                let requests = first_request
+                    .into_iter()
+                    .chain(REQUEST.receiver.try_iter().take(REQUEST_QUEUE_CAPACITY));
+                for request in requests {
+                    handle_request(
+                        request,
+                        &mut policy,
+                        &mut image_rebuilds,
+                        &mut registrations_cache,
+                        &mut orphans,
+                        &mut runner_workers,
+                        id_gen.last(),
+                    )?;
+                }
+                SharedState::publish(&policy, &image_rebuilds, &rebuild_guest_names);
+            }
+            recv(wakeups()) -> first_wakeup => {
+                // One pass is enough for any number of wakeups.
+                for wakeup in first_wakeup.into_iter().chain(wakeups().try_iter()) {
+                    match wakeup {
+                        Wakeup::LibvirtEvent { guest_name, event } => {
+                            info!(guest_name, event, "Woken up by libvirt event")
+                        }
+                        Wakeup::RebuildFinished { profile_key } => {
+                            info!(profile_key, "Woken up by image rebuild finishing")
+                        }
+                        Wakeup::ServoUpdateFinished => {
+                            info!("Woken up by Servo update finishing")
+                        }
+                        Wakeup::GarbageCollectionFinished => {
+                            info!("Woken up by garbage collection finishing")
+                        }
+                        Wakeup::OrphanCheckFinished => {
+                            info!("Woken up by orphan check finishing")
+                        }
+                        Wakeup::StuckBusyCheckFinished => {
+                            info!("Woken up by stuck busy runner check finishing")
+                        }
+                        Wakeup::RunnerOperationFinished {
+                            operation,
+                            runner_id,
+                        } => {
+                            info!(?operation, runner_id, "Woken up by runner operation finishing")
                         }
                     }
-                    let response = if !result.is_empty() {
-                        serde_json::to_string(&result)?
-                    } else {
-                        // TODO: send error when no runners available
-                        // TODO: send error when any reservations fail
-                        serde_json::to_string(&Option::<()>::None)?
-                    };
-                    response_tx
-                        .send(response)
-                        .expect("Failed to send Response to API thread");
-                }
-                Request::GetOverridePolicy { response_tx } => {
-                    response_tx
-                        .send(policy.get_override().cloned())
-                        .expect("Failed to send Response to API thread");
-                }
-                Request::OverridePolicy {
-                    response_tx,
-                    profile_override_counts,
-                } => {
-                    response_tx
-                        .send(policy.try_override(profile_override_counts).cloned())
-                        .expect("Failed to send Response to API thread");
                 }
-                Request::CancelOverridePolicy { response_tx } => {
-                    response_tx
-                        .send(policy.cancel_override())
-                        .expect("Failed to send Response to API thread");
-                }
-                Request::Screenshot {
-                    response_tx,
-                    runner_id,
-                } => {
-                    response_tx
-                        .send(policy.screenshot_runner(runner_id))
-                        .expect("Failed to send Response to API thread");
+            }
+            default(timeout) => info!(?timeout, "Did not receive an API request or wakeup"),
+        }
+    }
+}
+
+/// Handles a request from the API, which may change the state of the monitor thread.
+fn handle_request(
+    request: Request,
+    policy: &mut Policy,
+    image_rebuilds: &mut Rebuilds,
+    registrations_cache: &mut Cache<Vec<ApiRunner>>,
+    orphans: &mut Orphans,
+    runner_workers: &mut RunnerWorkers,
+    last_runner_id: Option<usize>,
+) -> eyre::Result<()> {
+    info!(?request, "Received API request");
+
+    match request {
+        Request::TakeRunners {
+            response_tx,
+            profile_key: profile,
+            query:
+                TakeRunnerQuery {
+                    unique_id,
+                    qualified_repo,
+                    run_id,
+                },
+            count,
+        } => {
+            let mut result = vec![];
+            let matching_runner_ids = policy
+                .runners()
+                .filter(|(_, runner)| {
+                    runner.status() == Status::Idle && runner.profile_name() == profile
+                })
+                .take(count)
+                .map(|(&id, _runner)| id)
+                .collect::<Vec<_>>();
+            for id in matching_runner_ids {
+                registrations_cache.invalidate();
+                // Reserving the runner also updates our copy of it, so later requests in the
+                // same batch won’t take it again.
+                if policy
+                    .reserve_runner(id, &unique_id, &qualified_repo, &run_id)
+                    .is_ok()
+                {
+                    // Flush the dashboard, so we don’t mislead clients into thinking
+                    // the runners that were taken are still idle.
+                    if let Ok(mut dashboard) = DASHBOARD.write() {
+                        *dashboard = None;
+                    }
+                    result.push(json!({
+                        "id": id,
+                        "runner": policy.runner(id),
+                    }));
                 }
-                Request::GithubJitconfig {
-                    response_tx,
-                    remote_addr,
-                } => {
-                    // The monitor runs a loop like (1) update our lists of resources, including guest IPv4 addresses,
-                    // (2) wait for up to 5 seconds for a message, (3) handle one message. If the DHCP lease and the
-                    // GET /github-jitconfig request both happen in step (2) without step (1) in between, we won’t know
-                    // the IPv4 address, so let’s update the IPv4 addresses before continuing.
-                    policy.update_ipv4_addresses_for_runner_guests()?;
-
-                    let result = policy
-                        .github_jitconfig(remote_addr)
-                        .map(|result| result.map(|ip| ip.to_owned()));
-                    if result.as_ref().map_or(false, |result| result.is_some()) {
-                        // TODO make this configurable?
-                        registrations_cache.invalidate_in(Duration::from_secs(10));
+            }
+            // If there weren’t enough idle runners, promote warm runners for the rest, reserving
+            // them for the job as soon as they are registered.
+            let warm_runner_ids = policy
+                .profile(&profile)
+                .into_iter()
+                .flat_map(|profile| policy.promotable_warm_runners_for_profile(profile))
+                .take(count - result.len())
+                .map(|(&id, _runner)| id)
+                .collect::<Vec<_>>();
+            for id in warm_runner_ids {
+                let reservation = Reservation {
+                    unique_id: unique_id.clone(),
+                    qualified_repo: qualified_repo.clone(),
+                    run_id: run_id.clone(),
+                };
+                match policy.promote_warm_runner(id, Some(reservation)) {
+                    Ok(work) => {
+                        runner_workers.enqueue(RunnerOperation::Create, id, &profile, work);
+                        // Count the runner as pending, so later requests won’t promote it again.
+                        policy.set_pending_runner_operations(runner_workers.pending());
+                        if let Ok(mut dashboard) = DASHBOARD.write() {
+                            *dashboard = None;
+                        }
+                        result.push(json!({
+                            "id": id,
+                            "runner": policy.runner(id),
+                        }));
                     }
+                    Err(error) => warn!(id, ?error, "Failed to promote warm runner: {error}"),
+                }
+            }
+            let response = if !result.is_empty() {
+                serde_json::to_string(&result)?
+            } else {
+                // TODO: send error when no runners available
+                // TODO: send error when any reservations fail
+                serde_json::to_string(&Option::<()>::None)?
+            };
+            send_response(response_tx, response);
+        }
+        Request::OverridePolicy {
+            response_tx,
+            profile_override_counts,
+        } => {
+            send_response(
+                response_tx,
+                policy.try_override(profile_override_counts).cloned(),
+            );
+        }
+        Request::CancelOverridePolicy { response_tx } => {
+            send_response(response_tx, policy.cancel_override());
+        }
+        Request::RebuildImage {
+            response_tx,
+            profile_key,
+        } => {
+            let result = image_rebuilds
+                .request_rebuild(policy, &profile_key)
+                .and_then(|()| image_rebuilds.snapshots(policy, &profile_key));
+            send_response(response_tx, result);
+        }
+        Request::ActivateSnapshot {
+            response_tx,
+            profile_key,
+            snapshot_name,
+        } => {
+            let result = image_rebuilds
+                .activate_snapshot(policy, &profile_key, &snapshot_name)
+                .and_then(|()| image_rebuilds.snapshots(policy, &profile_key));
+            send_response(response_tx, result);
+        }
+        Request::ResetRebuildFailures {
+            response_tx,
+            profile_key,
+        } => {
+            send_response(response_tx, policy.reset_rebuild_failures(&profile_key));
+        }
+        Request::PreserveRunner {
+            response_tx,
+            runner_id,
+            reason,
+        } => {
+            send_response(
+                response_tx,
+                policy.flag_runner_for_preservation(runner_id, &reason),
+            );
+        }
+        Request::DiscardPreservedRunner {
+            response_tx,
+            runner_id,
+        } => {
+            send_response(response_tx, discard_preserved_runner(runner_id));
+        }
+        Request::HoldRunner {
+            response_tx,
+            runner_id,
+            ttl,
+        } => {
+            // See Request::GithubJitconfig for why we update the IPv4 addresses here.
+            if let Err(error) = policy.update_ipv4_addresses_for_runner_guests() {
+                warn!(
+                    ?error,
+                    "Failed to update IPv4 addresses of runner guests: {error}"
+                );
+            }
+            send_response(response_tx, policy.hold_runner(runner_id, ttl));
+        }
+        Request::ReleaseRunner {
+            response_tx,
+            runner_id,
+        } => {
+            send_response(response_tx, policy.release_runner(runner_id));
+        }
+        Request::Orphans { response_tx, fix } => {
+            // The response is sent once the check finishes, and if it unregisters anything, our
+            // registrations cache is invalidated then.
+            orphans.request(
+                policy,
+                image_rebuilds,
+                policy.pending_runner_ids(),
+                last_runner_id,
+                fix,
+                response_tx,
+            );
+        }
+        Request::GithubJitconfig {
+            response_tx,
+            remote_addr,
+        } => {
+            // The monitor runs a loop like (1) update our lists of resources, including guest IPv4 addresses,
+            // (2) wait for up to 5 seconds for a message, (3) handle the queued messages. If the DHCP lease and the
+            // GET /github-jitconfig request both happen in step (2) without step (1) in between, we won’t know
+            // the IPv4 address, so let’s update the IPv4 addresses before continuing.
+            policy.update_ipv4_addresses_for_runner_guests()?;
 
-                    response_tx
-                        .send(result)
-                        .expect("Failed to send Response to API thread");
+            let result = policy
+                .github_jitconfig(remote_addr.clone())
+                .map(|result| result.map(|ip| ip.to_owned()));
+
+            // Smoke test guests for new images are not runners, but they still get a
+            // jitconfig, so we can check that their runners come online.
+            let result = result.or_else(|error| {
+                policy.update_ipv4_addresses_for_rebuild_guests(
+                    &image_rebuilds.rebuild_guest_names(),
+                );
+                match policy.rebuild_guest_profile_key(remote_addr) {
+                    Some(key) if image_rebuilds.smoke_testing_profile_keys().contains(key) => {
+                        Ok(image_rebuilds.smoke_test_github_jitconfig(key))
+                    }
+                    // Memory snapshot guests are ready once their boot script asks for a
+                    // jitconfig. They get none, so they keep asking until they are saved, and
+                    // runners restored from them ask again.
+                    Some(key)
+                        if image_rebuilds
+                            .memory_snapshotting_profile_keys()
+                            .contains(key) =>
+                    {
+                        image_rebuilds.report_memory_snapshot_ready(key)?;
+                        Ok(None)
+                    }
+                    _ => Err(error),
                 }
-                Request::BootScript {
-                    response_tx,
-                    remote_addr,
-                } => {
-                    // The monitor runs a loop like (1) update our lists of resources, including guest IPv4 addresses,
-                    // (2) wait for up to 5 seconds for a message, (3) handle one message. If the DHCP lease and the
-                    // GET /github-jitconfig request both happen in step (2) without step (1) in between, we won’t know
-                    // the IPv4 address, so let’s update the IPv4 addresses before continuing.
-                    policy.update_ipv4_addresses_for_runner_guests()?;
-                    policy.update_ipv4_addresses_for_rebuild_guests(&rebuild_guest_names);
-
-                    let result = policy
-                        .boot_script_for_runner_guest(remote_addr.clone())
-                        .transpose()
-                        .or_else(|| {
-                            policy
-                                .boot_script_for_rebuild_guest(remote_addr)
-                                .transpose()
-                        })
+            });
+            if result.as_ref().is_ok_and(|result| result.is_some()) {
+                // TODO make this configurable?
+                registrations_cache.invalidate_in(Duration::from_secs(10));
+            }
+
+            send_response(response_tx, result);
+        }
+        Request::BootScript {
+            response_tx,
+            remote_addr,
+        } => {
+            // The monitor runs a loop like (1) update our lists of resources, including guest IPv4 addresses,
+            // (2) wait for up to 5 seconds for a message, (3) handle the queued messages. If the DHCP lease and the
+            // GET /github-jitconfig request both happen in step (2) without step (1) in between, we won’t know
+            // the IPv4 address, so let’s update the IPv4 addresses before continuing.
+            policy.update_ipv4_addresses_for_runner_guests()?;
+            policy.update_ipv4_addresses_for_rebuild_guests(&image_rebuilds.rebuild_guest_names());
+
+            let result = policy
+                .boot_script_for_runner_guest(remote_addr.clone())
+                .transpose()
+                .or_else(|| {
+                    policy
+                        .boot_script_for_rebuild_guest(
+                            remote_addr,
+                            &image_rebuilds.smoke_testing_profile_keys(),
+                        )
                         .transpose()
-                        .and_then(|result| result.ok_or_eyre("No guest found with IP address"));
-                    response_tx
-                        .send(result)
-                        .expect("Failed to send Response to API thread");
-                }
+                })
+                .transpose()
+                .and_then(|result| result.ok_or_eyre("No guest found with IP address"));
+            send_response(response_tx, result);
+        }
+        Request::SmokeTestResult {
+            response_tx,
+            remote_addr,
+            result,
+        } => {
+            policy.update_ipv4_addresses_for_rebuild_guests(&image_rebuilds.rebuild_guest_names());
+            let response = policy
+                .rebuild_guest_profile_key(remote_addr)
+                .ok_or_eyre("No rebuild guest found with IP address")
+                .and_then(|key| image_rebuilds.report_smoke_test_result(key, result));
+            send_response(response_tx, response);
+        }
+        Request::Heartbeat {
+            response_tx,
+            remote_addr,
+            heartbeat,
+        } => {
+            // See Request::GithubJitconfig for why we may need to update the IPv4 addresses
+            // here. Heartbeats are frequent, so only do that if we don’t know the address yet.
+            if let Err(error) =
+                policy.update_ipv4_addresses_for_runner_guests_if_unknown(&remote_addr)
+            {
+                warn!(
+                    ?error,
+                    "Failed to update IPv4 addresses of runner guests: {error}"
+                );
             }
-        } else {
-            info!("Did not receive an API request");
+            let response = policy.record_runner_heartbeat(remote_addr, &heartbeat);
+            send_response(response_tx, response);
         }
     }
+
+    Ok(())
+}
+
+/// Sends a response to the API thread, which may have timed out and stopped waiting for it. The
+/// request has already been handled, so that’s not an error, but it is worth knowing about.
+fn send_response<T>(response_tx: Sender<T>, response: T) {
+    if response_tx.send(response).is_err() {
+        warn!("Failed to send response to API thread, which may have timed out");
+    }
 }
@@ -0,0 +1,619 @@
+//! Orphaned resources, which belong to no runner or image rebuild that we know about.
+//!
+//! [`Runners::new`](crate::runner::Runners::new) only tracks runners whose registration or guest
+//! has a name we can parse a runner id out of, so some resources are never cleaned up by the
+//! usual runner lifecycle: data directories of runners that are long gone, registrations that
+//! never came online or have unparseable names, guests whose data directory is missing, and
+//! `<profile>.init` guests left behind by an image rebuild that failed while defining its guest.
+//!
+//! Every `orphan_check_interval`, we find and report them, and if `fix_orphans` is set, clean
+//! them up too.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{read_dir, remove_dir_all, symlink_metadata},
+    io,
+    path::Path,
+    thread::{self, JoinHandle},
+    time::{Instant, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use cmd_lib::run_cmd;
+use crossbeam_channel::Sender;
+use jane_eyre::eyre::{self, eyre};
+use monitor::{
+    clock,
+    github::{unregister_runner, ApiRunner},
+};
+use serde::Serialize;
+use settings::{data::get_data_path, profile::Profile, TOML};
+use tracing::{error, info, warn};
+
+use crate::{
+    data::get_runner_data_path,
+    image::{destroy_runner, Rebuilds},
+    libvirt::{list_init_guests, list_runner_guests},
+    policy::Policy,
+    preserved::preserved_runner_image_path,
+    runner::{guest_runner_id, registration_runner_id},
+    send_response,
+    wakeup::{wake, Wakeup},
+};
+
+#[derive(Debug, Default)]
+pub struct Orphans {
+    last_run: Option<Instant>,
+    /// Registrations we have seen, keyed by their GitHub runner id.
+    registrations: BTreeMap<usize, ApiRunner>,
+    /// When we first saw each registration offline, since the API doesn’t tell us when it was
+    /// created, keyed by its GitHub runner id.
+    offline_since: BTreeMap<usize, Instant>,
+    thread: Option<JoinHandle<eyre::Result<OrphanReport>>>,
+    /// The API request waiting for [`Self::thread`], if any.
+    response_tx: Option<Sender<eyre::Result<OrphanReport>>>,
+    last_report: Option<OrphanReport>,
+}
+
+/// What an orphan check needs to know about our runners and profiles, taken on the monitor thread
+/// when it starts, so that it can run on its own thread.
+#[derive(Debug, Default)]
+struct OrphanCheckInputs {
+    profiles: BTreeMap<String, Profile>,
+    known_runner_ids: BTreeSet<usize>,
+    /// The last runner id we allocated, if any. Runners with later ids were created after we
+    /// started, so they are known too.
+    last_runner_id: Option<usize>,
+    rebuilding_profile_keys: BTreeSet<String>,
+    /// Orphaned registrations, which we find on the monitor thread, since that’s where we keep
+    /// track of how long they have been offline.
+    registrations: Vec<OrphanedRegistration>,
+    fix: bool,
+}
+
+impl OrphanCheckInputs {
+    fn is_known_runner(&self, runner_id: usize) -> bool {
+        self.known_runner_ids.contains(&runner_id)
+            || self.last_runner_id.is_none_or(|last| runner_id > last)
+    }
+}
+
+/// The orphaned resources found by the last check, for the API.
+#[derive(Clone, Debug, Serialize)]
+pub struct OrphanReport {
+    pub checked_at: DateTime<Utc>,
+    /// Whether the orphaned resources were cleaned up, rather than only reported.
+    pub fixed: bool,
+    /// Runner ids with a data directory, but no registration or guest.
+    pub data_dirs: Vec<usize>,
+    pub registrations: Vec<OrphanedRegistration>,
+    /// Runner guests with no data directory.
+    pub guests: Vec<String>,
+    pub init_guests: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OrphanedRegistration {
+    /// GitHub runner id, not our runner id.
+    pub id: usize,
+    pub name: String,
+}
+
+impl OrphanReport {
+    pub fn is_empty(&self) -> bool {
+        self.data_dirs.is_empty()
+            && self.registrations.is_empty()
+            && self.guests.is_empty()
+            && self.init_guests.is_empty()
+    }
+}
+
+impl Orphans {
+    /// Records the registrations listed by the monitor thread, including those with names we
+    /// can’t parse, which are not tracked anywhere else.
+    pub fn set_registrations(&mut self, registrations: &[ApiRunner]) {
+        self.registrations = registrations
+            .iter()
+            .map(|registration| (registration.id, registration.clone()))
+            .collect();
+        self.offline_since
+            .retain(|id, _| self.registrations.get(id).is_some_and(is_offline));
+        for (&id, registration) in self.registrations.iter() {
+            if is_offline(registration) {
+                self.offline_since.entry(id).or_insert_with(clock::instant);
+            }
+        }
+    }
+
+    /// Starts checking for orphaned resources on another thread if `orphan_check_interval` has
+    /// elapsed since the last time, cleaning them up if `fix_orphans` is set, and reaps the
+    /// thread once it has finished.
+    ///
+    /// Returns true iff any registrations were unregistered.
+    pub fn run_if_due(
+        &mut self,
+        policy: &Policy,
+        image_rebuilds: &Rebuilds,
+        pending_runner_ids: impl IntoIterator<Item = usize>,
+        last_runner_id: Option<usize>,
+    ) -> bool {
+        let unregistered = self.reap();
+        if self.thread.is_none()
+            && self.last_run.is_none_or(|last_run| {
+                clock::elapsed_since(last_run) >= TOML.orphan_check_interval()
+            })
+        {
+            self.start(
+                policy,
+                image_rebuilds,
+                pending_runner_ids,
+                last_runner_id,
+                TOML.fix_orphans(),
+                None,
+            );
+        }
+
+        unregistered
+    }
+
+    /// Starts checking for orphaned resources now, cleaning them up if `fix` is true, and sends
+    /// the report to `response_tx` once the check has finished.
+    pub fn request(
+        &mut self,
+        policy: &Policy,
+        image_rebuilds: &Rebuilds,
+        pending_runner_ids: impl IntoIterator<Item = usize>,
+        last_runner_id: Option<usize>,
+        fix: bool,
+        response_tx: Sender<eyre::Result<OrphanReport>>,
+    ) {
+        if self.thread.is_some() {
+            send_response(response_tx, Err(eyre!("Orphan check already running")));
+            return;
+        }
+        self.start(
+            policy,
+            image_rebuilds,
+            pending_runner_ids,
+            last_runner_id,
+            fix,
+            Some(response_tx),
+        );
+    }
+
+    pub fn last_report(&self) -> Option<&OrphanReport> {
+        self.last_report.as_ref()
+    }
+
+    fn start(
+        &mut self,
+        policy: &Policy,
+        image_rebuilds: &Rebuilds,
+        pending_runner_ids: impl IntoIterator<Item = usize>,
+        last_runner_id: Option<usize>,
+        fix: bool,
+        response_tx: Option<Sender<eyre::Result<OrphanReport>>>,
+    ) {
+        self.last_run = Some(clock::instant());
+
+        let runner_ids_with_guests = policy
+            .runners()
+            .filter(|(_id, runner)| runner.guest_name().is_some())
+            .map(|(&id, _runner)| id)
+            .collect();
+        let inputs = OrphanCheckInputs {
+            profiles: policy
+                .profiles()
+                .map(|(key, profile)| (key.clone(), profile.clone()))
+                .collect(),
+            known_runner_ids: policy
+                .runners()
+                .map(|(&id, _runner)| id)
+                .chain(pending_runner_ids)
+                .chain(image_rebuilds.runner_ids())
+                .collect(),
+            last_runner_id,
+            rebuilding_profile_keys: image_rebuilds.rebuild_guest_names().into_keys().collect(),
+            registrations: self.find_registrations(&runner_ids_with_guests),
+            fix,
+        };
+        let thread = thread::spawn(move || {
+            let result = check(&inputs);
+            wake(Wakeup::OrphanCheckFinished);
+            result
+        });
+        self.thread = Some(thread);
+        self.response_tx = response_tx;
+    }
+
+    /// Reaps the orphan check thread, if it has finished, and responds to the API request
+    /// waiting for it, if any.
+    ///
+    /// Returns true iff any registrations were unregistered.
+    fn reap(&mut self) -> bool {
+        let Some(thread) = self.thread.take() else {
+            return false;
+        };
+        if !thread.is_finished() {
+            self.thread = Some(thread);
+            return false;
+        }
+        let result = thread.join().unwrap_or_else(|panic| {
+            error!(?panic, "Orphan check thread panic");
+            Err(eyre!("Orphan check thread panicked"))
+        });
+        let unregistered = match &result {
+            Ok(report) => {
+                self.last_report = Some(report.clone());
+                report.fixed && !report.registrations.is_empty()
+            }
+            Err(error) => {
+                warn!(?error, "Failed to check for orphaned resources: {error}");
+                false
+            }
+        };
+        if let Some(response_tx) = self.response_tx.take() {
+            send_response(response_tx, result);
+        }
+
+        unregistered
+    }
+
+    /// Finds registrations that have been offline for longer than `orphan_min_age`, and don’t
+    /// belong to a runner with a guest.
+    fn find_registrations(
+        &self,
+        runner_ids_with_guests: &BTreeSet<usize>,
+    ) -> Vec<OrphanedRegistration> {
+        self.offline_since
+            .iter()
+            .filter(|(_id, &offline_since)| {
+                clock::elapsed_since(offline_since) >= TOML.orphan_min_age()
+            })
+            .flat_map(|(id, _offline_since)| self.registrations.get(id))
+            .filter(|registration| {
+                registration_runner_id(&registration.name)
+                    .is_none_or(|runner_id| !runner_ids_with_guests.contains(&runner_id))
+            })
+            .map(|registration| OrphanedRegistration {
+                id: registration.id,
+                name: registration.name.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Finds orphaned resources, and cleans them up if `inputs.fix` is true.
+fn check(inputs: &OrphanCheckInputs) -> eyre::Result<OrphanReport> {
+    let runners_path = get_data_path(Path::new("runners"))?;
+    let report = OrphanReport {
+        checked_at: clock::utc_now(),
+        fixed: inputs.fix,
+        data_dirs: find_data_dirs(&runners_path, inputs)?,
+        registrations: inputs.registrations.clone(),
+        guests: find_guests(&list_runner_guests()?, &runners_path, inputs),
+        init_guests: find_init_guests(&list_init_guests()?, inputs),
+    };
+    if report.is_empty() {
+        info!("No orphaned resources");
+    } else {
+        warn!(?report, "Found orphaned resources");
+    }
+    if inputs.fix {
+        fix(inputs, &report);
+    }
+
+    Ok(report)
+}
+
+/// Cleans up the orphaned resources in `report`. Unregistered registrations disappear from our
+/// list of registrations once the monitor thread lists them again.
+fn fix(inputs: &OrphanCheckInputs, report: &OrphanReport) {
+    for &runner_id in report.data_dirs.iter() {
+        info!(runner_id, "Deleting orphaned runner data directory");
+        let result =
+            get_runner_data_path(runner_id, None).and_then(|path| Ok(remove_dir_all(path)?));
+        if let Err(error) = result {
+            warn!(runner_id, ?error, "Failed to delete: {error}");
+        }
+    }
+    for registration in report.registrations.iter() {
+        info!(?registration, "Unregistering orphaned registration");
+        if let Err(error) = unregister_runner(registration.id) {
+            warn!(?registration, ?error, "Failed to unregister: {error}");
+        }
+    }
+    for guest_name in report.guests.iter() {
+        info!(guest_name, "Destroying orphaned runner guest");
+        if let Err(error) = destroy_orphaned_guest(inputs, guest_name) {
+            warn!(guest_name, ?error, "Failed to destroy: {error}");
+        }
+    }
+    for guest_name in report.init_guests.iter() {
+        info!(guest_name, "Undefining leftover init guest");
+        if let Err(error) = run_cmd!(virsh undefine -- $guest_name) {
+            warn!(guest_name, ?error, "Failed to undefine: {error}");
+        }
+    }
+}
+
+fn is_offline(registration: &ApiRunner) -> bool {
+    registration.status == "offline" && !registration.busy
+}
+
+/// Finds data directories of runners we don’t know about, which are older than
+/// `orphan_min_age`, except those of preserved runners.
+fn find_data_dirs(runners_path: &Path, inputs: &OrphanCheckInputs) -> eyre::Result<Vec<usize>> {
+    let mut result = vec![];
+    let Ok(entries) = read_dir(runners_path) else {
+        return Ok(result);
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Some(runner_id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        if inputs.is_known_runner(runner_id) || preserved_runner_image_path(runner_id).exists() {
+            continue;
+        }
+        // Writing to a file doesn’t change the modified time of its directory, so use the last
+        // time anything in it was written to, like a screenshot or a log.
+        let age = clock::elapsed_since_time(last_modified(&entry.path())?).unwrap_or_default();
+        if age >= TOML.orphan_min_age() {
+            result.push(runner_id);
+        }
+    }
+    result.sort();
+
+    Ok(result)
+}
+
+/// Returns the newest modified time of `path` and everything in it, without following symlinks.
+fn last_modified(path: &Path) -> io::Result<SystemTime> {
+    let metadata = symlink_metadata(path)?;
+    let mut result = metadata.modified()?;
+    if metadata.is_dir() {
+        for entry in read_dir(path)? {
+            result = result.max(last_modified(&entry?.path())?);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Finds runner guests we don’t know about, which have no data directory, because
+/// [`Runners::new`](crate::runner::Runners::new) can’t track a runner without one.
+fn find_guests(
+    guest_names: &[String],
+    runners_path: &Path,
+    inputs: &OrphanCheckInputs,
+) -> Vec<String> {
+    guest_names
+        .iter()
+        .filter(|guest_name| {
+            guest_runner_id(guest_name).is_some_and(|runner_id| {
+                !inputs.is_known_runner(runner_id)
+                    && !runners_path.join(runner_id.to_string()).exists()
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Finds `<profile>.init` guests of profiles we know about that are not being rebuilt. Other
+/// guests may happen to end in `.init`, but they aren’t ours to undefine.
+fn find_init_guests(guest_names: &[String], inputs: &OrphanCheckInputs) -> Vec<String> {
+    guest_names
+        .iter()
+        .filter(|guest_name| {
+            guest_name.strip_suffix(".init").is_some_and(|profile_key| {
+                inputs.profiles.contains_key(profile_key)
+                    && !inputs.rebuilding_profile_keys.contains(profile_key)
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+fn destroy_orphaned_guest(inputs: &OrphanCheckInputs, guest_name: &str) -> eyre::Result<()> {
+    let prefix = format!("{}-", TOML.libvirt_runner_guest_prefix());
+    let profile = guest_name
+        .strip_prefix(&prefix)
+        .and_then(|name| name.rsplit_once('.'))
+        .and_then(|(profile_key, _id)| inputs.profiles.get(profile_key));
+    match (profile, guest_runner_id(guest_name)) {
+        (Some(profile), Some(runner_id)) => destroy_runner(profile, guest_name, runner_id),
+        _ => {
+            // Without a profile, we don’t know how to delete its images, but garbage collection
+            // will get them.
+            let _ = run_cmd!(virsh destroy -- $guest_name);
+            run_cmd!(virsh undefine --nvram -- $guest_name)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::BTreeSet,
+        fs::{create_dir, File},
+        rc::Rc,
+        time::Duration,
+    };
+
+    use jane_eyre::eyre;
+    use mktemp::Temp;
+    use monitor::{
+        clock::{self, FakeClock},
+        github::ApiRunner,
+    };
+    use settings::TOML;
+
+    use crate::policy::test::profile;
+
+    use super::{
+        find_data_dirs, find_guests, find_init_guests, OrphanCheckInputs, OrphanedRegistration,
+        Orphans,
+    };
+
+    fn inputs(known_runner_ids: &[usize], last_runner_id: Option<usize>) -> OrphanCheckInputs {
+        OrphanCheckInputs {
+            profiles: [("linux".to_owned(), profile("linux", 1, 0, "0 B"))].into(),
+            known_runner_ids: known_runner_ids.iter().copied().collect(),
+            last_runner_id,
+            ..Default::default()
+        }
+    }
+
+    fn registration(id: usize, name: &str, status: &str, busy: bool) -> ApiRunner {
+        ApiRunner {
+            id,
+            busy,
+            name: name.to_owned(),
+            status: status.to_owned(),
+            labels: vec![],
+        }
+    }
+
+    #[test]
+    fn test_find_registrations() {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut orphans = Orphans::default();
+        let names = |registrations: Vec<OrphanedRegistration>| {
+            registrations
+                .into_iter()
+                .map(|registration| registration.name)
+                .collect::<Vec<_>>()
+        };
+
+        orphans.set_registrations(&[
+            registration(100, "ci-runner-linux.1@host", "offline", false),
+            registration(101, "ci-runner-linux.2@host", "offline", false),
+            registration(102, "ci-runner-linux.3@host", "online", false),
+            registration(103, "ci-runner-linux.4@host", "offline", true),
+            registration(104, "unparseable", "offline", false),
+        ]);
+        assert_eq!(
+            names(orphans.find_registrations(&BTreeSet::default())),
+            [] as [&str; 0]
+        );
+
+        // Offline registrations become orphans after `orphan_min_age`, unless their runner has
+        // a guest. Online and busy registrations never do.
+        clock.advance(TOML.orphan_min_age());
+        assert_eq!(
+            names(orphans.find_registrations(&BTreeSet::from([1]))),
+            ["ci-runner-linux.2@host", "unparseable"]
+        );
+
+        // Coming back online resets the age of a registration.
+        orphans.set_registrations(&[
+            registration(101, "ci-runner-linux.2@host", "online", false),
+            registration(104, "unparseable", "offline", false),
+        ]);
+        orphans.set_registrations(&[
+            registration(101, "ci-runner-linux.2@host", "offline", false),
+            registration(104, "unparseable", "offline", false),
+        ]);
+        assert_eq!(
+            names(orphans.find_registrations(&BTreeSet::default())),
+            ["unparseable"]
+        );
+        clock.advance(TOML.orphan_min_age());
+        assert_eq!(
+            names(orphans.find_registrations(&BTreeSet::default())),
+            ["ci-runner-linux.2@host", "unparseable"]
+        );
+
+        clock::set_clock_for_thread(None);
+    }
+
+    #[test]
+    fn test_find_data_dirs() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let dir = Temp::new_dir()?;
+        for runner_id in ["1", "2", "3", "9", "foo"] {
+            create_dir(dir.join(runner_id))?;
+        }
+        let log_path = dir.join("3").join("log");
+        File::create(&log_path)?;
+
+        // Data directories are young when created.
+        assert_eq!(
+            find_data_dirs(&dir, &inputs(&[1], Some(5)))?,
+            [] as [usize; 0]
+        );
+
+        // Once old enough, those of unknown runners are orphans, but runner 9 was created after
+        // we took our inputs.
+        clock.advance(TOML.orphan_min_age());
+        assert_eq!(find_data_dirs(&dir, &inputs(&[1], Some(5)))?, [2, 3]);
+
+        // Writing to a file in a data directory makes it young again, even though the modified
+        // time of the directory itself doesn’t change.
+        File::options()
+            .write(true)
+            .open(&log_path)?
+            .set_modified(clock::now())?;
+        assert_eq!(find_data_dirs(&dir, &inputs(&[1], Some(5)))?, [2]);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(find_data_dirs(&dir, &inputs(&[1], Some(5)))?, [2]);
+        clock.advance(TOML.orphan_min_age());
+        assert_eq!(find_data_dirs(&dir, &inputs(&[1], Some(5)))?, [2, 3]);
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_guests() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let dir = Temp::new_dir()?;
+        create_dir(dir.join("2"))?;
+        let guest_names = [
+            "ci-runner-linux.1",
+            "ci-runner-linux.2",
+            "ci-runner-linux.3",
+            "ci-runner-linux.9",
+            "ci-runner-linux",
+        ]
+        .map(str::to_owned);
+
+        // Guests with no data directory are orphans straight away, no matter how old they are,
+        // unless we know about them, or they were created after we took our inputs.
+        assert_eq!(
+            find_guests(&guest_names, &dir, &inputs(&[1], Some(5))),
+            ["ci-runner-linux.3"]
+        );
+        clock.advance(TOML.orphan_min_age());
+        assert_eq!(
+            find_guests(&guest_names, &dir, &inputs(&[1], Some(5))),
+            ["ci-runner-linux.3"]
+        );
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_init_guests() {
+        let guest_names =
+            ["linux.init", "windows.init", "linux", "other.init.bak"].map(str::to_owned);
+        let mut inputs = inputs(&[], None);
+
+        // Only init guests of profiles we know about are ours.
+        assert_eq!(find_init_guests(&guest_names, &inputs), ["linux.init"]);
+
+        // Init guests of profiles being rebuilt are still in use.
+        inputs.rebuilding_profile_keys.insert("linux".to_owned());
+        assert_eq!(find_init_guests(&guest_names, &inputs), [] as [&str; 0]);
+    }
+}
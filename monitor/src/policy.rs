@@ -1,32 +1,49 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs::{create_dir, read_link, File},
+    fs::{create_dir, read_link, remove_file, File},
     io::{Read, Write},
     net::Ipv4Addr,
     os::unix::fs::symlink,
     path::{Path, PathBuf},
     thread::{self, JoinHandle},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use chrono::DateTime;
 use itertools::Itertools;
 use jane_eyre::eyre::{self, bail, Context, OptionExt};
-use mktemp::Temp;
-use monitor::github::unregister_runner;
+use monitor::{
+    clock,
+    github::{
+        list_workflow_run_jobs, reserve_runner, unregister_runner, ApiGenerateJitconfigResponse,
+        ApiRunner,
+    },
+};
 use serde::Serialize;
 use settings::{
     profile::{ImageType, Profile},
     units::MemorySize,
     TOML,
 };
-use tracing::{debug, info, info_span, warn};
+use tracing::{debug, error, info, info_span, warn};
 
 use crate::{
     data::{get_profile_configuration_path, get_profile_data_path, get_runner_data_path},
-    image::{create_runner, destroy_runner, register_runner},
-    libvirt::{get_ipv4_address, update_screenshot},
-    runner::{Runner, Runners, Status},
+    image::{
+        create_runner, destroy_runner,
+        inputs::{read_rebuild_record, ImageInputs, RebuildRecord},
+        memory::start_or_restore_runner_guest,
+        register_runner,
+    },
+    libvirt::{capture_serial_console_to_file, get_ipv4_address},
+    preserved::preserve_runner,
+    runner::{
+        Heartbeat, ProvisioningStage, Runner, RunnerHold, RunnerHoldDetails, Runners, Status,
+        WARM_FLAG_FILENAME,
+    },
+    screenshots::ScreenshotGuest,
+    wakeup::{wake, Wakeup},
+    workers::{PendingOperation, RunnerOperation, Work},
 };
 
 #[derive(Debug)]
@@ -36,6 +53,73 @@ pub struct Policy {
     ipv4_addresses: BTreeMap<String, Option<Ipv4Addr>>,
     runners: Option<Runners>,
     current_override: Option<Override>,
+    /// Profiles with image rebuilds that are running or waiting for resources. Each of these
+    /// rebuild guests is charged against our available resources, like one extra runner.
+    rebuild_profile_keys: BTreeSet<String>,
+    /// Inputs that each current base image snapshot was built from, if known.
+    base_image_inputs: BTreeMap<String, ImageInputs>,
+    /// Inputs that each profile would be built from if it was rebuilt now, if known.
+    current_image_inputs: BTreeMap<String, ImageInputs>,
+    /// Snapshots that were activated manually, which won’t be rebuilt until requested.
+    pinned_base_image_snapshots: BTreeMap<String, String>,
+    /// Profiles with image rebuilds that were requested manually.
+    requested_rebuild_profile_keys: BTreeSet<String>,
+    /// Consecutive image rebuild failures for each profile, if any.
+    rebuild_failures: BTreeMap<String, RebuildFailures>,
+    /// Profiles that failed unexpectedly, with the reason. We stop creating runners and
+    /// rebuilding images for these profiles until the monitor is restarted, but keep serving
+    /// the other profiles.
+    quarantined_profiles: BTreeMap<String, String>,
+    /// When we first saw each busy runner being busy. Like overrides, these are forgotten if the
+    /// monitor is restarted, which only delays the checks for stuck busy runners.
+    busy_since: BTreeMap<usize, Instant>,
+    /// Runners that have been busy for longer than their profile allows, with what we found
+    /// when we last checked their jobs.
+    stuck_busy_runners: BTreeMap<usize, StuckBusyRunnerCheck>,
+    /// Thread looking up the jobs of stuck busy runners, since that can take many API requests.
+    stuck_busy_check: Option<JoinHandle<Vec<(usize, StuckBusyRunner)>>>,
+    /// Runners being created or destroyed by the runner workers, whose results are yet to be
+    /// handled by the monitor thread.
+    pending_runner_operations: BTreeMap<usize, PendingOperation>,
+    /// Runners that we created and started, which are still provisioning until they come online
+    /// or `monitor_start_timeout` elapses.
+    waiting_for_online: BTreeSet<usize>,
+    /// When we first saw each runner online, so that heartbeats sent before then can’t make it
+    /// unresponsive.
+    online_since: BTreeMap<usize, SystemTime>,
+}
+
+/// What we found when checking the job of a runner that has been busy for too long.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub enum StuckBusyRunner {
+    /// The job finished or was cancelled, so the runner should be destroyed.
+    JobFinished { conclusion: Option<String> },
+    /// The job is still running, or we couldn’t tell, so the runner is flagged on the dashboard.
+    Flagged { reason: String },
+}
+
+#[derive(Debug, Clone)]
+struct StuckBusyRunnerCheck {
+    result: StuckBusyRunner,
+    checked_at: Instant,
+}
+
+/// Consecutive image rebuild failures for a profile, for backing off and eventually giving up.
+/// Like overrides, these are forgotten if the monitor is restarted.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RebuildFailures {
+    pub count: usize,
+    pub last_error: String,
+    #[serde(skip)]
+    pub last_failed_at: Instant,
+}
+
+/// A job to reserve a runner for, as soon as it’s registered.
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub unique_id: String,
+    pub qualified_repo: String,
+    pub run_id: String,
 }
 
 /// Overrides compromise on some of our usual guarantees:
@@ -60,16 +144,39 @@ impl RunnerChanges {
     }
 }
 
+/// Changes to the warm pools, on top of the [`RunnerChanges`] they were computed from.
+#[derive(Debug, PartialEq, Default)]
+pub struct WarmPoolChanges {
+    /// Warm runners to register and start, instead of creating some of the runners.
+    pub promote_runner_ids: Vec<usize>,
+    pub destroy_runner_ids: Vec<usize>,
+    pub create_counts_by_profile_key: BTreeMap<String, usize>,
+}
+impl WarmPoolChanges {
+    pub fn is_empty(&self) -> bool {
+        self.promote_runner_ids.is_empty()
+            && self.destroy_runner_ids.is_empty()
+            && self.create_counts_by_profile_key.values().sum::<usize>() == 0
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct RunnerCounts {
     pub target: usize,
     pub healthy: usize,
+    pub provisioning: usize,
+    pub warm: usize,
     pub started_or_crashed: usize,
     pub idle: usize,
     pub reserved: usize,
     pub busy: usize,
+    pub unresponsive: usize,
+    pub held: usize,
     pub excess_healthy: usize,
     pub wanted: usize,
+    pub outdated_idle: usize,
+    pub rebuild_failures: usize,
+    pub quarantined: bool,
     pub image_age: Option<Duration>,
 }
 
@@ -81,6 +188,19 @@ impl Policy {
             ipv4_addresses: BTreeMap::default(),
             runners: None,
             current_override: None,
+            rebuild_profile_keys: BTreeSet::default(),
+            base_image_inputs: BTreeMap::default(),
+            current_image_inputs: BTreeMap::default(),
+            pinned_base_image_snapshots: BTreeMap::default(),
+            requested_rebuild_profile_keys: BTreeSet::default(),
+            rebuild_failures: BTreeMap::default(),
+            quarantined_profiles: BTreeMap::default(),
+            busy_since: BTreeMap::default(),
+            stuck_busy_runners: BTreeMap::default(),
+            stuck_busy_check: None,
+            pending_runner_operations: BTreeMap::default(),
+            waiting_for_online: BTreeSet::default(),
+            online_since: BTreeMap::default(),
         };
 
         let profile_target_counts = result
@@ -96,12 +216,26 @@ impl Policy {
         &self,
         profile_target_counts: &BTreeMap<String, usize>,
     ) -> eyre::Result<()> {
+        self.validate_resource_requirements_with_rebuilds(
+            profile_target_counts,
+            &self.rebuild_profile_keys,
+        )
+    }
+
+    fn validate_resource_requirements_with_rebuilds(
+        &self,
+        profile_target_counts: &BTreeMap<String, usize>,
+        rebuild_profile_keys: &BTreeSet<String>,
+    ) -> eyre::Result<()> {
+        let guest_count = |key: &str| {
+            let target_count = profile_target_counts.get(key).copied().unwrap_or(0);
+            let rebuild_count = usize::from(rebuild_profile_keys.contains(key));
+            target_count + rebuild_count
+        };
+
         let required_1g_hugepages = self
             .profiles()
-            .map(|(key, profile)| {
-                let target_count = profile_target_counts.get(&**key).copied().unwrap_or(0);
-                target_count * profile.requires_1g_hugepages
-            })
+            .map(|(key, profile)| guest_count(key) * profile.requires_1g_hugepages)
             .sum::<usize>();
         if required_1g_hugepages > TOML.available_1g_hugepages {
             bail!("Profile configuration requires too many 1G hugepages");
@@ -109,24 +243,126 @@ impl Policy {
 
         let required_normal_memory = self
             .profiles()
-            .map(|(key, profile)| {
-                let target_count = profile_target_counts.get(&**key).copied().unwrap_or(0);
-                target_count * profile.requires_normal_memory
-            })
+            .map(|(key, profile)| guest_count(key) * profile.requires_normal_memory)
             .sum::<MemorySize>();
         if required_normal_memory > TOML.available_normal_memory {
             bail!("Profile configuration requires too much normal memory");
         }
 
+        // Warm runners are shut off, so they only take up disk space.
+        if let Some(available_disk) = TOML.available_disk {
+            let required_disk = self
+                .profiles()
+                .map(|(key, profile)| {
+                    (guest_count(key) + profile.warm_pool_size) * profile.requires_disk
+                })
+                .sum::<MemorySize>();
+            if required_disk > available_disk {
+                bail!("Profile configuration requires too much disk");
+            }
+        }
+
         Ok(())
     }
 
+    /// Checks that there are enough resources to start an image rebuild for the given profile,
+    /// on top of our existing runners and any other image rebuilds.
+    pub fn validate_rebuild_resource_requirements(&self, profile: &Profile) -> eyre::Result<()> {
+        // Charge the runners that actually exist, not our target counts, because runners that
+        // are yet to be created can be deferred until the rebuild is finished.
+        let profile_runner_counts = self
+            .profiles()
+            .map(|(key, profile)| {
+                let count = self
+                    .runners_for_profile(profile)
+                    .filter(|(_id, runner)| {
+                        // Warm runners are shut off, so they only take up disk space.
+                        !matches!(
+                            runner.status(),
+                            Status::Invalid | Status::Provisioning | Status::Warm
+                        )
+                    })
+                    .count()
+                    + self.provisioning_runner_count(profile);
+                (key.clone(), count)
+            })
+            .collect();
+        let mut rebuild_profile_keys = self.rebuild_profile_keys.clone();
+        rebuild_profile_keys.insert(profile.profile_name.clone());
+
+        self.validate_resource_requirements_with_rebuilds(
+            &profile_runner_counts,
+            &rebuild_profile_keys,
+        )
+    }
+
+    /// Sets the profiles with image rebuilds that are running or waiting for resources.
+    pub fn set_rebuild_profile_keys(&mut self, rebuild_profile_keys: BTreeSet<String>) {
+        self.rebuild_profile_keys = rebuild_profile_keys;
+    }
+
+    /// Sets the runners being created or destroyed by the runner workers.
+    pub fn set_pending_runner_operations(
+        &mut self,
+        pending_runner_operations: BTreeMap<usize, PendingOperation>,
+    ) {
+        self.pending_runner_operations = pending_runner_operations;
+        let stages = self.provisioning_stages();
+        if let Some(runners) = self.runners.as_mut() {
+            runners.set_provisioning_stages(&stages);
+        }
+    }
+
+    /// Records that the runner workers created and started the given runner, so we keep
+    /// provisioning it until it comes online.
+    pub fn runner_started(&mut self, id: usize) {
+        self.waiting_for_online.insert(id);
+    }
+
+    fn provisioning_stages(&self) -> BTreeMap<usize, ProvisioningStage> {
+        let creating = self
+            .pending_runner_operations
+            .iter()
+            .filter(|(_id, pending)| pending.operation == RunnerOperation::Create)
+            .map(|(&id, pending)| (id, pending.stage.unwrap_or(ProvisioningStage::Queued)));
+        let waiting_for_online = self
+            .waiting_for_online
+            .iter()
+            .map(|&id| (id, ProvisioningStage::WaitingForOnline));
+
+        waiting_for_online.chain(creating).collect()
+    }
+
+    pub fn pending_runner_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pending_runner_operations.keys().copied()
+    }
+
+    pub fn pending_runner_operation(&self, id: usize) -> Option<RunnerOperation> {
+        self.pending_runner_operations
+            .get(&id)
+            .map(|pending| pending.operation)
+    }
+
     pub fn read_base_image_snapshots(&mut self) -> eyre::Result<()> {
         for (profile_key, profile) in self.profiles.iter() {
             if let Some(base_image_snapshot) = read_base_image_snapshot(profile)? {
+                match read_rebuild_record(profile_key, &base_image_snapshot) {
+                    Ok(Some(RebuildRecord {
+                        inputs: Some(inputs),
+                        ..
+                    })) => {
+                        self.base_image_inputs.insert(profile_key.clone(), inputs);
+                    }
+                    Ok(_) => {}
+                    Err(error) => warn!(profile_key, ?error, "Failed to read rebuild record"),
+                }
                 self.base_image_snapshots
                     .insert(profile_key.clone(), base_image_snapshot);
             }
+            if let Some(pinned_snapshot) = read_pinned_base_image_snapshot(profile)? {
+                self.pinned_base_image_snapshots
+                    .insert(profile_key.clone(), pinned_snapshot);
+            }
         }
 
         Ok(())
@@ -144,11 +380,131 @@ impl Policy {
         self.base_image_snapshots.get(profile_key)
     }
 
-    pub fn set_runners(&mut self, runners: Runners) {
+    /// Returns whether the current base image snapshot was activated manually.
+    pub fn base_image_snapshot_is_pinned(&self, profile_key: &str) -> bool {
+        self.pinned_base_image_snapshots
+            .get(profile_key)
+            .is_some_and(|pinned| self.base_image_snapshot(profile_key) == Some(pinned))
+    }
+
+    pub fn set_runners(&mut self, mut runners: Runners) {
+        // Stop provisioning runners that came online, went away, or took too long to come online.
+        runners.set_provisioning_stages(&self.provisioning_stages());
+        self.waiting_for_online.retain(|&id| {
+            runners.get(id).is_some_and(|runner| {
+                runner.status() == Status::Provisioning
+                    && runner
+                        .age()
+                        .is_ok_and(|age| age <= TOML.monitor_start_timeout())
+            })
+        });
+        runners.set_provisioning_stages(&self.provisioning_stages());
+        self.online_since.retain(|&id, _| runners.get(id).is_some());
+        for (&id, runner) in runners.iter() {
+            if runner.has_come_online() {
+                self.online_since.entry(id).or_insert_with(clock::now);
+            }
+        }
+        runners.set_online_since(&self.online_since);
+
+        let busy_ids = runners
+            .iter()
+            .filter(|(_id, runner)| runner.status() == Status::Busy)
+            .map(|(&id, _runner)| id)
+            .collect::<BTreeSet<_>>();
+        self.busy_since.retain(|id, _| busy_ids.contains(id));
+        self.stuck_busy_runners
+            .retain(|id, _| busy_ids.contains(id));
+        for id in busy_ids {
+            self.busy_since.entry(id).or_insert_with(clock::instant);
+        }
         self.runners = Some(runners);
         self.update_override_internal();
     }
 
+    /// Returns how long the given runner has been busy, as far as we know, if it is busy.
+    pub fn busy_duration(&self, id: usize) -> Option<Duration> {
+        self.busy_since
+            .get(&id)
+            .map(|&busy_since| clock::elapsed_since(busy_since))
+    }
+
+    pub fn stuck_busy_runner(&self, id: usize) -> Option<&StuckBusyRunner> {
+        self.stuck_busy_runners.get(&id).map(|check| &check.result)
+    }
+
+    /// Checks the jobs of runners that have been busy for longer than their profile allows,
+    /// at most once per `api_cache_timeout` for each runner.
+    ///
+    /// The jobs are looked up on another thread, so the results of each check only take effect
+    /// when we reap that thread in some later call.
+    pub fn update_stuck_busy_runners(&mut self) {
+        let Some(runners) = self.runners.as_ref() else {
+            return;
+        };
+
+        // Reap the stuck busy runner check thread, if needed.
+        let mut results = vec![];
+        if let Some(thread) = self.stuck_busy_check.take() {
+            if thread.is_finished() {
+                match thread.join() {
+                    Ok(result) => results = result,
+                    Err(panic) => error!(?panic, "Stuck busy runner check thread panic"),
+                }
+            } else {
+                self.stuck_busy_check = Some(thread);
+            }
+        }
+        for (id, result) in results {
+            warn!(runner_id = id, ?result, "Runner has been busy for too long");
+            self.stuck_busy_runners.insert(
+                id,
+                StuckBusyRunnerCheck {
+                    result,
+                    checked_at: clock::instant(),
+                },
+            );
+        }
+
+        // Forget the checks of runners that are no longer busy for too long, and find the
+        // runners that need checking.
+        let mut checks = BTreeMap::default();
+        let mut registrations_to_check = vec![];
+        for (&id, runner) in runners.iter() {
+            let Some(max_busy_duration) = self
+                .profile(runner.profile_name())
+                .and_then(|profile| profile.max_busy_duration())
+            else {
+                continue;
+            };
+            if self
+                .busy_duration(id)
+                .is_none_or(|duration| duration <= max_busy_duration)
+            {
+                continue;
+            }
+            if let Some(check) = self.stuck_busy_runners.get(&id) {
+                checks.insert(id, check.clone());
+                if clock::elapsed_since(check.checked_at) < TOML.api_cache_timeout() {
+                    continue;
+                }
+            }
+            registrations_to_check.push((id, runner.registration().cloned()));
+        }
+        self.stuck_busy_runners = checks;
+
+        if self.stuck_busy_check.is_none() && !registrations_to_check.is_empty() {
+            self.stuck_busy_check = Some(thread::spawn(move || {
+                let result = registrations_to_check
+                    .into_iter()
+                    .map(|(id, registration)| (id, check_stuck_busy_runner(registration.as_ref())))
+                    .collect();
+                wake(Wakeup::StuckBusyCheckFinished);
+                result
+            }));
+        }
+    }
+
     pub fn compute_runner_changes(&self) -> eyre::Result<RunnerChanges> {
         if self.runners.is_none() {
             bail!("Policy has no Runners!");
@@ -157,13 +513,20 @@ impl Policy {
         let mut result = RunnerChanges::default();
 
         // Invalid => unregister and destroy
+        // Unresponsive => unregister and destroy
         // DoneOrUnregistered => destroy (no need to unregister)
         // StartedOrCrashed and too old => unregister and destroy
         // Reserved for too long => unregister and destroy
+        // Busy for too long, and the job has finished => unregister and destroy
+        // Flagged for preservation and not Busy or Held => unregister and destroy (preserving it)
+        // Hold expired and not Busy => unregister and destroy
         // Idle or Busy => bleed off excess Idle runners
         let invalid = self
             .runners()
             .filter(|(_id, runner)| runner.status() == Status::Invalid);
+        let unresponsive = self
+            .runners()
+            .filter(|(_id, runner)| runner.status() == Status::Unresponsive);
         let done_or_unregistered = self
             .runners()
             .filter(|(_id, runner)| runner.status() == Status::DoneOrUnregistered)
@@ -175,6 +538,20 @@ impl Policy {
                     .age()
                     .map_or(true, |age| age > TOML.monitor_start_timeout())
         });
+        let busy_with_finished_job = self.runners().filter(|(&id, runner)| {
+            !runner.is_held()
+                && matches!(
+                    self.stuck_busy_runner(id),
+                    Some(StuckBusyRunner::JobFinished { .. })
+                )
+        });
+        let flagged_for_preservation = self.runners().filter(|(_id, runner)| {
+            runner.preserve_flag().is_some()
+                && !matches!(runner.status(), Status::Busy | Status::Held)
+        });
+        let hold_expired = self
+            .runners()
+            .filter(|(_id, runner)| runner.hold_expired() && runner.status() != Status::Busy);
         let reserved_for_too_long = self.runners().filter(|(_id, runner)| {
             runner.status() == Status::Reserved
                 && runner
@@ -184,8 +561,8 @@ impl Policy {
                     .map_or(true, |duration| duration > TOML.monitor_reserve_timeout())
         });
 
-        // Destroy invalid runners, but don’t count them as healthy.
-        for (&id, _runner) in invalid {
+        // Destroy invalid and unresponsive runners, but don’t count them as healthy.
+        for (&id, _runner) in invalid.chain(unresponsive) {
             result.unregister_and_destroy_runner_ids.push(id);
         }
 
@@ -197,12 +574,22 @@ impl Policy {
         for (&id, runner) in done_or_unregistered
             .chain(started_or_crashed_and_too_old)
             .chain(reserved_for_too_long)
+            .chain(busy_with_finished_job)
         {
             result.unregister_and_destroy_runner_ids.push(id);
             *proposed_healthy_destroy_counts
                 .get_mut(runner.profile_name())
                 .expect("Guaranteed by initialiser") += 1;
         }
+        for (&id, runner) in flagged_for_preservation.chain(hold_expired) {
+            // These runners may already be destroyed for another reason.
+            if !result.unregister_and_destroy_runner_ids.contains(&id) {
+                result.unregister_and_destroy_runner_ids.push(id);
+                *proposed_healthy_destroy_counts
+                    .get_mut(runner.profile_name())
+                    .expect("Guaranteed by initialiser") += 1;
+            }
+        }
 
         // Excess healthy runners should be destroyed if they are idle.
         // Compute this in a separate step, so we can take into account how many destroys we’ve already proposed.
@@ -217,6 +604,28 @@ impl Policy {
             result.unregister_and_destroy_runner_ids.push(id);
         }
 
+        // Profiles with rolling upgrades keep their runners while their images are rebuilt, so
+        // replace any idle runners created from older images, but only as many as we can spare.
+        let proposed_destroy_ids = result
+            .unregister_and_destroy_runner_ids
+            .iter()
+            .copied()
+            .collect::<BTreeSet<_>>();
+        // Profiles that were rolled back to an earlier image replace them all straight away.
+        let outdated_idle_runners = self.profiles().flat_map(|(key, profile)| {
+            let spare_count = if self.base_image_snapshot_is_pinned(key) {
+                usize::MAX
+            } else {
+                self.spare_runner_count_for_rolling_upgrade(profile, &proposed_destroy_ids)
+            };
+            self.outdated_idle_runners_for_profile(profile)
+                .filter(|(id, _runner)| !proposed_destroy_ids.contains(id))
+                .take(spare_count)
+        });
+        for (&id, _runner) in outdated_idle_runners {
+            result.unregister_and_destroy_runner_ids.push(id);
+        }
+
         // Adjust for critical runners, regardless of whether they fit the new policy.
         let mut scenario = self
             .profiles()
@@ -248,23 +657,82 @@ impl Policy {
                 .insert(profile_key.clone(), wanted_count);
         }
 
-        // If there are runners to destroy, do not create any new runners.
+        // Leave runners that are being created or destroyed to the runner workers.
+        result
+            .unregister_and_destroy_runner_ids
+            .retain(|id| !self.pending_runner_operations.contains_key(id));
+
+        // If there are runners to destroy, do not create any new runners, and if there are
+        // runners being destroyed, do not create any new runners for their profiles.
         // Destroying runners may fail, so we can’t assume that their resources will necessarily be freed.
         if !result.unregister_and_destroy_runner_ids.is_empty() {
             result.create_counts_by_profile_key.clear();
         }
+        for profile_key in self.profile_keys_being_destroyed() {
+            result.create_counts_by_profile_key.remove(profile_key);
+        }
 
         Ok(result)
     }
 
-    /// Spawn a thread that registers and creates a runner.
-    ///
-    /// Returns the libvirt guest name, since that can’t be parallelised without causing errors.
-    pub fn register_create_runner(
-        &self,
-        profile: &Profile,
-        id: usize,
-    ) -> eyre::Result<JoinHandle<eyre::Result<String>>> {
+    /// Computes changes to the warm pools, and moves as many of the runners to create in `changes`
+    /// as possible to warm runners that only need to be registered and started.
+    pub fn compute_warm_pool_changes(&self, changes: &mut RunnerChanges) -> WarmPoolChanges {
+        let mut result = WarmPoolChanges::default();
+        let profile_keys_being_destroyed = self.profile_keys_being_destroyed();
+
+        for (key, profile) in self.profiles() {
+            let destroying = !changes.unregister_and_destroy_runner_ids.is_empty()
+                || profile_keys_being_destroyed.contains(key.as_str());
+            let mut no_creates = 0;
+            let create_count = changes
+                .create_counts_by_profile_key
+                .get_mut(key)
+                .unwrap_or(&mut no_creates);
+            let (current, outdated): (Vec<_>, Vec<_>) = self
+                .warm_runners_for_profile(profile)
+                .map(|(&id, _runner)| id)
+                .partition(|&id| self.warm_runner_is_current(id));
+            let promote_count = (*create_count).min(current.len());
+            *create_count -= promote_count;
+            result
+                .promote_runner_ids
+                .extend(current.iter().take(promote_count));
+
+            // Replace warm runners created from older images, and bleed off excess warm runners.
+            let target_count = self.target_warm_runner_count(profile);
+            let warm_count = self.warm_runner_count(profile) - promote_count - outdated.len();
+            let excess_count = warm_count.saturating_sub(target_count);
+            result.destroy_runner_ids.extend(outdated);
+            result
+                .destroy_runner_ids
+                .extend(current.iter().skip(promote_count).take(excess_count));
+
+            // Like other runners, don’t create any while runners are being destroyed.
+            if !destroying && result.destroy_runner_ids.is_empty() {
+                result
+                    .create_counts_by_profile_key
+                    .insert(key.clone(), target_count.saturating_sub(warm_count));
+            }
+        }
+        if !result.destroy_runner_ids.is_empty() {
+            result.create_counts_by_profile_key.clear();
+        }
+
+        result
+    }
+
+    /// Returns the keys of the profiles with runners being destroyed by the runner workers.
+    fn profile_keys_being_destroyed(&self) -> BTreeSet<&str> {
+        self.pending_runner_operations
+            .values()
+            .filter(|pending| pending.operation == RunnerOperation::Destroy)
+            .map(|pending| pending.profile_key.as_str())
+            .collect()
+    }
+
+    /// Returns work that registers, creates, and starts a runner, for a runner worker to do.
+    pub fn register_create_runner(&self, profile: &Profile, id: usize) -> eyre::Result<Work> {
         let Some(base_image_snapshot) = self.base_image_snapshot(&profile.profile_name).cloned()
         else {
             bail!(
@@ -277,40 +745,120 @@ impl Policy {
         let profile_name = profile.profile_name.clone();
         let runner_guest_name = profile.runner_guest_name(id);
 
-        Ok(thread::spawn(move || {
+        Ok(Box::new(move |progress| {
             let _span = info_span!("create_runner_thread", runner_id = id, profile_name).entered();
             info!(runner_id = id, profile_name, "Creating runner");
+            progress.set_stage(ProvisioningStage::Registering);
             match profile.image_type {
                 ImageType::Rust => {
-                    create_dir(get_runner_data_path(id, None)?)?;
-                    let mut runner_toml =
-                        File::create_new(get_runner_data_path(id, Path::new("runner.toml"))?)?;
-                    writeln!(runner_toml, r#"image_type = "Rust""#)?;
-                    symlink(
-                        get_profile_configuration_path(&profile, Path::new("boot-script"))?,
-                        get_runner_data_path(id, Path::new("boot-script"))?,
-                    )?;
-                    if !TOML.dont_register_runners() {
-                        let github_api_registration =
-                            register_runner(&profile, &runner_guest_name)?;
-                        let mut github_api_registration_file = File::create_new(
-                            get_runner_data_path(id, Path::new("github-api-registration"))?,
-                        )?;
-                        github_api_registration_file
-                            .write_all(github_api_registration.as_bytes())?;
-                    }
+                    create_runner_data(&profile, &base_image_snapshot, id)?;
+                    register_runner_and_save(&profile, &runner_guest_name, id)?;
+                    progress.set_stage(ProvisioningStage::Cloning);
+                    create_runner(&profile, &base_image_snapshot, &runner_guest_name, id)?;
+                }
+            }
+
+            progress.set_stage(ProvisioningStage::Starting);
+            start_runner_guest(&profile, &base_image_snapshot, &runner_guest_name, id)
+        }))
+    }
+
+    /// Returns work that creates a warm runner, without registering or starting it, for a runner
+    /// worker to do.
+    pub fn create_warm_runner(&self, profile: &Profile, id: usize) -> eyre::Result<Work> {
+        let Some(base_image_snapshot) = self.base_image_snapshot(&profile.profile_name).cloned()
+        else {
+            bail!(
+                "Tried to create warm runner, but profile has no base image snapshot (profile {})",
+                profile.profile_name
+            );
+        };
+
+        let profile = profile.clone();
+        let profile_name = profile.profile_name.clone();
+        let runner_guest_name = profile.runner_guest_name(id);
 
-                    create_runner(&profile, &base_image_snapshot, &runner_guest_name, id)
+        Ok(Box::new(move |_progress| {
+            let _span =
+                info_span!("create_warm_runner_thread", runner_id = id, profile_name).entered();
+            info!(runner_id = id, profile_name, "Creating warm runner");
+            match profile.image_type {
+                ImageType::Rust => {
+                    create_runner_data(&profile, &base_image_snapshot, id)?;
+                    File::create_new(get_runner_data_path(id, Path::new(WARM_FLAG_FILENAME))?)?;
+                    create_runner(&profile, &base_image_snapshot, &runner_guest_name, id)?;
                 }
             }
+
+            Ok(())
         }))
     }
 
-    /// Spawn a thread that unregisters, stops, and destroys a runner.
-    pub fn unregister_stop_destroy_runner(
+    /// Returns whether the given warm runner was created from the current base image snapshot of
+    /// its profile, so it can be promoted.
+    fn warm_runner_is_current(&self, id: usize) -> bool {
+        self.runner(id).is_some_and(|runner| {
+            runner.base_image_snapshot()
+                == self
+                    .base_image_snapshot(runner.profile_name())
+                    .map(|snapshot| &**snapshot)
+        })
+    }
+
+    /// Returns warm runners that can be promoted for a job right away, because they were created
+    /// from the current base image snapshot and aren’t being promoted or destroyed already.
+    pub fn promotable_warm_runners_for_profile<'s, 'p: 's>(
+        &'s self,
+        profile: &'p Profile,
+    ) -> impl Iterator<Item = (&'s usize, &'s Runner)> {
+        self.warm_runners_for_profile(profile)
+            .filter(|(&id, _runner)| self.warm_runner_is_current(id))
+    }
+
+    /// Returns work that registers and starts a warm runner, for a runner worker to do. If a
+    /// `reservation` is given, the runner is reserved for that job as soon as it’s registered.
+    pub fn promote_warm_runner(
         &self,
         id: usize,
-    ) -> eyre::Result<JoinHandle<eyre::Result<()>>> {
+        reservation: Option<Reservation>,
+    ) -> eyre::Result<Work> {
+        let Some(runner) = self.runner(id) else {
+            bail!("Tried to promote warm runner, but no runner with id exists: {id}");
+        };
+        let Some(profile) = self.profile(runner.profile_name()).cloned() else {
+            bail!(
+                "Tried to promote warm runner, but runner has unknown profile {} (runner {id})",
+                runner.profile_name()
+            );
+        };
+        let Some(base_image_snapshot) = runner.base_image_snapshot().map(str::to_owned) else {
+            bail!(
+                "Tried to promote warm runner, but runner has no base image snapshot (runner {id})"
+            );
+        };
+        let profile_name = profile.profile_name.clone();
+        let runner_guest_name = profile.runner_guest_name(id);
+
+        Ok(Box::new(move |progress| {
+            let _span =
+                info_span!("promote_warm_runner_thread", runner_id = id, profile_name).entered();
+            info!(runner_id = id, profile_name, "Promoting warm runner");
+            progress.set_stage(ProvisioningStage::Registering);
+            register_runner_and_save(&profile, &runner_guest_name, id)?;
+            if let Some(reservation) = reservation {
+                reserve_registered_runner(id, &reservation)?;
+            }
+            remove_file(get_runner_data_path(id, Path::new(WARM_FLAG_FILENAME))?)?;
+            // Restart the clock for `monitor_start_timeout`, since the runner was cloned earlier.
+            File::create(get_runner_data_path(id, Path::new("created-time"))?)?;
+
+            progress.set_stage(ProvisioningStage::Starting);
+            start_runner_guest(&profile, &base_image_snapshot, &runner_guest_name, id)
+        }))
+    }
+
+    /// Returns work that unregisters, stops, and destroys a runner, for a runner worker to do.
+    pub fn unregister_stop_destroy_runner(&self, id: usize) -> eyre::Result<Work> {
         let runner = self
             .runner(id)
             .expect("Guaranteed by compute_runner_changes()")
@@ -319,15 +867,19 @@ impl Policy {
             .profile(runner.profile_name())
             .map(|profile| profile.to_owned())
         else {
-            bail!("Profile");
+            bail!(
+                "No profile with key {} for runner: {id}",
+                runner.profile_name()
+            );
         };
         info!(runner_id = id, profile.profile_name, "Destroying runner");
+        let preserve_reason = runner.preserve_reason();
 
         match profile.image_type {
             ImageType::Rust => {
                 let runner_guest_name = profile.runner_guest_name(id);
 
-                Ok(thread::spawn(move || {
+                Ok(Box::new(move |_progress| {
                     let _span =
                         info_span!("destroy_runner_thread", runner_id = id, runner_guest_name)
                             .entered();
@@ -336,6 +888,13 @@ impl Policy {
                             warn!(?error, "Failed to unregister runner: {error}");
                         }
                     }
+                    if let Some(reason) = preserve_reason {
+                        if let Err(error) =
+                            preserve_runner(id, &runner_guest_name, &profile.profile_name, &reason)
+                        {
+                            warn!(?error, "Failed to preserve runner: {error}");
+                        }
+                    }
                     if let Err(error) = destroy_runner(&profile, &runner_guest_name, id) {
                         warn!(?error, "Failed to destroy runner: {error}");
                     }
@@ -350,24 +909,67 @@ impl Policy {
         RunnerCounts {
             target: self.target_runner_count(profile),
             healthy: self.healthy_runner_count(profile),
+            provisioning: self.provisioning_runner_count(profile),
+            warm: self.warm_runner_count(profile),
             started_or_crashed: self.started_or_crashed_runner_count(profile),
             idle: self.idle_runner_count(profile),
             reserved: self.reserved_runner_count(profile),
             busy: self.busy_runner_count(profile),
+            unresponsive: self.unresponsive_runner_count(profile),
+            held: self.held_runner_count(profile),
             excess_healthy: self.excess_healthy_runner_count(profile),
             wanted: self.wanted_runner_count(profile),
+            outdated_idle: self.outdated_idle_runners_for_profile(profile).count(),
+            rebuild_failures: self
+                .rebuild_failures(&profile.profile_name)
+                .map_or(0, |failures| failures.count),
+            quarantined: self.profile_is_quarantined(&profile.profile_name),
             image_age: self.image_age(profile).ok().flatten(),
         }
     }
 
     pub fn target_runner_count(&self, profile: &Profile) -> usize {
-        if TOML.dont_create_runners() || self.image_needs_rebuild(profile).unwrap_or(true) {
+        let image_unavailable = self.image_needs_rebuild(profile).unwrap_or(true)
+            && !self.can_serve_during_rebuild(profile);
+        if TOML.dont_create_runners() || image_unavailable {
             0
         } else {
             self.target_runner_count_with_override(profile)
         }
     }
 
+    /// Returns whether the profile can keep creating runners from its current image, while its
+    /// image is being rebuilt.
+    pub fn can_serve_during_rebuild(&self, profile: &Profile) -> bool {
+        profile.uses_rolling_upgrades() && self.base_image_snapshot(&profile.profile_name).is_some()
+    }
+
+    /// Returns how many idle runners can be replaced as part of a rolling upgrade, after the given
+    /// runners are destroyed, without going below the minimum runner count for the profile.
+    fn spare_runner_count_for_rolling_upgrade(
+        &self,
+        profile: &Profile,
+        proposed_destroy_ids: &BTreeSet<usize>,
+    ) -> usize {
+        let Some(min_runners) = profile.rolling_upgrade_min_runners else {
+            return 0;
+        };
+        // There’s no point replacing runners until we have a newer image to replace them with.
+        if self.image_needs_rebuild(profile) != Some(false) {
+            return 0;
+        }
+        let proposed_healthy_destroy_count = self
+            .runners_for_profile(profile)
+            .filter(|(id, runner)| {
+                runner.status().is_healthy() && proposed_destroy_ids.contains(id)
+            })
+            .count();
+
+        self.healthy_runner_count(profile)
+            .saturating_sub(proposed_healthy_destroy_count)
+            .saturating_sub(min_runners)
+    }
+
     fn target_runner_count_with_override(&self, profile: &Profile) -> usize {
         if let Some(current_override) = self.current_override.as_ref() {
             if let Some(target_count) = current_override
@@ -384,11 +986,60 @@ impl Policy {
     }
 
     pub fn healthy_runner_count(&self, profile: &Profile) -> usize {
-        self.started_or_crashed_runner_count(profile)
+        self.provisioning_runner_count(profile)
+            + self.started_or_crashed_runner_count(profile)
             + self.idle_runner_count(profile)
             + self.reserved_runner_count(profile)
             + self.busy_runner_count(profile)
             + self.done_or_unregistered_runner_count(profile)
+            // Held runners still take up resources, so count them to avoid overcommitting.
+            + self.held_runner_count(profile)
+    }
+
+    /// Returns the number of runners being provisioned, including those that the runner workers
+    /// are yet to create any resources for.
+    pub fn provisioning_runner_count(&self, profile: &Profile) -> usize {
+        let without_resources = self
+            .pending_runner_operations
+            .iter()
+            .filter(|(&id, pending)| {
+                pending.operation == RunnerOperation::Create
+                    && pending.profile_key == profile.profile_name
+                    && self.runner(id).is_none()
+            })
+            .count();
+
+        self.runners_for_profile(profile)
+            .filter(|(_id, runner)| runner.status() == Status::Provisioning)
+            .count()
+            + without_resources
+    }
+
+    /// Returns the number of warm runners that are ready or being created, but not those that are
+    /// being promoted or destroyed.
+    pub fn warm_runner_count(&self, profile: &Profile) -> usize {
+        let creating = self
+            .pending_runner_operations
+            .values()
+            .filter(|pending| {
+                pending.operation == RunnerOperation::CreateWarm
+                    && pending.profile_key == profile.profile_name
+            })
+            .count();
+
+        self.warm_runners_for_profile(profile).count() + creating
+    }
+
+    /// Returns the number of warm runners we want to keep, which is none if we don’t want any
+    /// runners at all.
+    pub fn target_warm_runner_count(&self, profile: &Profile) -> usize {
+        if self.target_runner_count(profile) == 0
+            || self.profile_is_quarantined(&profile.profile_name)
+        {
+            0
+        } else {
+            profile.warm_pool_size
+        }
     }
 
     pub fn started_or_crashed_runner_count(&self, profile: &Profile) -> usize {
@@ -415,6 +1066,18 @@ impl Policy {
             .count()
     }
 
+    pub fn unresponsive_runner_count(&self, profile: &Profile) -> usize {
+        self.runners_for_profile(profile)
+            .filter(|(_id, runner)| runner.status() == Status::Unresponsive)
+            .count()
+    }
+
+    pub fn held_runner_count(&self, profile: &Profile) -> usize {
+        self.runners_for_profile(profile)
+            .filter(|(_id, runner)| runner.status() == Status::Held)
+            .count()
+    }
+
     pub fn done_or_unregistered_runner_count(&self, profile: &Profile) -> usize {
         self.runners_for_profile(profile)
             .filter(|(_id, runner)| runner.status() == Status::DoneOrUnregistered)
@@ -432,7 +1095,9 @@ impl Policy {
 
     pub fn wanted_runner_count(&self, profile: &Profile) -> usize {
         // Healthy runners below the target count are wanted runners.
-        if self.target_runner_count(profile) > self.healthy_runner_count(profile) {
+        if self.profile_is_quarantined(&profile.profile_name) {
+            0
+        } else if self.target_runner_count(profile) > self.healthy_runner_count(profile) {
             self.target_runner_count(profile) - self.healthy_runner_count(profile)
         } else {
             0
@@ -446,10 +1111,30 @@ impl Policy {
     /// Returns whether the image definitely needs to be rebuilt or not, or None
     /// if we don’t know.
     pub fn image_needs_rebuild(&self, profile: &Profile) -> Option<bool> {
+        self.image_rebuild_reasons(profile)
+            .map(|reasons| !reasons.is_empty())
+    }
+
+    /// Returns the reasons why the image needs to be rebuilt, if any, or None
+    /// if we don’t know.
+    pub fn image_rebuild_reasons(&self, profile: &Profile) -> Option<Vec<String>> {
+        let mut result = vec![];
+        if self
+            .requested_rebuild_profile_keys
+            .contains(&profile.profile_name)
+        {
+            result.push("rebuild requested".to_owned());
+        }
+
         if self.target_runner_count_with_override(profile) == 0 {
             // Profiles with zero target_count may have been set to zero because
             // there is insufficient hugepages space to run them
-            return Some(false);
+            return Some(result);
+        }
+
+        // If the image was activated manually, keep it until another rebuild is requested
+        if self.base_image_snapshot_is_pinned(&profile.profile_name) {
+            return Some(result);
         }
 
         // If we fail to get the image age, err on the side of caution
@@ -462,21 +1147,31 @@ impl Policy {
         };
 
         // If the profile has no image age, we may need to build its image for the first time
-        Some(image_age.map_or(true, |age| age > TOML.base_image_max_age()))
+        match image_age {
+            None => result.push("no image".to_owned()),
+            Some(age) if age > TOML.base_image_max_age() => {
+                result.push(format!("image too old: {age:?}"))
+            }
+            Some(_) => {}
+        }
+
+        // If we know what the image was built from, check if any of the inputs have changed
+        if let (Some(built), Some(current)) = (
+            self.base_image_inputs.get(&profile.profile_name),
+            self.current_image_inputs.get(&profile.profile_name),
+        ) {
+            result.extend(built.changes(current, &profile.rebuild_triggers));
+        }
+
+        Some(result)
     }
 
     pub fn image_age(&self, profile: &Profile) -> eyre::Result<Option<Duration>> {
         let Some(base_image_snapshot) = self.base_image_snapshot(&profile.profile_name) else {
             return Ok(None);
         };
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .wrap_err("Failed to get current time")?;
-        let creation_time = DateTime::parse_from_rfc3339(&base_image_snapshot)?
-            .signed_duration_since(DateTime::UNIX_EPOCH)
-            .to_std()?;
 
-        Ok(Some(now - creation_time))
+        Ok(Some(snapshot_age(base_image_snapshot)?))
     }
 
     pub fn set_base_image_snapshot(
@@ -486,55 +1181,192 @@ impl Policy {
     ) -> eyre::Result<()> {
         self.base_image_snapshots
             .insert(profile_key.to_owned(), base_image_snapshot.to_owned());
+        self.base_image_inputs.remove(profile_key);
+        self.requested_rebuild_profile_keys.remove(profile_key);
 
         Ok(())
     }
 
-    pub fn update_ipv4_addresses_for_rebuild_guests(
+    /// Marks the given snapshot as activated manually, or clears that mark if None.
+    pub fn set_pinned_base_image_snapshot(
         &mut self,
-        rebuild_guest_names: &BTreeMap<String, String>,
+        profile_key: &str,
+        pinned_snapshot: Option<&str>,
     ) {
-        for (profile_key, guest_name) in rebuild_guest_names {
-            let ipv4_address = get_ipv4_address(&guest_name);
-            let entry = self.ipv4_addresses.entry(profile_key.clone()).or_default();
-            if ipv4_address != *entry {
-                info!(
-                    "IPv4 address changed for profile guest {profile_key}: {:?} -> {:?}",
-                    *entry, ipv4_address
-                );
-            }
-            *entry = ipv4_address;
+        if let Some(pinned_snapshot) = pinned_snapshot {
+            self.pinned_base_image_snapshots
+                .insert(profile_key.to_owned(), pinned_snapshot.to_owned());
+        } else {
+            self.pinned_base_image_snapshots.remove(profile_key);
         }
     }
 
-    pub fn boot_script_for_rebuild_guest(
-        &self,
-        remote_addr: web::auth::RemoteAddr,
-    ) -> eyre::Result<Option<String>> {
-        for (key, ipv4_address) in self.ipv4_addresses.iter() {
-            if let Some(ipv4_address) = ipv4_address {
-                if remote_addr == *ipv4_address {
-                    let profile = self.profiles.get(key).expect("Guaranteed by Profiles impl");
-                    let path = get_profile_configuration_path(profile, Path::new("boot-script"))?;
-                    let mut result = String::default();
-                    File::open(path)?.read_to_string(&mut result)?;
-                    return Ok(Some(result));
-                }
-            }
-        }
-
-        Ok(None)
+    pub fn rebuild_failures(&self, profile_key: &str) -> Option<&RebuildFailures> {
+        self.rebuild_failures.get(profile_key)
     }
 
-    pub fn runners(&self) -> impl Iterator<Item = (&usize, &Runner)> {
-        self.runners.iter().flat_map(|runners| runners.iter())
+    pub fn record_rebuild_failure(&mut self, profile_key: &str, error: String) {
+        let count = self
+            .rebuild_failures(profile_key)
+            .map_or(0, |failures| failures.count);
+        self.rebuild_failures.insert(
+            profile_key.to_owned(),
+            RebuildFailures {
+                count: count + 1,
+                last_error: error,
+                last_failed_at: clock::instant(),
+            },
+        );
     }
 
-    pub fn runner(&self, id: usize) -> Option<&Runner> {
-        self.runners.as_ref().and_then(|runners| runners.get(id))
+    pub fn record_rebuild_success(&mut self, profile_key: &str) {
+        self.rebuild_failures.remove(profile_key);
     }
 
-    pub fn runners_for_profile<'s, 'p: 's>(
+    /// Forgets the image rebuild failures for the given profile, so we can try again.
+    pub fn reset_rebuild_failures(
+        &mut self,
+        profile_key: &str,
+    ) -> eyre::Result<Option<RebuildFailures>> {
+        if !self.profiles.contains_key(profile_key) {
+            bail!("No profile with key {profile_key}");
+        }
+
+        Ok(self.rebuild_failures.remove(profile_key))
+    }
+
+    /// Returns whether we have stopped retrying image rebuilds for the given profile, because
+    /// of too many consecutive failures.
+    pub fn rebuild_circuit_open(&self, profile_key: &str) -> bool {
+        self.rebuild_failures(profile_key)
+            .is_some_and(|failures| failures.count >= TOML.rebuild_max_failures())
+    }
+
+    /// Returns how long until something would change on its own, like a runner timing out or an
+    /// image rebuild becoming eligible for retry, so the monitor thread can wake up for it.
+    pub fn time_until_next_timer(&self) -> Option<Duration> {
+        let runner_timeouts = self
+            .runners()
+            .flat_map(|(_id, runner)| runner.time_until_next_timeout());
+        let rebuild_backoffs = self
+            .profiles()
+            .flat_map(|(key, _profile)| self.rebuild_backoff_remaining(key));
+
+        // Timers that have already fired were handled in this pass, and would only make us spin.
+        runner_timeouts
+            .chain(rebuild_backoffs)
+            .filter(|duration| !duration.is_zero())
+            .min()
+    }
+
+    /// Returns how long to wait before retrying an image rebuild for the given profile, if at all.
+    pub fn rebuild_backoff_remaining(&self, profile_key: &str) -> Option<Duration> {
+        let failures = self.rebuild_failures(profile_key)?;
+        rebuild_backoff(failures.count).checked_sub(clock::elapsed_since(failures.last_failed_at))
+    }
+
+    pub fn quarantine_reason(&self, profile_key: &str) -> Option<&str> {
+        self.quarantined_profiles
+            .get(profile_key)
+            .map(|reason| &**reason)
+    }
+
+    pub fn profile_is_quarantined(&self, profile_key: &str) -> bool {
+        self.quarantined_profiles.contains_key(profile_key)
+    }
+
+    /// Stops creating runners and rebuilding images for the given profile, keeping the reason
+    /// from the first time it was quarantined.
+    pub fn quarantine_profile(&mut self, profile_key: &str, reason: String) {
+        if !self.profile_is_quarantined(profile_key) {
+            error!(profile_key, reason, "Quarantining profile");
+            self.quarantined_profiles
+                .insert(profile_key.to_owned(), reason);
+        }
+    }
+
+    /// Forces the image for the given profile to be rebuilt, even if its target count is zero.
+    /// This also forgets any rebuild failures, so a tripped circuit breaker won’t hold it back.
+    pub fn request_rebuild(&mut self, profile_key: &str) -> eyre::Result<()> {
+        if !self.profiles.contains_key(profile_key) {
+            bail!("No profile with key {profile_key}");
+        }
+        self.requested_rebuild_profile_keys
+            .insert(profile_key.to_owned());
+        self.rebuild_failures.remove(profile_key);
+
+        Ok(())
+    }
+
+    pub fn set_base_image_inputs(&mut self, profile_key: &str, inputs: ImageInputs) {
+        self.base_image_inputs
+            .insert(profile_key.to_owned(), inputs);
+    }
+
+    pub fn set_current_image_inputs(&mut self, profile_key: &str, inputs: ImageInputs) {
+        self.current_image_inputs
+            .insert(profile_key.to_owned(), inputs);
+    }
+
+    pub fn update_ipv4_addresses_for_rebuild_guests(
+        &mut self,
+        rebuild_guest_names: &BTreeMap<String, String>,
+    ) {
+        for (profile_key, guest_name) in rebuild_guest_names {
+            let ipv4_address = get_ipv4_address(&guest_name);
+            let entry = self.ipv4_addresses.entry(profile_key.clone()).or_default();
+            if ipv4_address != *entry {
+                info!(
+                    "IPv4 address changed for profile guest {profile_key}: {:?} -> {:?}",
+                    *entry, ipv4_address
+                );
+            }
+            *entry = ipv4_address;
+        }
+    }
+
+    /// Returns the boot script for the rebuild guest with the given address, or the health
+    /// script if that guest is smoke testing a new image.
+    pub fn boot_script_for_rebuild_guest(
+        &self,
+        remote_addr: web::auth::RemoteAddr,
+        smoke_testing_profile_keys: &BTreeSet<String>,
+    ) -> eyre::Result<Option<String>> {
+        let Some(key) = self.rebuild_guest_profile_key(remote_addr) else {
+            return Ok(None);
+        };
+        let profile = self.profiles.get(key).expect("Guaranteed by Profiles impl");
+        let filename = if smoke_testing_profile_keys.contains(key) {
+            "health-script"
+        } else {
+            "boot-script"
+        };
+        let path = get_profile_configuration_path(profile, Path::new(filename))?;
+        let mut result = String::default();
+        File::open(path)?.read_to_string(&mut result)?;
+
+        Ok(Some(result))
+    }
+
+    /// Returns the key of the profile whose rebuild guest has the given address, if any.
+    pub fn rebuild_guest_profile_key(&self, remote_addr: web::auth::RemoteAddr) -> Option<&str> {
+        self.ipv4_addresses
+            .iter()
+            .find(|(_key, ipv4_address)| {
+                ipv4_address.is_some_and(|ipv4_address| remote_addr == ipv4_address)
+            })
+            .map(|(key, _ipv4_address)| key.as_str())
+    }
+
+    pub fn runners(&self) -> impl Iterator<Item = (&usize, &Runner)> {
+        self.runners.iter().flat_map(|runners| runners.iter())
+    }
+
+    pub fn runner(&self, id: usize) -> Option<&Runner> {
+        self.runners.as_ref().and_then(|runners| runners.get(id))
+    }
+
+    pub fn runners_for_profile<'s, 'p: 's>(
         &'s self,
         profile: &'p Profile,
     ) -> impl Iterator<Item = (&'s usize, &'s Runner)> {
@@ -558,22 +1390,49 @@ impl Policy {
             .filter(|(_id, runner)| runner.status() == Status::Idle)
     }
 
-    pub fn update_screenshots(&self, rebuild_guest_names: &BTreeMap<String, String>) {
-        if let Some(runners) = self.runners.as_ref() {
-            runners.update_screenshots();
-        }
+    /// Returns warm runners that are ready to be promoted or destroyed.
+    pub fn warm_runners_for_profile<'s, 'p: 's>(
+        &'s self,
+        profile: &'p Profile,
+    ) -> impl Iterator<Item = (&'s usize, &'s Runner)> {
+        self.runners_for_profile(profile).filter(|(id, runner)| {
+            runner.status() == Status::Warm && !self.pending_runner_operations.contains_key(id)
+        })
+    }
+
+    /// Returns idle runners that were created from a base image snapshot other than the current one.
+    pub fn outdated_idle_runners_for_profile<'s, 'p: 's>(
+        &'s self,
+        profile: &'p Profile,
+    ) -> impl Iterator<Item = (&'s usize, &'s Runner)> {
+        let current_snapshot = self.base_image_snapshot(&profile.profile_name);
+        self.idle_runners_for_profile(profile)
+            .filter(move |(_id, runner)| {
+                runner.base_image_snapshot() != current_snapshot.map(|s| s.as_str())
+            })
+    }
+
+    /// Returns where to keep the screenshots for each runner and rebuild guest.
+    pub fn screenshot_guests(
+        &self,
+        rebuild_guest_names: &BTreeMap<String, String>,
+    ) -> Vec<ScreenshotGuest> {
+        let mut result = self
+            .runners
+            .as_ref()
+            .map_or(vec![], |runners| runners.screenshot_guests());
         for (profile_key, guest_name) in rebuild_guest_names {
-            if let Err(error) = self.try_update_screenshot(&profile_key, &guest_name) {
-                debug!(guest_name, ?error, "Failed to update screenshot for guest");
+            match ScreenshotGuest::rebuild(profile_key, guest_name) {
+                Ok(guest) => result.push(guest),
+                Err(error) => debug!(
+                    guest_name,
+                    ?error,
+                    "Failed to compute screenshot paths for guest"
+                ),
             }
         }
-    }
 
-    fn try_update_screenshot(&self, profile_key: &str, guest_name: &str) -> eyre::Result<()> {
-        let output_dir = get_profile_data_path(&profile_key, None)?;
-        update_screenshot(guest_name, &output_dir)?;
-
-        Ok(())
+        result
     }
 
     pub fn get_override(&self) -> Option<&Override> {
@@ -799,25 +1658,46 @@ impl Policy {
 /// Proxies to [Runner].
 impl Policy {
     pub fn reserve_runner(
-        &self,
+        &mut self,
         id: usize,
         unique_id: &str,
         qualified_repo: &str,
         run_id: &str,
     ) -> eyre::Result<()> {
-        let Some(runners) = self.runners.as_ref() else {
+        let Some(runners) = self.runners.as_mut() else {
             bail!("Policy has no Runners!");
         };
 
         runners.reserve_runner(id, unique_id, qualified_repo, run_id)
     }
 
-    pub fn screenshot_runner(&self, id: usize) -> eyre::Result<Temp> {
+    pub fn hold_runner(&mut self, id: usize, ttl: Duration) -> eyre::Result<RunnerHoldDetails> {
+        // Runners whose job has finished are destroyed soon after, and once that starts, holding
+        // the runner would not stop it.
+        if self.pending_runner_operation(id) == Some(RunnerOperation::Destroy) {
+            bail!("Tried to hold a runner that is already being destroyed: {id}");
+        }
+        let Some(runners) = self.runners.as_mut() else {
+            bail!("Policy has no Runners!");
+        };
+
+        runners.hold_runner(id, ttl)
+    }
+
+    pub fn release_runner(&mut self, id: usize) -> eyre::Result<RunnerHold> {
+        let Some(runners) = self.runners.as_mut() else {
+            bail!("Policy has no Runners!");
+        };
+
+        runners.release_runner(id)
+    }
+
+    pub fn flag_runner_for_preservation(&self, id: usize, reason: &str) -> eyre::Result<()> {
         let Some(runners) = self.runners.as_ref() else {
             bail!("Policy has no Runners!");
         };
 
-        runners.screenshot_runner(id)
+        runners.flag_runner_for_preservation(id, reason)
     }
 
     pub fn github_jitconfig(
@@ -831,6 +1711,18 @@ impl Policy {
         runners.github_jitconfig(remote_addr)
     }
 
+    pub fn record_runner_heartbeat(
+        &self,
+        remote_addr: web::auth::RemoteAddr,
+        heartbeat: &Heartbeat,
+    ) -> eyre::Result<()> {
+        let Some(runners) = self.runners.as_ref() else {
+            bail!("Policy has no Runners!");
+        };
+
+        runners.record_heartbeat(remote_addr, heartbeat)
+    }
+
     pub fn update_ipv4_addresses_for_runner_guests(&mut self) -> eyre::Result<()> {
         let Some(runners) = self.runners.as_mut() else {
             bail!("Policy has no Runners!");
@@ -841,6 +1733,22 @@ impl Policy {
         Ok(())
     }
 
+    /// Updates the IPv4 addresses of runner guests, unless one of them already has the given
+    /// address, since that runs virsh for every guest.
+    pub fn update_ipv4_addresses_for_runner_guests_if_unknown(
+        &mut self,
+        remote_addr: &web::auth::RemoteAddr,
+    ) -> eyre::Result<()> {
+        let Some(runners) = self.runners.as_ref() else {
+            bail!("Policy has no Runners!");
+        };
+        if runners.has_ipv4_address(remote_addr) {
+            return Ok(());
+        }
+
+        self.update_ipv4_addresses_for_runner_guests()
+    }
+
     pub fn boot_script_for_runner_guest(
         &self,
         remote_addr: web::auth::RemoteAddr,
@@ -854,11 +1762,13 @@ impl Policy {
 }
 
 pub fn template_or_rebuild_images_path(profile: &Profile) -> PathBuf {
-    Path::new("/var/lib/libvirt/images/base").join(&profile.profile_name)
+    TOML.libvirt_images_path()
+        .join("base")
+        .join(&profile.profile_name)
 }
 
 pub fn runner_images_path() -> PathBuf {
-    PathBuf::from("/var/lib/libvirt/images/runner")
+    TOML.libvirt_images_path().join("runner")
 }
 
 pub fn template_or_rebuild_image_path(
@@ -873,8 +1783,152 @@ pub fn runner_image_path(runner_id: usize, filename: impl AsRef<str>) -> PathBuf
     runner_images_path().join(format!("{runner_id}-{}", filename.as_ref()))
 }
 
-fn read_base_image_snapshot(profile: &Profile) -> eyre::Result<Option<String>> {
-    let path = get_profile_data_path(&profile.profile_name, Path::new("snapshot"))?;
+/// Returns how long to wait before retrying an image rebuild, after the given number of
+/// consecutive failures.
+pub fn rebuild_backoff(failure_count: usize) -> Duration {
+    if failure_count == 0 {
+        return Duration::ZERO;
+    }
+    let exponent = u32::try_from(failure_count - 1).unwrap_or(u32::MAX).min(31);
+
+    TOML.rebuild_backoff_initial()
+        .saturating_mul(1 << exponent)
+        .min(TOML.rebuild_backoff_max())
+}
+
+/// Returns the age of the given snapshot, based on its name.
+pub fn snapshot_age(snapshot_name: &str) -> eyre::Result<Duration> {
+    let now = clock::now()
+        .duration_since(UNIX_EPOCH)
+        .wrap_err("Failed to get current time")?;
+    let creation_time = DateTime::parse_from_rfc3339(snapshot_name)?
+        .signed_duration_since(DateTime::UNIX_EPOCH)
+        .to_std()?;
+
+    Ok(now - creation_time)
+}
+
+/// Looks up the job of a runner that has been busy for too long, in the workflow run that
+/// reserved it.
+fn check_stuck_busy_runner(registration: Option<&ApiRunner>) -> StuckBusyRunner {
+    let flagged = |reason: &str| StuckBusyRunner::Flagged {
+        reason: reason.to_owned(),
+    };
+    let Some(registration) = registration else {
+        return flagged("runner not registered");
+    };
+    let Some(reserved_by) = registration.label_with_key("reserved-by") else {
+        return flagged("runner was not reserved, so its job is unknown");
+    };
+    let Some((qualified_repo, run_id)) = reserved_by.split_once("/actions/runs/") else {
+        return flagged("bad reserved-by label");
+    };
+    let jobs = match list_workflow_run_jobs(qualified_repo, run_id) {
+        Ok(jobs) => jobs,
+        Err(error) => return flagged(&format!("failed to list jobs: {error}")),
+    };
+    match jobs
+        .into_iter()
+        .find(|job| job.runner_id == Some(registration.id))
+    {
+        Some(job) if job.status == "completed" => StuckBusyRunner::JobFinished {
+            conclusion: job.conclusion,
+        },
+        Some(job) => flagged(&format!("job {} is {}", job.id, job.status)),
+        None => flagged(&format!("no job found in {reserved_by}")),
+    }
+}
+
+pub fn read_base_image_snapshot(profile: &Profile) -> eyre::Result<Option<String>> {
+    read_profile_data_symlink(profile, "snapshot")
+}
+
+/// Creates the runner data directory for a new runner.
+fn create_runner_data(profile: &Profile, base_image_snapshot: &str, id: usize) -> eyre::Result<()> {
+    create_dir(get_runner_data_path(id, None)?)?;
+    let mut runner_toml = File::create_new(get_runner_data_path(id, Path::new("runner.toml"))?)?;
+    writeln!(runner_toml, r#"image_type = "Rust""#)?;
+    writeln!(
+        runner_toml,
+        r#"base_image_snapshot = "{base_image_snapshot}""#
+    )?;
+    symlink(
+        get_profile_configuration_path(profile, Path::new("boot-script"))?,
+        get_runner_data_path(id, Path::new("boot-script"))?,
+    )?;
+
+    Ok(())
+}
+
+/// Registers the runner with GitHub, unless `dont_register_runners` is set, saving the
+/// registration for the guest to fetch.
+fn register_runner_and_save(
+    profile: &Profile,
+    runner_guest_name: &str,
+    id: usize,
+) -> eyre::Result<()> {
+    if !TOML.dont_register_runners() {
+        let github_api_registration =
+            register_runner(profile, runner_guest_name, &profile.github_runner_label)?;
+        let mut github_api_registration_file = File::create_new(get_runner_data_path(
+            id,
+            Path::new("github-api-registration"),
+        )?)?;
+        github_api_registration_file.write_all(github_api_registration.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Reserves a runner we just registered for the given job, like
+/// [`Runners::reserve_runner`], which only works for runners we have listed registrations for.
+fn reserve_registered_runner(id: usize, reservation: &Reservation) -> eyre::Result<()> {
+    if TOML.dont_register_runners() {
+        return Ok(());
+    }
+    let path = get_runner_data_path(id, Path::new("github-api-registration"))?;
+    let registration: ApiGenerateJitconfigResponse = serde_json::from_reader(File::open(path)?)?;
+    info!(
+        runner_id = id,
+        registration.runner.id, "Reserving promoted warm runner"
+    );
+    let Reservation {
+        unique_id,
+        qualified_repo,
+        run_id,
+    } = reservation;
+    let reserved_by = format!("{qualified_repo}/actions/runs/{run_id}");
+    reserve_runner(
+        registration.runner.id,
+        unique_id,
+        clock::now(),
+        &reserved_by,
+    )
+}
+
+fn start_runner_guest(
+    profile: &Profile,
+    base_image_snapshot: &str,
+    runner_guest_name: &str,
+    id: usize,
+) -> eyre::Result<()> {
+    start_or_restore_runner_guest(profile, base_image_snapshot, runner_guest_name, id)?;
+    // Keep the console log, in case the runner needs to be preserved.
+    let result = get_runner_data_path(id, Path::new("console.log"))
+        .and_then(|path| capture_serial_console_to_file(runner_guest_name, &path));
+    if let Err(error) = result {
+        warn!(?error, "Failed to capture serial console: {error}");
+    }
+
+    Ok(())
+}
+
+fn read_pinned_base_image_snapshot(profile: &Profile) -> eyre::Result<Option<String>> {
+    read_profile_data_symlink(profile, "pinned-snapshot")
+}
+
+fn read_profile_data_symlink(profile: &Profile, filename: &str) -> eyre::Result<Option<String>> {
+    let path = get_profile_data_path(&profile.profile_name, Path::new(filename))?;
     if let Ok(path) = read_link(path) {
         let snapshot_name = path.to_str().ok_or_eyre("Symlink target is unsupported")?;
         return Ok(Some(snapshot_name.to_owned()));
@@ -884,22 +1938,32 @@ fn read_base_image_snapshot(profile: &Profile) -> eyre::Result<Option<String>> {
 }
 
 #[cfg(test)]
-mod test {
-    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+pub(crate) mod test {
+    use std::{
+        collections::BTreeMap,
+        rc::Rc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
 
-    use chrono::{SecondsFormat, Utc};
+    use chrono::SecondsFormat;
     use jane_eyre::eyre;
-    use monitor::github::{ApiRunner, ApiRunnerLabel};
+    use monitor::{
+        clock::{self, FakeClock},
+        github::{ApiRunner, ApiRunnerLabel},
+    };
     use settings::{profile::Profile, TOML};
 
     use crate::{
-        policy::{Override, RunnerChanges},
-        runner::{set_runner_created_time_for_test, Runners, Status},
+        policy::{Override, RunnerChanges, WarmPoolChanges},
+        runner::{
+            set_fake_runner_data_for_test, FakeRunnerData, Heartbeat, RunnerHold, Runners, Status,
+        },
+        workers::{PendingOperation, RunnerOperation},
     };
 
-    use super::Policy;
+    use super::{rebuild_backoff, Policy, StuckBusyRunner, StuckBusyRunnerCheck};
 
-    fn profile(
+    pub(crate) fn profile(
         key: &'static str,
         target_count: usize,
         requires_1g_hugepages: usize,
@@ -912,47 +1976,115 @@ mod test {
             image_type: settings::profile::ImageType::Rust,
             requires_1g_hugepages,
             requires_normal_memory: requires_normal_memory.parse().expect("Bad value in test"),
+            rolling_upgrade_min_runners: None,
+            rebuild_triggers: vec![],
+            max_busy_duration: None,
+            warm_pool_size: 0,
+            requires_disk: Default::default(),
+            memory_snapshot: false,
         }
     }
 
+    /// A runner as seen by [`runners`], which is created at the current [`clock::now`] the first
+    /// time its id is seen, so tests can age runners with a [`FakeClock`].
     #[derive(Debug, Clone)]
     struct FakeRunner {
         profile_key: &'static str,
         status: Status,
-        created_time: SystemTime,
         reserved_since: Option<Duration>,
+        heartbeat: Option<(Heartbeat, SystemTime)>,
+        preserve_flag: Option<&'static str>,
+        hold: Option<RunnerHold>,
+        base_image_snapshot: Option<String>,
+    }
+    impl Default for FakeRunner {
+        fn default() -> Self {
+            Self {
+                profile_key: "linux",
+                status: Status::Idle,
+                reserved_since: None,
+                heartbeat: None,
+                preserve_flag: None,
+                hold: None,
+                base_image_snapshot: None,
+            }
+        }
     }
     impl FakeRunner {
         fn idle(profile_key: &'static str) -> Self {
             Self {
                 profile_key,
-                status: Status::Idle,
-                created_time: system_time_minus_seconds(9001),
-                reserved_since: None,
+                ..Self::default()
             }
         }
         fn busy(profile_key: &'static str) -> Self {
             Self {
                 profile_key,
                 status: Status::Busy,
-                created_time: system_time_minus_seconds(9001),
-                reserved_since: None,
+                ..Self::default()
             }
         }
         fn reserved(profile_key: &'static str) -> Self {
             Self {
                 profile_key,
                 status: Status::Reserved,
-                created_time: system_time_minus_seconds(9001),
                 reserved_since: Some(epoch_duration_now()),
+                ..Self::default()
+            }
+        }
+        fn unresponsive(profile_key: &'static str) -> Self {
+            Self {
+                profile_key,
+                status: Status::Unresponsive,
+                heartbeat: Some((
+                    healthy_heartbeat(),
+                    clock::now()
+                        .checked_sub(TOML.runner_heartbeat_timeout() * 2)
+                        .expect("Bad time to run this test"),
+                )),
+                ..Self::default()
+            }
+        }
+        fn warm(profile_key: &'static str) -> Self {
+            Self {
+                profile_key,
+                status: Status::Warm,
+                ..Self::default()
             }
         }
         fn done_or_unregistered(profile_key: &'static str) -> Self {
             Self {
                 profile_key,
                 status: Status::DoneOrUnregistered,
-                created_time: system_time_minus_seconds(9001),
                 reserved_since: Some(epoch_duration_now()),
+                ..Self::default()
+            }
+        }
+        fn with_heartbeat(self, heartbeat: Heartbeat) -> Self {
+            Self {
+                heartbeat: Some((heartbeat, clock::now())),
+                ..self
+            }
+        }
+        fn with_preserve_flag(self, reason: &'static str) -> Self {
+            Self {
+                preserve_flag: Some(reason),
+                ..self
+            }
+        }
+        fn with_hold(self, expires_in_seconds: i64) -> Self {
+            Self {
+                hold: Some(RunnerHold {
+                    held_at: clock::utc_now(),
+                    expires_at: clock::utc_now() + chrono::Duration::seconds(expires_in_seconds),
+                }),
+                ..self
+            }
+        }
+        fn with_base_image_snapshot(self, snapshot_name: &str) -> Self {
+            Self {
+                base_image_snapshot: Some(snapshot_name.to_owned()),
+                ..self
             }
         }
     }
@@ -981,12 +2113,22 @@ mod test {
 
         let mut registrations = vec![];
         let mut guest_names = vec![];
+        let mut runner_data = BTreeMap::new();
         for fake in fake_runners {
             let (runner_id, guest_name) = make_runner_id_and_guest_name(fake.profile_key);
-            set_runner_created_time_for_test(runner_id, fake.created_time);
+            runner_data.insert(
+                runner_id,
+                FakeRunnerData {
+                    base_image_snapshot: fake.base_image_snapshot,
+                    heartbeat: fake.heartbeat,
+                    preserve_flag: fake.preserve_flag.map(str::to_owned),
+                    hold: fake.hold,
+                    warm: fake.status == Status::Warm,
+                },
+            );
             match fake.status {
                 Status::Invalid => registrations.push(make_registration(&guest_name)),
-                Status::DoneOrUnregistered => guest_names.push(guest_name),
+                Status::DoneOrUnregistered | Status::Warm => guest_names.push(guest_name),
                 Status::Busy => {
                     let mut api_runner = make_registration(&guest_name);
                     api_runner.busy = true;
@@ -1007,29 +2149,35 @@ mod test {
                     registrations.push(api_runner);
                     guest_names.push(guest_name);
                 }
-                Status::Idle => {
+                Status::Idle | Status::Unresponsive | Status::Held => {
                     let mut api_runner = make_registration(&guest_name);
                     api_runner.status = "online".to_owned();
                     registrations.push(api_runner);
                     guest_names.push(guest_name);
                 }
-                Status::StartedOrCrashed => {
+                // Provisioning depends on the policy, but looks like this once the guest exists.
+                Status::StartedOrCrashed | Status::Provisioning => {
                     registrations.push(make_registration(&guest_name));
                     guest_names.push(guest_name);
                 }
             }
         }
 
+        set_fake_runner_data_for_test(runner_data);
+
         Runners::new(registrations, guest_names)
     }
 
-    fn snapshot_now_minus_seconds(delta: u64) -> String {
-        (Utc::now() - Duration::from_secs(delta)).to_rfc3339_opts(SecondsFormat::Nanos, true)
+    fn healthy_heartbeat() -> Heartbeat {
+        Heartbeat {
+            disk_free: TOML.runner_min_disk_free(),
+            load: 1.0,
+            runner_alive: true,
+        }
     }
-    fn system_time_minus_seconds(delta: u64) -> SystemTime {
-        SystemTime::now()
-            .checked_sub(Duration::from_secs(delta))
-            .expect("Bad delta")
+
+    fn snapshot_now_minus_seconds(delta: u64) -> String {
+        (clock::utc_now() - Duration::from_secs(delta)).to_rfc3339_opts(SecondsFormat::Nanos, true)
     }
     fn epoch_duration_minus_seconds(delta: u64) -> Duration {
         let now = epoch_duration_now();
@@ -1037,7 +2185,7 @@ mod test {
         now - Duration::from_secs(delta)
     }
     fn epoch_duration_now() -> Duration {
-        SystemTime::now()
+        clock::now()
             .duration_since(UNIX_EPOCH)
             .expect("Bad time to run this test")
     }
@@ -1096,6 +2244,8 @@ mod test {
 
     #[test]
     fn test_compute_runner_changes() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
         let mut policy = Policy::new(
             [
                 ("linux".to_owned(), profile("linux", 5, 0, "0B")),
@@ -1164,49 +2314,34 @@ mod test {
             },
         );
 
-        // All of the reasons we might destroy runners.
+        // All of the reasons we might destroy runners. Runners [0] [1] [2] were created long
+        // enough ago to have timed out while starting, the rest were created just now.
+        policy.set_runners(runners(vec![FakeRunner::default(); 3]));
+        clock.advance(TOML.monitor_start_timeout() + Duration::from_secs(1));
         let fake_runners = vec![
             // [0] Invalid => unregister and destroy
             FakeRunner {
-                profile_key: "linux",
                 status: Status::Invalid,
-                created_time: SystemTime::now(),
-                reserved_since: None,
+                ..FakeRunner::default()
             },
             // [1] DoneOrUnregistered => unregister and destroy
+            FakeRunner::done_or_unregistered("linux"),
+            // [2] StartedOrCrashed and too old => unregister and destroy
             FakeRunner {
-                profile_key: "linux",
-                status: Status::DoneOrUnregistered,
-                created_time: SystemTime::now(),
-                reserved_since: None,
-            },
-            // [2] StartedOrCrashed, but not too old => keep (1/5)
-            FakeRunner {
-                profile_key: "linux",
                 status: Status::StartedOrCrashed,
-                created_time: SystemTime::now(),
-                reserved_since: None,
+                ..FakeRunner::default()
             },
-            // [3] StartedOrCrashed and too old => unregister and destroy
+            // [3] StartedOrCrashed, but not too old => keep (1/5)
             FakeRunner {
-                profile_key: "linux",
                 status: Status::StartedOrCrashed,
-                created_time: system_time_minus_seconds(130),
-                reserved_since: None,
+                ..FakeRunner::default()
             },
             // [4] Reserved, but not for too long => keep (2/5)
-            FakeRunner {
-                profile_key: "linux",
-                status: Status::Reserved,
-                created_time: system_time_minus_seconds(9001),
-                reserved_since: Some(epoch_duration_now()),
-            },
+            FakeRunner::reserved("linux"),
             // [5] Reserved for too long => unregister and destroy
             FakeRunner {
-                profile_key: "linux",
-                status: Status::Reserved,
-                created_time: system_time_minus_seconds(9001),
                 reserved_since: Some(epoch_duration_minus_seconds(210)),
+                ..FakeRunner::reserved("linux")
             },
             // [6] [7] [8] [9] [10] [11] [12] Idle or Busy => bleed off excess Idle runners
             // => destroy (1) (2) (3) (4) keep (3/5) (4/5) (5/5)
@@ -1222,7 +2357,7 @@ mod test {
         assert_eq!(
             policy.compute_runner_changes()?,
             RunnerChanges {
-                unregister_and_destroy_runner_ids: vec![0, 1, 3, 5, 6, 7, 8, 9],
+                unregister_and_destroy_runner_ids: vec![0, 1, 2, 5, 6, 7, 8, 9],
                 create_counts_by_profile_key: [].into(),
             },
         );
@@ -1231,16 +2366,18 @@ mod test {
         assert_eq!(
             policy.compute_runner_changes()?,
             RunnerChanges {
-                unregister_and_destroy_runner_ids: vec![0, 1, 3, 5, 6, 7, 8, 9],
+                unregister_and_destroy_runner_ids: vec![0, 1, 2, 5, 6, 7, 8, 9],
                 create_counts_by_profile_key: [].into(),
             },
         );
 
-        // All destroys succeeded? Now create runners.
+        // All destroys succeeded? Now create runners. The runners that were kept get new ids,
+        // so delete all runners first, lest they inherit the created times of the old ids.
+        policy.set_runners(runners(vec![]));
         let fake_runners = fake_runners
             .into_iter()
             .enumerate()
-            .filter(|(i, _)| ![0, 1, 3, 5, 6, 7, 8, 9].contains(i))
+            .filter(|(i, _)| ![0, 1, 2, 5, 6, 7, 8, 9].contains(i))
             .map(|(_, fake)| fake)
             .collect::<Vec<_>>();
         policy.set_runners(runners(fake_runners.clone()));
@@ -1360,6 +2497,7 @@ mod test {
             },
         );
 
+        clock::set_clock_for_thread(None);
         Ok(())
     }
 
@@ -1401,6 +2539,970 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_compute_runner_changes_with_pending_operations() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 3, 0, "0B"))].into())?;
+        let fresh = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &fresh)?;
+        let pending = |operation| PendingOperation {
+            operation,
+            profile_key: "linux".to_owned(),
+            stage: None,
+        };
+
+        // Runners being created count towards the target, even before they have a guest, and
+        // they are provisioning rather than invalid.
+        policy.set_runners(runners(vec![
+            FakeRunner {
+                status: Status::Invalid,
+                ..FakeRunner::default()
+            },
+            FakeRunner::idle("linux"),
+        ]));
+        policy.set_pending_runner_operations(
+            [
+                (0, pending(RunnerOperation::Create)),
+                (7, pending(RunnerOperation::Create)),
+            ]
+            .into(),
+        );
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 0)].into(),
+            },
+        );
+
+        // Runners being destroyed aren’t destroyed again, and no runners are created until
+        // they are destroyed.
+        policy.set_runners(runners(vec![FakeRunner::done_or_unregistered("linux")]));
+        policy.set_pending_runner_operations([(0, pending(RunnerOperation::Destroy))].into());
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Runners being destroyed only stop runners being created for their own profile.
+        let mut policy = Policy::new(
+            [
+                ("linux".to_owned(), profile("linux", 3, 0, "0B")),
+                (
+                    "windows".to_owned(),
+                    Profile {
+                        warm_pool_size: 1,
+                        ..profile("windows", 2, 0, "0B")
+                    },
+                ),
+            ]
+            .into(),
+        )?;
+        policy.set_base_image_snapshot("linux", &fresh)?;
+        policy.set_base_image_snapshot("windows", &fresh)?;
+        policy.set_runners(runners(vec![FakeRunner::done_or_unregistered("linux")]));
+        policy.set_pending_runner_operations([(0, pending(RunnerOperation::Destroy))].into());
+        let mut changes = policy.compute_runner_changes()?;
+        assert_eq!(
+            changes,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("windows".to_owned(), 2)].into(),
+            },
+        );
+        assert_eq!(
+            policy.compute_warm_pool_changes(&mut changes),
+            WarmPoolChanges {
+                promote_runner_ids: vec![],
+                destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("windows".to_owned(), 1)].into(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_provisioning_runners() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 2, 0, "0B"))].into())?;
+        let fresh = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &fresh)?;
+        let started = || FakeRunner {
+            status: Status::StartedOrCrashed,
+            ..FakeRunner::default()
+        };
+
+        // Runners we started are provisioning until they come online, and count towards the target.
+        policy.runner_started(0);
+        policy.set_runners(runners(vec![started()]));
+        assert_eq!(
+            policy.runner(0).map(|r| r.status()),
+            Some(Status::Provisioning)
+        );
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 1)].into(),
+            },
+        );
+
+        // Once they come online, they are no longer provisioning.
+        policy.set_runners(runners(vec![FakeRunner::idle("linux")]));
+        assert_eq!(policy.runner(0).map(|r| r.status()), Some(Status::Idle));
+        policy.set_runners(runners(vec![started()]));
+        assert_eq!(
+            policy.runner(0).map(|r| r.status()),
+            Some(Status::StartedOrCrashed)
+        );
+
+        // Runners that take too long to come online are destroyed.
+        policy.runner_started(0);
+        policy.set_runners(runners(vec![started()]));
+        clock.advance(TOML.monitor_start_timeout() + Duration::from_secs(1));
+        policy.set_runners(runners(vec![started()]));
+        assert_eq!(
+            policy.runner(0).map(|r| r.status()),
+            Some(Status::StartedOrCrashed)
+        );
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_warm_pool() -> eyre::Result<()> {
+        let mut policy = Policy::new(
+            [(
+                "linux".to_owned(),
+                Profile {
+                    warm_pool_size: 2,
+                    ..profile("linux", 2, 0, "0B")
+                },
+            )]
+            .into(),
+        )?;
+        let fresh = snapshot_now_minus_seconds(0);
+        let stale = snapshot_now_minus_seconds(9001);
+        policy.set_base_image_snapshot("linux", &fresh)?;
+
+        // Runners to create are taken from the warm pool first, then the pool is topped up.
+        policy.set_runners(runners(vec![
+            FakeRunner::warm("linux").with_base_image_snapshot(&fresh)
+        ]));
+        assert_eq!(policy.runner(0).map(|r| r.status()), Some(Status::Warm));
+        assert_eq!(
+            policy.healthy_runner_count(policy.profile("linux").unwrap()),
+            0
+        );
+        let mut changes = policy.compute_runner_changes()?;
+        assert_eq!(
+            policy.compute_warm_pool_changes(&mut changes),
+            WarmPoolChanges {
+                promote_runner_ids: vec![0],
+                destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 2)].into(),
+            },
+        );
+        assert_eq!(
+            changes.create_counts_by_profile_key,
+            [("linux".to_owned(), 1)].into(),
+        );
+
+        // Warm runners that are being created count towards the pool.
+        policy.set_pending_runner_operations(
+            [(
+                1,
+                PendingOperation {
+                    operation: RunnerOperation::CreateWarm,
+                    profile_key: "linux".to_owned(),
+                    stage: None,
+                },
+            )]
+            .into(),
+        );
+        let mut changes = RunnerChanges::default();
+        assert_eq!(
+            policy.compute_warm_pool_changes(&mut changes),
+            WarmPoolChanges {
+                promote_runner_ids: vec![],
+                destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 0)].into(),
+            },
+        );
+        policy.set_pending_runner_operations([].into());
+
+        // Warm runners created from an older image are destroyed, not promoted.
+        policy.set_runners(runners(vec![
+            FakeRunner::warm("linux").with_base_image_snapshot(&stale),
+            FakeRunner::warm("linux").with_base_image_snapshot(&fresh),
+            FakeRunner::idle("linux"),
+        ]));
+        let mut changes = policy.compute_runner_changes()?;
+        assert_eq!(
+            policy.compute_warm_pool_changes(&mut changes),
+            WarmPoolChanges {
+                promote_runner_ids: vec![1],
+                destroy_runner_ids: vec![0],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+        assert_eq!(
+            changes.create_counts_by_profile_key,
+            [("linux".to_owned(), 0)].into(),
+        );
+
+        // Only warm runners created from the current image can be promoted for a job, and only
+        // if they aren’t being promoted already.
+        let linux = policy
+            .profile("linux")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+        let promotable_ids = |policy: &Policy| {
+            policy
+                .promotable_warm_runners_for_profile(&linux)
+                .map(|(&id, _runner)| id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(promotable_ids(&policy), [1]);
+        policy.set_pending_runner_operations(
+            [(
+                1,
+                PendingOperation {
+                    operation: RunnerOperation::Create,
+                    profile_key: "linux".to_owned(),
+                    stage: None,
+                },
+            )]
+            .into(),
+        );
+        assert!(promotable_ids(&policy).is_empty());
+        policy.set_pending_runner_operations([].into());
+        let error = policy.promote_warm_runner(3, None).err().unwrap();
+        assert!(error.to_string().contains("no runner with id exists: 3"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_runner_changes_with_rebuilds() -> eyre::Result<()> {
+        let mut policy = Policy::new(
+            [
+                ("linux".to_owned(), profile("linux", 2, 24, "0B")),
+                ("windows".to_owned(), profile("windows", 1, 24, "0B")),
+                ("macos".to_owned(), profile("macos", 1, 24, "0B")),
+            ]
+            .into(),
+        )?;
+        let fresh = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &fresh)?;
+        policy.set_base_image_snapshot("windows", &fresh)?;
+        policy.set_base_image_snapshot("macos", &fresh)?;
+        policy.set_runners(runners(vec![]));
+
+        // Rebuild guests are charged like runners, so creates should be deferred to make room.
+        policy.set_rebuild_profile_keys(["macos".to_owned()].into());
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [
+                    ("linux".to_owned(), 1),
+                    ("windows".to_owned(), 1),
+                    ("macos".to_owned(), 1),
+                ]
+                .into(),
+            },
+        );
+
+        // Rebuilds can only start if they fit alongside the runners that actually exist.
+        policy.set_rebuild_profile_keys([].into());
+        let fake_runners = vec![
+            FakeRunner::idle("linux"),
+            FakeRunner::idle("linux"),
+            FakeRunner::idle("windows"),
+        ];
+        policy.set_runners(runners(fake_runners));
+        let macos = policy
+            .profile("macos")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+        assert!(policy
+            .validate_rebuild_resource_requirements(&macos)
+            .is_ok());
+        let fake_runners = vec![
+            FakeRunner::idle("linux"),
+            FakeRunner::idle("linux"),
+            FakeRunner::idle("windows"),
+            FakeRunner::busy("macos"),
+        ];
+        policy.set_runners(runners(fake_runners));
+        assert!(policy
+            .validate_rebuild_resource_requirements(&macos)
+            .is_err());
+
+        // Other rebuilds count too.
+        let fake_runners = vec![FakeRunner::idle("linux"), FakeRunner::idle("linux")];
+        policy.set_runners(runners(fake_runners));
+        assert!(policy
+            .validate_rebuild_resource_requirements(&macos)
+            .is_ok());
+        policy.set_rebuild_profile_keys(["windows".to_owned()].into());
+        assert!(policy
+            .validate_rebuild_resource_requirements(&macos)
+            .is_ok());
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux"),
+            FakeRunner::idle("linux"),
+            FakeRunner::idle("windows"),
+        ]));
+        assert!(policy
+            .validate_rebuild_resource_requirements(&macos)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_runner_changes_rolling_upgrade() -> eyre::Result<()> {
+        let mut linux = profile("linux", 4, 0, "0B");
+        linux.rolling_upgrade_min_runners = Some(3);
+        let mut policy = Policy::new([("linux".to_owned(), linux)].into())?;
+
+        // Profiles with no image at all can’t serve jobs during a rebuild.
+        policy.set_runners(runners(vec![]));
+        assert_eq!(
+            policy.target_runner_count(policy.profile("linux").expect("Guaranteed by Policy::new")),
+            0
+        );
+
+        // While the image is being rebuilt, keep the old runners and keep creating runners.
+        let old = snapshot_now_minus_seconds(86500);
+        policy.set_base_image_snapshot("linux", &old)?;
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 1)].into(),
+            },
+        );
+
+        // Once the new image is ready, replace outdated idle runners, but only as many as we
+        // can spare without going below the minimum.
+        let new = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &new)?;
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Busy runners count towards the minimum, but they are never replaced.
+        policy.set_runners(runners(vec![
+            FakeRunner::busy("linux").with_base_image_snapshot(&old),
+            FakeRunner::busy("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&new),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![3],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Unhealthy runners being destroyed don’t count towards the minimum, so they don’t
+        // reduce how many idle runners we can spare...
+        policy.set_runners(runners(vec![
+            FakeRunner::unresponsive("linux").with_base_image_snapshot(&old),
+            FakeRunner::unresponsive("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0, 1, 2],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // ...and even if they outnumber the healthy runners, we just can’t spare any.
+        policy.set_runners(runners(vec![
+            FakeRunner::unresponsive("linux").with_base_image_snapshot(&old),
+            FakeRunner::unresponsive("linux").with_base_image_snapshot(&old),
+            FakeRunner::unresponsive("linux").with_base_image_snapshot(&old),
+            FakeRunner::idle("linux").with_base_image_snapshot(&old),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0, 1, 2],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Runners created from the current image are never replaced.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_base_image_snapshot(&new),
+            FakeRunner::idle("linux").with_base_image_snapshot(&new),
+            FakeRunner::idle("linux").with_base_image_snapshot(&new),
+            FakeRunner::idle("linux").with_base_image_snapshot(&new),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 0)].into(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_base_image_snapshot() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 2, 0, "0B"))].into())?;
+        let linux = policy
+            .profile("linux")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+        let old = snapshot_now_minus_seconds(86500);
+        let bad = snapshot_now_minus_seconds(0);
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_base_image_snapshot(&bad),
+            FakeRunner::idle("linux").with_base_image_snapshot(&bad),
+        ]));
+
+        // Pinned snapshots are not rebuilt, even if they are too old.
+        policy.set_base_image_snapshot("linux", &old)?;
+        assert_eq!(policy.image_needs_rebuild(&linux), Some(true));
+        policy.set_pinned_base_image_snapshot("linux", Some(&old));
+        assert_eq!(policy.image_rebuild_reasons(&linux), Some(vec![]));
+
+        // Idle runners created from other images are replaced straight away.
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0, 1],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Requested rebuilds happen even if the snapshot is pinned.
+        policy.request_rebuild("linux")?;
+        assert_eq!(
+            policy.image_rebuild_reasons(&linux),
+            Some(vec!["rebuild requested".to_owned()])
+        );
+        assert!(policy.request_rebuild("unknown").is_err());
+
+        // Once the image is rebuilt, the request is satisfied.
+        let new = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &new)?;
+        policy.set_pinned_base_image_snapshot("linux", None);
+        assert_eq!(policy.image_needs_rebuild(&linux), Some(false));
+        assert!(!policy.base_image_snapshot_is_pinned("linux"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_rebuild() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 0, 0, "0B"))].into())?;
+        let linux = policy
+            .profile("linux")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+
+        // Images for profiles with zero target count are not rebuilt on their own.
+        assert_eq!(policy.image_rebuild_reasons(&linux), Some(vec![]));
+        for _ in 0..TOML.rebuild_max_failures() {
+            policy.record_rebuild_failure("linux", "error".to_owned());
+        }
+        assert!(policy.rebuild_circuit_open("linux"));
+
+        // Requested rebuilds happen anyway, and reset the circuit breaker.
+        policy.request_rebuild("linux")?;
+        assert_eq!(
+            policy.image_rebuild_reasons(&linux),
+            Some(vec!["rebuild requested".to_owned()])
+        );
+        assert!(!policy.rebuild_circuit_open("linux"));
+        assert_eq!(policy.rebuild_backoff_remaining("linux"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_failures() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 2, 0, "0B"))].into())?;
+
+        // Backoff doubles after each consecutive failure, up to a maximum.
+        assert_eq!(rebuild_backoff(0), Duration::ZERO);
+        assert_eq!(rebuild_backoff(1), TOML.rebuild_backoff_initial());
+        assert_eq!(rebuild_backoff(2), TOML.rebuild_backoff_initial() * 2);
+        assert_eq!(rebuild_backoff(3), TOML.rebuild_backoff_initial() * 4);
+        assert_eq!(rebuild_backoff(100), TOML.rebuild_backoff_max());
+
+        // Failures make us back off, until we give up entirely.
+        assert_eq!(policy.rebuild_backoff_remaining("linux"), None);
+        for count in 1..TOML.rebuild_max_failures() {
+            policy.record_rebuild_failure("linux", format!("error {count}"));
+            assert!(policy.rebuild_backoff_remaining("linux").is_some());
+            assert!(!policy.rebuild_circuit_open("linux"));
+        }
+        policy.record_rebuild_failure("linux", "last error".to_owned());
+        assert!(policy.rebuild_circuit_open("linux"));
+        assert_eq!(
+            policy
+                .rebuild_failures("linux")
+                .map(|failures| (failures.count, failures.last_error.as_str())),
+            Some((TOML.rebuild_max_failures(), "last error"))
+        );
+
+        // Resetting the failures lets us try again.
+        assert!(policy.reset_rebuild_failures("linux")?.is_some());
+        assert!(!policy.rebuild_circuit_open("linux"));
+        assert_eq!(policy.rebuild_backoff_remaining("linux"), None);
+        assert!(policy.reset_rebuild_failures("unknown").is_err());
+
+        // Successful rebuilds also reset the failures.
+        policy.record_rebuild_failure("linux", "error".to_owned());
+        policy.record_rebuild_success("linux");
+        assert_eq!(policy.rebuild_failures("linux"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantined_profiles() -> eyre::Result<()> {
+        let mut policy = Policy::new(
+            [
+                ("linux".to_owned(), profile("linux", 2, 0, "0B")),
+                ("windows".to_owned(), profile("windows", 1, 0, "0B")),
+            ]
+            .into(),
+        )?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+        policy.set_base_image_snapshot("windows", &now)?;
+        policy.set_runners(runners(vec![FakeRunner::idle("linux")]));
+
+        // Quarantined profiles keep their runners, but get no new runners.
+        policy.quarantine_profile("linux", "first error".to_owned());
+        policy.quarantine_profile("linux", "second error".to_owned());
+        assert_eq!(policy.quarantine_reason("linux"), Some("first error"));
+        assert_eq!(policy.quarantine_reason("windows"), None);
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 0), ("windows".to_owned(), 1),]
+                    .into(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unresponsive_runners() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 2, 0, "0B"))].into())?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+
+        // Runners that stop sending heartbeats are destroyed, and don’t count as healthy.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux"),
+            FakeRunner::unresponsive("linux"),
+        ]));
+        let linux = policy
+            .profile("linux")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+        assert_eq!(policy.healthy_runner_count(&linux), 1);
+        assert_eq!(policy.unresponsive_runner_count(&linux), 1);
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![1],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Runners with recent heartbeats are unresponsive only if they report problems.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_heartbeat(healthy_heartbeat())
+        ]));
+        assert_eq!(policy.idle_runner_count(&linux), 1);
+        for heartbeat in [
+            Heartbeat {
+                runner_alive: false,
+                ..healthy_heartbeat()
+            },
+            Heartbeat {
+                disk_free: TOML.runner_min_disk_free() - 1,
+                ..healthy_heartbeat()
+            },
+        ] {
+            policy.set_runners(runners(vec![
+                FakeRunner::idle("linux").with_heartbeat(heartbeat)
+            ]));
+            assert_eq!(policy.unresponsive_runner_count(&linux), 1);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_heartbeats_before_coming_online() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 1, 0, "0B"))].into())?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+        let linux = policy
+            .profile("linux")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+        let not_alive = Heartbeat {
+            runner_alive: false,
+            ..healthy_heartbeat()
+        };
+
+        // Guests send heartbeats from boot, before the runner process has started.
+        let starting = FakeRunner {
+            status: Status::StartedOrCrashed,
+            ..FakeRunner::default()
+        }
+        .with_heartbeat(not_alive.clone());
+        policy.set_runners(runners(vec![starting.clone()]));
+        assert_eq!(policy.unresponsive_runner_count(&linux), 0);
+
+        // Those heartbeats are still current when the runner comes online, but they are older
+        // than when it came online, so the runner is idle rather than unresponsive.
+        clock.advance(Duration::from_secs(10));
+        let idle = FakeRunner {
+            heartbeat: starting.heartbeat.clone(),
+            ..FakeRunner::idle("linux")
+        };
+        policy.set_runners(runners(vec![idle.clone()]));
+        assert_eq!(policy.idle_runner_count(&linux), 1);
+        assert_eq!(policy.unresponsive_runner_count(&linux), 0);
+        assert!(policy
+            .compute_runner_changes()?
+            .unregister_and_destroy_runner_ids
+            .is_empty());
+
+        // They still count towards the heartbeat timeout, though.
+        clock.advance(TOML.runner_heartbeat_timeout());
+        policy.set_runners(runners(vec![idle]));
+        assert_eq!(policy.unresponsive_runner_count(&linux), 1);
+
+        // Heartbeats sent after the runner came online count as usual.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_heartbeat(not_alive)
+        ]));
+        assert_eq!(policy.unresponsive_runner_count(&linux), 1);
+        assert_eq!(
+            policy
+                .compute_runner_changes()?
+                .unregister_and_destroy_runner_ids,
+            [0]
+        );
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeouts_with_fake_clock() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 2, 0, "0B"))].into())?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+        let fake_runners = vec![
+            FakeRunner {
+                status: Status::StartedOrCrashed,
+                ..FakeRunner::default()
+            },
+            FakeRunner::idle("linux").with_heartbeat(healthy_heartbeat()),
+        ];
+
+        // Runners are given time to start and send heartbeats...
+        policy.set_runners(runners(fake_runners.clone()));
+        assert!(policy
+            .compute_runner_changes()?
+            .unregister_and_destroy_runner_ids
+            .is_empty());
+
+        // ...but not forever.
+        clock.advance(
+            TOML.monitor_start_timeout()
+                .max(TOML.runner_heartbeat_timeout()),
+        );
+        clock.advance(Duration::from_secs(1));
+        policy.set_runners(runners(fake_runners));
+        let mut destroy_ids = policy
+            .compute_runner_changes()?
+            .unregister_and_destroy_runner_ids;
+        destroy_ids.sort();
+        assert_eq!(destroy_ids, vec![0, 1]);
+
+        // Image rebuilds are retried once their backoff has elapsed.
+        policy.record_rebuild_failure("linux", "error".to_owned());
+        assert_eq!(
+            policy.rebuild_backoff_remaining("linux"),
+            Some(TOML.rebuild_backoff_initial())
+        );
+        clock.advance(TOML.rebuild_backoff_initial() - Duration::from_secs(1));
+        assert_eq!(
+            policy.rebuild_backoff_remaining("linux"),
+            Some(Duration::from_secs(1))
+        );
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(policy.rebuild_backoff_remaining("linux"), None);
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserved_runners() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 3, 0, "0B"))].into())?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+
+        // Runners flagged for preservation are destroyed once they are not busy.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_preserve_flag("flaky"),
+            FakeRunner::busy("linux").with_preserve_flag("flaky"),
+            FakeRunner::done_or_unregistered("linux").with_preserve_flag("flaky"),
+        ]));
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![2, 0],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+        for id in [0, 1, 2] {
+            let runner = policy.runner(id).expect("Guaranteed by set_runners()");
+            assert_eq!(runner.preserve_reason().as_deref(), Some("flaky"));
+        }
+
+        // Runners that are not flagged are not preserved, unless enabled in the settings.
+        let started = || FakeRunner {
+            status: Status::StartedOrCrashed,
+            ..FakeRunner::default()
+        };
+        policy.set_runners(runners(vec![started()]));
+        clock.advance(TOML.monitor_start_timeout() + Duration::from_secs(1));
+        policy.set_runners(runners(vec![started()]));
+        let runner = policy.runner(0).expect("Guaranteed by set_runners()");
+        assert_eq!(
+            runner.preserve_reason().is_some(),
+            TOML.preserve_failed_runners()
+        );
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_held_runners() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 1, 0, "0B"))].into())?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+        let linux = policy
+            .profile("linux")
+            .expect("Guaranteed by Policy::new")
+            .clone();
+
+        // Held runners are kept even if they would otherwise be destroyed, but still count as
+        // healthy, so they take up a slot. Busy runners stay busy until their jobs finish.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_hold(3600),
+            FakeRunner::done_or_unregistered("linux").with_hold(3600),
+            FakeRunner {
+                status: Status::StartedOrCrashed,
+                ..FakeRunner::default().with_hold(3600)
+            },
+            FakeRunner::busy("linux").with_hold(3600),
+        ]));
+        assert_eq!(policy.held_runner_count(&linux), 3);
+        assert_eq!(policy.busy_runner_count(&linux), 1);
+        assert_eq!(policy.healthy_runner_count(&linux), 4);
+        assert_eq!(policy.idle_runner_count(&linux), 0);
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![],
+                create_counts_by_profile_key: [("linux".to_owned(), 0)].into(),
+            },
+        );
+
+        // Runners that are already being destroyed can’t be held, since that wouldn’t stop them.
+        policy.set_runners(runners(vec![FakeRunner::done_or_unregistered("linux")]));
+        policy.set_pending_runner_operations(
+            [(
+                0,
+                PendingOperation {
+                    operation: RunnerOperation::Destroy,
+                    profile_key: "linux".to_owned(),
+                    stage: None,
+                },
+            )]
+            .into(),
+        );
+        assert!(policy.hold_runner(0, Duration::from_secs(3600)).is_err());
+        policy.set_pending_runner_operations([].into());
+
+        // Once the hold expires, runners are destroyed, unless they are busy.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux").with_hold(-1),
+            FakeRunner::busy("linux").with_hold(-1),
+        ]));
+        assert_eq!(policy.held_runner_count(&linux), 0);
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stuck_busy_runners() -> eyre::Result<()> {
+        let mut policy = Policy::new([("linux".to_owned(), profile("linux", 2, 0, "0B"))].into())?;
+        let now = snapshot_now_minus_seconds(0);
+        policy.set_base_image_snapshot("linux", &now)?;
+        let fake_runners = vec![FakeRunner::busy("linux"), FakeRunner::busy("linux")];
+        policy.set_runners(runners(fake_runners.clone()));
+        assert!(policy.busy_duration(0).is_some());
+
+        // Busy runners are destroyed only if their jobs have finished.
+        for (id, result) in [
+            (
+                0,
+                StuckBusyRunner::JobFinished {
+                    conclusion: Some("cancelled".to_owned()),
+                },
+            ),
+            (
+                1,
+                StuckBusyRunner::Flagged {
+                    reason: "job 123 is in_progress".to_owned(),
+                },
+            ),
+        ] {
+            let checked_at = clock::instant();
+            policy
+                .stuck_busy_runners
+                .insert(id, StuckBusyRunnerCheck { result, checked_at });
+        }
+        assert_eq!(
+            policy.compute_runner_changes()?,
+            RunnerChanges {
+                unregister_and_destroy_runner_ids: vec![0],
+                create_counts_by_profile_key: [].into(),
+            },
+        );
+
+        // Runners that are no longer busy are forgotten.
+        policy.set_runners(runners(vec![
+            FakeRunner::idle("linux"),
+            FakeRunner::busy("linux"),
+        ]));
+        assert_eq!(policy.busy_duration(0), None);
+        assert_eq!(policy.stuck_busy_runner(0), None);
+        assert!(policy.stuck_busy_runner(1).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_stuck_busy_runners() -> eyre::Result<()> {
+        let clock = Rc::new(FakeClock::new());
+        clock::set_clock_for_thread(Some(clock.clone()));
+        let mut linux = profile("linux", 2, 0, "0B");
+        linux.max_busy_duration = Some(60);
+        let mut policy = Policy::new([("linux".to_owned(), linux)].into())?;
+        let fake_runners = vec![FakeRunner::busy("linux")];
+        policy.set_runners(runners(fake_runners.clone()));
+
+        // Runners that are not busy for too long are not checked.
+        policy.update_stuck_busy_runners();
+        assert!(policy.stuck_busy_check.is_none());
+
+        // Runners that are busy for too long are checked on another thread, and the results
+        // take effect once it finishes.
+        clock.advance(Duration::from_secs(61));
+        policy.set_runners(runners(fake_runners.clone()));
+        policy.update_stuck_busy_runners();
+        assert_eq!(policy.stuck_busy_runner(0), None);
+        while !policy
+            .stuck_busy_check
+            .as_ref()
+            .is_some_and(|thread| thread.is_finished())
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        policy.update_stuck_busy_runners();
+        assert!(policy.stuck_busy_check.is_none());
+        assert_eq!(
+            policy.stuck_busy_runner(0),
+            Some(&StuckBusyRunner::Flagged {
+                reason: "runner was not reserved, so its job is unknown".to_owned()
+            })
+        );
+
+        // Runners are not checked again until `api_cache_timeout` elapses.
+        policy.update_stuck_busy_runners();
+        assert!(policy.stuck_busy_check.is_none());
+        clock.advance(TOML.api_cache_timeout());
+        policy.update_stuck_busy_runners();
+        assert!(policy.stuck_busy_check.is_some());
+        assert!(policy.stuck_busy_runner(0).is_some());
+
+        clock::set_clock_for_thread(None);
+        Ok(())
+    }
+
     #[test]
     fn test_try_override() -> eyre::Result<()> {
         let mut policy = Policy::new(
@@ -0,0 +1,244 @@
+//! Runners that were destroyed but preserved for debugging.
+//!
+//! Preserving a runner shuts down its guest and keeps a copy of its disk image, alongside the
+//! usual screenshot and console log in its data directory, until it’s discarded via the API or to
+//! stay within `preserved_runners_max_size_gib`.
+
+use std::{
+    fs::{create_dir_all, read_dir, remove_file},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use cmd_lib::{run_fun, spawn_with_output};
+use jane_eyre::eyre::{self, bail};
+use serde::{Deserialize, Serialize};
+use settings::TOML;
+use tracing::{info, warn};
+
+use crate::{
+    data::get_runner_data_path,
+    libvirt::update_screenshot,
+    policy::{runner_image_path, runner_images_path},
+    runner::PRESERVE_FLAG_FILENAME,
+    screenshots::runner_screenshot_history_path,
+    shell::log_output_as_info,
+    storage,
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PreservedRunner {
+    pub id: usize,
+    pub profile_name: String,
+    pub reason: String,
+    pub preserved_at: DateTime<Utc>,
+    /// Disk space used by the preserved disk image, in bytes.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub disk_size: Option<u64>,
+}
+
+pub fn preserved_runners_path() -> PathBuf {
+    runner_images_path().join("preserved")
+}
+
+pub fn preserved_runner_image_path(runner_id: usize) -> PathBuf {
+    preserved_runners_path().join(format!("{runner_id}-base.img"))
+}
+
+fn preserved_runner_toml_path(runner_id: usize) -> eyre::Result<PathBuf> {
+    get_runner_data_path(runner_id, Path::new("preserved.toml"))
+}
+
+/// Shuts down the guest of a runner that is about to be destroyed, and keeps a copy of its disk
+/// image and libvirt domain XML.
+pub fn preserve_runner(
+    runner_id: usize,
+    runner_guest_name: &str,
+    profile_name: &str,
+    reason: &str,
+) -> eyre::Result<()> {
+    info!(runner_id, runner_guest_name, reason, "Preserving runner");
+    let runner_data_path = get_runner_data_path(runner_id, None)?;
+    let history_dir = runner_screenshot_history_path(runner_id)?;
+    if let Err(error) = update_screenshot(runner_guest_name, &runner_data_path, &history_dir) {
+        warn!(?error, "Failed to update screenshot: {error}");
+    }
+    match run_fun!(virsh dumpxml -- $runner_guest_name) {
+        Ok(xml) => std::fs::write(runner_data_path.join("guest.xml"), xml)?,
+        Err(error) => warn!(?error, "Failed to dump guest XML: {error}"),
+    }
+
+    // Shut down the guest before copying its disk, so the copy doesn’t change underneath us.
+    let pipe = || |reader| log_output_as_info(reader);
+    let _ =
+        spawn_with_output!(virsh destroy -- $runner_guest_name 2>&1)?.wait_with_pipe(&mut pipe());
+
+    // Make room for the copy before making it, so we never go over budget, even for a moment.
+    let runner_image_path = runner_image_path(runner_id, "base.img");
+    let size = storage::export_image_size(&runner_image_path)?;
+    discard_preserved_runners_to_fit(size)?;
+
+    create_dir_all(preserved_runners_path())?;
+    storage::export_image(&runner_image_path, &preserved_runner_image_path(runner_id))?;
+    let preserved_runner = PreservedRunner {
+        id: runner_id,
+        profile_name: profile_name.to_owned(),
+        reason: reason.to_owned(),
+        preserved_at: Utc::now(),
+        disk_size: None,
+    };
+    std::fs::write(
+        preserved_runner_toml_path(runner_id)?,
+        toml::to_string(&preserved_runner)?,
+    )?;
+
+    // The runner is about to be destroyed, so we no longer need the flag.
+    let _ = remove_file(get_runner_data_path(
+        runner_id,
+        Path::new(PRESERVE_FLAG_FILENAME),
+    )?);
+
+    Ok(())
+}
+
+/// Returns the preserved runner with the given id, if its disk image is still preserved.
+pub fn read_preserved_runner(runner_id: usize) -> eyre::Result<Option<PreservedRunner>> {
+    let Ok(metadata) = std::fs::metadata(preserved_runner_image_path(runner_id)) else {
+        return Ok(None);
+    };
+    let mut result: PreservedRunner = toml::from_str(&std::fs::read_to_string(
+        preserved_runner_toml_path(runner_id)?,
+    )?)?;
+    result.disk_size = Some(metadata.blocks() * 512);
+
+    Ok(Some(result))
+}
+
+/// Returns all preserved runners, oldest first.
+pub fn list_preserved_runners() -> eyre::Result<Vec<PreservedRunner>> {
+    let mut result = vec![];
+    let Ok(entries) = read_dir(preserved_runners_path()) else {
+        return Ok(result);
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Some(runner_id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_suffix("-base.img"))
+            .and_then(|id| id.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        match read_preserved_runner(runner_id) {
+            Ok(Some(preserved_runner)) => result.push(preserved_runner),
+            Ok(None) => {}
+            Err(error) => warn!(
+                runner_id,
+                ?error,
+                "Failed to read preserved runner: {error}"
+            ),
+        }
+    }
+    result.sort_by_key(|preserved_runner| preserved_runner.preserved_at);
+
+    Ok(result)
+}
+
+/// Deletes the preserved disk image of a runner, keeping the rest of its data directory.
+pub fn discard_preserved_runner(runner_id: usize) -> eyre::Result<PreservedRunner> {
+    let Some(result) = read_preserved_runner(runner_id)? else {
+        bail!("No preserved runner with id exists: {runner_id}");
+    };
+    info!(runner_id, "Discarding preserved runner");
+    remove_file(preserved_runner_image_path(runner_id))?;
+    remove_file(preserved_runner_toml_path(runner_id)?)?;
+
+    Ok(result)
+}
+
+/// Discards the oldest preserved runners until they fit in `preserved_runners_max_size_gib`.
+pub fn enforce_preserved_runners_budget() -> eyre::Result<()> {
+    discard_preserved_runners_to_fit(0)
+}
+
+/// Discards the oldest preserved runners until they fit in `preserved_runners_max_size_gib`,
+/// alongside a new preserved runner of the given size.
+fn discard_preserved_runners_to_fit(new_size: u64) -> eyre::Result<()> {
+    let preserved_runners = list_preserved_runners()?;
+    let discard_ids = preserved_runners_to_discard(
+        &preserved_runners,
+        new_size,
+        TOML.preserved_runners_max_size(),
+    )?;
+    for runner_id in discard_ids {
+        info!(runner_id, new_size, "Preserved runners are over budget");
+        discard_preserved_runner(runner_id)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the oldest of the given preserved runners that need to be discarded, so that they fit
+/// in `max_size` alongside a new preserved runner of the given size, or an error if it would never
+/// fit.
+fn preserved_runners_to_discard(
+    preserved_runners: &[PreservedRunner],
+    new_size: u64,
+    max_size: u64,
+) -> eyre::Result<Vec<usize>> {
+    if new_size > max_size {
+        bail!("Runner needs {new_size} bytes, more than preserved_runners_max_size_gib");
+    }
+    let mut result = vec![];
+    let mut total_size = preserved_runners
+        .iter()
+        .flat_map(|preserved_runner| preserved_runner.disk_size)
+        .sum::<u64>()
+        + new_size;
+    for preserved_runner in preserved_runners {
+        if total_size <= max_size {
+            break;
+        }
+        result.push(preserved_runner.id);
+        total_size -= preserved_runner.disk_size.unwrap_or(0);
+    }
+
+    Ok(result)
+}
+
+#[test]
+fn test_preserved_runners_to_discard() -> eyre::Result<()> {
+    let preserved_runner = |id, disk_size| PreservedRunner {
+        id,
+        profile_name: "servo-ubuntu2204".to_owned(),
+        reason: "test".to_owned(),
+        preserved_at: Utc::now(),
+        disk_size: Some(disk_size),
+    };
+    let preserved_runners = [preserved_runner(1, 30), preserved_runner(2, 30)];
+
+    // Nothing is discarded if everything fits.
+    assert!(preserved_runners_to_discard(&preserved_runners, 0, 60)?.is_empty());
+    assert!(preserved_runners_to_discard(&preserved_runners, 40, 100)?.is_empty());
+
+    // The oldest are discarded first, to make room for the new one.
+    assert_eq!(
+        preserved_runners_to_discard(&preserved_runners, 41, 100)?,
+        [1]
+    );
+    assert_eq!(
+        preserved_runners_to_discard(&preserved_runners, 0, 59)?,
+        [1]
+    );
+    assert_eq!(
+        preserved_runners_to_discard(&preserved_runners, 100, 100)?,
+        [1, 2]
+    );
+
+    // Runners that would never fit are not preserved at all.
+    assert!(preserved_runners_to_discard(&preserved_runners, 101, 100).is_err());
+
+    Ok(())
+}
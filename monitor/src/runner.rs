@@ -8,20 +8,36 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use bytesize::ByteSize;
 use cfg_if::cfg_if;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
-use jane_eyre::eyre::{self, bail};
-use mktemp::Temp;
-use monitor::github::{reserve_runner, ApiRunner};
+use jane_eyre::eyre::{self, bail, OptionExt};
+use monitor::{
+    clock,
+    github::{reserve_runner, ApiRunner, ApiRunnerLabel},
+};
 use serde::{Deserialize, Serialize};
 use settings::{profile::ImageType, TOML};
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
     data::get_runner_data_path,
-    libvirt::{get_ipv4_address, take_screenshot, update_screenshot},
+    libvirt::{get_display_port, get_ipv4_address},
+    screenshots::ScreenshotGuest,
 };
 
+/// Marker file in the runner data directory, containing the reason the runner was flagged for
+/// preservation.
+pub const PRESERVE_FLAG_FILENAME: &str = "preserve";
+
+/// File in the runner data directory, containing the [`RunnerHold`] of the runner, if any.
+const HOLD_FILENAME: &str = "hold.toml";
+
+/// Marker file in the runner data directory, if the runner is a warm runner that has yet to be
+/// registered and started.
+pub const WARM_FLAG_FILENAME: &str = "warm";
+
 #[derive(Debug, Serialize)]
 pub struct Runners {
     runners: BTreeMap<usize, Runner>,
@@ -38,37 +54,141 @@ pub struct Runner {
     #[serde(skip)]
     github_jitconfig: Option<String>,
     details: RunnerDetails,
+    heartbeat: Option<Heartbeat>,
+    heartbeat_time: Option<SystemTime>,
+    /// When we first saw the runner online, if it has come online.
+    online_since: Option<SystemTime>,
+    /// Why the runner was flagged for preservation (POST /runner/<id>/preserve), if at all.
+    preserve_flag: Option<String>,
+    hold: Option<RunnerHold>,
+    /// Whether the runner was cloned for the warm pool, and has yet to be registered and started.
+    warm: bool,
+    /// How far the runner has got, if we are still provisioning it.
+    provisioning_stage: Option<ProvisioningStage>,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct RunnerDetails {
     image_type: ImageType,
+    /// The base image snapshot that the runner was created from, if known.
+    #[serde(default)]
+    base_image_snapshot: Option<String>,
+}
+
+/// Health report sent periodically by the boot script in a runner guest (POST /heartbeat).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Heartbeat {
+    /// Free space on the disk where jobs run, in bytes.
+    pub disk_free: u64,
+    /// Load average over the last minute, or CPU usage percentage on Windows.
+    pub load: f64,
+    /// Whether the GitHub Actions runner process is running.
+    pub runner_alive: bool,
+}
+
+/// Hold on a runner for interactive debugging (POST /runner/<id>/hold).
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RunnerHold {
+    pub held_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// How to connect to a held runner, via the monitor host.
+#[derive(Debug, Serialize)]
+pub struct RunnerHoldDetails {
+    pub runner_id: usize,
+    pub guest_name: String,
+    pub ipv4_address: Option<Ipv4Addr>,
+    pub hold: RunnerHold,
+    /// Command to ssh into the guest, using the monitor host as a jump host.
+    pub ssh_command: Option<String>,
+    /// Command to forward the graphical console of the guest to your machine, if any.
+    pub display_tunnel_command: Option<String>,
+    /// URI to open with a SPICE or VNC client, once the tunnel is running.
+    pub display_uri: Option<String>,
+}
+
+/// Stages of provisioning a runner, from being queued until the runner comes online.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisioningStage {
+    /// Waiting for a runner worker.
+    Queued,
+    /// Registering the runner with GitHub.
+    Registering,
+    /// Cloning the libvirt guest and its images.
+    Cloning,
+    /// Starting the libvirt guest.
+    Starting,
+    /// Waiting for the runner to come online, for up to `monitor_start_timeout`.
+    WaitingForOnline,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Status {
     Invalid,
+    /// Being created by a runner worker, or started by us but not yet online. Provisioning
+    /// runners are never destroyed, and count towards the target like healthy runners.
+    Provisioning,
+    /// Cloned for the warm pool, but unregistered and shut off until it’s needed. Warm runners
+    /// don’t count towards the target.
+    Warm,
     StartedOrCrashed,
     Idle,
     Reserved,
     Busy,
     DoneOrUnregistered,
+    /// Idle or reserved according to GitHub, but the guest has stopped sending heartbeats or
+    /// reported that it can’t run jobs.
+    Unresponsive,
+    /// Held for interactive debugging, and not busy. Held runners can’t be reserved, and are not
+    /// destroyed until their hold expires.
+    Held,
+}
+
+impl Status {
+    /// Whether runners with this status count as healthy in
+    /// [`Policy::healthy_runner_count`](crate::policy::Policy::healthy_runner_count).
+    pub fn is_healthy(&self) -> bool {
+        matches!(
+            self,
+            Status::Provisioning
+                | Status::StartedOrCrashed
+                | Status::Idle
+                | Status::Reserved
+                | Status::Busy
+                | Status::DoneOrUnregistered
+                | Status::Held
+        )
+    }
 }
 
 impl Runners {
     pub fn new(registrations: Vec<ApiRunner>, guest_names: Vec<String>) -> Self {
-        // Gather all known runner ids with live resources.
-        let registration_ids = registrations
-            .iter()
-            .flat_map(|registration| registration.name.rsplit_once('@'))
-            .flat_map(|(name, _host)| name.rsplit_once('.'))
-            .flat_map(|(_, id)| id.parse())
-            .collect::<Vec<usize>>();
-        let guest_ids = guest_names
-            .iter()
-            .flat_map(|guest| guest.rsplit_once('.'))
-            .flat_map(|(_, id)| id.parse())
-            .collect::<Vec<usize>>();
+        // Gather all known runner ids with live resources. Keep each id with the resource it came
+        // from, so that resources with unparseable names can’t shift the rest out of alignment.
+        let registrations = registrations
+            .into_iter()
+            .filter_map(|registration| {
+                let Some(id) = registration_runner_id(&registration.name) else {
+                    debug!(registration_name = %registration.name, "Ignoring registration with unparseable name");
+                    return None;
+                };
+                Some((id, registration))
+            })
+            .collect::<Vec<_>>();
+        let guest_names = guest_names
+            .into_iter()
+            .filter_map(|guest_name| {
+                let Some(id) = guest_runner_id(&guest_name) else {
+                    debug!(guest_name, "Ignoring guest with unparseable name");
+                    return None;
+                };
+                Some((id, guest_name))
+            })
+            .collect::<Vec<_>>();
+        let registration_ids = registrations.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let guest_ids = guest_names.iter().map(|(id, _)| *id).collect::<Vec<_>>();
         let ids: BTreeSet<usize> = registration_ids
             .iter()
             .copied()
@@ -94,13 +214,13 @@ impl Runners {
         }
 
         // Populate the tracking objects with references to live resources.
-        for (id, registration) in registration_ids.iter().zip(registrations) {
-            if let Some(runner) = runners.get_mut(id) {
+        for (id, registration) in registrations {
+            if let Some(runner) = runners.get_mut(&id) {
                 runner.registration = Some(registration);
             }
         }
-        for (id, guest_name) in guest_ids.iter().zip(guest_names) {
-            if let Some(runner) = runners.get_mut(id) {
+        for (id, guest_name) in guest_names {
+            if let Some(runner) = runners.get_mut(&id) {
                 let ipv4_address = runner_ipv4_address(&guest_name);
                 runner.guest_name = Some(guest_name);
                 runner.ipv4_address = ipv4_address;
@@ -110,6 +230,20 @@ impl Runners {
         Self { runners }
     }
 
+    /// Records how far each runner has got, if we are still provisioning it.
+    pub fn set_provisioning_stages(&mut self, stages: &BTreeMap<usize, ProvisioningStage>) {
+        for (id, runner) in self.runners.iter_mut() {
+            runner.provisioning_stage = stages.get(id).copied();
+        }
+    }
+
+    /// Records when we first saw each runner online, if it has come online.
+    pub fn set_online_since(&mut self, online_since: &BTreeMap<usize, SystemTime>) {
+        for (id, runner) in self.runners.iter_mut() {
+            runner.online_since = online_since.get(id).copied();
+        }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&usize, &Runner)> {
         self.runners.iter()
     }
@@ -125,56 +259,52 @@ impl Runners {
     }
 
     pub fn reserve_runner(
-        &self,
+        &mut self,
         id: usize,
         unique_id: &str,
         qualified_repo: &str,
         run_id: &str,
     ) -> eyre::Result<()> {
-        let Some(runner) = self.runners.get(&id) else {
+        let Some(runner) = self.runners.get_mut(&id) else {
             bail!("No runner with id exists: {id}");
         };
-        let Some(registration) = runner.registration() else {
+        let Some(registration) = runner.registration.as_mut() else {
             bail!("Tried to reserve an unregistered runner");
         };
         info!(runner_id = id, registration.id, "Reserving runner");
         let reserved_by = format!("{qualified_repo}/actions/runs/{run_id}");
-        reserve_runner(registration.id, unique_id, SystemTime::now(), &reserved_by)
-    }
-
-    pub fn screenshot_runner(&self, id: usize) -> eyre::Result<Temp> {
-        let Some(runner) = self.runners.get(&id) else {
-            bail!("No runner with id exists: {id}");
-        };
-        let Some(guest_name) = runner.guest_name.as_deref() else {
-            bail!("Tried to screenshot a runner with no libvirt guest: {id}");
-        };
-        let result = Temp::new_file()?;
-        let output_path = result.clone();
-        take_screenshot(guest_name, &output_path)?;
+        let reserved_since = clock::now();
+        reserve_runner(registration.id, unique_id, reserved_since, &reserved_by)?;
+
+        // Update our copy of the labels too, so the runner is no longer Idle if another request
+        // wants to take a runner before we next list the registrations.
+        let reserved_since = reserved_since.duration_since(UNIX_EPOCH)?.as_secs();
+        registration.labels.extend(
+            [
+                format!("reserved-for:{unique_id}"),
+                format!("reserved-since:{reserved_since}"),
+                format!("reserved-by:{reserved_by}"),
+            ]
+            .map(|name| ApiRunnerLabel { name }),
+        );
 
-        Ok(result)
+        Ok(())
     }
 
-    pub fn update_screenshots(&self) {
-        for &id in self.runners.keys() {
-            if let Err(error) = self.update_screenshot(id) {
-                error!(id, ?error, "Failed to update screenshot for runner");
+    /// Returns where to keep the screenshots for each runner guest.
+    pub fn screenshot_guests(&self) -> Vec<ScreenshotGuest> {
+        let mut result = vec![];
+        for (&id, runner) in self.runners.iter() {
+            let Some(guest_name) = runner.guest_name.as_deref() else {
+                continue;
+            };
+            match ScreenshotGuest::runner(id, guest_name) {
+                Ok(guest) => result.push(guest),
+                Err(error) => error!(id, ?error, "Failed to compute screenshot paths for runner"),
             }
         }
-    }
-
-    fn update_screenshot(&self, id: usize) -> eyre::Result<()> {
-        let Some(runner) = self.runners.get(&id) else {
-            bail!("No runner with id exists: {id}");
-        };
-        let Some(guest_name) = runner.guest_name.as_deref() else {
-            bail!("Tried to screenshot a runner with no libvirt guest: {id}");
-        };
-        let output_dir = get_runner_data_path(id, None)?;
-        update_screenshot(guest_name, &output_dir)?;
 
-        Ok(())
+        result
     }
 
     pub fn github_jitconfig(
@@ -192,6 +322,123 @@ impl Runners {
         bail!("No runner found with IP address: {}", remote_addr)
     }
 
+    pub fn record_heartbeat(
+        &self,
+        remote_addr: web::auth::RemoteAddr,
+        heartbeat: &Heartbeat,
+    ) -> eyre::Result<()> {
+        for (&id, runner) in self.runners.iter() {
+            if let Some(ipv4_address) = runner.ipv4_address {
+                if remote_addr == ipv4_address {
+                    let path = get_runner_data_path(id, Path::new("heartbeat.json"))?;
+                    serde_json::to_writer(File::create(path)?, heartbeat)?;
+                    return Ok(());
+                }
+            }
+        }
+
+        bail!("No runner found with IP address: {}", remote_addr)
+    }
+
+    /// Flags a runner for preservation, so it will be destroyed once it’s not busy, keeping its
+    /// disk for debugging.
+    pub fn flag_runner_for_preservation(&self, id: usize, reason: &str) -> eyre::Result<()> {
+        if !self.runners.contains_key(&id) {
+            bail!("No runner with id exists: {id}");
+        }
+        info!(runner_id = id, reason, "Flagging runner for preservation");
+        let path = get_runner_data_path(id, Path::new(PRESERVE_FLAG_FILENAME))?;
+        std::fs::write(path, reason)?;
+
+        Ok(())
+    }
+
+    /// Holds a runner for interactive debugging, or extends its hold, until the given TTL elapses.
+    pub fn hold_runner(&mut self, id: usize, ttl: Duration) -> eyre::Result<RunnerHoldDetails> {
+        let Some(runner) = self.runners.get_mut(&id) else {
+            bail!("No runner with id exists: {id}");
+        };
+        let Some(guest_name) = runner.guest_name.clone() else {
+            bail!("Tried to hold a runner with no libvirt guest: {id}");
+        };
+        if runner.hold_expired() {
+            bail!("Hold has already expired for runner: {id}");
+        }
+        if runner.status() == Status::Reserved {
+            bail!("Tried to hold a reserved runner, which may be about to take a job: {id}");
+        }
+        let hold = RunnerHold {
+            held_at: runner
+                .hold
+                .as_ref()
+                .map_or_else(clock::utc_now, |hold| hold.held_at),
+            expires_at: clock::utc_now() + ttl,
+        };
+        info!(runner_id = id, ?hold, "Holding runner");
+        write_hold(id, &hold)?;
+        runner.hold = Some(hold.clone());
+
+        let ssh_destination = match TOML.runner_hold_ssh_destination() {
+            Some(destination) => destination.to_owned(),
+            None => TOML
+                .external_base_url
+                .parse::<http::Uri>()?
+                .host()
+                .ok_or_eyre("external_base_url has no host")?
+                .trim_matches(['[', ']'])
+                .to_owned(),
+        };
+        let ssh_command = runner
+            .ipv4_address
+            .map(|address| format!("ssh -J {ssh_destination} {address}"));
+        let display_port = match get_display_port(&guest_name, None) {
+            Ok(result) => result,
+            Err(error) => {
+                warn!(?error, "Failed to get display port of runner");
+                None
+            }
+        };
+
+        Ok(RunnerHoldDetails {
+            runner_id: id,
+            guest_name,
+            ipv4_address: runner.ipv4_address,
+            hold,
+            ssh_command,
+            display_tunnel_command: display_port
+                .as_ref()
+                .map(|(_, port)| format!("ssh -NL {port}:127.0.0.1:{port} {ssh_destination}")),
+            display_uri: display_port.map(|(scheme, port)| format!("{scheme}://127.0.0.1:{port}")),
+        })
+    }
+
+    /// Releases a held runner, so it will be destroyed as if its hold had expired.
+    pub fn release_runner(&mut self, id: usize) -> eyre::Result<RunnerHold> {
+        let Some(runner) = self.runners.get_mut(&id) else {
+            bail!("No runner with id exists: {id}");
+        };
+        let Some(hold) = runner.hold.as_ref() else {
+            bail!("Runner is not held: {id}");
+        };
+        let hold = RunnerHold {
+            held_at: hold.held_at,
+            expires_at: clock::utc_now(),
+        };
+        info!(runner_id = id, ?hold, "Releasing runner");
+        write_hold(id, &hold)?;
+        runner.hold = Some(hold.clone());
+
+        Ok(hold)
+    }
+
+    /// Returns whether any runner guest is known to have the given address.
+    pub fn has_ipv4_address(&self, remote_addr: &web::auth::RemoteAddr) -> bool {
+        self.runners
+            .values()
+            .filter_map(|runner| runner.ipv4_address)
+            .any(|ipv4_address| *remote_addr == ipv4_address)
+    }
+
     pub fn update_ipv4_addresses(&mut self) {
         for (&id, runner) in self.runners.iter_mut() {
             if let Some(guest_name) = runner.guest_name.as_deref() {
@@ -241,6 +488,39 @@ impl Runner {
 
         let details = runner_details(id)?;
 
+        let (heartbeat, heartbeat_time) = match read_heartbeat(id) {
+            Ok(Some((heartbeat, heartbeat_time))) => (Some(heartbeat), Some(heartbeat_time)),
+            Ok(None) => (None, None),
+            Err(error) => {
+                warn!(?error, "Failed to read heartbeat of runner");
+                (None, None)
+            }
+        };
+
+        let hold = match read_hold(id) {
+            Ok(result) => result,
+            Err(error) => {
+                warn!(?error, "Failed to read hold of runner");
+                None
+            }
+        };
+
+        let warm = match read_warm_flag(id) {
+            Ok(result) => result,
+            Err(error) => {
+                warn!(?error, "Failed to read warm flag of runner");
+                false
+            }
+        };
+
+        let preserve_flag = match read_preserve_flag(id) {
+            Ok(result) => result,
+            Err(error) => {
+                warn!(?error, "Failed to read preserve flag of runner");
+                None
+            }
+        };
+
         Ok(Self {
             id,
             created_time,
@@ -249,13 +529,129 @@ impl Runner {
             ipv4_address: None,
             github_jitconfig: github_jitconfig,
             details,
+            heartbeat,
+            heartbeat_time,
+            online_since: None,
+            preserve_flag,
+            hold,
+            warm,
+            provisioning_stage: None,
         })
     }
 
+    pub fn guest_name(&self) -> Option<&str> {
+        self.guest_name.as_deref()
+    }
+
     pub fn registration(&self) -> Option<&ApiRunner> {
         self.registration.as_ref()
     }
 
+    pub fn base_image_snapshot(&self) -> Option<&str> {
+        self.details.base_image_snapshot.as_deref()
+    }
+
+    pub fn provisioning_stage(&self) -> Option<ProvisioningStage> {
+        self.provisioning_stage
+    }
+
+    pub fn heartbeat(&self) -> Option<&Heartbeat> {
+        self.heartbeat.as_ref()
+    }
+
+    /// Returns why the guest seems unable to run jobs, based on its heartbeats, if at all.
+    ///
+    /// Runners that have never sent a heartbeat are left to GitHub’s idea of their status, since
+    /// their images may predate heartbeats. Heartbeats sent before the runner came online are
+    /// only used to tell whether the guest is still alive, since the runner process may not have
+    /// started yet when they were sent.
+    pub fn unresponsive_reason(&self) -> Option<String> {
+        let heartbeat = self.heartbeat.as_ref()?;
+        let heartbeat_time = self.heartbeat_time?;
+        let since_heartbeat = clock::elapsed_since_time(heartbeat_time).unwrap_or_default();
+        if since_heartbeat > TOML.runner_heartbeat_timeout() {
+            Some(format!("no heartbeat for {since_heartbeat:?}"))
+        } else if self
+            .online_since
+            .is_none_or(|online_since| heartbeat_time < online_since)
+        {
+            None
+        } else if !heartbeat.runner_alive {
+            Some("runner process not running".to_owned())
+        } else if heartbeat.disk_free < TOML.runner_min_disk_free() {
+            Some(format!(
+                "only {} disk free",
+                ByteSize::b(heartbeat.disk_free)
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn hold(&self) -> Option<&RunnerHold> {
+        self.hold.as_ref()
+    }
+
+    /// Returns whether the runner has a hold that has not yet expired.
+    pub fn is_held(&self) -> bool {
+        self.hold
+            .as_ref()
+            .is_some_and(|hold| hold.expires_at > clock::utc_now())
+    }
+
+    /// Returns whether the runner had a hold that has since expired.
+    pub fn hold_expired(&self) -> bool {
+        self.hold
+            .as_ref()
+            .is_some_and(|hold| hold.expires_at <= clock::utc_now())
+    }
+
+    /// Returns how long until the status of the runner would change on its own, because it has
+    /// been starting, silent, or held for too long, if any of those apply.
+    pub fn time_until_next_timeout(&self) -> Option<Duration> {
+        let mut result = vec![];
+        if matches!(
+            self.status(),
+            Status::Provisioning | Status::StartedOrCrashed
+        ) {
+            if let Ok(age) = self.age() {
+                result.push(TOML.monitor_start_timeout().saturating_sub(age));
+            }
+        }
+        if let Some(heartbeat_time) = self.heartbeat_time {
+            let since_heartbeat = clock::elapsed_since_time(heartbeat_time).unwrap_or_default();
+            result.push(
+                TOML.runner_heartbeat_timeout()
+                    .saturating_sub(since_heartbeat),
+            );
+        }
+        if let Some(hold) = self.hold.as_ref().filter(|_| self.is_held()) {
+            result.extend((hold.expires_at - clock::utc_now()).to_std().ok());
+        }
+
+        result.into_iter().min()
+    }
+
+    pub fn preserve_flag(&self) -> Option<&str> {
+        self.preserve_flag.as_deref()
+    }
+
+    /// Returns why the runner should be preserved for debugging when destroyed, if at all.
+    pub fn preserve_reason(&self) -> Option<String> {
+        if let Some(reason) = self.preserve_flag() {
+            return Some(reason.to_owned());
+        }
+        if TOML.preserve_failed_runners() && self.status() == Status::StartedOrCrashed {
+            if let Ok(age) = self.age() {
+                if age > TOML.monitor_start_timeout() {
+                    return Some(format!("crashed: not online after {age:?}"));
+                }
+            }
+        }
+
+        None
+    }
+
     pub fn log_info(&self) {
         fn fmt_option_display<T: Display>(x: Option<T>) -> String {
             x.map_or("None".to_owned(), |x| format!("{}", x))
@@ -273,6 +669,15 @@ impl Runner {
             self.github_jitconfig.as_ref().map_or("no", |_| "yes"),
             fmt_option_debug(self.reserved_since().ok().flatten()),
         );
+        if let Some(reason) = self.unresponsive_reason() {
+            info!("[{}] - unresponsive: {reason}", self.id);
+        }
+        if let Some(reason) = self.preserve_flag() {
+            info!("[{}] - flagged for preservation: {reason}", self.id);
+        }
+        if let Some(hold) = self.hold() {
+            info!("[{}] - held until {}", self.id, hold.expires_at);
+        }
         if let Some(registration) = self.registration() {
             if !registration.labels.is_empty() {
                 info!(
@@ -291,7 +696,7 @@ impl Runner {
     }
 
     pub fn age(&self) -> eyre::Result<Duration> {
-        Ok(self.created_time.elapsed()?)
+        Ok(clock::elapsed_since_time(self.created_time)?)
     }
 
     pub fn reserved_since(&self) -> eyre::Result<Option<Duration>> {
@@ -299,7 +704,7 @@ impl Runner {
             if let Some(reserved_since) = registration.label_with_key("reserved-since") {
                 let reserved_since = reserved_since.parse::<u64>()?;
                 let reserved_since = UNIX_EPOCH + Duration::from_secs(reserved_since);
-                return Ok(Some(reserved_since.elapsed()?));
+                return Ok(Some(clock::elapsed_since_time(reserved_since)?));
             }
         }
 
@@ -307,9 +712,22 @@ impl Runner {
     }
 
     pub fn status(&self) -> Status {
+        if self.provisioning_stage.is_some() && !self.has_come_online() {
+            return Status::Provisioning;
+        }
         if self.guest_name.is_none() {
             return Status::Invalid;
         };
+        if self.warm && self.registration.is_none() {
+            return Status::Warm;
+        }
+        let busy = self
+            .registration
+            .as_ref()
+            .is_some_and(|registration| registration.busy);
+        if self.is_held() && !busy {
+            return Status::Held;
+        }
         let Some(registration) = &self.registration else {
             return Status::DoneOrUnregistered;
         };
@@ -317,14 +735,29 @@ impl Runner {
             return Status::Busy;
         }
         if registration.label_with_key("reserved-for").is_some() {
+            if self.unresponsive_reason().is_some() {
+                return Status::Unresponsive;
+            }
             return Status::Reserved;
         }
         if registration.status == "online" {
+            if self.unresponsive_reason().is_some() {
+                return Status::Unresponsive;
+            }
             return Status::Idle;
         }
         return Status::StartedOrCrashed;
     }
 
+    /// Returns whether GitHub has ever seen the runner online, as far as we can tell.
+    pub fn has_come_online(&self) -> bool {
+        self.registration.as_ref().is_some_and(|registration| {
+            registration.busy
+                || registration.status == "online"
+                || registration.label_with_key("reserved-for").is_some()
+        })
+    }
+
     pub fn profile_name(&self) -> &str {
         self.profile_name_from_registration()
             .or_else(|| self.profile_name_from_guest_name())
@@ -353,6 +786,43 @@ impl Runner {
     }
 }
 
+/// Returns the runner id in the name of a registration, `<guest name>@<github_api_suffix>`.
+pub fn registration_runner_id(registration_name: &str) -> Option<usize> {
+    let (guest_name, _suffix) = registration_name.rsplit_once('@')?;
+    guest_runner_id(guest_name)
+}
+
+/// Returns the runner id in the name of a runner guest, `<prefix>-<profile>.<id>`.
+pub fn guest_runner_id(guest_name: &str) -> Option<usize> {
+    let (_base, id) = guest_name.rsplit_once('.')?;
+    id.parse().ok()
+}
+
+#[test]
+fn test_runner_ids_from_names() {
+    assert_eq!(guest_runner_id("ci-runner-servo-ubuntu2204.42"), Some(42));
+    assert_eq!(guest_runner_id("ci-runner-servo-ubuntu2204"), None);
+    assert_eq!(
+        registration_runner_id("ci-runner-servo-ubuntu2204.42@host.example"),
+        Some(42)
+    );
+    assert_eq!(
+        registration_runner_id("ci-runner-servo-ubuntu2204.42"),
+        None
+    );
+    assert_eq!(
+        registration_runner_id("someone-elses-runner@host.example"),
+        None
+    );
+}
+
+fn write_hold(id: usize, hold: &RunnerHold) -> eyre::Result<()> {
+    let path = get_runner_data_path(id, Path::new(HOLD_FILENAME))?;
+    std::fs::write(path, toml::to_string(hold)?)?;
+
+    Ok(())
+}
+
 cfg_if! {
     if #[cfg(not(test))] {
         use monitor::github::ApiGenerateJitconfigResponse;
@@ -386,16 +856,83 @@ cfg_if! {
             }
         }
 
+        fn read_heartbeat(id: usize) -> eyre::Result<Option<(Heartbeat, SystemTime)>> {
+            let path = get_runner_data_path(id, Path::new("heartbeat.json"))?;
+            let Ok(file) = File::open(&path) else {
+                return Ok(None);
+            };
+            let heartbeat_time = file.metadata()?.modified()?;
+
+            Ok(Some((serde_json::from_reader(file)?, heartbeat_time)))
+        }
+
+        fn read_preserve_flag(id: usize) -> eyre::Result<Option<String>> {
+            let path = get_runner_data_path(id, Path::new(PRESERVE_FLAG_FILENAME))?;
+            let Ok(mut file) = File::open(&path) else {
+                return Ok(None);
+            };
+            let mut result = String::default();
+            file.read_to_string(&mut result)?;
+
+            Ok(Some(result))
+        }
+
+        fn read_warm_flag(id: usize) -> eyre::Result<bool> {
+            let path = get_runner_data_path(id, Path::new(WARM_FLAG_FILENAME))?;
+
+            Ok(path.try_exists()?)
+        }
+
+        fn read_hold(id: usize) -> eyre::Result<Option<RunnerHold>> {
+            let path = get_runner_data_path(id, Path::new(HOLD_FILENAME))?;
+            let Ok(mut file) = File::open(&path) else {
+                return Ok(None);
+            };
+            let mut contents = String::default();
+            file.read_to_string(&mut contents)?;
+
+            Ok(Some(toml::from_str(&contents)?))
+        }
+
         fn runner_ipv4_address(guest_name: &String) -> Option<Ipv4Addr> {
             get_ipv4_address(guest_name)
         }
     } else {
         use std::cell::RefCell;
 
-        use jane_eyre::eyre::OptionExt;
+        /// The contents of the data directory of a fake runner.
+        #[derive(Clone, Debug, Default)]
+        pub(crate) struct FakeRunnerData {
+            pub base_image_snapshot: Option<String>,
+            pub heartbeat: Option<(Heartbeat, SystemTime)>,
+            pub preserve_flag: Option<String>,
+            pub hold: Option<RunnerHold>,
+            pub warm: bool,
+        }
 
         thread_local! {
-            static RUNNER_CREATED_TIMES: RefCell<BTreeMap<usize, SystemTime>> = RefCell::new(BTreeMap::new());
+            static FAKE_RUNNER_DATA: RefCell<BTreeMap<usize, (SystemTime, FakeRunnerData)>> = const { RefCell::new(BTreeMap::new()) };
+        }
+
+        /// Replaces the data directories of all fake runners. Like real data directories, those
+        /// of new runners are created at the current [`clock::now`], and those of runners that
+        /// are missing are deleted.
+        pub(crate) fn set_fake_runner_data_for_test(runner_data: BTreeMap<usize, FakeRunnerData>) {
+            FAKE_RUNNER_DATA.with_borrow_mut(|all_data| {
+                *all_data = runner_data
+                    .into_iter()
+                    .map(|(id, data)| {
+                        let created_time = all_data
+                            .get(&id)
+                            .map_or_else(clock::now, |&(created_time, _)| created_time);
+                        (id, (created_time, data))
+                    })
+                    .collect();
+            });
+        }
+
+        fn fake_runner_data<T>(id: usize, f: impl FnOnce(&FakeRunnerData) -> T) -> Option<T> {
+            FAKE_RUNNER_DATA.with_borrow(|all_data| all_data.get(&id).map(|(_, data)| f(data)))
         }
 
         fn read_github_jitconfig(_id: usize) -> eyre::Result<String> {
@@ -403,23 +940,32 @@ cfg_if! {
         }
 
         fn runner_created_time(id: usize) -> eyre::Result<SystemTime> {
-            RUNNER_CREATED_TIMES.with_borrow(|created_times| {
-                created_times.get(&id).copied().ok_or_eyre("Failed to check runner created time (fake)")
+            FAKE_RUNNER_DATA.with_borrow(|all_data| {
+                all_data.get(&id).map(|&(created_time, _)| created_time).ok_or_eyre("Failed to check runner created time (fake)")
             })
         }
 
-        pub(crate) fn set_runner_created_time_for_test(id: usize, created_time: impl Into<Option<SystemTime>>) {
-            RUNNER_CREATED_TIMES.with_borrow_mut(|created_times| {
-                if let Some(created_time) = created_time.into() {
-                    created_times.insert(id, created_time);
-                } else {
-                    created_times.remove(&id);
-                }
-            });
+        fn runner_details(id: usize) -> eyre::Result<RunnerDetails> {
+            Ok(RunnerDetails {
+                base_image_snapshot: fake_runner_data(id, |data| data.base_image_snapshot.clone()).flatten(),
+                ..RunnerDetails::default()
+            })
+        }
+
+        fn read_heartbeat(id: usize) -> eyre::Result<Option<(Heartbeat, SystemTime)>> {
+            Ok(fake_runner_data(id, |data| data.heartbeat.clone()).flatten())
+        }
+
+        fn read_preserve_flag(id: usize) -> eyre::Result<Option<String>> {
+            Ok(fake_runner_data(id, |data| data.preserve_flag.clone()).flatten())
+        }
+
+        fn read_warm_flag(id: usize) -> eyre::Result<bool> {
+            Ok(fake_runner_data(id, |data| data.warm).unwrap_or(false))
         }
 
-        fn runner_details(_id: usize) -> eyre::Result<RunnerDetails> {
-            Ok(RunnerDetails::default())
+        fn read_hold(id: usize) -> eyre::Result<Option<RunnerHold>> {
+            Ok(fake_runner_data(id, |data| data.hold.clone()).flatten())
         }
 
         fn runner_ipv4_address(_guest_name: &String) -> Option<Ipv4Addr> {
@@ -0,0 +1,252 @@
+//! History of screenshots for runner and rebuild guests, for time-lapses.
+//!
+//! Each time a guest’s `screenshot.png` is updated, we hard link it into a `screenshots`
+//! directory as `<unix time in ms>-<content hash>.png`, unless it’s identical to the previous
+//! screenshot, then delete the oldest screenshots beyond `screenshot_history_length`.
+//!
+//! Screenshots are taken by [`screenshot_thread`], so that `virsh screenshot` never blocks the
+//! monitor thread. The monitor thread only tells it which guests exist, and where to put their
+//! screenshots, via [`set_screenshot_guests`].
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    fs::{create_dir_all, hard_link, read_dir, remove_file},
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use jane_eyre::eyre;
+use serde::Serialize;
+use settings::{profile::parse_rebuild_guest_name, TOML};
+use tracing::{debug, error, trace};
+
+use crate::{
+    data::{get_profile_data_path, get_runner_data_path, get_snapshot_data_path},
+    libvirt::{list_running_guests, update_screenshot},
+};
+
+static SCREENSHOT_GUESTS: RwLock<Vec<ScreenshotGuest>> = RwLock::new(vec![]);
+
+/// A runner or rebuild guest, and where to keep its screenshots.
+#[derive(Clone, Debug)]
+pub struct ScreenshotGuest {
+    pub guest_name: String,
+    /// Directory for the latest `screenshot.png`.
+    pub output_dir: PathBuf,
+    pub history_dir: PathBuf,
+}
+
+impl ScreenshotGuest {
+    pub fn runner(runner_id: usize, guest_name: &str) -> eyre::Result<Self> {
+        Ok(Self {
+            guest_name: guest_name.to_owned(),
+            output_dir: get_runner_data_path(runner_id, None)?,
+            history_dir: runner_screenshot_history_path(runner_id)?,
+        })
+    }
+
+    pub fn rebuild(profile_key: &str, guest_name: &str) -> eyre::Result<Self> {
+        let (_, snapshot_name) = parse_rebuild_guest_name(guest_name)?;
+        Ok(Self {
+            guest_name: guest_name.to_owned(),
+            output_dir: get_profile_data_path(profile_key, None)?,
+            history_dir: rebuild_screenshot_history_path(profile_key, snapshot_name)?,
+        })
+    }
+}
+
+/// Replaces the guests that [`screenshot_thread`] takes screenshots of.
+pub fn set_screenshot_guests(guests: Vec<ScreenshotGuest>) {
+    let mut screenshot_guests = SCREENSHOT_GUESTS.write().unwrap_or_else(|error| {
+        // The guests are replaced wholesale, so they can’t be left half updated by a panic.
+        error!(?error, "Failed to acquire RwLock");
+        SCREENSHOT_GUESTS.clear_poison();
+        error.into_inner()
+    });
+    *screenshot_guests = guests;
+}
+
+/// Takes screenshots of running guests, at most once per `screenshot_interval` for each guest,
+/// and with at most `screenshot_concurrency` screenshots in flight at once.
+pub fn screenshot_thread() {
+    let mut last_taken = BTreeMap::<String, Instant>::default();
+    loop {
+        let started_at = Instant::now();
+        let guests = SCREENSHOT_GUESTS
+            .read()
+            .unwrap_or_else(|error| {
+                // Keep taking screenshots, rather than leaving them stale until we restart.
+                error!(?error, "Failed to acquire RwLock");
+                SCREENSHOT_GUESTS.clear_poison();
+                error.into_inner()
+            })
+            .clone();
+        // Forget guests that no longer exist, so their names can be reused.
+        last_taken.retain(|guest_name, _| guests.iter().any(|g| &g.guest_name == guest_name));
+
+        // Guests that are shut off would only fail, and slowly.
+        let running_guests = match list_running_guests() {
+            Ok(result) => result.into_iter().collect::<BTreeSet<_>>(),
+            Err(error) => {
+                error!(?error, "Failed to list running guests: {error}");
+                thread::sleep(TOML.screenshot_interval());
+                continue;
+            }
+        };
+        let due_guests = guests
+            .into_iter()
+            .filter(|guest| running_guests.contains(&guest.guest_name))
+            .filter(|guest| {
+                last_taken
+                    .get(&guest.guest_name)
+                    .is_none_or(|t| t.elapsed() >= TOML.screenshot_interval())
+            })
+            .collect::<Vec<_>>();
+        trace!(count = due_guests.len(), "Taking screenshots");
+        for guest in due_guests.iter() {
+            last_taken.insert(guest.guest_name.clone(), Instant::now());
+        }
+        take_screenshots(due_guests);
+
+        // Wake up often enough to honour the interval for each guest, but don’t spin.
+        let elapsed = started_at.elapsed();
+        thread::sleep(Duration::from_secs(1).saturating_sub(elapsed));
+    }
+}
+
+fn take_screenshots(guests: Vec<ScreenshotGuest>) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    for guest in guests {
+        let _ = sender.send(guest);
+    }
+    drop(sender);
+
+    thread::scope(|scope| {
+        for _ in 0..TOML.screenshot_concurrency().max(1) {
+            let receiver = receiver.clone();
+            scope.spawn(move || {
+                for guest in receiver {
+                    let ScreenshotGuest {
+                        guest_name,
+                        output_dir,
+                        history_dir,
+                    } = &guest;
+                    if let Err(error) = update_screenshot(guest_name, output_dir, history_dir) {
+                        debug!(guest_name, ?error, "Failed to update screenshot for guest");
+                    }
+                }
+            });
+        }
+    });
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoricalScreenshot {
+    pub name: String,
+    pub time: DateTime<Utc>,
+    #[serde(skip)]
+    hash: String,
+}
+
+pub fn runner_screenshot_history_path(runner_id: usize) -> eyre::Result<PathBuf> {
+    get_runner_data_path(runner_id, Path::new("screenshots"))
+}
+
+pub fn rebuild_screenshot_history_path(
+    profile_key: &str,
+    snapshot_name: &str,
+) -> eyre::Result<PathBuf> {
+    get_snapshot_data_path(profile_key, snapshot_name, Path::new("screenshots"))
+}
+
+/// Adds the given screenshot to the history, if it differs from the previous one.
+pub fn record_screenshot(screenshot_path: &Path, history_dir: &Path) -> eyre::Result<()> {
+    let mut hasher = DefaultHasher::new();
+    std::fs::read(screenshot_path)?.hash(&mut hasher);
+    let hash = format!("{:016x}", hasher.finish());
+
+    create_dir_all(history_dir)?;
+    let mut history = list_screenshot_history(history_dir)?;
+    if history.last().is_some_and(|last| last.hash == hash) {
+        return Ok(());
+    }
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    hard_link(
+        screenshot_path,
+        history_dir.join(format!("{time}-{hash}.png")),
+    )?;
+
+    // Count the screenshot we just added, but never delete it.
+    let excess = (history.len() + 1).saturating_sub(TOML.screenshot_history_length());
+    for screenshot in history.drain(..excess.min(history.len())) {
+        remove_file(history_dir.join(screenshot.name))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the screenshots in the given history, oldest first.
+pub fn list_screenshot_history(history_dir: &Path) -> eyre::Result<Vec<HistoricalScreenshot>> {
+    let mut result = vec![];
+    let Ok(entries) = read_dir(history_dir) else {
+        return Ok(result);
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(screenshot) = name.to_str().and_then(parse_screenshot_name) else {
+            continue;
+        };
+        result.push(screenshot);
+    }
+    result.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(result)
+}
+
+/// Parses the name of a screenshot in a history, which is also how we validate names in the API.
+pub fn parse_screenshot_name(name: &str) -> Option<HistoricalScreenshot> {
+    let (time, hash) = name.strip_suffix(".png")?.split_once('-')?;
+    let time = DateTime::from_timestamp_millis(time.parse().ok()?)?;
+    if hash.len() != 16 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some(HistoricalScreenshot {
+        name: name.to_owned(),
+        time,
+        hash: hash.to_owned(),
+    })
+}
+
+#[test]
+fn test_parse_screenshot_name() {
+    let screenshot = parse_screenshot_name("1760000000000-0123456789abcdef.png")
+        .expect("Guaranteed by argument");
+    assert_eq!(screenshot.time.timestamp(), 1760000000);
+    assert_eq!(screenshot.hash, "0123456789abcdef");
+    assert!(parse_screenshot_name("1760000000000-0123456789abcdef.ppm").is_none());
+    assert!(parse_screenshot_name("1760000000000-../../etc/passwd.png").is_none());
+    assert!(parse_screenshot_name("screenshot.png").is_none());
+}
+
+#[test]
+fn test_set_screenshot_guests_after_panic() {
+    // Poison the lock, by panicking while holding it.
+    let _ = thread::spawn(|| {
+        let _guests = SCREENSHOT_GUESTS.write();
+        panic!("Poisoning the lock");
+    })
+    .join();
+    assert!(SCREENSHOT_GUESTS.is_poisoned());
+
+    // Setting the guests still works, and recovers the lock for the screenshot thread.
+    set_screenshot_guests(vec![]);
+    assert!(!SCREENSHOT_GUESTS.is_poisoned());
+    assert!(SCREENSHOT_GUESTS
+        .read()
+        .is_ok_and(|guests| guests.is_empty()));
+}
@@ -1,6 +1,6 @@
 use std::{
-    fs::rename,
-    io::{BufRead, BufReader, Read},
+    fs::{rename, File},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
     os::unix::fs::symlink,
     path::Path,
     str,
@@ -8,7 +8,7 @@ use std::{
 
 use jane_eyre::eyre::{self, OptionExt};
 use mktemp::Temp;
-use reflink::reflink_or_copy;
+use reflink::reflink;
 use tracing::{info, trace, warn};
 
 macro_rules! impl_log_output_as {
@@ -73,6 +73,23 @@ macro_rules! impl_log_output_as {
 impl_log_output_as!(log_output_as_trace, trace);
 impl_log_output_as!(log_output_as_info, info);
 
+/// Like `run_cmd!()`, but logs the output of the command (stdout and stderr) from the current
+/// thread, once it exits.
+///
+/// cmd_lib logs stderr from a thread of its own, which has no thread log, so those logs never make
+/// it into the log for an image rebuild. `wait_with_pipe()` would let us log as the output comes
+/// in, but it ignores the exit status of the command.
+macro_rules! run_cmd_logged {
+    ($($cmd:tt)*) => {
+        cmd_lib::spawn_with_output!($($cmd)* 2>&1).and_then(|mut children| {
+            let (result, output, _stderr) = children.wait_with_all();
+            $crate::shell::log_output_as_info(Box::new(std::io::Cursor::new(output)));
+            result
+        })
+    };
+}
+pub(crate) use run_cmd_logged;
+
 pub fn atomic_symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> eyre::Result<()> {
     let link_path = link.as_ref();
     let link_parent = link_path.parent().ok_or_eyre("Link path has no parent")?;
@@ -88,7 +105,8 @@ pub fn reflink_or_copy_with_warning(
 ) -> eyre::Result<()> {
     let original = original.as_ref();
     let new = new.as_ref();
-    if let Some(written) = reflink_or_copy(original, new)? {
+    if reflink(original, new).is_err() {
+        let written = copy_sparse(original, new)?;
         warn!(
             ?original,
             ?new,
@@ -98,3 +116,81 @@ pub fn reflink_or_copy_with_warning(
 
     Ok(())
 }
+
+/// Copy a file without writing its runs of zeros, so sparse disk images stay sparse.
+///
+/// `reflink_or_copy()` falls back to `std::fs::copy()`, which writes every zero of a sparse file,
+/// so on filesystems without reflink support (like ext4), each copy of a 90 GiB image would
+/// write 90 GiB.
+///
+/// Returns the number of bytes actually written.
+fn copy_sparse(original: &Path, new: &Path) -> eyre::Result<u64> {
+    const CHUNK_SIZE: usize = 1 << 20;
+    static ZEROS: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+
+    let mut reader = File::open(original)?;
+    let mut writer = File::create(new)?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut len = 0;
+    let mut written = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        if buffer[..n] == ZEROS[..n] {
+            writer.seek(SeekFrom::Current(n.try_into()?))?;
+        } else {
+            writer.write_all(&buffer[..n])?;
+            written += n as u64;
+        }
+        len += n as u64;
+    }
+    writer.set_len(len)?;
+    writer.set_permissions(reader.metadata()?.permissions())?;
+
+    Ok(written)
+}
+
+#[test]
+fn test_copy_sparse() -> eyre::Result<()> {
+    use std::{
+        fs::{read, set_permissions, write, Permissions},
+        os::unix::fs::{MetadataExt, PermissionsExt},
+    };
+
+    let dir = Temp::new_dir()?;
+    let original = dir.join("original");
+    let new = dir.join("new");
+
+    // Data, then a hole, then data that doesn’t fill a whole chunk, then a hole at the end.
+    let mut file = File::create(&original)?;
+    file.write_all(b"start")?;
+    file.seek(SeekFrom::Start(64 << 20))?;
+    file.write_all(b"end")?;
+    file.set_len(128 << 20)?;
+    drop(file);
+    set_permissions(&original, Permissions::from_mode(0o640))?;
+
+    // Only the chunks with data are written.
+    assert_eq!(copy_sparse(&original, &new)?, 2 << 20);
+    assert_eq!(read(&new)?, read(&original)?);
+    assert!(new.metadata()?.blocks() * 512 <= 4 << 20);
+    assert_eq!(new.metadata()?.permissions().mode() & 0o777, 0o640);
+
+    // Files smaller than a chunk, and empty files, are copied too.
+    write(&original, b"small")?;
+    assert_eq!(copy_sparse(&original, &new)?, 5);
+    assert_eq!(read(&new)?, b"small");
+    write(&original, b"")?;
+    assert_eq!(copy_sparse(&original, &new)?, 0);
+    assert_eq!(read(&new)?, b"");
+
+    Ok(())
+}
+
+#[test]
+fn test_run_cmd_logged() {
+    assert!(run_cmd_logged!(true).is_ok());
+    assert!(run_cmd_logged!(sh -c "echo output; echo error >&2; exit 3").is_err());
+}
@@ -0,0 +1,461 @@
+//! Disk images for templates and runners, stored with the backend configured in `[storage]`.
+//!
+//! Images are always found at their usual paths, like [`crate::policy::runner_image_path`], so
+//! that libvirt and the rest of the monitor don’t need to care about the backend. For backends
+//! that store images as block devices, those paths are symlinks to the devices.
+//!
+//! All images are raw, except runner images in the qcow2 backend. Guests can write anything to
+//! a raw image, including a qcow2 header with a backing file elsewhere on the host, so we never
+//! guess the format of an image from its contents.
+
+use std::{
+    fs::{create_dir_all, read_dir, read_link, remove_file, set_permissions, File},
+    io::{Seek, Write},
+    os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use bytesize::ByteSize;
+use cmd_lib::run_fun;
+use jane_eyre::eyre::{self, OptionExt};
+use serde::{Deserialize, Serialize};
+use settings::{storage::StorageConfig, TOML};
+use tracing::info;
+
+use crate::{
+    policy::runner_images_path,
+    shell::{reflink_or_copy_with_warning, run_cmd_logged},
+};
+
+/// Creates a new image, for an image rebuild to write to, with the contents of
+/// `initial_contents_path` if any, extended to `size`.
+pub fn create_image(
+    path: &Path,
+    size: ByteSize,
+    initial_contents_path: Option<&Path>,
+) -> eyre::Result<()> {
+    info!(?path, %size, ?initial_contents_path, "Creating image");
+    match &TOML.storage {
+        StorageConfig::Reflink | StorageConfig::Qcow2 => {
+            create_image_file(path, size, initial_contents_path)
+        }
+        StorageConfig::Zfs { dataset } => {
+            let volume = format!("{dataset}/{}", volume_name(path)?);
+            let size = format!("{}M", size.0.div_ceil(1 << 20));
+            run_cmd_logged!(zfs create -s -V $size -- $volume)?;
+            create_device_symlink(Path::new("/dev/zvol").join(&volume), path)?;
+            write_initial_contents(path, initial_contents_path)
+        }
+        StorageConfig::LvmThin {
+            volume_group,
+            thin_pool,
+        } => {
+            let name = volume_name(path)?;
+            let size = format!("{}M", size.0.div_ceil(1 << 20));
+            run_cmd_logged!(lvcreate -y -V $size -T $volume_group/$thin_pool -n $name)?;
+            create_device_symlink(Path::new("/dev").join(volume_group).join(&name), path)?;
+            write_initial_contents(path, initial_contents_path)
+        }
+    }
+}
+
+/// Creates a writable clone of an image that won’t change, like a template image, for a runner.
+///
+/// For the qcow2 backend, the clone is a qcow2 image, so guests using it need
+/// [`set_runner_disk_driver_type`].
+pub fn clone_image(original: &Path, new: &Path) -> eyre::Result<()> {
+    info!(?original, ?new, "Cloning image");
+    match &TOML.storage {
+        StorageConfig::Reflink => reflink_or_copy_with_warning(original, new),
+        StorageConfig::Qcow2 => {
+            run_cmd_logged!(qemu-img create -q -f qcow2 -F raw -b $original -- $new)?;
+            Ok(())
+        }
+        StorageConfig::Zfs { dataset } => {
+            // Name the snapshot after the clone, so we can find and destroy it with the clone.
+            let new_name = volume_name(new)?;
+            let snapshot = format!("{dataset}/{}@{new_name}", volume_name(original)?);
+            let volume = format!("{dataset}/{new_name}");
+            run_cmd_logged!(zfs snapshot -- $snapshot)?;
+            run_cmd_logged!(zfs clone -- $snapshot $volume)?;
+            create_device_symlink(Path::new("/dev/zvol").join(&volume), new)
+        }
+        StorageConfig::LvmThin { volume_group, .. } => {
+            let original_name = volume_name(original)?;
+            let new_name = volume_name(new)?;
+            run_cmd_logged!(lvcreate -y -s -kn -n $new_name $volume_group/$original_name)?;
+            create_device_symlink(Path::new("/dev").join(volume_group).join(&new_name), new)
+        }
+    }
+}
+
+/// Copies a runner image within the storage backend, such that the copy is a raw image that
+/// doesn’t depend on the original or the image it was cloned from.
+pub fn copy_image(original: &Path, new: &Path) -> eyre::Result<()> {
+    info!(?original, ?new, "Copying image");
+    match &TOML.storage {
+        StorageConfig::Reflink => reflink_or_copy_with_warning(original, new),
+        // Flatten the qcow2 overlay, so the copy doesn’t depend on its template image.
+        StorageConfig::Qcow2 => {
+            run_cmd_logged!(qemu-img convert -f qcow2 -O raw -- $original $new)?;
+            Ok(())
+        }
+        StorageConfig::Zfs { dataset } => {
+            let new_name = volume_name(new)?;
+            let snapshot = format!("{dataset}/{}@{new_name}", volume_name(original)?);
+            let volume = format!("{dataset}/{new_name}");
+            run_cmd_logged!(zfs snapshot -- $snapshot)?;
+            run_cmd_logged!(zfs send -- $snapshot | zfs recv -- $volume)?;
+            run_cmd_logged!(zfs destroy -- $snapshot)?;
+            create_device_symlink(Path::new("/dev/zvol").join(&volume), new)
+        }
+        // Thin snapshots don’t depend on their origin, so they can be used as copies.
+        StorageConfig::LvmThin { .. } => clone_image(original, new),
+    }
+}
+
+/// Copies a runner image to a standalone image file, outside the storage backend, which doesn’t
+/// depend on the template image it was cloned from.
+pub fn export_image(original: &Path, new: &Path) -> eyre::Result<()> {
+    info!(?original, ?new, "Exporting image");
+    match &TOML.storage {
+        StorageConfig::Reflink => reflink_or_copy_with_warning(original, new),
+        StorageConfig::Qcow2 => {
+            run_cmd_logged!(qemu-img convert -f qcow2 -O qcow2 -- $original $new)?;
+            Ok(())
+        }
+        StorageConfig::Zfs { .. } | StorageConfig::LvmThin { .. } => {
+            run_cmd_logged!(qemu-img convert -f raw -O qcow2 -- $original $new)?;
+            Ok(())
+        }
+    }
+}
+
+/// Returns how much space [`export_image`] would use for a copy of the given image, without
+/// making the copy.
+pub fn export_image_size(original: &Path) -> eyre::Result<u64> {
+    match &TOML.storage {
+        // Reflinked or sparse copies take up no more blocks than the original.
+        StorageConfig::Reflink => Ok(original.metadata()?.blocks() * 512),
+        StorageConfig::Qcow2 => {
+            let output = run_fun!(qemu-img measure --output=json -f qcow2 -O qcow2 -- $original)?;
+            parse_qemu_img_measure_output(&output)
+        }
+        StorageConfig::Zfs { .. } | StorageConfig::LvmThin { .. } => {
+            let output = run_fun!(qemu-img measure --output=json -f raw -O qcow2 -- $original)?;
+            parse_qemu_img_measure_output(&output)
+        }
+    }
+}
+
+fn parse_qemu_img_measure_output(output: &str) -> eyre::Result<u64> {
+    #[derive(Deserialize)]
+    struct Measure {
+        required: u64,
+    }
+    let measure: Measure = serde_json::from_str(output)?;
+
+    Ok(measure.required)
+}
+
+#[test]
+fn test_parse_qemu_img_measure_output() -> eyre::Result<()> {
+    let output = r#"{
+    "bitmaps": 0,
+    "required": 3351314432,
+    "fully-allocated": 96647249920
+}"#;
+    assert_eq!(parse_qemu_img_measure_output(output)?, 3351314432);
+    assert!(parse_qemu_img_measure_output("").is_err());
+
+    Ok(())
+}
+
+/// Deletes an image, or any other file alongside the images.
+pub fn delete_image(path: &Path) -> eyre::Result<()> {
+    let Ok(device_path) = read_link(path) else {
+        remove_file(path)?;
+        return Ok(());
+    };
+    info!(?path, ?device_path, "Deleting volume");
+    match &TOML.storage {
+        StorageConfig::Reflink | StorageConfig::Qcow2 => {}
+        StorageConfig::Zfs { dataset } => {
+            let volume = format!("{dataset}/{}", volume_name(path)?);
+            // Clones have a snapshot of their origin, which we destroy along with them. Snapshots
+            // of the volume itself are destroyed too, but only if no clones depend on them, which
+            // callers can check with [`has_dependents`].
+            let origin = run_fun!(zfs get -H -o value origin -- $volume)?;
+            run_cmd_logged!(zfs destroy -r -- $volume)?;
+            if origin != "-" {
+                run_cmd_logged!(zfs destroy -- $origin)?;
+            }
+        }
+        StorageConfig::LvmThin { volume_group, .. } => {
+            let name = volume_name(path)?;
+            run_cmd_logged!(lvremove -y $volume_group/$name)?;
+        }
+    }
+    remove_file(path)?;
+
+    Ok(())
+}
+
+/// Returns true iff other images depend on the given image, like runner images cloned from a
+/// template image with the qcow2 or ZFS backends, such that it can’t be deleted yet.
+pub fn has_dependents(path: &Path) -> eyre::Result<bool> {
+    match &TOML.storage {
+        StorageConfig::Reflink | StorageConfig::LvmThin { .. } => Ok(false),
+        StorageConfig::Qcow2 => {
+            let Ok(entries) = read_dir(runner_images_path()) else {
+                return Ok(false);
+            };
+            for entry in entries {
+                let entry = entry?;
+                // Skip the preserved runners directory, whose images are standalone.
+                if entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let runner_image_path = entry.path();
+                let output = run_fun!(qemu-img info -f qcow2 --output=json -- $runner_image_path)?;
+                if parse_qemu_img_info_backing_filename(&output).as_deref() == Some(path) {
+                    return Ok(true);
+                }
+            }
+
+            Ok(false)
+        }
+        StorageConfig::Zfs { dataset } => {
+            let volume = format!("{dataset}/{}", volume_name(path)?);
+            let output = run_fun!(zfs list -H -t snapshot -d 1 -o clones -- $volume)?;
+
+            Ok(parse_zfs_list_clones_output(&output))
+        }
+    }
+}
+
+fn parse_qemu_img_info_backing_filename(output: &str) -> Option<PathBuf> {
+    let info: serde_json::Value = serde_json::from_str(output).ok()?;
+
+    info.get("backing-filename")?.as_str().map(PathBuf::from)
+}
+
+fn parse_zfs_list_clones_output(output: &str) -> bool {
+    output
+        .lines()
+        .map(str::trim)
+        .any(|clones| !clones.is_empty() && clones != "-")
+}
+
+#[test]
+fn test_parse_dependents_output() {
+    assert_eq!(
+        parse_qemu_img_info_backing_filename(
+            r#"{"virtual-size": 107374182400, "filename": "/var/lib/libvirt/images/runner/1-base.img", "format": "qcow2", "backing-filename": "/var/lib/libvirt/images/base/servo-ubuntu2204/base.img@2025-01-01T00:00:00.000000000Z", "backing-filename-format": "raw"}"#
+        ),
+        Some(PathBuf::from(
+            "/var/lib/libvirt/images/base/servo-ubuntu2204/base.img@2025-01-01T00:00:00.000000000Z"
+        ))
+    );
+    assert_eq!(
+        parse_qemu_img_info_backing_filename(r#"{"format": "qcow2"}"#),
+        None
+    );
+    assert!(parse_zfs_list_clones_output(
+        "tank/servo-ci/runner_1-base.img\n-\n"
+    ));
+    assert!(!parse_zfs_list_clones_output("-\n-\n"));
+    assert!(!parse_zfs_list_clones_output(""));
+}
+
+/// Space used by images in the storage backend, out of the space available to them.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct StorageUsage {
+    pub used: u64,
+    pub size: u64,
+}
+
+impl StorageUsage {
+    pub fn percent(&self) -> f64 {
+        if self.size == 0 {
+            return 100.0;
+        }
+        self.used as f64 / self.size as f64 * 100.0
+    }
+}
+
+/// Returns how much of the storage backend is used, whether by images or anything else.
+pub fn usage() -> eyre::Result<StorageUsage> {
+    match &TOML.storage {
+        StorageConfig::Reflink | StorageConfig::Qcow2 => {
+            let path = TOML.libvirt_images_path();
+            let output = run_fun!(df -B1 --output=used,size -- $path)?;
+            parse_df_output(&output).ok_or_eyre("Failed to parse df output")
+        }
+        StorageConfig::Zfs { dataset } => {
+            let output = run_fun!(zfs get -Hp -o value used,available -- $dataset)?;
+            parse_zfs_get_output(&output).ok_or_eyre("Failed to parse zfs get output")
+        }
+        StorageConfig::LvmThin {
+            volume_group,
+            thin_pool,
+        } => {
+            let output = run_fun!(lvs --noheadings --units b --nosuffix -o lv_size,data_percent $volume_group/$thin_pool)?;
+            parse_lvs_output(&output).ok_or_eyre("Failed to parse lvs output")
+        }
+    }
+}
+
+fn parse_df_output(output: &str) -> Option<StorageUsage> {
+    let mut fields = output.lines().nth(1)?.split_ascii_whitespace();
+    let used = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+
+    Some(StorageUsage { used, size })
+}
+
+fn parse_zfs_get_output(output: &str) -> Option<StorageUsage> {
+    let mut lines = output.lines();
+    let used = lines.next()?.trim().parse().ok()?;
+    let available = lines.next()?.trim().parse::<u64>().ok()?;
+
+    Some(StorageUsage {
+        used,
+        size: used + available,
+    })
+}
+
+fn parse_lvs_output(output: &str) -> Option<StorageUsage> {
+    let mut fields = output.split_ascii_whitespace();
+    let size = fields.next()?.parse::<u64>().ok()?;
+    let data_percent = fields.next()?.parse::<f64>().ok()?;
+
+    Some(StorageUsage {
+        used: (size as f64 * data_percent / 100.0) as u64,
+        size,
+    })
+}
+
+#[test]
+fn test_parse_usage_output() {
+    assert_eq!(
+        parse_df_output("         Used     1B-blocks\n 375809638400 1000204886016\n"),
+        Some(StorageUsage {
+            used: 375809638400,
+            size: 1000204886016,
+        })
+    );
+    assert_eq!(
+        parse_zfs_get_output("375809638400\n624395247616\n"),
+        Some(StorageUsage {
+            used: 375809638400,
+            size: 1000204886016,
+        })
+    );
+    assert_eq!(
+        parse_lvs_output("  1000204886016 37.50\n"),
+        Some(StorageUsage {
+            used: 375076832256,
+            size: 1000204886016,
+        })
+    );
+    assert_eq!(parse_df_output(""), None);
+}
+
+/// Updates the given disk of a runner guest, cloned from a template guest, to match the format
+/// of images made by [`clone_image`].
+pub fn set_runner_disk_driver_type(runner_guest_name: &str, target_dev: &str) -> eyre::Result<()> {
+    if let StorageConfig::Qcow2 = &TOML.storage {
+        let target = format!("target={target_dev}");
+        run_cmd_logged!(virt-xml $runner_guest_name --edit $target --disk driver.type=qcow2)?;
+    }
+
+    Ok(())
+}
+
+fn create_image_file(
+    path: &Path,
+    size: ByteSize,
+    initial_contents_path: Option<&Path>,
+) -> eyre::Result<()> {
+    let mut file = if let Some(from) = initial_contents_path {
+        reflink_or_copy_with_warning(from, path)?;
+
+        // Copying out of the nix store yields a file with mode 444 (read only). Make sure the file is writable.
+        set_permissions(path, PermissionsExt::from_mode(0o644))?;
+
+        File::options().write(true).open(path)?
+    } else {
+        File::create_new(path)?
+    };
+
+    let delta = size
+        .0
+        .checked_sub(file.stream_position()?)
+        .ok_or_eyre("`size` is smaller than `initial_contents`")?;
+
+    // If `size` is bigger than `initial_contents`, extend the file quickly by seeking and writing at least one byte.
+    // We could write all the zeros, but this is not necessarily helpful since ZFS is a COW file system.
+    if let Some(delta) = delta.checked_sub(1) {
+        file.seek_relative(delta.try_into()?)?;
+        file.write_all(&[0])?;
+    }
+
+    Ok(())
+}
+
+fn write_initial_contents(path: &Path, initial_contents_path: Option<&Path>) -> eyre::Result<()> {
+    if let Some(from) = initial_contents_path {
+        run_cmd_logged!(qemu-img convert -n -f raw -O raw -- $from $path)?;
+    }
+
+    Ok(())
+}
+
+fn create_device_symlink(device_path: PathBuf, path: &Path) -> eyre::Result<()> {
+    // Wait for udev to create the device node, so libvirt can use it right away.
+    run_cmd_logged!(udevadm settle)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    symlink(device_path, path)?;
+
+    Ok(())
+}
+
+/// Returns the name of the volume for an image, which is its path relative to the libvirt images
+/// directory, using only characters that are valid in both ZFS and LVM names.
+fn volume_name(path: &Path) -> eyre::Result<String> {
+    let path = path.strip_prefix(TOML.libvirt_images_path())?;
+    let path = path.to_str().ok_or_eyre("Unsupported path")?;
+
+    Ok(path
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect())
+}
+
+#[test]
+fn test_volume_name() {
+    let images_path = TOML.libvirt_images_path();
+    assert_eq!(
+        volume_name(
+            &images_path
+                .join("base/servo-ubuntu2204")
+                .join("base.img@2025-01-01T00:00:00.000000000Z")
+        )
+        .ok()
+        .as_deref(),
+        Some("base_servo-ubuntu2204_base.img_2025-01-01T00_00_00.000000000Z")
+    );
+    assert_eq!(
+        volume_name(&images_path.join("runner/1-base.img"))
+            .ok()
+            .as_deref(),
+        Some("runner_1-base.img")
+    );
+    assert!(volume_name(Path::new("/elsewhere/base.img")).is_err());
+}
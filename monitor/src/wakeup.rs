@@ -0,0 +1,114 @@
+//! Events that wake the monitor thread for another pass, without waiting for a request or for
+//! `monitor_poll_interval` to elapse.
+
+use std::{
+    io::{BufRead, BufReader},
+    sync::LazyLock,
+    thread,
+    time::Duration,
+};
+
+use cmd_lib::spawn_with_output;
+use crossbeam_channel::{Receiver, Sender};
+use jane_eyre::eyre;
+use settings::TOML;
+use tracing::{debug, error};
+
+use crate::workers::RunnerOperation;
+
+#[derive(Debug)]
+pub enum Wakeup {
+    /// A libvirt guest of ours started, stopped, was defined, etc.
+    LibvirtEvent { guest_name: String, event: String },
+    /// An image rebuild thread finished, successfully or not.
+    RebuildFinished { profile_key: String },
+    /// Our cached Servo repo finished updating.
+    ServoUpdateFinished,
+    /// Garbage collection finished, successfully or not.
+    GarbageCollectionFinished,
+    /// An orphan check finished, successfully or not.
+    OrphanCheckFinished,
+    /// Looking up the jobs of runners busy for too long finished.
+    StuckBusyCheckFinished,
+    /// A runner worker finished creating or destroying a runner, successfully or not.
+    RunnerOperationFinished {
+        operation: RunnerOperation,
+        runner_id: usize,
+    },
+}
+
+static WAKEUPS: LazyLock<(Sender<Wakeup>, Receiver<Wakeup>)> =
+    LazyLock::new(crossbeam_channel::unbounded);
+
+pub fn wake(wakeup: Wakeup) {
+    debug!(?wakeup, "Waking monitor thread");
+    // The receiver is static, so this never fails.
+    let _ = WAKEUPS.0.send(wakeup);
+}
+
+pub fn wakeups() -> &'static Receiver<Wakeup> {
+    &WAKEUPS.1
+}
+
+/// Wakes the monitor thread whenever the lifecycle of one of our libvirt guests changes.
+pub fn libvirt_event_thread() {
+    loop {
+        if let Err(error) = watch_libvirt_events() {
+            error!(?error, "Failed to watch libvirt events: {error}");
+        }
+        // Don’t spin if virsh keeps failing, e.g. because libvirtd is restarting.
+        thread::sleep(Duration::from_secs(5));
+    }
+}
+
+fn watch_libvirt_events() -> eyre::Result<()> {
+    let prefixes = [
+        TOML.libvirt_runner_guest_prefix(),
+        TOML.libvirt_rebuild_guest_prefix(),
+        TOML.libvirt_template_guest_prefix(),
+    ]
+    .map(|prefix| format!("{prefix}-"));
+    let mut child = spawn_with_output!(virsh event --all --loop --event lifecycle)?;
+    let mut result = Ok(());
+    child.wait_with_pipe(&mut |stdout| {
+        for line in BufReader::new(stdout).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    result = Err(error.into());
+                    break;
+                }
+            };
+            let Some((guest_name, event)) = parse_virsh_event_output(&line) else {
+                continue;
+            };
+            if prefixes.iter().any(|prefix| guest_name.starts_with(prefix)) {
+                wake(Wakeup::LibvirtEvent {
+                    guest_name: guest_name.to_owned(),
+                    event: event.to_owned(),
+                });
+            }
+        }
+    })?;
+
+    result
+}
+
+/// Parses a line like `event 'lifecycle' for domain 'guest': Stopped Destroyed`.
+fn parse_virsh_event_output(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix("event 'lifecycle' for domain '")?;
+    let (guest_name, event) = rest.split_once("': ")?;
+
+    Some((guest_name, event.trim()))
+}
+
+#[test]
+fn test_parse_virsh_event_output() {
+    assert_eq!(
+        parse_virsh_event_output(
+            "event 'lifecycle' for domain 'ci-runner-servo-ubuntu2204.42': Stopped Destroyed\n"
+        ),
+        Some(("ci-runner-servo-ubuntu2204.42", "Stopped Destroyed"))
+    );
+    assert_eq!(parse_virsh_event_output("events received: 1"), None);
+}
@@ -0,0 +1,512 @@
+//! Long-lived worker pools that create and destroy runners in the background, so that slow
+//! operations don’t hold up the monitor thread, reaping, or other profiles.
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, VecDeque},
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use jane_eyre::eyre;
+use serde::Serialize;
+use settings::TOML;
+
+use crate::{
+    runner::ProvisioningStage,
+    wakeup::{wake, Wakeup},
+};
+
+/// Work for a runner worker to do, such as from [`crate::policy::Policy::register_create_runner`].
+pub type Work = Box<dyn FnOnce(&Progress) -> eyre::Result<()> + Send>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunnerOperation {
+    /// Register, create, and start a runner, or register and start a warm runner.
+    Create,
+    /// Create a warm runner, without registering or starting it.
+    CreateWarm,
+    Destroy,
+}
+
+/// An operation that was queued, and whose result is yet to be handled by the monitor thread.
+#[derive(Clone, Debug, Serialize)]
+pub struct PendingOperation {
+    pub operation: RunnerOperation,
+    pub profile_key: String,
+    /// How far the runner has got, if it is being created.
+    pub stage: Option<ProvisioningStage>,
+}
+
+/// Lets work report how far it has got, while it runs.
+pub struct Progress<'pool> {
+    pool: &'pool Pool,
+    runner_id: usize,
+}
+
+#[derive(Debug)]
+pub struct FinishedOperation {
+    pub operation: RunnerOperation,
+    pub runner_id: usize,
+    pub profile_key: String,
+    /// The result of the work, or a description of the panic if it panicked.
+    pub result: Result<eyre::Result<()>, String>,
+}
+
+/// Queue depth and in-flight operations of each pool, for the dashboard. The create pool also
+/// creates warm runners.
+#[derive(Debug, Serialize)]
+pub struct RunnerWorkersStatus {
+    pub create: PoolStatus,
+    pub destroy: PoolStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolStatus {
+    pub concurrency: usize,
+    pub queued: Vec<OperationStatus>,
+    pub in_flight: Vec<OperationStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperationStatus {
+    pub runner_id: usize,
+    pub profile_key: String,
+    pub stage: Option<ProvisioningStage>,
+}
+
+/// Handle to the runner worker pools, owned by the monitor thread.
+pub struct RunnerWorkers {
+    create: Arc<Pool>,
+    destroy: Arc<Pool>,
+    finished: Receiver<FinishedOperation>,
+    pending: BTreeMap<usize, PendingOperation>,
+}
+
+struct Pool {
+    concurrency: usize,
+    state: Mutex<PoolState>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct PoolState {
+    queue: VecDeque<Job>,
+    in_flight: BTreeMap<usize, InFlight>,
+}
+
+struct InFlight {
+    profile_key: String,
+    stage: Option<ProvisioningStage>,
+}
+
+struct Job {
+    operation: RunnerOperation,
+    runner_id: usize,
+    profile_key: String,
+    work: Work,
+}
+
+impl RunnerWorkers {
+    /// Starts `runner_create_concurrency` workers for creating runners, and
+    /// `runner_destroy_concurrency` workers for destroying runners.
+    pub fn start() -> Self {
+        Self::with_concurrency(
+            TOML.runner_create_concurrency(),
+            TOML.runner_destroy_concurrency(),
+        )
+    }
+
+    fn with_concurrency(create_concurrency: usize, destroy_concurrency: usize) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let create = Pool::start(create_concurrency, &sender);
+        let destroy = Pool::start(destroy_concurrency, &sender);
+
+        Self {
+            create,
+            destroy,
+            finished: receiver,
+            pending: BTreeMap::default(),
+        }
+    }
+
+    pub fn enqueue(
+        &mut self,
+        operation: RunnerOperation,
+        runner_id: usize,
+        profile_key: &str,
+        work: Work,
+    ) {
+        self.pending.insert(
+            runner_id,
+            PendingOperation {
+                operation,
+                profile_key: profile_key.to_owned(),
+                stage: (operation == RunnerOperation::Create).then_some(ProvisioningStage::Queued),
+            },
+        );
+        self.pool(operation).push(Job {
+            operation,
+            runner_id,
+            profile_key: profile_key.to_owned(),
+            work,
+        });
+    }
+
+    /// Returns the operations that were queued, and whose results are yet to be taken, with
+    /// how far each runner being created has got.
+    pub fn pending(&self) -> BTreeMap<usize, PendingOperation> {
+        let create = self.create.lock();
+        let mut result = self.pending.clone();
+        for (runner_id, pending) in result.iter_mut() {
+            if pending.operation != RunnerOperation::Create {
+                continue;
+            }
+            if let Some(in_flight) = create.in_flight.get(runner_id) {
+                pending.stage = in_flight.stage;
+            } else if !create.queue.iter().any(|job| job.runner_id == *runner_id) {
+                // Finished, but the monitor thread has yet to take the result.
+                pending.stage = Some(ProvisioningStage::WaitingForOnline);
+            }
+        }
+
+        result
+    }
+
+    /// Takes the results of any operations that have finished, without blocking.
+    pub fn take_finished(&mut self) -> Vec<FinishedOperation> {
+        let result = self.finished.try_iter().collect::<Vec<_>>();
+        for operation in result.iter() {
+            self.pending.remove(&operation.runner_id);
+        }
+
+        result
+    }
+
+    pub fn status(&self) -> RunnerWorkersStatus {
+        RunnerWorkersStatus {
+            create: self.create.status(),
+            destroy: self.destroy.status(),
+        }
+    }
+
+    fn pool(&self, operation: RunnerOperation) -> &Pool {
+        match operation {
+            RunnerOperation::Create | RunnerOperation::CreateWarm => &self.create,
+            RunnerOperation::Destroy => &self.destroy,
+        }
+    }
+}
+
+impl Pool {
+    fn start(concurrency: usize, finished: &Sender<FinishedOperation>) -> Arc<Self> {
+        let result = Arc::new(Self {
+            concurrency: concurrency.max(1),
+            state: Mutex::default(),
+            condvar: Condvar::new(),
+        });
+        for _ in 0..result.concurrency {
+            let pool = result.clone();
+            let finished = finished.clone();
+            thread::spawn(move || pool.worker_thread(finished));
+        }
+
+        result
+    }
+
+    fn push(&self, job: Job) {
+        self.lock().queue.push_back(job);
+        self.condvar.notify_one();
+    }
+
+    fn worker_thread(&self, finished: Sender<FinishedOperation>) {
+        loop {
+            let Job {
+                operation,
+                runner_id,
+                profile_key,
+                work,
+            } = self.take_job();
+            let progress = Progress {
+                pool: self,
+                runner_id,
+            };
+            let result = catch_unwind(AssertUnwindSafe(|| work(&progress))).map_err(describe_panic);
+            self.lock().in_flight.remove(&runner_id);
+            // The monitor thread owns the receiver, so this only fails if it has exited.
+            let _ = finished.send(FinishedOperation {
+                operation,
+                runner_id,
+                profile_key,
+                result,
+            });
+            wake(Wakeup::RunnerOperationFinished {
+                operation,
+                runner_id,
+            });
+        }
+    }
+
+    /// Waits for a job, then takes the oldest job for the profile with the fewest operations in
+    /// flight, so that one profile with slow operations can’t hold up the others.
+    fn take_job(&self) -> Job {
+        let mut state = self.lock();
+        loop {
+            let index = (0..state.queue.len()).min_by_key(|&index| {
+                let profile_key = &state.queue[index].profile_key;
+                state
+                    .in_flight
+                    .values()
+                    .filter(|in_flight| in_flight.profile_key == *profile_key)
+                    .count()
+            });
+            if let Some(job) = index.and_then(|index| state.queue.remove(index)) {
+                let stage = (job.operation == RunnerOperation::Create)
+                    .then_some(ProvisioningStage::Registering);
+                state.in_flight.insert(
+                    job.runner_id,
+                    InFlight {
+                        profile_key: job.profile_key.clone(),
+                        stage,
+                    },
+                );
+                return job;
+            }
+            state = self
+                .condvar
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    fn status(&self) -> PoolStatus {
+        let state = self.lock();
+        PoolStatus {
+            concurrency: self.concurrency,
+            queued: state
+                .queue
+                .iter()
+                .map(|job| OperationStatus {
+                    runner_id: job.runner_id,
+                    profile_key: job.profile_key.clone(),
+                    stage: (job.operation == RunnerOperation::Create)
+                        .then_some(ProvisioningStage::Queued),
+                })
+                .collect(),
+            in_flight: state
+                .in_flight
+                .iter()
+                .map(|(&runner_id, in_flight)| OperationStatus {
+                    runner_id,
+                    profile_key: in_flight.profile_key.clone(),
+                    stage: in_flight.stage,
+                })
+                .collect(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // The state is always valid, even if another thread panicked while holding the lock.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Progress<'_> {
+    pub fn set_stage(&self, stage: ProvisioningStage) {
+        if let Some(in_flight) = self.pool.lock().in_flight.get_mut(&self.runner_id) {
+            in_flight.stage = Some(stage);
+        }
+    }
+}
+
+fn describe_panic(panic: Box<dyn Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        format!("panic: {message}")
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        format!("panic: {message}")
+    } else {
+        format!("panic: {panic:?}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crossbeam_channel::Receiver;
+
+    use crate::runner::ProvisioningStage;
+
+    use super::{FinishedOperation, RunnerOperation, RunnerWorkers, Work};
+
+    /// Polls until `condition` is true, panicking if it takes too long.
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "Timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Takes finished operations until there are `count` of them.
+    fn take_finished(workers: &mut RunnerWorkers, count: usize) -> Vec<FinishedOperation> {
+        let mut result = vec![];
+        wait_until(|| {
+            result.extend(workers.take_finished());
+            result.len() >= count
+        });
+
+        result
+    }
+
+    /// Work that blocks until `gate` is closed.
+    fn blocking_work(gate: &Receiver<()>) -> Work {
+        let gate = gate.clone();
+        Box::new(move |_progress| {
+            let _ = gate.recv();
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let mut workers = RunnerWorkers::with_concurrency(2, 1);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let (gate_tx, gate_rx) = crossbeam_channel::bounded::<()>(0);
+        for runner_id in 0..5 {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            let gate = gate_rx.clone();
+            workers.enqueue(
+                RunnerOperation::Create,
+                runner_id,
+                "linux",
+                Box::new(move |_progress| {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    let _ = gate.recv();
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+            );
+        }
+
+        // No more than `concurrency` jobs run at once, and the rest wait in the queue.
+        wait_until(|| running.load(Ordering::SeqCst) == 2);
+        let status = workers.status();
+        assert_eq!(status.create.concurrency, 2);
+        assert_eq!(status.create.in_flight.len(), 2);
+        assert_eq!(status.create.queued.len(), 3);
+
+        drop(gate_tx);
+        let finished = take_finished(&mut workers, 5);
+        assert!(finished
+            .iter()
+            .all(|operation| matches!(operation.result, Ok(Ok(())))));
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_panic() {
+        let mut workers = RunnerWorkers::with_concurrency(1, 1);
+        workers.enqueue(
+            RunnerOperation::Destroy,
+            0,
+            "linux",
+            Box::new(|_progress| panic!("oops")),
+        );
+        let finished = take_finished(&mut workers, 1);
+        assert_eq!(finished[0].runner_id, 0);
+        assert_eq!(finished[0].operation, RunnerOperation::Destroy);
+        assert!(matches!(&finished[0].result, Err(message) if message == "panic: oops"));
+
+        // The worker survives the panic, and can do more work.
+        workers.enqueue(RunnerOperation::Destroy, 1, "linux", Box::new(|_| Ok(())));
+        let finished = take_finished(&mut workers, 1);
+        assert_eq!(finished[0].runner_id, 1);
+        assert!(matches!(finished[0].result, Ok(Ok(()))));
+        assert!(workers.pending().is_empty());
+    }
+
+    #[test]
+    fn test_pending() {
+        let mut workers = RunnerWorkers::with_concurrency(1, 1);
+        let (gate_tx, gate_rx) = crossbeam_channel::bounded::<()>(0);
+        let stage = |workers: &RunnerWorkers, runner_id| {
+            workers
+                .pending()
+                .get(&runner_id)
+                .expect("Runner is pending")
+                .stage
+        };
+        let gate = gate_rx.clone();
+        workers.enqueue(
+            RunnerOperation::Create,
+            0,
+            "linux",
+            Box::new(move |progress| {
+                progress.set_stage(ProvisioningStage::Cloning);
+                let _ = gate.recv();
+                Ok(())
+            }),
+        );
+        workers.enqueue(RunnerOperation::Create, 1, "linux", blocking_work(&gate_rx));
+        workers.enqueue(
+            RunnerOperation::Destroy,
+            2,
+            "linux",
+            blocking_work(&gate_rx),
+        );
+
+        // Runners being created report the stage of their work once in flight, and are queued
+        // until then. Other operations have no stage.
+        wait_until(|| stage(&workers, 0) == Some(ProvisioningStage::Cloning));
+        assert_eq!(stage(&workers, 1), Some(ProvisioningStage::Queued));
+        assert_eq!(stage(&workers, 2), None);
+        let status = workers.status();
+        assert_eq!(status.create.in_flight.len(), 1);
+        assert_eq!(status.create.in_flight[0].runner_id, 0);
+        assert_eq!(
+            status.create.in_flight[0].stage,
+            Some(ProvisioningStage::Cloning)
+        );
+        assert_eq!(status.create.queued.len(), 1);
+        assert_eq!(status.create.queued[0].runner_id, 1);
+        assert_eq!(status.destroy.in_flight.len(), 1);
+
+        // Finished operations stay pending until their results are taken, with runners being
+        // created waiting to come online.
+        drop(gate_tx);
+        wait_until(|| {
+            let status = workers.status();
+            status.create.in_flight.is_empty()
+                && status.create.queued.is_empty()
+                && status.destroy.in_flight.is_empty()
+        });
+        assert_eq!(
+            stage(&workers, 0),
+            Some(ProvisioningStage::WaitingForOnline)
+        );
+        assert_eq!(
+            stage(&workers, 1),
+            Some(ProvisioningStage::WaitingForOnline)
+        );
+        assert_eq!(workers.pending().len(), 3);
+        let finished = take_finished(&mut workers, 3);
+        assert_eq!(finished.len(), 3);
+        assert!(workers.pending().is_empty());
+    }
+}
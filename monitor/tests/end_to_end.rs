@@ -0,0 +1,694 @@
+//! End-to-end tests, which run the real monitor (and queue) binaries against a fake GitHub API and
+//! a fake hypervisor.
+//!
+//! The fakes are scripts in `tests/fake/bin` that stand in for `gh`, `virsh`, and friends, keeping
+//! their state in files under a temporary directory. Each test runs a scenario in
+//! `tests/scenarios`, which is a TOML file like this:
+//!
+//! ```toml
+//! # Start these profiles with an active snapshot, so they don’t need to be rebuilt first.
+//! snapshots = ["base-ubuntu2204"]
+//!
+//! # Settings to merge over DEFAULT_SETTINGS.
+//! [settings.profiles.base-ubuntu2204]
+//! target_count = 1
+//!
+//! [[steps]]
+//! action = "github_runner"
+//! name = "ci-runner-base-ubuntu2204.0@"
+//! status = "online"
+//!
+//! [[steps]]
+//! action = "wait"
+//! path = "/dashboard.json"
+//! json = { "/profile_runner_counts/base-ubuntu2204/idle" = 1 }
+//! ```
+//!
+//! See [`Step`] for the available actions.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs::{create_dir_all, read_dir, read_to_string, write, File},
+    net::TcpListener,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
+
+use chrono::{SecondsFormat, Utc};
+use jane_eyre::eyre::{self, bail, OptionExt, WrapErr};
+use mktemp::Temp;
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// The API token accepted by test builds of the monitor (see `Dotenv::load_for_tests`).
+const MONITOR_API_TOKEN: &str = "ChangedMe";
+
+/// Settings for every scenario, before merging the scenario’s own settings. Paths and ports are
+/// filled in by [`Harness::start`].
+const DEFAULT_SETTINGS: &str = r#"
+listen_on = ["127.0.0.1"]
+github_api_scope = "/repos/servo/servo"
+allowed_qualified_repo_prefix = "servo/"
+github_api_suffix = "test"
+monitor_poll_interval = 1
+api_cache_timeout = 0
+tokenless_select_artifact_max_age = 300
+monitor_start_timeout = 120
+monitor_reserve_timeout = 60
+monitor_thread_send_timeout = 10
+monitor_thread_recv_timeout = 10
+base_image_max_age = 86400
+dont_update_cached_servo_repo = true
+smoke_test_timeout = 60
+screenshot_interval = 3600
+available_1g_hugepages = 96
+available_normal_memory = "16G"
+
+[profiles.base-ubuntu2204]
+profile_name = "base-ubuntu2204"
+github_runner_label = "self-hosted-image:base-ubuntu2204"
+target_count = 0
+requires_1g_hugepages = 12
+requires_normal_memory = "1G"
+"#;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+macro_rules! scenarios {
+    ($($name:ident),+ $(,)?) => {
+        $(
+            #[tokio::test(flavor = "multi_thread")]
+            async fn $name() -> eyre::Result<()> {
+                run_scenario(stringify!($name)).await
+            }
+        )+
+    };
+}
+
+scenarios![
+    runner_lifecycle,
+    policy_override,
+    image_rebuild,
+    memory_snapshot,
+    tokenless_select,
+    queue_forwarding,
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    /// Profiles that start with an active snapshot, so they don’t need to be rebuilt first.
+    #[serde(default)]
+    snapshots: Vec<String>,
+    /// Whether to run a queue server, with the monitor as its only server.
+    #[serde(default)]
+    queue: bool,
+    /// Settings to merge over [`DEFAULT_SETTINGS`].
+    #[serde(default)]
+    settings: toml::Table,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Step {
+    /// Send a request, and check the response.
+    Request(Request),
+    /// Send a request until the response passes its checks, or the timeout elapses.
+    Wait(Request),
+    /// Update runner registrations in our fake GitHub, as if the runner came online or started
+    /// a job.
+    GithubRunner {
+        /// Prefix of the registration name, like `ci-runner-base-ubuntu2204.0@`.
+        name: String,
+        status: Option<String>,
+        busy: Option<bool>,
+    },
+    /// Remove runner registrations from our fake GitHub, as if the runner finished its job.
+    RemoveGithubRunner { name: String },
+    /// Upload an artifact for a workflow run to our fake GitHub.
+    GithubArtifact {
+        qualified_repo: String,
+        run_id: String,
+        name: String,
+        contents: String,
+    },
+    /// Wait for a guest in our fake hypervisor to exist, optionally in the given state (like
+    /// `running` or `shut off`), or to not exist.
+    WaitForGuest {
+        /// Prefix of the guest name, like `ci-runner-base-ubuntu2204.0`.
+        name: String,
+        #[serde(default = "default_true")]
+        exists: bool,
+        state: Option<String>,
+        /// Whether the guest was restored from a memory snapshot, rather than booted.
+        restored: Option<bool>,
+        timeout: Option<u64>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Request {
+    /// Which server to send the request to.
+    #[serde(default)]
+    server: Server,
+    #[serde(default = "default_method")]
+    method: String,
+    /// Path and query, which may contain `${variable}` references.
+    path: String,
+    /// Whether to send the monitor API token.
+    #[serde(default)]
+    auth: bool,
+    /// Send the request as if it came from the guest with the given name prefix.
+    as_guest: Option<String>,
+    #[serde(default)]
+    body: String,
+    /// Expected status code.
+    #[serde(default = "default_status")]
+    status: u16,
+    /// Expected values in the JSON response, keyed by JSON pointer.
+    #[serde(default)]
+    json: BTreeMap<String, Value>,
+    /// Expected substring of the response.
+    contains: Option<String>,
+    /// Variables to set from the JSON response, keyed by variable name, with values being JSON
+    /// pointers. The empty pointer saves the whole response as text.
+    #[serde(default)]
+    save: BTreeMap<String, String>,
+    /// How long to keep trying, in seconds (`wait` only).
+    timeout: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Server {
+    #[default]
+    Monitor,
+    Queue,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_method() -> String {
+    "GET".to_owned()
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+async fn run_scenario(name: &str) -> eyre::Result<()> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/scenarios")
+        .join(format!("{name}.toml"));
+    let scenario: Scenario = toml::from_str(&read_to_string(&path)?)
+        .wrap_err_with(|| format!("Failed to parse scenario: {path:?}"))?;
+
+    let mut harness = Harness::start(&scenario).await?;
+    for (i, step) in scenario.steps.iter().enumerate() {
+        if let Err(error) = harness.run_step(step).await {
+            return Err(error.wrap_err(format!(
+                "Step {} failed: {step:?}\n{}",
+                i + 1,
+                harness.logs()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+struct Harness {
+    /// Deleted when the harness is dropped, after the processes have been killed.
+    dir: Temp,
+    processes: Vec<Process>,
+    client: Client,
+    variables: BTreeMap<String, String>,
+}
+
+struct Process {
+    server: Server,
+    child: Child,
+    base_url: String,
+    log_path: PathBuf,
+}
+
+impl Harness {
+    async fn start(scenario: &Scenario) -> eyre::Result<Self> {
+        let dir = Temp::new_dir()?;
+        let mut result = Self {
+            dir,
+            processes: vec![],
+            client: Client::builder().timeout(Duration::from_secs(30)).build()?,
+            variables: BTreeMap::default(),
+        };
+        create_dir_all(result.fake_state_dir())?;
+        create_dir_all(result.dir.join("servo"))?;
+        let image_deps_dir = result.dir.join("image-deps/ubuntu2204");
+        create_dir_all(&image_deps_dir)?;
+        File::create(image_deps_dir.join("jammy-server-cloudimg-amd64.raw"))?;
+        // `cli::init()` needs a `.env`, even though test builds ignore it.
+        write(result.dir.join(".env"), "")?;
+
+        let monitor_port = unused_port()?;
+        let mut settings: toml::Table = toml::from_str(DEFAULT_SETTINGS)?;
+        settings.insert("listen_port".to_owned(), i64::from(monitor_port).into());
+        settings.insert(
+            "external_base_url".to_owned(),
+            format!("http://127.0.0.1:{monitor_port}/").into(),
+        );
+        settings.insert("main_repo_path".to_owned(), result.path("servo")?.into());
+        settings.insert(
+            "libvirt_images_path".to_owned(),
+            result.path("images")?.into(),
+        );
+        merge_settings(&mut settings, scenario.settings.clone());
+        write(result.dir.join("monitor.toml"), toml::to_string(&settings)?)?;
+
+        for profile_key in scenario.snapshots.iter() {
+            result.create_snapshot(profile_key)?;
+        }
+
+        result
+            .spawn(
+                Server::Monitor,
+                monitor_port,
+                "monitor.toml",
+                "/dashboard.json",
+            )
+            .await?;
+
+        if scenario.queue {
+            let queue_port = unused_port()?;
+            let mut queue = toml::Table::default();
+            queue.insert(
+                "servers".to_owned(),
+                vec![format!("http://127.0.0.1:{monitor_port}")].into(),
+            );
+            queue.insert("listen_port".to_owned(), i64::from(queue_port).into());
+            settings.insert("queue".to_owned(), queue.into());
+            write(result.dir.join("queue.toml"), toml::to_string(&settings)?)?;
+            result
+                .spawn(Server::Queue, queue_port, "queue.toml", "/dashboard.txt")
+                .await?;
+        }
+
+        Ok(result)
+    }
+
+    async fn spawn(
+        &mut self,
+        server: Server,
+        port: u16,
+        settings_filename: &str,
+        ready_path: &str,
+    ) -> eyre::Result<()> {
+        let (program, log_filename) = match server {
+            Server::Monitor => (env!("CARGO_BIN_EXE_monitor"), "monitor.log"),
+            Server::Queue => (env!("CARGO_BIN_EXE_queue"), "queue.log"),
+        };
+        let log_path = self.dir.join(log_filename);
+        let log = File::create(&log_path)?;
+        let child = Command::new(program)
+            .current_dir(&*self.dir)
+            .env("PATH", fake_path()?)
+            .env("FAKE_STATE_DIR", self.fake_state_dir())
+            .env(
+                "SERVO_CI_MONITOR_TEST_TOML",
+                self.dir.join(settings_filename),
+            )
+            .env("LIB_MONITOR_DIR", repo_path()?)
+            .env("IMAGE_DEPS_DIR", self.dir.join("image-deps"))
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()?;
+        self.processes.push(Process {
+            server,
+            child,
+            base_url: format!("http://127.0.0.1:{port}"),
+            log_path,
+        });
+
+        // Wait for the server to start answering requests.
+        let url = format!("http://127.0.0.1:{port}{ready_path}");
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self
+                .processes
+                .last_mut()
+                .expect("Pushed above")
+                .child
+                .try_wait()?
+            {
+                bail!(
+                    "{server:?} exited during startup: {status}\n{}",
+                    self.logs()
+                );
+            }
+            if let Ok(response) = self.client.get(&url).send().await {
+                if response.status().is_success() {
+                    return Ok(());
+                }
+            }
+            if start.elapsed() > DEFAULT_TIMEOUT {
+                bail!("{server:?} did not start in time\n{}", self.logs());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn run_step(&mut self, step: &Step) -> eyre::Result<()> {
+        match step {
+            Step::Request(request) => self.request(request).await,
+            Step::Wait(request) => {
+                let timeout = request.timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+                let start = Instant::now();
+                loop {
+                    match self.request(request).await {
+                        Ok(()) => return Ok(()),
+                        Err(error) if start.elapsed() > timeout => {
+                            return Err(error.wrap_err(format!("Timed out after {timeout:?}")))
+                        }
+                        Err(_) => tokio::time::sleep(POLL_INTERVAL).await,
+                    }
+                }
+            }
+            Step::GithubRunner { name, status, busy } => {
+                let mut fields = vec![];
+                if let Some(status) = status {
+                    fields.push(format!("status={status}"));
+                }
+                if let Some(busy) = busy {
+                    fields.push(format!("busy={busy}"));
+                }
+                let mut args = vec!["api", "--method", "PATCH"];
+                for field in fields.iter() {
+                    args.extend(["-f", field]);
+                }
+                let endpoint = format!("/fake/runners/{}", self.expand(name));
+                args.push(&endpoint);
+                self.fake_gh(&args)
+            }
+            Step::RemoveGithubRunner { name } => {
+                let endpoint = format!("/fake/runners/{}", self.expand(name));
+                self.fake_gh(&["api", "--method", "DELETE", &endpoint])
+            }
+            Step::GithubArtifact {
+                qualified_repo,
+                run_id,
+                name,
+                contents,
+            } => self.create_artifact(
+                qualified_repo,
+                run_id,
+                &self.expand(name),
+                &self.expand(contents),
+            ),
+            Step::WaitForGuest {
+                name,
+                exists,
+                state,
+                restored,
+                timeout,
+            } => {
+                let name = self.expand(name);
+                let timeout = timeout.map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+                let start = Instant::now();
+                loop {
+                    let guest = self.guest_dir(&name)?;
+                    let done = match (&guest, state) {
+                        (Some(guest), Some(state)) => {
+                            *exists && read_to_string(guest.join("state"))?.trim() == state
+                        }
+                        (Some(_), None) => *exists,
+                        (None, _) => !*exists,
+                    } && match (&guest, restored) {
+                        (Some(guest), Some(restored)) => {
+                            guest.join("restored-from").exists() == *restored
+                        }
+                        _ => true,
+                    };
+                    if done {
+                        return Ok(());
+                    }
+                    if start.elapsed() > timeout {
+                        bail!("Timed out after {timeout:?} (guest: {guest:?})");
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn request(&mut self, request: &Request) -> eyre::Result<()> {
+        let process = self
+            .processes
+            .iter()
+            .find(|process| process.server == request.server)
+            .ok_or_eyre("Server not running")?;
+        let method = Method::from_bytes(request.method.as_bytes())?;
+        let url = format!("{}{}", process.base_url, self.expand(&request.path));
+        let mut builder = self
+            .client
+            .request(method, url)
+            .body(self.expand(&request.body));
+        if request.auth {
+            builder = builder.bearer_auth(MONITOR_API_TOKEN);
+        }
+        if let Some(guest_name) = &request.as_guest {
+            let guest = self
+                .guest_dir(&self.expand(guest_name))?
+                .ok_or_eyre("No such guest")?;
+            let address = read_to_string(guest.join("address"))?;
+            builder = builder.header("X-Real-IP", address.trim());
+        }
+        let response = builder.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+
+        if status.as_u16() != request.status {
+            bail!(
+                "Expected status {}, actual {status}: {text}",
+                request.status
+            );
+        }
+        if let Some(contains) = &request.contains {
+            if !text.contains(&self.expand(contains)) {
+                bail!("Expected response to contain {contains:?}, actual: {text}");
+            }
+        }
+        let json = || serde_json::from_str::<Value>(&text).wrap_err("Failed to parse JSON");
+        for (pointer, expected) in request.json.iter() {
+            let expected = match expected {
+                Value::String(expected) => Value::String(self.expand(expected)),
+                other => other.clone(),
+            };
+            let actual = json()?.pointer(pointer).cloned();
+            if actual.as_ref() != Some(&expected) {
+                bail!("Expected {pointer} to be {expected}, actual {actual:?}: {text}");
+            }
+        }
+        for (variable, pointer) in request.save.iter() {
+            let value = if pointer.is_empty() {
+                text.clone()
+            } else {
+                match json()?.pointer(pointer) {
+                    Some(Value::String(value)) => value.clone(),
+                    Some(value) => value.to_string(),
+                    None => bail!("No value at {pointer}: {text}"),
+                }
+            };
+            self.variables.insert(variable.clone(), value);
+        }
+
+        Ok(())
+    }
+
+    /// Gives the profile an active snapshot, with a template guest and base image.
+    fn create_snapshot(&self, profile_key: &str) -> eyre::Result<()> {
+        let snapshot_name = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let profile_data_dir = self.dir.join("data/profiles").join(profile_key);
+        create_dir_all(&profile_data_dir)?;
+        symlink(&snapshot_name, profile_data_dir.join("snapshot"))?;
+
+        let base_images_dir = self.dir.join("images/base").join(profile_key);
+        create_dir_all(&base_images_dir)?;
+        File::create(base_images_dir.join(format!("base.img@{snapshot_name}")))?;
+
+        let status = Command::new(fake_bin_path().join("virt-clone"))
+            .env("FAKE_STATE_DIR", self.fake_state_dir())
+            .arg("--define-only")
+            .arg(format!("ci-template-{profile_key}@{snapshot_name}"))
+            .status()?;
+        if !status.success() {
+            bail!("Failed to define template guest: {status}");
+        }
+
+        Ok(())
+    }
+
+    /// Adds an artifact to the given workflow run. The fake `funzip` passes the contents through,
+    /// so they are stored uncompressed.
+    fn create_artifact(
+        &self,
+        qualified_repo: &str,
+        run_id: &str,
+        name: &str,
+        contents: &str,
+    ) -> eyre::Result<()> {
+        let api_dir = self.fake_state_dir().join("github/api");
+        let run_dir = api_dir.join(format!("repos/{qualified_repo}/actions/runs/{run_id}"));
+        create_dir_all(&run_dir)?;
+        let artifacts_path = run_dir.join("artifacts");
+        let mut artifacts = match read_to_string(&artifacts_path) {
+            Ok(artifacts) => serde_json::from_str(&artifacts)?,
+            Err(_) => json!({ "artifacts": [] }),
+        };
+        let artifacts_array = artifacts["artifacts"]
+            .as_array_mut()
+            .ok_or_eyre("Bad artifacts file")?;
+
+        let download_path = format!(
+            "repos/{qualified_repo}/actions/artifacts/{run_id}-{}/zip",
+            artifacts_array.len()
+        );
+        create_dir_all(api_dir.join(&download_path).parent().expect("Has parent"))?;
+        write(api_dir.join(&download_path), contents)?;
+
+        artifacts_array.push(json!({
+            "name": name,
+            "created_at": Utc::now().to_rfc3339(),
+            "archive_download_url": format!("/{download_path}"),
+        }));
+        write(artifacts_path, artifacts.to_string())?;
+
+        Ok(())
+    }
+
+    fn fake_gh(&self, args: &[&str]) -> eyre::Result<()> {
+        let output = Command::new(fake_bin_path().join("gh"))
+            .env("FAKE_STATE_DIR", self.fake_state_dir())
+            .args(args)
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "Fake gh failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the state directory of the only guest with the given name prefix, if any.
+    fn guest_dir(&self, prefix: &str) -> eyre::Result<Option<PathBuf>> {
+        let libvirt_dir = self.fake_state_dir().join("libvirt");
+        let mut result = None;
+        if let Ok(entries) = read_dir(&libvirt_dir) {
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().starts_with(prefix) {
+                    if result.is_some() {
+                        bail!("More than one guest named {prefix}*");
+                    }
+                    result = Some(entry.path());
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Replaces `${variable}` references with saved values.
+    fn expand(&self, text: &str) -> String {
+        let mut result = text.to_owned();
+        for (variable, value) in self.variables.iter() {
+            result = result.replace(&format!("${{{variable}}}"), value);
+        }
+
+        result
+    }
+
+    /// Returns the last lines of each server’s log, for diagnosing failures.
+    fn logs(&self) -> String {
+        let mut result = String::default();
+        for process in self.processes.iter() {
+            let log = read_to_string(&process.log_path).unwrap_or_default();
+            let lines = log.lines().collect::<Vec<_>>();
+            let _ = writeln!(result, ">>> {:?} log (last 100 lines)", process.server);
+            for line in &lines[lines.len().saturating_sub(100)..] {
+                let _ = writeln!(result, "{line}");
+            }
+        }
+
+        result
+    }
+
+    fn fake_state_dir(&self) -> PathBuf {
+        self.dir.join("fake")
+    }
+
+    fn path(&self, path: &str) -> eyre::Result<String> {
+        Ok(self
+            .dir
+            .join(path)
+            .to_str()
+            .ok_or_eyre("Unsupported path")?
+            .to_owned())
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        for process in self.processes.iter_mut() {
+            let _ = process.child.kill();
+            let _ = process.child.wait();
+        }
+    }
+}
+
+/// Merges `overrides` into `settings`, recursively for tables like `profiles`.
+fn merge_settings(settings: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (settings.get_mut(&key), value) {
+            (Some(toml::Value::Table(settings)), toml::Value::Table(overrides)) => {
+                merge_settings(settings, overrides)
+            }
+            (_, value) => {
+                settings.insert(key, value);
+            }
+        }
+    }
+}
+
+fn unused_port() -> eyre::Result<u16> {
+    Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+}
+
+fn repo_path() -> eyre::Result<PathBuf> {
+    Ok(Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("..")
+        .canonicalize()?)
+}
+
+fn fake_bin_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fake/bin")
+}
+
+/// Returns a `PATH` where our fakes come first.
+fn fake_path() -> eyre::Result<String> {
+    let path = env::var("PATH").unwrap_or_default();
+    let fake_bin_path = fake_bin_path();
+    let fake_bin_path = fake_bin_path.to_str().ok_or_eyre("Unsupported path")?;
+
+    Ok(format!("{fake_bin_path}:{path}"))
+}
@@ -14,7 +14,10 @@
   gnused,
   jq,
   libvirt,
+  lvm2,
+  novnc,
   openssh,
+  qemu-utils,
   time,
   unzip,
   virt-manager,
@@ -51,10 +54,12 @@ in stdenv.mkDerivation rec {
     gnused  # for sed(1)
     jq
     libvirt  # for virsh(1)
+    lvm2  # for lvcreate(8), with the lvm-thin storage backend
     openssh  # for ssh(1)
+    qemu-utils  # for qemu-img(1), with storage backends other than reflink
     time  # for time(1)
     unzip  # for funzip(1)
-    virt-manager  # for virt-clone(1)
+    virt-manager  # for virt-clone(1), and virt-xml(1) with the qcow2 storage backend
     zfs
     zsh
   ];
@@ -76,7 +81,8 @@ in stdenv.mkDerivation rec {
   postFixup = ''
     wrapProgram $out/bin/monitor --set PATH ${lib.makeBinPath buildInputs} \
       --set LIB_MONITOR_DIR $out/lib/monitor \
-      --set IMAGE_DEPS_DIR ${image-deps}
+      --set IMAGE_DEPS_DIR ${image-deps} \
+      --set NOVNC_DIR ${novnc}/share/webapps/novnc
     wrapProgram $out/bin/queue --set PATH ${lib.makeBinPath buildInputs} \
       --set LIB_MONITOR_DIR $out/lib/queue \
       --set IMAGE_DEPS_DIR ${image-deps}